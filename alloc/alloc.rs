@@ -12,6 +12,7 @@ use spin::{Mutex, MutexGuard};
 
 use crate::{_heap_size, _heap_start};
 
+pub use self::frame::{FrameAlloc, FRAMES, init_frames};
 pub use self::global::Global;
 pub use self::heap::{HEAP, init_heap};
 pub use self::layout::Layout;
@@ -20,6 +21,7 @@ use self::page::PAGE_SIZE;
 pub mod comp;
 pub mod ctx;
 pub mod entity;
+pub mod frame;
 pub mod global;
 pub mod heap;
 pub mod layout;
@@ -0,0 +1,454 @@
+//! A physical frame allocator built from the memory map in the device tree.
+//!
+//! Every frame of RAM has one bit in a bitmap (set while the frame is in
+//! use or is not usable RAM at all), and every free frame is threaded onto
+//! an intrusive doubly-linked free list stored in the frame itself. Single
+//! frames are popped from and pushed onto the list in O(1); contiguous runs
+//! are found by scanning the bitmap and then unlinked frame by frame.
+
+use core::ptr::{self, null_mut};
+
+use spin::Mutex;
+
+use super::{align_up, page::PAGE_SIZE};
+use crate::{KERNEL_STACK_START, DATA_START, RODATA_END, TEXT_START};
+
+/// The size of a physical frame, the same as a base page.
+pub const FRAME_SIZE: usize = PAGE_SIZE;
+
+/// Either our global frame allocator, or `None` if it hasn't been built yet.
+pub static FRAMES: Mutex<Option<FrameAlloc>> = Mutex::new(None);
+
+/// Builds the global frame allocator from the usable `memory` ranges,
+/// keeping every frame that touches a `reserved` range out of it.
+///
+/// # Safety
+///
+/// Every frame of `memory` outside `reserved` is overwritten, and must be
+/// reachable at its physical address.
+pub unsafe fn init_frames<M, R>(memory: M, reserved: R)
+  where
+      M: Iterator<Item=Region> + Clone,
+      R: Iterator<Item=Region> + Clone,
+{
+  let mut frames = FRAMES.lock();
+  *frames = FrameAlloc::new(memory, reserved);
+}
+
+/// The ranges the kernel image occupies: text and read-only data, then
+/// data, bss, heap and stacks.
+pub fn kernel_image() -> [Region; 2]
+{
+  unsafe {
+    [
+      Region::new(TEXT_START, RODATA_END - TEXT_START),
+      Region::new(DATA_START, KERNEL_STACK_START - DATA_START),
+    ]
+  }
+}
+
+/// A range of physical memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region
+{
+  /// The first byte of the range.
+  pub base: usize,
+  /// The length of the range in bytes.
+  pub size: usize,
+}
+
+impl Region
+{
+  /// Creates a new `Region`.
+  #[inline]
+  pub const fn new(base: usize, size: usize) -> Self
+  {
+    Self { base, size }
+  }
+
+  /// One past the last byte of the range.
+  #[inline]
+  pub fn end(&self) -> usize
+  {
+    self.base.saturating_add(self.size)
+  }
+
+  /// Returns true if the two ranges share at least one byte.
+  #[inline]
+  pub fn overlaps(&self, other: &Region) -> bool
+  {
+    self.base < other.end() && other.base < self.end()
+  }
+}
+
+/// The links of a free frame, written into the first bytes of the frame.
+struct FreeFrame
+{
+  prev: *mut FreeFrame,
+  next: *mut FreeFrame,
+}
+
+/// The interface to the physical frames.
+///
+/// Like `Heap`, the allocator's own bookkeeping lives in the memory it
+/// manages: the bitmap is carved out of the first free spot large enough.
+pub struct FrameAlloc
+{
+  /// The physical address of the frame behind bit 0.
+  base: usize,
+
+  /// The number of frames the bitmap covers.
+  frames: usize,
+
+  /// One bit per frame, set while the frame is unavailable.
+  bitmap: *mut u64,
+
+  /// The first free frame, or null when everything is in use.
+  head: *mut FreeFrame,
+
+  /// The number of frames on the free list.
+  free: usize,
+
+  /// The number of usable frames, free or not.
+  total: usize,
+}
+
+unsafe impl Send for FrameAlloc {}
+
+impl FrameAlloc
+{
+  /// Builds an allocator over the usable `memory` ranges, keeping every
+  /// frame that touches a `reserved` range out of it.
+  ///
+  /// Returns `None` if there is no memory, or nowhere to put the bitmap.
+  ///
+  /// # Safety
+  ///
+  /// Every frame of `memory` outside `reserved` is overwritten, and must be
+  /// reachable at its physical address.
+  pub unsafe fn new<M, R>(memory: M, reserved: R) -> Option<Self>
+    where
+        M: Iterator<Item=Region> + Clone,
+        R: Iterator<Item=Region> + Clone,
+  {
+    let lo = memory.clone().map(|r| align_up(r.base, FRAME_SIZE)).min()?;
+    let hi = memory.clone().map(|r| r.end() & !(FRAME_SIZE - 1)).max()?;
+    if hi <= lo {
+      return None;
+    }
+
+    let frames = (hi - lo) / FRAME_SIZE;
+    let words = (frames + 63) / 64;
+    let bitmap_size = align_up(words * 8, FRAME_SIZE);
+    let bitmap = Self::place_bitmap(memory.clone(), reserved.clone(), bitmap_size)?;
+
+    // Start with everything unavailable, then free what we may use.
+    ptr::write_bytes(bitmap as *mut u64, 0xff, words);
+
+    let mut alloc = Self {
+      base: lo,
+      frames,
+      bitmap: bitmap as *mut u64,
+      head: null_mut(),
+      free: 0,
+      total: 0,
+    };
+
+    let taken = Region::new(bitmap, bitmap_size);
+    for r in memory {
+      let mut addr = align_up(r.base, FRAME_SIZE);
+      while addr + FRAME_SIZE <= r.end() {
+        let frame = Region::new(addr, FRAME_SIZE);
+        if !frame.overlaps(&taken) && !reserved.clone().any(|res| res.overlaps(&frame)) {
+          alloc.push(addr);
+          alloc.total += 1;
+        }
+        addr += FRAME_SIZE;
+      }
+    }
+
+    Some(alloc)
+  }
+
+  /// Finds `size` bytes of usable memory, outside every reserved range,
+  /// for the bitmap.
+  fn place_bitmap<M, R>(memory: M, reserved: R, size: usize) -> Option<usize>
+    where
+        M: Iterator<Item=Region>,
+        R: Iterator<Item=Region> + Clone,
+  {
+    for r in memory {
+      let mut addr = align_up(r.base, FRAME_SIZE);
+      while addr + size <= r.end() {
+        let candidate = Region::new(addr, size);
+        match reserved.clone().find(|res| res.overlaps(&candidate)) {
+          Some(res) => addr = align_up(res.end(), FRAME_SIZE),
+          None => return Some(addr),
+        }
+      }
+    }
+
+    None
+  }
+
+  /// The number of frames currently free.
+  #[inline]
+  pub fn free_frames(&self) -> usize
+  {
+    self.free
+  }
+
+  /// The number of usable frames, free or not.
+  #[inline]
+  pub fn total_frames(&self) -> usize
+  {
+    self.total
+  }
+
+  #[inline]
+  fn index(&self, addr: usize) -> usize
+  {
+    assert_eq!(addr % FRAME_SIZE, 0, "frame address is not aligned");
+    assert!(addr >= self.base, "frame address is below the managed memory");
+    let index = (addr - self.base) / FRAME_SIZE;
+    assert!(index < self.frames, "frame address is above the managed memory");
+    index
+  }
+
+  #[inline]
+  fn is_used(&self, index: usize) -> bool
+  {
+    unsafe { *self.bitmap.add(index / 64) & (1 << (index % 64)) != 0 }
+  }
+
+  #[inline]
+  fn set_used(&mut self, index: usize, used: bool)
+  {
+    unsafe {
+      let word = self.bitmap.add(index / 64);
+      if used {
+        *word |= 1 << (index % 64);
+      } else {
+        *word &= !(1 << (index % 64));
+      }
+    }
+  }
+
+  /// Marks the frame at `addr` free and puts it at the head of the list.
+  fn push(&mut self, addr: usize)
+  {
+    let index = self.index(addr);
+    self.set_used(index, false);
+
+    let frame = addr as *mut FreeFrame;
+    unsafe {
+      (*frame).prev = null_mut();
+      (*frame).next = self.head;
+      if !self.head.is_null() {
+        (*self.head).prev = frame;
+      }
+    }
+
+    self.head = frame;
+    self.free += 1;
+  }
+
+  /// Takes the free frame at `addr` off the list and marks it used.
+  fn unlink(&mut self, addr: usize)
+  {
+    let index = self.index(addr);
+    self.set_used(index, true);
+
+    let frame = addr as *mut FreeFrame;
+    unsafe {
+      let FreeFrame { prev, next } = ptr::read(frame);
+      if prev.is_null() {
+        self.head = next;
+      } else {
+        (*prev).next = next;
+      }
+      if !next.is_null() {
+        (*next).prev = prev;
+      }
+    }
+
+    self.free -= 1;
+  }
+
+  /// Allocates a single frame and returns its physical address.
+  pub fn alloc(&mut self) -> Option<usize>
+  {
+    if self.head.is_null() {
+      return None;
+    }
+
+    let addr = self.head as usize;
+    self.unlink(addr);
+    Some(addr)
+  }
+
+  /// Returns the frame at `addr` to the allocator.
+  pub fn free(&mut self, addr: usize)
+  {
+    let index = self.index(addr);
+    // If the following assertion fails, it is most likely
+    // caused by a double-free.
+    assert!(self.is_used(index), "Possible double-free detected!");
+    self.push(addr);
+  }
+
+  /// Allocates `count` physically contiguous frames and returns the
+  /// address of the first one.
+  pub fn alloc_contiguous(&mut self, count: usize) -> Option<usize>
+  {
+    assert!(count > 0);
+    if count == 1 {
+      return self.alloc();
+    }
+
+    let mut run = 0;
+    for index in 0..self.frames {
+      if self.is_used(index) {
+        run = 0;
+        continue;
+      }
+
+      run += 1;
+      if run == count {
+        let first = self.base + (index + 1 - count) * FRAME_SIZE;
+        for i in 0..count {
+          self.unlink(first + i * FRAME_SIZE);
+        }
+        return Some(first);
+      }
+    }
+
+    None
+  }
+
+  /// Returns `count` contiguous frames starting at `addr` to the allocator.
+  pub fn free_contiguous(&mut self, addr: usize, count: usize)
+  {
+    for i in 0..count {
+      self.free(addr + i * FRAME_SIZE);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use std::alloc::{alloc, dealloc, Layout};
+
+  /// A page-aligned piece of host memory standing in for RAM.
+  struct Ram
+  {
+    base: *mut u8,
+    size: usize,
+  }
+
+  impl Ram
+  {
+    fn new(frames: usize) -> Self
+    {
+      let size = frames * FRAME_SIZE;
+      let base = unsafe { alloc(Layout::from_size_align(size, FRAME_SIZE).unwrap()) };
+      Self { base, size }
+    }
+
+    fn region(&self) -> Region
+    {
+      Region::new(self.base as usize, self.size)
+    }
+  }
+
+  impl Drop for Ram
+  {
+    fn drop(&mut self)
+    {
+      unsafe { dealloc(self.base, Layout::from_size_align(self.size, FRAME_SIZE).unwrap()) };
+    }
+  }
+
+  #[test]
+  fn alloc_free()
+  {
+    let ram = Ram::new(16);
+    let mut frames = unsafe {
+      FrameAlloc::new(core::iter::once(ram.region()), core::iter::empty()).unwrap()
+    };
+
+    // One frame goes to the bitmap.
+    assert_eq!(frames.total_frames(), 15);
+    assert_eq!(frames.free_frames(), 15);
+
+    let a = frames.alloc().unwrap();
+    let b = frames.alloc().unwrap();
+    assert_ne!(a, b);
+    assert_eq!(frames.free_frames(), 13);
+
+    frames.free(a);
+    frames.free(b);
+    assert_eq!(frames.free_frames(), 15);
+
+    for _ in 0..15 {
+      assert!(frames.alloc().is_some());
+    }
+    assert_eq!(frames.alloc(), None);
+  }
+
+  #[test]
+  fn reserved()
+  {
+    let ram = Ram::new(16);
+    let base = ram.base as usize;
+    let reserved = [
+      Region::new(base, 2 * FRAME_SIZE),
+      Region::new(base + 10 * FRAME_SIZE + 1, 1),
+    ];
+    let mut frames = unsafe {
+      FrameAlloc::new(core::iter::once(ram.region()), reserved.iter().copied()).unwrap()
+    };
+
+    // Two reserved frames at the start, one in the middle, and the bitmap.
+    assert_eq!(frames.total_frames(), 12);
+    while let Some(addr) = frames.alloc() {
+      let frame = Region::new(addr, FRAME_SIZE);
+      assert!(!reserved.iter().any(|r| r.overlaps(&frame)));
+    }
+  }
+
+  #[test]
+  fn contiguous()
+  {
+    let ram = Ram::new(16);
+    let mut frames = unsafe {
+      FrameAlloc::new(core::iter::once(ram.region()), core::iter::empty()).unwrap()
+    };
+
+    let run = frames.alloc_contiguous(4).unwrap();
+    assert_eq!(frames.free_frames(), 11);
+    for _ in 0..11 {
+      let addr = frames.alloc().unwrap();
+      assert!(addr < run || addr >= run + 4 * FRAME_SIZE);
+    }
+
+    frames.free_contiguous(run, 4);
+    assert_eq!(frames.alloc_contiguous(4), Some(run));
+  }
+
+  #[test]
+  #[should_panic]
+  fn double_free()
+  {
+    let ram = Ram::new(4);
+    let mut frames = unsafe {
+      FrameAlloc::new(core::iter::once(ram.region()), core::iter::empty()).unwrap()
+    };
+
+    let a = frames.alloc().unwrap();
+    frames.free(a);
+    frames.free(a);
+  }
+}
@@ -1,7 +1,7 @@
 //! Memory allocations layout.
 
 use core::fmt::{self, Display};
-use core::mem::{size_of, align_of};
+use core::mem::{size_of, align_of, size_of_val, align_of_val};
 
 /// Defines the layout of memory to be allocated.
 #[derive(Copy, Clone)]
@@ -25,6 +25,16 @@ impl Layout
     }
   }
 
+  /// Creates a new instance of a Layout for the value `t`, which may be unsized.
+  #[inline]
+  pub fn for_value<T: ?Sized>(t: &T) -> Self
+  {
+    Layout {
+      size: size_of_val(t),
+      align: align_of_val(t),
+    }
+  }
+
   /// Creates a new instance of a Layout from the supplied type.
   #[inline]
   pub fn from_size(size: usize) -> Self
@@ -1,8 +1,7 @@
 use core::cmp::min;
-use core::ffi::c_void;
-use core::ptr::{NonNull, null_mut};
+use core::ptr::{self, NonNull};
 
-use crate::{_heap_size, _heap_start, alloc::{AllocRef, Layout}};
+use crate::alloc::{AllocRef, Layout, FRAMES};
 
 
 pub mod entry;
@@ -13,68 +12,44 @@ pub mod table;
 
 use table::*;
 
-static mut ALLOC_START: usize = 0;
-pub const PAGE_SIZE: usize = 1 << 12;
-const PAGE_ORDER: usize = 12;
+pub mod mode;
 
-/// These are the page flags, represented
-/// as a u8 since the Page stores this flag.
-#[repr(u8)]
-pub enum PageBits
-{
-  Empty = 0,
-  Taken = 1 << 0,
-  Last = 1 << 1,
-}
+pub use self::mode::PagingMode;
 
-pub struct Page
-{
-  flags: u8,
-}
+pub mod space;
 
-impl Page {
-  // If this page has been marked as the final allocation,
-  // this function returns true. Otherwise, it returns false.
-  pub fn is_last(&self) -> bool {
-    self.flags & PageBits::Last.val() != 0
-  }
+pub use self::space::AddressSpace;
 
-  // If the page is marked as being taken (allocated), then
-  // this function returns true. Otherwise, it returns false.
-  pub fn is_taken(&self) -> bool {
-    self.flags & PageBits::Taken.val() != 0
-  }
+pub const PAGE_SIZE: usize = 1 << 12;
+const PAGE_ORDER: usize = 12;
 
-  // This is the opposite of is_taken().
-  pub fn is_free(&self) -> bool {
-    !self.is_taken()
-  }
+/// The size of a 2 MiB megapage, mapped by a leaf at level 1.
+pub const MEGAPAGE_SIZE: usize = 1 << 21;
 
-  // Clear the Page structure and all associated allocations.
-  pub fn clear(&mut self) {
-    self.flags = PageBits::Empty.val();
-  }
+/// The size of a 1 GiB gigapage, mapped by a leaf at level 2.
+pub const GIGAPAGE_SIZE: usize = 1 << 30;
 
-  // Set a certain flag. We ran into trouble here since PageBits
-  // is an enumeration and we haven't implemented the BitOr Trait
-  // on it.
-  pub fn set_flag(&mut self, flag: PageBits) {
-    self.flags |= flag.val();
-  }
+/// The size of a 512 GiB terapage, mapped by a leaf at level 3 (Sv48 and up).
+pub const TERAPAGE_SIZE: usize = 1 << 39;
 
-  pub fn clear_flag(&mut self, flag: PageBits) {
-    self.flags &= !(flag.val());
-  }
+/// The number of bytes mapped by a leaf at `level`.
+#[inline]
+pub const fn level_size(level: usize) -> usize
+{
+  PAGE_SIZE << (9 * level)
 }
 
-
+/// Allocates whole pages, backed by the physical frame allocator.
+///
+/// For this allocator, `Layout::size()` is a number of pages rather than a
+/// number of bytes.
 #[derive(Copy, Clone)]
 pub struct PageAlloc;
 
 impl PageAlloc
 {
-  /// Map a virtual address to a physical address using 4096-byte page
-  /// size.
+  /// Map a virtual address to a physical address using a page of the
+  /// size given by `level`.
   /// root: a mutable reference to the root Table
   /// vaddr: The virtual address to map
   /// paddr: The physical address to map
@@ -84,9 +59,18 @@ impl PageAlloc
   ///       The bits MUST include one or more of the following:
   ///          Read, Write, Execute
   ///       The valid bit automatically gets added.
+  /// mode: The paging mode the table is laid out for.
+  /// level: 0 for a 4 KiB page, 1 for a 2 MiB megapage, 2 for a 1 GiB
+  ///        gigapage, and so on up to the root level of `mode`. Both
+  ///        addresses must be aligned to that size.
+  ///
+  /// If a larger leaf already covers `vaddr`, it is split so that the
+  /// rest of it keeps its old mapping. If a table already hangs off the
+  /// entry being replaced by a large leaf, that table is freed.
   pub fn map(
     &self,
     root: &mut Table,
+    mode: PagingMode,
     vaddr: usize,
     paddr: usize,
     bits: usize,
@@ -95,44 +79,43 @@ impl PageAlloc
     // Make sure that Read, Write, or Execute have been provided
     // otherwise, we'll leak memory and always create a page fault.
     assert!(bits & 0xe != 0);
-    // Extract out each VPN from the virtual address
-    // On the virtual address, each VPN is exactly 9 bits,
-    // which is why we use the mask 0x1ff = 0b1_1111_1111 (9 bits)
-    let vpn = [
-      // VPN[0] = vaddr[20:12]
-      (vaddr >> 12) & 0x1ff,
-      // VPN[1] = vaddr[29:21]
-      (vaddr >> 21) & 0x1ff,
-      // VPN[2] = vaddr[38:30]
-      (vaddr >> 30) & 0x1ff,
-    ];
-
-    // Just like the virtual address, extract the physical address
-    // numbers (PPN). However, PPN[2] is different in that it stores
-    // 26 bits instead of 9. Therefore, we use,
-    // 0x3ff_ffff = 0b11_1111_1111_1111_1111_1111_1111 (26 bits).
-    let ppn = [
-      // PPN[0] = paddr[20:12]
-      (paddr >> 12) & 0x1ff,
-      // PPN[1] = paddr[29:21]
-      (paddr >> 21) & 0x1ff,
-      // PPN[2] = paddr[55:30]
-      (paddr >> 30) & 0x3ff_ffff,
-    ];
+    assert!(level <= mode.root_level(), "no leaves above the root level");
+    // Leaves must be naturally aligned, otherwise the low PPN fields
+    // would be non-zero and the hardware raises a page fault.
+    assert_eq!(vaddr % level_size(level), 0, "misaligned virtual address");
+    assert_eq!(paddr % level_size(level), 0, "misaligned physical address");
+
+    let v = self.walk_create(root, mode, vaddr, level);
+    if level > 0 && v.is_valid() && v.is_branch() {
+      // A table used to live here; everything it mapped is replaced by
+      // the new large leaf.
+      unsafe { self.free_table(v.addr() as *mut Table, level - 1) };
+    }
+
+    // The entry structure is Figure 4.18 in the RISC-V Privileged
+    // Specification. Every mode stores the 44-bit PPN at [53:10], so the
+    // page number of the physical address is simply shifted into place.
+    let entry = ((paddr >> 12) << 10) |
+        bits |                    // Specified bits, such as User, Read, Write, etc
+        EntryBits::Valid.val() |  // Valid bit
+        EntryBits::Dirty.val() |  // Some machines require this to =1
+        EntryBits::Access.val()   // Just like dirty, some machines require this
+        ;
+    v.set_entry(entry);
+  }
+
+  /// Walk from the root down to the entry for `vaddr` at `level`,
+  /// allocating missing tables and splitting any larger leaf in the way.
+  fn walk_create<'a>(&self, root: &'a mut Table, mode: PagingMode, vaddr: usize, level: usize)
+      -> &'a mut Entry
+  {
     // We will use this as a floating reference so that we can set
     // individual entries as we walk the table.
-    let mut v = &mut root.entries[vpn[2]];
-    // Now, we're going to traverse the page table and set the bits
-    // properly. We expect the root to be valid, however we're required to
-    // create anything beyond the root.
-    // In Rust, we create a range iterator using the .. operator.
-    // The .rev() will reverse the iteration since we need to start with
-    // VPN[2] The .. operator is inclusive on start but exclusive on end.
-    // So, (0..2) will iterate 0 and 1.
-    for i in (level..2).rev() {
+    let mut v = &mut root.entries[mode.vpn(vaddr, mode.root_level())];
+    for i in (level..mode.root_level()).rev() {
       if !v.is_valid() {
         // Allocate a page
-        let page = unsafe { self.zalloc(1).unwrap() };
+        let page = unsafe { self.zalloc(1).unwrap().as_ptr() };
         // The page is already aligned by 4,096, so store it
         // directly The page is stored in the entry shifted
         // right by 2 places.
@@ -140,25 +123,132 @@ impl PageAlloc
           (page as usize >> 2)
               | EntryBits::Valid.val(),
         );
+      } else if v.is_leaf() {
+        // A larger page covers this address; break it up one level.
+        self.split(v, i + 1);
       }
-      let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
-      v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+      let entry = v.addr() as *mut Entry;
+      v = unsafe { entry.add(mode.vpn(vaddr, i)).as_mut().unwrap() };
     }
-    // When we get here, we should be at VPN[0] and v should be pointing to
-    // our entry.
-    // The entry structure is Figure 4.18 in the RISC-V Privileged
-    // Specification
-    let entry = (ppn[2] << 28) |   // PPN[2] = [53:28]
-        (ppn[1] << 19) |   // PPN[1] = [27:19]
-        (ppn[0] << 10) |   // PPN[0] = [18:10]
-        bits |                    // Specified bits, such as User, Read, Write, etc
-        EntryBits::Valid.val() |  // Valid bit
-        EntryBits::Dirty.val() |  // Some machines require this to =1
-        EntryBits::Access.val()   // Just like dirty, some machines require this
-        ;
-    // Set the entry. V should be set to the correct pointer by the loop
-    // above.
+
+    v
+  }
+
+  /// Replace the leaf `v`, which lives at `level`, with a table of 512
+  /// leaves one level down which map the same range with the same bits.
+  fn split(&self, v: &mut Entry, level: usize)
+  {
+    assert!(level > 0 && v.is_leaf());
+
+    let table = unsafe { self.zalloc(1).unwrap().as_ptr() as *mut Entry };
+    let step = (level_size(level - 1) >> 12) << 10;
+    for i in 0..Table::len() {
+      unsafe {
+        (*table.add(i)).set_entry(v.get_entry() + i * step);
+      }
+    }
+
+    v.set_entry((table as usize >> 2) | EntryBits::Valid.val());
+  }
+
+  /// Frees `table`, which lives at `level`, and every table below it.
+  /// Leaves are not touched; the memory they map is not owned by the table.
+  unsafe fn free_table(&self, table: *mut Table, level: usize)
+  {
+    if level > 0 {
+      for entry in (*table).entries.iter() {
+        if entry.is_valid() && entry.is_branch() {
+          self.free_table(entry.addr() as *mut Table, level - 1);
+        }
+      }
+    }
+
+    self.dealloc(table as *mut u8, Layout::from_size(1));
+  }
+
+  /// Find the leaf entry which maps `vaddr`, along with the level it
+  /// lives at.
+  fn leaf_mut(root: &mut Table, mode: PagingMode, vaddr: usize) -> Option<(&mut Entry, usize)>
+  {
+    let mut v = &mut root.entries[mode.vpn(vaddr, mode.root_level())];
+    for i in (0..=mode.root_level()).rev() {
+      if v.is_invalid() {
+        return None;
+      } else if v.is_leaf() {
+        return Some((v, i));
+      } else if i == 0 {
+        // A branch at level 0 is malformed; treat it as unmapped.
+        return None;
+      }
+
+      let entry = v.addr() as *mut Entry;
+      v = unsafe { entry.add(mode.vpn(vaddr, i - 1)).as_mut().unwrap() };
+    }
+
+    None
+  }
+
+  /// The level of the leaf which maps `vaddr`, or `None` if unmapped.
+  pub fn leaf_level(root: &mut Table, mode: PagingMode, vaddr: usize) -> Option<usize>
+  {
+    Self::leaf_mut(root, mode, vaddr).map(|(_, level)| level)
+  }
+
+  /// Find the leaf which maps `vaddr`, splitting it until it is no
+  /// larger than `level`.
+  fn leaf_at<'a>(&self, root: &'a mut Table, mode: PagingMode, vaddr: usize, level: usize)
+      -> Option<&'a mut Entry>
+  {
+    match Self::leaf_level(root, mode, vaddr)? {
+      found if found > level => Some(self.walk_create(root, mode, vaddr, level)),
+      _ => Self::leaf_mut(root, mode, vaddr).map(|(v, _)| v),
+    }
+  }
+
+  /// Remove the mapping of the `level`-sized page at `vaddr`.
+  ///
+  /// If the leaf covering `vaddr` is larger than `level`, it is split
+  /// first so that the rest of it stays mapped. The tables leading up to
+  /// the leaf are left in place; they are freed by `unmap` when the whole
+  /// table is torn down.
+  /// Returns false if `vaddr` was not mapped.
+  pub fn unmap_page(&self, root: &mut Table, mode: PagingMode, vaddr: usize, level: usize) -> bool
+  {
+    assert_eq!(vaddr % level_size(level), 0, "misaligned virtual address");
+
+    match self.leaf_at(root, mode, vaddr, level) {
+      Some(v) => {
+        v.set_entry(EntryBits::None.val());
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Replace the permission bits of the `level`-sized page at `vaddr`.
+  ///
+  /// `bits` follows the same rules as for `map`. A larger leaf covering
+  /// `vaddr` is split first so the rest of it keeps its permissions.
+  /// Returns false if `vaddr` was not mapped.
+  pub fn protect_page(
+    &self,
+    root: &mut Table,
+    mode: PagingMode,
+    vaddr: usize,
+    bits: usize,
+    level: usize) -> bool
+  {
+    assert!(bits & 0xe != 0);
+    assert_eq!(vaddr % level_size(level), 0, "misaligned virtual address");
+
+    let v = match self.leaf_at(root, mode, vaddr, level) {
+      Some(v) => v,
+      None => return false,
+    };
+
+    let entry = (v.get_entry() & !EntryBits::Permissions.val()) | bits;
     v.set_entry(entry);
+    true
   }
 
   /// Unmaps and frees all memory associated with a table.
@@ -167,35 +257,18 @@ impl PageAlloc
   /// freed manually.
   /// The reason we don't free the root is because it is
   /// usually embedded into the Process structure.
-  pub fn unmap(&self, root: &mut Table) {
-    unsafe {
-      // Start with level 2
-      for lv2 in 0..Table::len() {
-        let ref entry_lv2 = root.entries[lv2];
-        if entry_lv2.is_valid() && entry_lv2.is_branch() {
-          // This is a valid entry, so drill down and free.
-          let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-          let table_lv1 = unsafe {
-            // Make table_lv1 a mutable reference instead of
-            // a pointer.
-            (memaddr_lv1 as *mut Table).as_mut().unwrap()
-          };
-          for lv1 in 0..Table::len() {
-            let ref entry_lv1 = table_lv1.entries[lv1];
-            if entry_lv1.is_valid() && entry_lv1.is_branch()
-            {
-              // The next level is level 0, which
-              // cannot have branches, therefore,
-              // we free here.
-              let memaddr_lv0 = (entry_lv1.get_entry()
-                  & !0x3ff) << 2;
-
-              self.dealloc(memaddr_lv0 as *mut u8, Layout::from_size(entry_lv1.len()));
-            }
-          }
-          self.dealloc(memaddr_lv1 as *mut u8, Layout::from_size(entry_lv2.len()));
-        }
+  ///
+  /// Leaves may live at any level; only the tables below the root are
+  /// freed, never the memory the leaves map. `mode` is the paging mode
+  /// the table is laid out for.
+  pub fn unmap(&self, root: &mut Table, mode: PagingMode) {
+    let below_root = mode.root_level() - 1;
+    for entry in root.entries.iter_mut() {
+      if entry.is_valid() && entry.is_branch() {
+        // This is a valid entry, so drill down and free.
+        unsafe { self.free_table(entry.addr() as *mut Table, below_root) };
       }
+      entry.set_entry(EntryBits::None.val());
     }
   }
 
@@ -203,19 +276,10 @@ impl PageAlloc
   /// physical address.
   /// If a page fault would occur, this returns None
   /// Otherwise, it returns Some with the physical address.
-  pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
+  pub fn virt_to_phys(root: &Table, mode: PagingMode, vaddr: usize) -> Option<usize> {
     // Walk the page table pointed to by root
-    let vpn = [
-      // VPN[0] = vaddr[20:12]
-      (vaddr >> 12) & 0x1ff,
-      // VPN[1] = vaddr[29:21]
-      (vaddr >> 21) & 0x1ff,
-      // VPN[2] = vaddr[38:30]
-      (vaddr >> 30) & 0x1ff,
-    ];
-
-    let mut v = &root.entries[vpn[2]];
-    for i in (0..=2).rev() {
+    let mut v = &root.entries[mode.vpn(vaddr, mode.root_level())];
+    for i in (0..=mode.root_level()).rev() {
       if v.is_invalid() {
         // This is an invalid entry, page fault.
         break;
@@ -225,19 +289,20 @@ impl PageAlloc
         // The offset mask masks off the PPN. Each PPN is 9
         // bits and they start at bit #12. So, our formula
         // 12 + i * 9
-        let off_mask = (1 << (12 + i * 9)) - 1;
+        let off_mask = level_size(i) - 1;
         let vaddr_pgoff = vaddr & off_mask;
-        let addr = ((v.get_entry() << 2) as usize) & !off_mask;
+        let addr = v.addr() & !off_mask;
         return Some(addr | vaddr_pgoff);
+      } else if i == 0 {
+        // A branch at level 0 has nowhere to point, page fault.
+        break;
       }
       // Set v to the next entry which is pointed to by this
       // entry. However, the address was shifted right by 2 places
       // when stored in the page table entry, so we shift it left
       // to get it back into place.
-      let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
-      // We do i - 1 here, however we should get None or Some() above
-      // before we do 0 - 1 = -1.
-      v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
+      let entry = v.addr() as *const Entry;
+      v = unsafe { entry.add(mode.vpn(vaddr, i - 1)).as_ref().unwrap() };
     }
 
     // If we get here, we've exhausted all valid tables and haven't
@@ -250,90 +315,216 @@ unsafe impl AllocRef for PageAlloc
 {
   unsafe fn alloc(&self, layout: Layout) -> Option<NonNull<u8>>
   {
-    // Need to find a contiguous allocation of pages.
     assert!(layout.size() > 0);
-    // We create a Page structure for each page on the heap.
-    // We actually might have more since HEAP_SIZE moves as
-    // well as the size of our structure, but we'll only waste a few bytes.
-    let num_pages = _heap_size / PAGE_SIZE;
-    let ptr = _heap_start as *mut Page;
-    for i in 0..num_pages - layout.size() {
-      let mut found = false;
-      // Check to see if this Page is free.
-      // If so, we have our first candidate memory address.
-      if (*ptr.add(i)).is_free() {
-        // It was free!
-        found = true;
-        for j in i..i + layout.size() {
-          // Now check to see if we have a contiguous allocation
-          // for all of the requested pages.
-          // If not, we should check elsewhere.
-          if (*ptr.add(j)).is_taken() {
-            found = false;
-            break;
-          }
-        }
-      }
-      // We've checked to see if there are enough contiguous
-      // pages to form what we need.
-      // If we couldn't, 'found' will be false, otherwise it will be true.
-      // Which means that we've found valid memory that we can allocate.
-      if found {
-        for k in i..i + layout.size() - 1 {
-          (*ptr.add(k)).set_flag(PageBits::Taken);
-        }
+    let addr = FRAMES
+        .lock()
+        .as_mut()
+        .expect("must initialise frames before calling!")
+        .alloc_contiguous(layout.size())?;
+
+    NonNull::new(addr as *mut u8)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+  {
+    // Make sure we don't try to free a null pointer.
+    assert!(!ptr.is_null());
+    FRAMES
+        .lock()
+        .as_mut()
+        .expect("must initialise frames before calling!")
+        .free_contiguous(ptr as usize, layout.size().max(1));
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, layout: Layout) -> Option<NonNull<u8>>
+  {
+    let new_ptr = self.alloc(layout)?;
+    ptr::copy_nonoverlapping(ptr, new_ptr.as_ptr(), min(layout.size(), old_size) * PAGE_SIZE);
+    self.dealloc(ptr, Layout::from_size(old_size));
+
+    Some(new_ptr)
+  }
+
+  unsafe fn zalloc(&self, pages: usize) -> Option<NonNull<u8>>
+  {
+    let ret = self.alloc(Layout::from_size(pages))?;
+    ptr::write_bytes(ret.as_ptr(), 0, pages * PAGE_SIZE);
 
-        (*ptr.add(i + layout.size() - 1)).set_flag(PageBits::Taken);
-        (*ptr.add(i + layout.size() - 1)).set_flag(PageBits::Taken);
+    Some(ret)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::alloc::frame::{init_frames, Region};
+  use std::sync::{Mutex, MutexGuard};
+
+  /// Somewhere in the second 128 GiB, so that the modes index it differently.
+  const VADDR: usize = 0x20_4000_0000;
+  const PADDR: usize = 0x8020_0000;
 
-        return Some(NonNull::new_unchecked((ALLOC_START + PAGE_SIZE * i) as *mut u8));
+  /// Host memory standing in for RAM, from which every table is taken. The
+  /// tests share the global frame allocator, so they take turns.
+  fn ram() -> MutexGuard<'static, ()>
+  {
+    static TURN: Mutex<()> = Mutex::new(());
+    let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+    if FRAMES.lock().is_none() {
+      let size = 64 * PAGE_SIZE;
+      let layout = std::alloc::Layout::from_size_align(size, PAGE_SIZE).unwrap();
+      unsafe {
+        let base = std::alloc::alloc(layout) as usize;
+        init_frames(core::iter::once(Region::new(base, size)), core::iter::empty());
       }
     }
+    turn
+  }
 
-    // If we get here, it means that no contiguous allocation was found.
-    None
+  fn free_frames() -> usize
+  {
+    FRAMES.lock().as_ref().unwrap().free_frames()
   }
 
-  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+  /// A root table for `mode`, freed with everything below it.
+  struct Root
   {
-    // Make sure we don't try to free a null pointer.
-    assert!(!ptr.is_null());
-    let addr =
-        _heap_start + (ptr as usize - ALLOC_START) / PAGE_SIZE;
-    // Make sure that the address makes sense. The address we
-    // calculate here is the page structure, not the HEAP address!
-    assert!(addr >= _heap_start && addr < ALLOC_START);
-    let mut p = addr as *mut Page;
-    // println!("PTR in is {:p}, addr is 0x{:x}", ptr, addr);
-    assert!((*p).is_taken(), "Freeing a non-taken page?");
-    // Keep clearing pages until we hit the last page.
-    while (*p).is_taken() && !(*p).is_last() {
-      (*p).clear();
-      p = p.add(1);
+    table: NonNull<Table>,
+    mode: PagingMode,
+  }
+
+  impl Root
+  {
+    fn new(mode: PagingMode) -> Self
+    {
+      let table = unsafe { PageAlloc.zalloc(1).unwrap().cast::<Table>() };
+      Self { table, mode }
+    }
+
+    fn table(&mut self) -> &mut Table
+    {
+      unsafe { self.table.as_mut() }
+    }
+
+    fn map(&mut self, vaddr: usize, paddr: usize, bits: usize, level: usize)
+    {
+      let mode = self.mode;
+      PageAlloc.map(self.table(), mode, vaddr, paddr, bits, level);
+    }
+
+    fn translate(&mut self, vaddr: usize) -> Option<usize>
+    {
+      let mode = self.mode;
+      PageAlloc::virt_to_phys(self.table(), mode, vaddr)
+    }
+
+    /// The permission bits and level of the leaf mapping `vaddr`.
+    fn leaf(&mut self, vaddr: usize) -> Option<(usize, usize)>
+    {
+      let mode = self.mode;
+      PageAlloc::leaf_mut(self.table(), mode, vaddr).map(|(v, level)| (v.bits(), level))
     }
-    // If the following assertion fails, it is most likely
-    // caused by a double-free.
-    assert!(
-      (*p).is_last() == true,
-      "Possible double-free detected! (Not taken found \
-		         before last)"
-    );
-    // If we get here, we've taken care of all previous pages and
-    // we are on the last page.
-    (*p).clear();
   }
 
-  unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, layout: Layout) -> Option<NonNull<u8>>
+  impl Drop for Root
   {
-    let new_ptr = self.alloc(layout);
-    if new_ptr.is_none() {
-      return new_ptr;
-    } else {
-      ptr::copy(ptr, new_ptr, min(layout.size(), old_size));
+    fn drop(&mut self)
+    {
+      let mode = self.mode;
+      PageAlloc.unmap(self.table(), mode);
+      unsafe { PageAlloc.dealloc(self.table.as_ptr() as *mut u8, Layout::from_size(1)) };
+    }
+  }
 
-      self.dealloc(ptr, layout);
+  #[test]
+  fn split()
+  {
+    let _ram = ram();
+    let rw = EntryBits::ReadWrite.val();
+    let rx = EntryBits::ReadExecute.val();
+
+    for &mode in PagingMode::ALL.iter() {
+      let before = free_frames();
+      let mut root = Root::new(mode);
+
+      // A leaf as large as the mode allows, then a page in the middle of it.
+      let top = mode.root_level();
+      let base = VADDR - VADDR % level_size(top);
+      root.map(base, base, rw, top);
+      assert_eq!(root.leaf(VADDR), Some((rw, top)));
+      root.map(VADDR + 0x3000, 0x9000_0000, rx, 0);
+
+      // One table for each level the leaf was split through.
+      assert_eq!(free_frames(), before - 1 - top);
+      assert_eq!(root.translate(VADDR + 0x3005), Some(0x9000_0005));
+      assert_eq!(root.leaf(VADDR + 0x3000), Some((rx, 0)));
+      assert_eq!(root.translate(VADDR + 0x2fff), Some(VADDR + 0x2fff));
+      assert_eq!(root.leaf(VADDR + 0x4000), Some((rw, 0)));
+      assert_eq!(root.leaf(VADDR + MEGAPAGE_SIZE), Some((rw, 1)));
+      let last = base + level_size(top) - 1;
+      assert_eq!(root.translate(last), Some(last));
+
+      // A large leaf over small ones frees the table they were in.
+      root.map(VADDR, PADDR, rw, 1);
+      assert_eq!(free_frames(), before - top);
+      assert_eq!(root.translate(VADDR + 0x3005), Some(PADDR + 0x3005));
+
+      drop(root);
+      assert_eq!(free_frames(), before);
+    }
+  }
+
+  #[test]
+  fn unmap_partial()
+  {
+    let _ram = ram();
+    let rw = EntryBits::ReadWrite.val();
+
+    for &mode in PagingMode::ALL.iter() {
+      let before = free_frames();
+      let mut root = Root::new(mode);
+      root.map(VADDR, PADDR, rw, 1);
+
+      let table = root.table();
+      assert!(PageAlloc.unmap_page(table, mode, VADDR + 0x2000, 0));
+      assert!(!PageAlloc.unmap_page(table, mode, VADDR + 0x2000, 0));
+      assert!(!PageAlloc.unmap_page(table, mode, VADDR + MEGAPAGE_SIZE, 0));
+      assert_eq!(root.translate(VADDR + 0x2000), None);
+      assert_eq!(root.translate(VADDR + 0x1fff), Some(PADDR + 0x1fff));
+      assert_eq!(root.translate(VADDR + 0x3000), Some(PADDR + 0x3000));
+      assert_eq!(root.leaf(VADDR + 0x1f_f000), Some((rw, 0)));
+
+      // Unmapping the whole table frees everything but the root.
+      PageAlloc.unmap(root.table(), mode);
+      assert_eq!(root.translate(VADDR), None);
+      assert_eq!(free_frames(), before - 1);
+    }
+  }
 
-      new_ptr
+  #[test]
+  fn protect()
+  {
+    let _ram = ram();
+    let r = EntryBits::Read.val();
+    let rw = EntryBits::ReadWrite.val();
+
+    for &mode in PagingMode::ALL.iter() {
+      let mut root = Root::new(mode);
+      root.map(VADDR, PADDR, rw, 1);
+
+      let table = root.table();
+      assert!(PageAlloc.protect_page(table, mode, VADDR + 0x5000, r, 0));
+      assert!(!PageAlloc.protect_page(table, mode, VADDR + MEGAPAGE_SIZE, r, 0));
+      assert_eq!(root.leaf(VADDR + 0x5000), Some((r, 0)));
+      assert_eq!(root.leaf(VADDR + 0x6000), Some((rw, 0)));
+      assert_eq!(root.translate(VADDR + 0x5123), Some(PADDR + 0x5123));
+
+      // A leaf no larger than the page is changed whole.
+      root.map(VADDR + MEGAPAGE_SIZE, PADDR, rw, 1);
+      let table = root.table();
+      assert!(PageAlloc.protect_page(table, mode, VADDR + MEGAPAGE_SIZE, r, 1));
+      assert_eq!(root.leaf(VADDR + MEGAPAGE_SIZE + 0x6000), Some((r, 1)));
     }
   }
 }
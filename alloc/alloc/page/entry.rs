@@ -1,7 +1,9 @@
-
+/// A single page table entry (PTE).
+#[derive(Copy, Clone)]
+#[repr(transparent)]
 pub struct Entry
 {
-  pub entry: i64,
+  pub entry: usize,
 }
 
 impl Entry
@@ -11,15 +13,70 @@ impl Entry
     self.get() & EntryBits::Valid.val() != 0
   }
 
-  pub fn set(&mut self, entry: i64)
+  pub fn set(&mut self, entry: usize)
   {
     self.entry = entry;
   }
 
-  pub fn get(&self) -> i64
+  pub fn get(&self) -> usize
   {
     self.entry
   }
+
+  /// Returns true if the valid bit of this entry is set.
+  #[inline]
+  pub fn is_valid(&self) -> bool
+  {
+    self.valid()
+  }
+
+  /// The opposite of `is_valid()`.
+  #[inline]
+  pub fn is_invalid(&self) -> bool
+  {
+    !self.is_valid()
+  }
+
+  /// A leaf has one or more of the Read, Write or Execute bits set.
+  #[inline]
+  pub fn is_leaf(&self) -> bool
+  {
+    self.get() & 0xe != 0
+  }
+
+  /// A branch has none of the Read, Write or Execute bits set.
+  #[inline]
+  pub fn is_branch(&self) -> bool
+  {
+    !self.is_leaf()
+  }
+
+  #[inline]
+  pub fn set_entry(&mut self, entry: usize)
+  {
+    self.set(entry);
+  }
+
+  #[inline]
+  pub fn get_entry(&self) -> usize
+  {
+    self.get()
+  }
+
+  /// The physical address this entry points to, either the next
+  /// table (for a branch) or the start of the page (for a leaf).
+  #[inline]
+  pub fn addr(&self) -> usize
+  {
+    (self.get() & !0x3ff) << 2
+  }
+
+  /// The permission bits (R, W, X, U, G) of this entry.
+  #[inline]
+  pub fn bits(&self) -> usize
+  {
+    self.get() & EntryBits::Permissions.val()
+  }
 }
 
 #[repr(usize)]
@@ -45,6 +102,9 @@ pub enum EntryBits
   UserReadWrite = 1 << 1 | 1 << 2 | 1 << 4,
   UserReadExecute = 1 << 1 | 1 << 3 | 1 << 4,
   UserReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
+
+  // Every bit a caller may pass to `PageAlloc::map`.
+  Permissions = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4 | 1 << 5,
 }
 
 impl EntryBits
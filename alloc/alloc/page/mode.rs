@@ -0,0 +1,141 @@
+//! Implements the `PagingMode` type, which describes the shape of the page
+//! tables for each of the RISC-V virtual memory schemes.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// The paging mode every new table is built for.
+static CURRENT: AtomicU8 = AtomicU8::new(PagingMode::Sv39 as u8);
+
+/// A RISC-V virtual memory scheme.
+///
+/// The discriminant is the value of the `MODE` field of `satp`.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingMode
+{
+  /// Three levels of tables, 39-bit virtual addresses.
+  Sv39 = 8,
+  /// Four levels of tables, 48-bit virtual addresses.
+  Sv48 = 9,
+  /// Five levels of tables, 57-bit virtual addresses.
+  Sv57 = 10,
+}
+
+impl PagingMode
+{
+  /// Every mode, from the largest address space to the smallest.
+  pub const ALL: [PagingMode; 3] = [PagingMode::Sv57, PagingMode::Sv48, PagingMode::Sv39];
+
+  /// The mode new address spaces are built for.
+  #[inline]
+  pub fn current() -> Self
+  {
+    Self::from_satp_mode(CURRENT.load(Ordering::Relaxed) as usize).unwrap_or(PagingMode::Sv39)
+  }
+
+  /// Select the mode new address spaces are built for.
+  ///
+  /// Tables built for a different mode must not be used afterwards.
+  #[inline]
+  pub fn set_current(mode: Self)
+  {
+    CURRENT.store(mode as u8, Ordering::Relaxed);
+  }
+
+  /// Decode the `MODE` field of `satp`.
+  pub fn from_satp_mode(mode: usize) -> Option<Self>
+  {
+    match mode {
+      8 => Some(PagingMode::Sv39),
+      9 => Some(PagingMode::Sv48),
+      10 => Some(PagingMode::Sv57),
+      _ => None,
+    }
+  }
+
+  /// Decode the `mmu-type` property of a device tree cpu node, such as
+  /// `"riscv,sv48"`. `"riscv,none"` and unknown schemes give `None`.
+  pub fn from_mmu_type(mmu_type: &str) -> Option<Self>
+  {
+    match mmu_type {
+      "riscv,sv39" => Some(PagingMode::Sv39),
+      "riscv,sv48" => Some(PagingMode::Sv48),
+      "riscv,sv57" => Some(PagingMode::Sv57),
+      _ => None,
+    }
+  }
+
+  /// The value of the `MODE` field of `satp` for this mode.
+  #[inline]
+  pub fn satp_mode(self) -> usize
+  {
+    self as usize
+  }
+
+  /// The number of levels of tables, including the root.
+  #[inline]
+  pub fn levels(self) -> usize
+  {
+    match self {
+      PagingMode::Sv39 => 3,
+      PagingMode::Sv48 => 4,
+      PagingMode::Sv57 => 5,
+    }
+  }
+
+  /// The level of the root table; leaves may live at any level up to it.
+  #[inline]
+  pub fn root_level(self) -> usize
+  {
+    self.levels() - 1
+  }
+
+  /// The number of significant bits in a virtual address.
+  #[inline]
+  pub fn va_bits(self) -> usize
+  {
+    12 + 9 * self.levels()
+  }
+
+  /// Extract the virtual page number `vaddr` uses to index the table at `level`.
+  ///
+  /// On the virtual address, each VPN is exactly 9 bits, which is why
+  /// we use the mask 0x1ff = 0b1_1111_1111 (9 bits).
+  #[inline]
+  pub fn vpn(self, vaddr: usize, level: usize) -> usize
+  {
+    (vaddr >> (12 + 9 * level)) & 0x1ff
+  }
+
+  /// Find the largest mode the current hart accepts, trying each mode
+  /// from Sv57 down to Sv39.
+  ///
+  /// `satp` ignores writes which select a mode the hart does not
+  /// implement, so each candidate is written and read back. `satp` is
+  /// left at zero (bare) afterwards.
+  ///
+  /// # Safety
+  ///
+  /// Must run in M-mode, where `satp` does not translate the caller's own
+  /// accesses, since the probe briefly installs a root table at address 0.
+  pub unsafe fn probe() -> Option<Self>
+  {
+    for &mode in Self::ALL.iter() {
+      let wanted = mode.satp_mode() << 60;
+      let read: usize;
+      asm!(
+        "csrw satp, {0}",
+        "csrr {1}, satp",
+        "csrw satp, zero",
+        in(reg) wanted,
+        out(reg) read,
+      );
+
+      if read >> 60 == mode.satp_mode() {
+        return Some(mode);
+      }
+    }
+
+    None
+  }
+}
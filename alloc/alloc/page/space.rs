@@ -0,0 +1,288 @@
+//! Implements an `AddressSpace` type on top of `PageAlloc`.
+//!
+//! An address space owns a root `Table` and everything reachable from it.
+//! It maps, unmaps and protects whole ranges at a time, and can install
+//! itself into `satp` to turn on translation for the current hart, in
+//! whichever `PagingMode` was current when the space was created.
+
+use core::cmp::min;
+use core::ptr::NonNull;
+
+use super::{level_size, PageAlloc, PagingMode, PAGE_SIZE, entry::EntryBits, table::Table};
+use crate::alloc::{align_up, AllocRef, Layout};
+use crate::{
+  BSS_END, BSS_START, DATA_END, DATA_START, HEAP_SIZE, HEAP_START, KERNEL_STACK_END,
+  KERNEL_STACK_START, RODATA_END, RODATA_START, TEXT_END, TEXT_START,
+};
+
+/// The largest address space identifier representable in `satp`.
+pub const MAX_ASID: u16 = 0xffff;
+
+/// A virtual address space rooted at a single page table.
+pub struct AddressSpace
+{
+  /// The root table, allocated from `PageAlloc`.
+  root: NonNull<Table>,
+
+  /// The paging mode the tables of this space are laid out for.
+  mode: PagingMode,
+
+  /// The address space identifier tagged onto this space's TLB entries.
+  asid: u16,
+}
+
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace
+{
+  /// Allocates an empty address space tagged with `asid`, laid out for
+  /// the current `PagingMode`.
+  ///
+  /// Returns `None` if no page is available for the root table.
+  pub fn new(asid: u16) -> Option<Self>
+  {
+    let root = unsafe { PageAlloc.zalloc(1)? };
+
+    Some(Self {
+      root: root.cast::<Table>(),
+      mode: PagingMode::current(),
+      asid,
+    })
+  }
+
+  /// The paging mode of this space.
+  #[inline]
+  pub fn mode(&self) -> PagingMode
+  {
+    self.mode
+  }
+
+  /// The address space identifier of this space.
+  #[inline]
+  pub fn asid(&self) -> u16
+  {
+    self.asid
+  }
+
+  /// The root table of this space.
+  #[inline]
+  pub fn root(&self) -> &Table
+  {
+    unsafe { self.root.as_ref() }
+  }
+
+  #[inline]
+  fn root_mut(&mut self) -> &mut Table
+  {
+    unsafe { self.root.as_mut() }
+  }
+
+  /// Maps `size` bytes at `vaddr` onto the physical range starting at `paddr`.
+  ///
+  /// Both addresses must be page aligned; `size` is rounded up to a whole
+  /// number of pages. `bits` follows the same rules as `PageAlloc::map`.
+  /// Wherever both addresses are suitably aligned, the range is mapped
+  /// with the largest leaves the paging mode allows instead of 4 KiB ones.
+  /// Whatever was mapped in the range before is replaced, and its TLB
+  /// entries flushed if this space is active.
+  pub fn map(&mut self, vaddr: usize, paddr: usize, size: usize, bits: usize)
+  {
+    assert_eq!(vaddr % PAGE_SIZE, 0, "unaligned virtual address");
+    assert_eq!(paddr % PAGE_SIZE, 0, "unaligned physical address");
+
+    let mode = self.mode;
+    let size = align_up(size, PAGE_SIZE);
+    let mut off = 0;
+    while off < size {
+      let level = self.fit_level(vaddr + off, paddr + off, size - off);
+      PageAlloc.map(self.root_mut(), mode, vaddr + off, paddr + off, bits, level);
+      self.flush_leaf(vaddr + off, level);
+      off += level_size(level);
+    }
+  }
+
+  /// Removes every mapping in the `size` bytes starting at `vaddr`.
+  ///
+  /// Pages in the range which were never mapped are skipped, and large
+  /// pages which straddle either end of the range are split. The TLB
+  /// entries for the range are flushed if this space is active.
+  pub fn unmap(&mut self, vaddr: usize, size: usize)
+  {
+    assert_eq!(vaddr % PAGE_SIZE, 0, "unaligned virtual address");
+
+    let mode = self.mode;
+    let size = align_up(size, PAGE_SIZE);
+    let mut off = 0;
+    while off < size {
+      let addr = vaddr + off;
+      let level = match PageAlloc::leaf_level(self.root_mut(), mode, addr) {
+        Some(found) => min(found, self.fit_level(addr, addr, size - off)),
+        None => {
+          off += PAGE_SIZE;
+          continue;
+        }
+      };
+
+      PageAlloc.unmap_page(self.root_mut(), mode, addr, level);
+      self.flush(addr);
+      off += level_size(level);
+    }
+  }
+
+  /// Changes the permissions of every mapped page in the `size` bytes
+  /// starting at `vaddr` to `bits`.
+  ///
+  /// Large pages which straddle either end of the range are split so that
+  /// the memory outside the range keeps its old permissions.
+  pub fn protect(&mut self, vaddr: usize, size: usize, bits: usize)
+  {
+    assert_eq!(vaddr % PAGE_SIZE, 0, "unaligned virtual address");
+
+    let mode = self.mode;
+    let size = align_up(size, PAGE_SIZE);
+    let mut off = 0;
+    while off < size {
+      let addr = vaddr + off;
+      let level = match PageAlloc::leaf_level(self.root_mut(), mode, addr) {
+        Some(found) => min(found, self.fit_level(addr, addr, size - off)),
+        None => {
+          off += PAGE_SIZE;
+          continue;
+        }
+      };
+
+      PageAlloc.protect_page(self.root_mut(), mode, addr, bits, level);
+      self.flush(addr);
+      off += level_size(level);
+    }
+  }
+
+  /// Maps the physical range `start..end` onto the same virtual addresses.
+  ///
+  /// `start` is rounded down and `end` rounded up to page boundaries.
+  pub fn identity_map(&mut self, start: usize, end: usize, bits: usize)
+  {
+    let start = start & !(PAGE_SIZE - 1);
+    let end = align_up(end, PAGE_SIZE);
+    if end > start {
+      self.map(start, start, end - start, bits);
+    }
+  }
+
+  /// Identity maps every section of the kernel image, the kernel stacks
+  /// and the heap, with the permissions each of them needs.
+  pub fn map_kernel(&mut self)
+  {
+    let global = EntryBits::Global.val();
+
+    unsafe {
+      self.identity_map(TEXT_START, TEXT_END, EntryBits::ReadExecute.val() | global);
+      self.identity_map(RODATA_START, RODATA_END, EntryBits::Read.val() | global);
+      self.identity_map(DATA_START, DATA_END, EntryBits::ReadWrite.val() | global);
+      self.identity_map(BSS_START, BSS_END, EntryBits::ReadWrite.val() | global);
+      // The stack grows down from KERNEL_STACK_START to KERNEL_STACK_END.
+      self.identity_map(KERNEL_STACK_END, KERNEL_STACK_START, EntryBits::ReadWrite.val() | global);
+      self.identity_map(HEAP_START, HEAP_START + HEAP_SIZE, EntryBits::ReadWrite.val() | global);
+    }
+  }
+
+  /// Walks this space's tables to translate `vaddr` into a physical address.
+  #[inline]
+  pub fn translate(&self, vaddr: usize) -> Option<usize>
+  {
+    PageAlloc::virt_to_phys(self.root(), self.mode, vaddr)
+  }
+
+  /// The value to write into `satp` to make this space current.
+  #[inline]
+  pub fn satp(&self) -> usize
+  {
+    (self.mode.satp_mode() << 60)
+        | ((self.asid as usize) << 44)
+        | (self.root.as_ptr() as usize >> 12)
+  }
+
+  /// Returns true if this space is the one installed in `satp`.
+  pub fn is_active(&self) -> bool
+  {
+    let satp: usize;
+    unsafe {
+      asm!("csrr {}, satp", out(reg) satp);
+    }
+
+    satp == self.satp()
+  }
+
+  /// Installs this space into `satp` and flushes its stale TLB entries.
+  ///
+  /// # Safety
+  ///
+  /// The currently executing code, its stack and anything it touches next
+  /// must be mapped in this space, otherwise the hart faults immediately.
+  pub unsafe fn activate(&self)
+  {
+    let satp = self.satp();
+    let asid = self.asid as usize;
+    asm!(
+      "csrw satp, {0}",
+      "sfence.vma zero, {1}",
+      in(reg) satp,
+      in(reg) asid,
+    );
+  }
+
+  /// The largest leaf level which can map `size` bytes of `vaddr` onto `paddr`.
+  fn fit_level(&self, vaddr: usize, paddr: usize, size: usize) -> usize
+  {
+    (1..=self.mode.root_level())
+        .rev()
+        .find(|&level| {
+          let sz = level_size(level);
+          vaddr % sz == 0 && paddr % sz == 0 && size >= sz
+        })
+        .unwrap_or(0)
+  }
+
+  /// Flushes the TLB entries a new `level` leaf at `vaddr` may have
+  /// replaced, if this space is active. A large leaf can replace a table of
+  /// smaller ones, which a fence on one address would leave behind, so
+  /// then the whole space is flushed.
+  fn flush_leaf(&self, vaddr: usize, level: usize)
+  {
+    if level == 0 {
+      return self.flush(vaddr);
+    }
+    if self.is_active() {
+      let asid = self.asid as usize;
+      unsafe {
+        asm!("sfence.vma zero, {0}", in(reg) asid);
+      }
+    }
+  }
+
+  /// Flushes the TLB entry for `vaddr` if this space is active.
+  pub fn flush(&self, vaddr: usize)
+  {
+    if !self.is_active() {
+      return;
+    }
+
+    let asid = self.asid as usize;
+    unsafe {
+      asm!("sfence.vma {0}, {1}", in(reg) vaddr, in(reg) asid);
+    }
+  }
+}
+
+impl Drop for AddressSpace
+{
+  fn drop(&mut self)
+  {
+    // PageAlloc::unmap frees everything except the root itself.
+    let mode = self.mode;
+    PageAlloc.unmap(self.root_mut(), mode);
+    unsafe {
+      PageAlloc.dealloc(self.root.as_ptr() as *mut u8, Layout::from_size(1));
+    }
+  }
+}
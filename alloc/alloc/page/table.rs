@@ -1,5 +1,6 @@
 use super::entry::Entry;
 
+#[repr(C, align(4096))]
 pub struct Table
 {
   pub entries: [Entry; 512]
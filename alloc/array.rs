@@ -22,6 +22,10 @@ pub struct Array<T, A: AllocRef = Global>
   buf: RawArray<T, A>,
 }
 
+// The array owns its elements, as a `Vec` does.
+unsafe impl<T: Send, A: AllocRef + Send> Send for Array<T, A> {}
+unsafe impl<T: Sync, A: AllocRef + Sync> Sync for Array<T, A> {}
+
 impl<T, A: AllocRef> Array<T, A>
 {
   pub fn new_with(alloc: A) -> Self
@@ -4,6 +4,7 @@
 #![allow(dead_code)]
 #![allow(incomplete_features)]
 #![feature(allocator_api)]
+#![feature(asm)]
 #![feature(alloc_error_handler)]
 #![feature(associated_type_defaults)]
 #![feature(coerce_unsized)]
@@ -42,6 +43,7 @@ extern crate mopa;
 /////////////////////////////////
 
 pub use self::array::Array;
+pub use self::shared::Shared;
 pub use self::string::String;
 pub use self::string::StringWide;
 
@@ -59,6 +61,33 @@ extern "C"
 
   /// The overall size of the Heap
   pub static _heap_size: usize;
+
+  // Kernel section boundaries, exported by `boot/asm/mem.S`.
+
+  /// The first byte of `.text`.
+  pub static TEXT_START: usize;
+  /// One past the last byte of `.text`.
+  pub static TEXT_END: usize;
+  /// The first byte of `.rodata`.
+  pub static RODATA_START: usize;
+  /// One past the last byte of `.rodata`.
+  pub static RODATA_END: usize;
+  /// The first byte of `.data`.
+  pub static DATA_START: usize;
+  /// One past the last byte of `.data`.
+  pub static DATA_END: usize;
+  /// The first byte of `.bss`.
+  pub static BSS_START: usize;
+  /// One past the last byte of `.bss`.
+  pub static BSS_END: usize;
+  /// The top of the kernel stacks, where the stack pointer starts.
+  pub static KERNEL_STACK_START: usize;
+  /// The bottom of the kernel stacks.
+  pub static KERNEL_STACK_END: usize;
+  /// The first byte of the heap.
+  pub static HEAP_START: usize;
+  /// The size of the heap.
+  pub static HEAP_SIZE: usize;
 }
 
 // END "allocation routines" ////
@@ -1 +1,220 @@
-// todo: IMPLEMENT SHARED-REFERENCE SMART POINTER TYPE
+//! Implements an allocator-aware, reference-counted smart pointer called `Shared`.
+
+use core::borrow::Borrow;
+use core::fmt;
+use core::marker::{PhantomData, Unsize};
+use core::ops::{CoerceUnsized, Deref};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+use crate::alloc::{AllocRef, Global, Layout};
+
+/// The allocation behind a `Shared`: the count of pointers to it, then the value.
+struct SharedInner<T: ?Sized>
+{
+  count: AtomicUsize,
+  value: T,
+}
+
+/// An allocator-aware, thread-safe, reference-counted pointer, most similar
+/// to C++'s `shared_ptr`. The value is dropped, and its memory freed, with
+/// the last pointer to it.
+///
+/// # Usage
+///
+/// ```no_compile
+/// let a = Shared::new(100_000);
+/// let b = a.clone();
+/// assert!(Shared::ptr_eq(&a, &b));
+/// ```
+pub struct Shared<T: ?Sized, A: AllocRef + Clone = Global>
+{
+  ptr: NonNull<SharedInner<T>>,
+  alloc: A,
+  _ghost: PhantomData<SharedInner<T>>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync, A: AllocRef + Clone + Send> Send for Shared<T, A> {}
+unsafe impl<T: ?Sized + Send + Sync, A: AllocRef + Clone + Sync> Sync for Shared<T, A> {}
+
+impl<T, A: AllocRef + Clone> Shared<T, A>
+{
+  /// Moves `val` into memory from `alloc`, with one pointer to it.
+  pub fn new_with(val: T, alloc: A) -> Self
+  {
+    let inner = SharedInner { count: AtomicUsize::new(1), value: val };
+    let ptr = unsafe {
+      let ptr = alloc
+          .alloc_aligned(Layout::new::<SharedInner<T>>())
+          .expect("allocation error")
+          .cast::<SharedInner<T>>();
+      ptr::write(ptr.as_ptr(), inner);
+      ptr
+    };
+
+    Self { ptr, alloc, _ghost: PhantomData }
+  }
+}
+
+impl<T> Shared<T, Global>
+{
+  /// Moves `val` onto the heap, with one pointer to it.
+  pub fn new(val: T) -> Self
+  {
+    Self::new_with(val, Global)
+  }
+}
+
+impl<T: ?Sized, A: AllocRef + Clone> Shared<T, A>
+{
+  #[inline]
+  fn inner(&self) -> &SharedInner<T>
+  {
+    unsafe { self.ptr.as_ref() }
+  }
+
+  /// Returns true if `a` and `b` point at the same value.
+  #[inline]
+  pub fn ptr_eq(a: &Self, b: &Self) -> bool
+  {
+    a.ptr.as_ptr() as *const u8 == b.ptr.as_ptr() as *const u8
+  }
+
+  /// How many pointers there are to the value.
+  #[inline]
+  pub fn count(this: &Self) -> usize
+  {
+    this.inner().count.load(Ordering::Acquire)
+  }
+
+  /// The value, if this is the only pointer to it.
+  pub fn get_mut(this: &mut Self) -> Option<&mut T>
+  {
+    if Self::count(this) == 1 {
+      Some(unsafe { &mut this.ptr.as_mut().value })
+    } else {
+      None
+    }
+  }
+}
+
+impl<T: ?Sized, A: AllocRef + Clone> Clone for Shared<T, A>
+{
+  #[inline]
+  fn clone(&self) -> Self
+  {
+    // As for `Arc`, a new pointer can only come from an existing one, so
+    // nothing needs ordering against the increment.
+    self.inner().count.fetch_add(1, Ordering::Relaxed);
+    Self { ptr: self.ptr, alloc: self.alloc.clone(), _ghost: PhantomData }
+  }
+}
+
+impl<T: ?Sized, A: AllocRef + Clone> Drop for Shared<T, A>
+{
+  fn drop(&mut self)
+  {
+    if self.inner().count.fetch_sub(1, Ordering::Release) != 1 {
+      return;
+    }
+    // Every other pointer's uses happen before the value is dropped.
+    atomic::fence(Ordering::Acquire);
+
+    unsafe {
+      let layout = Layout::for_value(self.ptr.as_ref());
+      ptr::drop_in_place(&mut self.ptr.as_mut().value);
+      self.alloc.dealloc_aligned(self.ptr.cast().as_ptr(), layout);
+    }
+  }
+}
+
+impl<T: ?Sized, A: AllocRef + Clone> Deref for Shared<T, A>
+{
+  type Target = T;
+
+  #[inline]
+  fn deref(&self) -> &T
+  {
+    &self.inner().value
+  }
+}
+
+impl<T: ?Sized, A: AllocRef + Clone> AsRef<T> for Shared<T, A>
+{
+  #[inline]
+  fn as_ref(&self) -> &T
+  {
+    self
+  }
+}
+
+impl<T: ?Sized, A: AllocRef + Clone> Borrow<T> for Shared<T, A>
+{
+  #[inline]
+  fn borrow(&self) -> &T
+  {
+    self
+  }
+}
+
+impl<T: ?Sized + fmt::Display, A: AllocRef + Clone> fmt::Display for Shared<T, A>
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    fmt::Display::fmt(&**self, f)
+  }
+}
+
+impl<T: ?Sized + fmt::Debug, A: AllocRef + Clone> fmt::Debug for Shared<T, A>
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    fmt::Debug::fmt(&**self, f)
+  }
+}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized, A: AllocRef + Clone> CoerceUnsized<Shared<U, A>> for Shared<T, A> {}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use core::cell::Cell;
+
+  struct Counted<'a>(&'a Cell<usize>);
+
+  impl Drop for Counted<'_>
+  {
+    fn drop(&mut self)
+    {
+      self.0.set(self.0.get() + 1);
+    }
+  }
+
+  #[test]
+  fn count()
+  {
+    let drops = Cell::new(0);
+    let a = Shared::new(Counted(&drops));
+    let b = a.clone();
+
+    assert!(Shared::ptr_eq(&a, &b));
+    assert_eq!(Shared::count(&a), 2);
+    drop(a);
+    assert_eq!(drops.get(), 0);
+    drop(b);
+    assert_eq!(drops.get(), 1);
+  }
+
+  #[test]
+  fn unsized_value()
+  {
+    let mut a: Shared<dyn fmt::Display> = Shared::new(42);
+    assert_eq!(format!("{}", a), "42");
+    assert!(Shared::get_mut(&mut a).is_some());
+
+    let b = a.clone();
+    assert!(Shared::get_mut(&mut a).is_none());
+    assert!(Shared::ptr_eq(&a, &b));
+  }
+}
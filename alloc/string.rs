@@ -81,6 +81,13 @@ impl<A: AllocRef> String<A>
     c.encode_utf8(&mut bytes);
     self.buf.extend(bytes[0..c.len_utf8()].iter());
   }
+
+  /// Appends `s` to the end of the current instance of `String`.
+  #[inline]
+  pub fn push_str(&mut self, s: &str)
+  {
+    self.buf.extend(s.as_bytes().iter());
+  }
 }
 
 impl<A: AllocRef> core::convert::TryFrom<Array<u8, A>> for String<A>
@@ -289,6 +296,23 @@ impl<A: AllocRef> StringWide<A>
     let start = self.buf.len() - len;
     c.encode_utf16(&mut self.buf[start..]);
   }
+
+  /// Appends UTF-16 code units as they are, whether or not they pair up.
+  #[inline]
+  pub fn push_units(&mut self, units: &[u16])
+  {
+    self.buf.extend(units);
+  }
+
+  /// Decodes the string, with U+FFFD in place of unpaired surrogates.
+  pub fn to_string_lossy(&self) -> String
+  {
+    let mut s = String::new();
+    for c in char::decode_utf16(self.buf.iter().copied()) {
+      s.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+    s
+  }
 }
 
 impl StringWide<Global>
@@ -409,6 +433,15 @@ mod tests
     assert_eq!(s, "aé漢");
   }
 
+  #[test]
+  fn push_str()
+  {
+    let mut s = String::from("/usr");
+    s.push_str("/share");
+    s.push('/');
+    assert_eq!(s, "/usr/share/");
+  }
+
   #[test]
   fn from_array()
   {
@@ -418,4 +451,16 @@ mod tests
     let string: String = array.try_into().unwrap();
     assert_eq!(string, "abé漢");
   }
+
+  #[test]
+  fn wide()
+  {
+    let mut s = StringWide::from("a漢");
+    s.push('𝄞');
+    assert_eq!(s.len(), 4);
+    assert_eq!(s.to_string_lossy(), "a漢𝄞");
+
+    s.push_units(&[0xd800, 0x41]);
+    assert_eq!(s.to_string_lossy(), "a漢𝄞\u{fffd}A");
+  }
 }
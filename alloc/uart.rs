@@ -1,7 +1,9 @@
 //! Implements a Universal Asynchronous Receiver / Transmitter.
 
 lazy_static! {
-  pub static ref UART_DRIVER: Mutex<Uart> = Mutex::new(Uart::new(0x1000_0000));
+  /// The console UART. Its base stays zero until `init` is handed the
+  /// address the device tree gives for it.
+  pub static ref UART_DRIVER: Mutex<Uart> = Mutex::new(Uart::new(0));
 }
 
 use core::{
@@ -32,7 +34,7 @@ impl Uart
   }
 
   /// Read from the UART.
-  pub fn read<T>(&self) -> Option<T>
+  pub fn read(&self) -> Option<u8>
   {
     let ptr = self.base as *mut u8;
     unsafe {
@@ -59,10 +61,12 @@ impl Uart
   }
 }
 
-/// Initialize the UART driver by setting
+/// Initialize the UART driver at `base` by setting
 /// the word length, FIFOs, and interrupts
 pub fn init(base: usize)
 {
+  UART_DRIVER.lock().base = base;
+
   let ptr = base as *mut u8;
   unsafe {
     // First, set the word length, which
@@ -4,7 +4,7 @@
 use crate::{
   marker::PhantomData,
   spin::Mutex,
-  uart::Uart,
+  uart::{Uart, UART_DRIVER},
 };
 
 use core::ops::Deref;
@@ -35,7 +35,7 @@ impl<R> Volatile<R, ReadWrite>
   pub fn new(reference: R) -> Self
   {
     Self {
-      driver: Uart::new(0x1000_0000),
+      driver: *UART_DRIVER.lock(),
       reference: reference,
       _phantom: PhantomData,
     }
@@ -53,7 +53,7 @@ impl<R> Volatile<R, ReadWrite>
       A: Readable,
   {
     Self {
-      driver: Uart::new(0x1000_0000),
+      driver: *UART_DRIVER.lock(),
       reference: reference,
       _phantom: PhantomData,
     }
@@ -71,7 +71,7 @@ impl<R> Volatile<R, ReadWrite>
       A: Writeable,
   {
     Self {
-      driver: Uart::new(0x1000_0000),
+      driver: *UART_DRIVER.lock(),
       reference: reference,
       _phantom: PhantomData,
     }
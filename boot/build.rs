@@ -32,7 +32,14 @@ fn main()
   let mut fi = File::create(&out_dir.join("memory.ld"))
       .expect("could not create file");
 
-  fi.write_all(include_bytes!("scripts/memory.ld"))
+  // OpenSBI occupies the start of RAM and jumps to 0x80200000.
+  let memory: &[u8] = if env::var_os("CARGO_FEATURE_OPENSBI").is_some() {
+    include_bytes!("scripts/memory-opensbi.ld")
+  } else {
+    include_bytes!("scripts/memory.ld")
+  };
+
+  fi.write_all(memory)
       .expect("could not write file");
 
   // Put the linker script somewhere the linker can find it
@@ -50,4 +57,6 @@ fn main()
 
   println!("cargo:rerun-if-changed=build.rs");
   println!("cargo:rerun-if-changed=link.ld");
+  println!("cargo:rerun-if-changed=scripts/memory.ld");
+  println!("cargo:rerun-if-changed=scripts/memory-opensbi.ld");
 }
@@ -0,0 +1,143 @@
+//! Driver for the RISC-V core-local interruptor (CLINT), or the timer and
+//! software-interrupt devices of its successor, the ACLINT.
+//!
+//! Every hart shares one free-running `mtime` counter and has its own
+//! `mtimecmp`; the hart's timer interrupt is pending whenever
+//! `mtime >= mtimecmp`. Each hart also has an `msip` register which raises
+//! its software interrupt.
+//!
+//! The registers are only reachable from M-mode once PMP is set up, so the
+//! kernel reaches them through the SBI TIME and IPI extensions.
+
+use core::ptr;
+
+use fdt::Fdt;
+use spin::Once;
+
+/// Compatible strings of a classic CLINT.
+pub const COMPATIBLE: [&str; 2] = ["sifive,clint0", "riscv,clint0"];
+
+/// Compatible string of an ACLINT machine timer.
+pub const ACLINT_MTIMER: &str = "riscv,aclint-mtimer";
+
+/// Compatible string of an ACLINT machine software interrupt device.
+pub const ACLINT_MSWI: &str = "riscv,aclint-mswi";
+
+// Offsets of each device inside a classic CLINT.
+const CLINT_MSIP: usize = 0x0;
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+
+/// The timer, once `init` has found it.
+pub static CLINT: Once<Clint> = Once::new();
+
+/// The registers of a CLINT, or of an ACLINT's timer and software
+/// interrupt devices.
+pub struct Clint
+{
+  mtime: usize,
+  /// The `mtimecmp` of hart 0; the others follow at 8 byte strides.
+  mtimecmp: usize,
+  /// The `msip` of hart 0; the others follow at 4 byte strides.
+  msip: Option<usize>,
+}
+
+impl Clint
+{
+  /// A classic CLINT at `base`.
+  pub fn new(base: usize) -> Self
+  {
+    Self {
+      mtime: base + CLINT_MTIME,
+      mtimecmp: base + CLINT_MTIMECMP,
+      msip: Some(base + CLINT_MSIP),
+    }
+  }
+
+  /// Finds the timer in the device tree, preferring a classic CLINT.
+  ///
+  /// Returns the driver and the MMIO ranges it needs mapped; the second
+  /// range is empty unless the ACLINT splits its devices up.
+  pub fn from_fdt(fdt: &Fdt) -> Option<(Self, [(usize, usize); 2])>
+  {
+    if let Some(node) = fdt.find_compatible(&COMPATIBLE) {
+      let reg = node.reg()?.next()?;
+      let mmio = (reg.base as usize, reg.size as usize);
+      return Some((Self::new(reg.base as usize), [mmio, (0, 0)]));
+    }
+
+    // An ACLINT mtimer lists its mtimecmp array, then mtime.
+    let mtimer = fdt.find_compatible(&[ACLINT_MTIMER])?;
+    let mut regs = mtimer.reg()?;
+    let mtimecmp = regs.next()?;
+    let mtime = regs.next()?;
+    let mswi = fdt
+        .find_compatible(&[ACLINT_MSWI])
+        .and_then(|node| node.reg()?.next());
+
+    let start = mtimecmp.base.min(mtime.base);
+    let end = mtimecmp.end().max(mtime.end());
+    let clint = Self {
+      mtime: mtime.base as usize,
+      mtimecmp: mtimecmp.base as usize,
+      msip: mswi.map(|r| r.base as usize),
+    };
+    let mmio = [
+      (start as usize, (end - start) as usize),
+      mswi.map_or((0, 0), |r| (r.base as usize, r.size as usize)),
+    ];
+
+    Some((clint, mmio))
+  }
+
+  /// The current value of the shared timer.
+  #[inline]
+  pub fn mtime(&self) -> u64
+  {
+    unsafe { ptr::read_volatile(self.mtime as *const u64) }
+  }
+
+  /// Raises the timer interrupt of `hart` once `mtime` reaches `deadline`.
+  /// `u64::MAX` effectively disarms it.
+  #[inline]
+  pub fn set_mtimecmp(&self, hart: usize, deadline: u64)
+  {
+    unsafe {
+      ptr::write_volatile((self.mtimecmp + 8 * hart) as *mut u64, deadline);
+    }
+  }
+
+  /// The deadline `hart` is armed for.
+  #[inline]
+  pub fn mtimecmp(&self, hart: usize) -> u64
+  {
+    unsafe { ptr::read_volatile((self.mtimecmp + 8 * hart) as *const u64) }
+  }
+
+  /// Raises the software interrupt of `hart`.
+  pub fn send_ipi(&self, hart: usize)
+  {
+    if let Some(msip) = self.msip {
+      unsafe {
+        ptr::write_volatile((msip + 4 * hart) as *mut u32, 1);
+      }
+    }
+  }
+
+  /// Clears the software interrupt of `hart`.
+  pub fn clear_ipi(&self, hart: usize)
+  {
+    if let Some(msip) = self.msip {
+      unsafe {
+        ptr::write_volatile((msip + 4 * hart) as *mut u32, 0);
+      }
+    }
+  }
+}
+
+/// Finds the timer in the device tree.
+pub fn init(fdt: &Fdt) -> Option<&'static Clint>
+{
+  let (clint, _) = Clint::from_fdt(fdt)?;
+  Some(CLINT.call_once(|| clint))
+}
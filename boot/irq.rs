@@ -0,0 +1,289 @@
+//! Runtime registration of interrupt handlers.
+//!
+//! Core interrupts (software, timer and external, for each privilege level)
+//! are dispatched straight from `_start_trap_rust`. External interrupt
+//! sources sit behind an interrupt controller; its driver claims a source
+//! and hands it to `dispatch_external`. Every handler is registered with an
+//! opaque context pointer which is passed back to it on each call.
+
+use core::cell::Cell;
+use core::fmt::{self, Display};
+use core::{mem, ptr};
+
+use spin::Mutex;
+
+/// The number of core interrupt cause codes.
+pub const CORE_INTERRUPTS: usize = 12;
+
+/// The number of external interrupt sources; the PLIC allows at most 1023,
+/// and source 0 means "no interrupt".
+pub const EXTERNAL_INTERRUPTS: usize = 1024;
+
+/// An interrupt handler. The argument is the context pointer it was
+/// registered with.
+pub type Handler = fn(ctx: *mut ());
+
+/// The core interrupts, numbered by their cause code in `scause`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Interrupt
+{
+  /// User software interrupt.
+  UserSoft = 0,
+  /// Supervisor software interrupt.
+  SupervisorSoft = 1,
+  /// Machine software interrupt.
+  MachineSoft = 3,
+  /// User timer interrupt.
+  UserTimer = 4,
+  /// Supervisor timer interrupt.
+  SupervisorTimer = 5,
+  /// Machine timer interrupt.
+  MachineTimer = 7,
+  /// User external interrupt.
+  UserExternal = 8,
+  /// Supervisor external interrupt.
+  SupervisorExternal = 9,
+  /// Machine external interrupt.
+  MachineExternal = 11,
+}
+
+impl Interrupt
+{
+  /// Decodes an interrupt cause code.
+  pub fn from_code(code: usize) -> Option<Self>
+  {
+    match code {
+      0 => Some(Interrupt::UserSoft),
+      1 => Some(Interrupt::SupervisorSoft),
+      3 => Some(Interrupt::MachineSoft),
+      4 => Some(Interrupt::UserTimer),
+      5 => Some(Interrupt::SupervisorTimer),
+      7 => Some(Interrupt::MachineTimer),
+      8 => Some(Interrupt::UserExternal),
+      9 => Some(Interrupt::SupervisorExternal),
+      11 => Some(Interrupt::MachineExternal),
+      _ => None,
+    }
+  }
+
+  /// The cause code of this interrupt.
+  #[inline]
+  pub fn code(self) -> usize
+  {
+    self as usize
+  }
+}
+
+/// The reasons a handler could not be registered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IrqError
+{
+  /// Another handler is already registered; unregister it first.
+  Busy,
+  /// The external source number is 0 or too large.
+  InvalidSource(u32),
+}
+
+impl Display for IrqError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      IrqError::Busy => write!(f, "interrupt already has a handler"),
+      IrqError::InvalidSource(s) => write!(f, "invalid external interrupt source {}", s),
+    }
+  }
+}
+
+#[derive(Copy, Clone)]
+struct Slot
+{
+  handler: Option<Handler>,
+  ctx: *mut (),
+}
+
+// The context pointers belong to whoever registered them; the table only
+// hands them back.
+unsafe impl Send for Slot {}
+
+const EMPTY: Slot = Slot { handler: None, ctx: ptr::null_mut() };
+
+// The tables are only changed with interrupts masked, so that a handler on
+// this hart never spins on a lock we hold. Dispatch copies the slot out and
+// releases the lock before calling it, so handlers may (un)register freely.
+static CORE: Mutex<[Slot; CORE_INTERRUPTS]> = Mutex::new([EMPTY; CORE_INTERRUPTS]);
+static EXTERNAL: Mutex<[Slot; EXTERNAL_INTERRUPTS]> = Mutex::new([EMPTY; EXTERNAL_INTERRUPTS]);
+
+crate::percpu! {
+  /// How many interrupt handlers this hart is running.
+  static DEPTH: Cell<usize> = Cell::new(0);
+}
+
+fn install(slots: &mut [Slot], index: usize, handler: Handler, ctx: *mut ()) -> Result<(), IrqError>
+{
+  if slots[index].handler.is_some() {
+    return Err(IrqError::Busy);
+  }
+
+  slots[index] = Slot { handler: Some(handler), ctx };
+  Ok(())
+}
+
+fn remove(slots: &mut [Slot], index: usize) -> Option<(Handler, *mut ())>
+{
+  let slot = mem::replace(&mut slots[index], EMPTY);
+  slot.handler.map(|h| (h, slot.ctx))
+}
+
+/// Calls the handler of `slot`, if there is one.
+fn call(slot: Slot) -> bool
+{
+  match slot.handler {
+    Some(handler) => {
+      handler(slot.ctx);
+      true
+    }
+    None => false,
+  }
+}
+
+fn external_index(source: u32) -> Result<usize, IrqError>
+{
+  match source as usize {
+    0 => Err(IrqError::InvalidSource(source)),
+    s if s >= EXTERNAL_INTERRUPTS => Err(IrqError::InvalidSource(source)),
+    s => Ok(s),
+  }
+}
+
+/// Masks interrupts on the current hart, returning whether they were
+/// enabled, for `restore`.
+#[inline]
+pub fn save_and_disable() -> bool
+{
+  let sstatus: usize;
+  unsafe {
+    // sstatus.SIE
+    asm!("csrrci {}, sstatus, 2", out(reg) sstatus);
+  }
+
+  sstatus & 2 != 0
+}
+
+/// Enables interrupts on the current hart again if `enabled`, as returned
+/// by `save_and_disable`.
+#[inline]
+pub fn restore(enabled: bool)
+{
+  if enabled {
+    unsafe {
+      asm!("csrsi sstatus, 2");
+    }
+  }
+}
+
+/// Runs `f` with interrupts masked on the current hart.
+///
+/// `riscv::interrupt::free` works on `mstatus`, which S-mode cannot touch.
+pub fn free<R>(f: impl FnOnce() -> R) -> R
+{
+  let enabled = save_and_disable();
+  let result = f();
+  restore(enabled);
+  result
+}
+
+/// Registers `handler` for a core interrupt.
+pub fn register(interrupt: Interrupt, handler: Handler, ctx: *mut ()) -> Result<(), IrqError>
+{
+  free(|| install(&mut *CORE.lock(), interrupt.code(), handler, ctx))
+}
+
+/// Removes the handler of a core interrupt, returning it and its context.
+pub fn unregister(interrupt: Interrupt) -> Option<(Handler, *mut ())>
+{
+  free(|| remove(&mut *CORE.lock(), interrupt.code()))
+}
+
+/// Registers `handler` for the external interrupt `source`.
+pub fn register_external(source: u32, handler: Handler, ctx: *mut ()) -> Result<(), IrqError>
+{
+  let index = external_index(source)?;
+  free(|| install(&mut *EXTERNAL.lock(), index, handler, ctx))
+}
+
+/// Removes the handler of the external interrupt `source`, returning it
+/// and its context.
+pub fn unregister_external(source: u32) -> Option<(Handler, *mut ())>
+{
+  let index = external_index(source).ok()?;
+  free(|| remove(&mut *EXTERNAL.lock(), index))
+}
+
+/// Runs the handler for the core interrupt with cause code `code`.
+/// Returns false if no handler is registered.
+pub fn dispatch(code: usize) -> bool
+{
+  if code >= CORE_INTERRUPTS {
+    return false;
+  }
+
+  let slot = CORE.lock()[code];
+
+  // A handler runs to completion on the hart it interrupted.
+  let depth = unsafe { DEPTH.get_unchecked() };
+  depth.set(depth.get() + 1);
+  let handled = call(slot);
+  depth.set(depth.get() - 1);
+
+  handled
+}
+
+/// Returns true while the current hart is running an interrupt handler.
+#[inline]
+pub fn in_interrupt() -> bool
+{
+  unsafe { DEPTH.get_unchecked() }.get() != 0
+}
+
+/// Runs the handler for the external interrupt `source`, for use by
+/// interrupt controller drivers once they have claimed it. Returns false
+/// if no handler is registered.
+pub fn dispatch_external(source: u32) -> bool
+{
+  let index = match external_index(source) {
+    Ok(index) => index,
+    Err(_) => return false,
+  };
+
+  let slot = EXTERNAL.lock()[index];
+  call(slot)
+}
+
+/// Unmasks a core interrupt on the current hart.
+pub fn enable(interrupt: Interrupt)
+{
+  unsafe {
+    asm!("csrs sie, {}", in(reg) 1usize << interrupt.code());
+  }
+}
+
+/// Masks a core interrupt on the current hart.
+pub fn disable(interrupt: Interrupt)
+{
+  unsafe {
+    asm!("csrc sie, {}", in(reg) 1usize << interrupt.code());
+  }
+}
+
+/// Lets the current hart take the interrupts unmasked with `enable`.
+///
+/// # Safety
+///
+/// Every enabled interrupt must have a handler ready for it.
+pub unsafe fn enable_global()
+{
+  // sstatus.SIE
+  asm!("csrs sstatus, {}", in(reg) 1usize << 1);
+}
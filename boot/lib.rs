@@ -14,18 +14,29 @@
 
 extern crate r0;
 extern crate riscv;
+extern crate spin;
+extern crate t_alloc;
+extern crate t_fdt as fdt;
 extern crate t_macros;
 
 #[doc(hidden)]
 pub mod asm;
 
+pub mod clint;
+pub mod irq;
+pub mod machine;
+pub mod percpu;
+pub mod sbi;
+pub mod smp;
 pub mod trap;
 
-use self::trap::TrapFrame;
+use self::trap::{Trap, TrapFrame};
 
-pub use t_macros::{entry, pre_init};
+pub use t_macros::{entry, pre_init, secondary_entry, syscall};
 
-use riscv::register::mcause;
+
+/// The most harts the boot crate and the kernel support.
+pub const MAX_HARTS: usize = 32;
 
 #[export_name = "error: bootloader appears more than once in the dependency graph"]
 #[doc(hidden)]
@@ -45,75 +56,135 @@ extern "C"
   static _sidata: u32;
 }
 
+/// Rust entry point (_start_rust)
+///
+/// `hartid` and `dtb` are the values the firmware left in `a0` and `a1`:
+/// the id of this hart and the physical address of the device tree blob.
+///
+/// Without the `opensbi` feature this runs in M-mode, straight from reset:
+/// the boot hart initialises memory and the SBI implementation in
+/// `machine`, then drops to S-mode, while the other harts park until they
+/// are started through HSM. With it, OpenSBI has already done all of that
+/// and entered us in S-mode.
 #[export_name = "_start_rust"]
 #[link_section = ".init.rust"]
-pub unsafe extern "C" fn start_rust() -> !
+pub unsafe extern "C" fn start_rust(hartid: usize, dtb: usize) -> !
 {
   #[rustfmt::skip]
   extern "Rust"
   {
-    fn kmain() -> !;
-
     fn __pre_init();
 
-    fn _setup_interrupts();
+    fn _mp_hook(hartid: usize) -> bool;
+  }
 
-    fn _mp_hook() -> bool;
+  if !_mp_hook(hartid) {
+    other_hart(hartid);
   }
 
-  if _mp_hook() {
-    __pre_init();
+  __pre_init();
+
+  r0::zero_bss(&mut _sbss, &mut _ebss);
+  r0::init_data(&mut _sdata, &mut _edata, &_sidata);
 
-    r0::zero_bss(&mut _sbss, &mut _ebss);
-    r0::init_data(&mut _sdata, &mut _edata, &_sidata);
+  boot_hart(hartid, dtb);
+}
+
+#[cfg(not(feature = "opensbi"))]
+unsafe fn boot_hart(hartid: usize, dtb: usize) -> !
+{
+  machine::init(dtb);
+  machine::init_hart(hartid);
+  machine::set_ready();
+  machine::enter_supervisor(hartid, start_supervisor as usize, dtb);
+}
+
+#[cfg(feature = "opensbi")]
+unsafe fn boot_hart(hartid: usize, dtb: usize) -> !
+{
+  start_supervisor(hartid, dtb);
+}
+
+#[cfg(not(feature = "opensbi"))]
+unsafe fn other_hart(hartid: usize) -> !
+{
+  machine::wait_ready();
+  machine::init_hart(hartid);
+  machine::park(hartid);
+}
+
+#[cfg(feature = "opensbi")]
+unsafe fn other_hart(hartid: usize) -> !
+{
+  loop {
+    riscv::asm::wfi();
   }
+}
 
-  // TODO: Enable FPU when available.
+/// S-mode entry point of the boot hart, with paging off.
+///
+/// Sets up the hart's per-hart data and calls `kmain` with every interrupt
+/// masked.
+unsafe extern "C" fn start_supervisor(hartid: usize, dtb: usize) -> !
+{
+  #[rustfmt::skip]
+  extern "Rust"
+  {
+    fn kmain(dtb: usize) -> !;
 
+    fn _setup_interrupts();
+  }
+
+  smp::init_hart(hartid);
   _setup_interrupts();
+  smp::set_online();
 
-  kmain();
+  kmain(dtb);
 }
 
 /// Trap entry point rust (_start_trap_rust)
 ///
-/// `mcause` is read to determine the cause of the trap. XLEN-1 bit indicates
-/// if it's an interrupt or an exception. The result is examined and ExceptionHandler
-/// or one of the core interrupt handlers is called.
+/// The cause saved in the trap frame is decoded to determine whether this
+/// is an interrupt or an exception. Exceptions are passed to
+/// ExceptionHandler along with the frame, which it may modify; interrupts
+/// are dispatched to the handler registered for them with `irq::register`,
+/// or to DefaultHandler if there is none, and then to `_interrupt_exit`,
+/// where the kernel may switch to another thread.
 #[link_section = ".trap.rust"]
 #[export_name = "_start_trap_rust"]
-pub extern "C" fn start_trap_rust(trap_frame: *const TrapFrame)
+pub extern "C" fn start_trap_rust(trap_frame: *mut TrapFrame)
 {
   extern "C"
   {
-    fn ExceptionHandler(trap_frame: &TrapFrame);
+    fn ExceptionHandler(trap_frame: &mut TrapFrame);
     fn DefaultHandler();
   }
 
+  extern "Rust"
+  {
+    fn _interrupt_exit();
+  }
+
   unsafe
       {
-        let cause = mcause::read();
-        if cause.is_exception() {
-          ExceptionHandler(&*trap_frame)
-        } else {
-          let code = cause.code();
-          if code < __INTERRUPTS.len() {
-            let h = &__INTERRUPTS[code];
-            if h.reserved == 0 {
+        match (*trap_frame).trap() {
+          Trap::Exception(_) => ExceptionHandler(&mut *trap_frame),
+          Trap::Interrupt(code) => {
+            if !irq::dispatch(code) {
               DefaultHandler();
-            } else {
-              (h.handler)();
             }
-          } else {
-            DefaultHandler();
+            _interrupt_exit();
           }
         }
       }
 }
 
+/// Exception handler used when the kernel does not provide its own.
+///
+/// There is no console this early, so the frame is left for a debugger.
 #[doc(hidden)]
 #[no_mangle]
-pub fn DefaultExceptionHandler(tf: &TrapFrame) -> !
+pub fn DefaultExceptionHandler(tf: &mut TrapFrame) -> !
 {
   loop {
     continue;
@@ -132,89 +203,41 @@ pub fn DefaultInterruptHandler()
   }
 }
 
-/* Interrupts */
-#[doc(hidden)]
-pub enum Interrupt
-{
-  UserSoft,
-  SupervisorSoft,
-  MachineSoft,
-  UserTimer,
-  SupervisorTimer,
-  MachineTimer,
-  UserExternal,
-  SupervisorExternal,
-  MachineExternal,
-}
-
+pub use self::irq::Interrupt;
 pub use self::Interrupt as interrupt;
 
-extern "C"
-{
-  fn UserSoft();
-  fn SupervisorSoft();
-  fn MachineSoft();
-  fn UserTimer();
-  fn SupervisorTimer();
-  fn MachineTimer();
-  fn UserExternal();
-  fn SupervisorExternal();
-  fn MachineExternal();
-}
+/// The id of the hart this runs on.
+///
+/// `mhartid` cannot be read from S-mode; the id is kept per hart instead.
+pub use self::smp::hart_id;
 
 #[doc(hidden)]
-pub union Vector
-{
-  handler: unsafe extern "C" fn(),
-  reserved: usize,
-}
+#[no_mangle]
+#[rustfmt::skip]
+pub unsafe extern "Rust" fn default_pre_init() {}
 
 #[doc(hidden)]
 #[no_mangle]
-pub static __INTERRUPTS: [Vector; 12] = [
-  Vector { handler: UserSoft },
-  Vector {
-    handler: SupervisorSoft,
-  },
-  Vector { reserved: 0 },
-  Vector {
-    handler: MachineSoft,
-  },
-  Vector { handler: UserTimer },
-  Vector {
-    handler: SupervisorTimer,
-  },
-  Vector { reserved: 0 },
-  Vector {
-    handler: MachineTimer,
-  },
-  Vector {
-    handler: UserExternal,
-  },
-  Vector {
-    handler: SupervisorExternal,
-  },
-  Vector { reserved: 0 },
-  Vector {
-    handler: MachineExternal,
-  },
-];
+#[rustfmt::skip]
+pub extern "Rust" fn default_mp_hook(hartid: usize) -> bool
+{
+  // OpenSBI only enters the boot hart here; the others wait in the
+  // firmware to be started through HSM.
+  cfg!(feature = "opensbi") || hartid == 0
+}
 
 #[doc(hidden)]
 #[no_mangle]
 #[rustfmt::skip]
-pub unsafe extern "Rust" fn default_pre_init() {}
+pub extern "Rust" fn default_interrupt_exit() {}
 
 #[doc(hidden)]
 #[no_mangle]
-#[rustfmt::skip]
-pub extern "Rust" fn default_mp_hook() -> bool
+pub extern "Rust" fn default_kmain_secondary(hartid: usize) -> !
 {
-  use riscv::register::mhartid;
-  match mhartid::read() {
-    0 => true,
-    _ => loop {
-      unsafe { riscv::asm::wfi() }
-    },
+  // Nothing for this hart to do; give it back to the firmware.
+  sbi::hart_stop();
+  loop {
+    unsafe { riscv::asm::wfi() };
   }
 }
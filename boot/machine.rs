@@ -0,0 +1,473 @@
+//! The M-mode half of the boot crate: a small SBI implementation.
+//!
+//! Without the `opensbi` feature the kernel is entered straight from reset
+//! in M-mode. Each hart then sets up trap delegation and PMP, drops to
+//! S-mode, and comes back here only through `_start_mtrap`: for SBI calls,
+//! and for the machine timer and software interrupts, which are forwarded
+//! to S-mode as their supervisor counterparts.
+
+use core::ptr;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+
+use fdt::Fdt;
+use spin::Once;
+use t_alloc::alloc::page::PagingMode;
+
+use crate::clint::{self, Clint};
+use crate::sbi::{self, base, hsm, rfence, HartState, SbiError, SbiResult};
+use crate::trap::{Exception, Trap, TrapFrame};
+use crate::MAX_HARTS;
+
+/// The size of each hart's M-mode trap stack.
+pub const STACK_SIZE: usize = 4096;
+
+/// Our implementation id. This is not one of the registered ids.
+pub const IMPL_ID: usize = 0x5452_4944;
+
+/// Our implementation version.
+pub const IMPL_VERSION: usize = 1;
+
+// mstatus
+const MSTATUS_MPP: usize = 3 << 11;
+const MSTATUS_MPP_S: usize = 1 << 11;
+const MSTATUS_MPIE: usize = 1 << 7;
+const MSTATUS_FS_INITIAL: usize = 1 << 13;
+
+// mip / mie
+const SSIP: usize = 1 << 1;
+const MSIP: usize = 1 << 3;
+const STIP: usize = 1 << 5;
+const MTIP: usize = 1 << 7;
+const SEIP: usize = 1 << 9;
+
+/// Every exception except the `ecall`s from S-mode and M-mode, which are
+/// ours to handle.
+const MEDELEG: usize = 0xb1ff;
+
+/// The supervisor interrupts. The machine timer and software interrupts
+/// stay here and are forwarded.
+const MIDELEG: usize = SSIP | STIP | SEIP;
+
+// Work one hart asks of another with an IPI.
+const WORK_IPI: usize = 1 << 0;
+const WORK_FENCE_I: usize = 1 << 1;
+const WORK_SFENCE_VMA: usize = 1 << 2;
+
+// Our own hart states. The zero value marks a hart which never booted.
+const ABSENT: usize = 0;
+const STARTED: usize = 1;
+const STOPPED: usize = 2;
+const START_PENDING: usize = 3;
+/// Claimed by `hart_start`, which has yet to give it an address.
+const CLAIMED: usize = 4;
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+const NO_STACK: Stack = Stack([0; STACK_SIZE]);
+
+static mut STACKS: [Stack; MAX_HARTS] = [NO_STACK; MAX_HARTS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+static WORK: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+static STATE: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+static START_ADDR: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+static START_ARG: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+/// The `satp` mode each hart accepted when it was probed, or zero.
+static PAGING_MODE: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+
+/// Set by the boot hart once memory is initialised.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// A register write which powers the machine off or resets it.
+#[derive(Copy, Clone)]
+struct Syscon
+{
+  addr: usize,
+  value: u32,
+}
+
+static POWEROFF: Once<Syscon> = Once::new();
+static REBOOT: Once<Syscon> = Once::new();
+
+extern "C"
+{
+  fn _start_mtrap();
+}
+
+/// Finds the devices the SBI implementation needs in the device tree.
+/// Runs once, on the boot hart, after memory is initialised.
+pub fn init(dtb: usize)
+{
+  let fdt = match unsafe { Fdt::from_ptr(dtb as *const u8) } {
+    Ok(fdt) => fdt,
+    // Without a device tree there is nothing to boot with anyway; the
+    // kernel will report it.
+    Err(_) => return,
+  };
+
+  clint::init(&fdt);
+  if let Some(syscon) = syscon(&fdt, "syscon-poweroff") {
+    POWEROFF.call_once(|| syscon);
+  }
+  if let Some(syscon) = syscon(&fdt, "syscon-reboot") {
+    REBOOT.call_once(|| syscon);
+  }
+}
+
+fn syscon(fdt: &Fdt, compat: &str) -> Option<Syscon>
+{
+  let node = fdt.compatible(compat).next()?;
+  let regmap = fdt.find_phandle(node.property("regmap")?.as_u32()?)?;
+  let base = regmap.reg()?.next()?.base as usize;
+  let offset = node.property("offset").and_then(|p| p.as_u32()).unwrap_or(0);
+  let value = node.property("value")?.as_u32()?;
+
+  Some(Syscon { addr: base + offset as usize, value })
+}
+
+/// Lets the other harts out of `wait_ready`.
+pub fn set_ready()
+{
+  READY.store(true, Ordering::Release);
+}
+
+/// Spins until the boot hart has initialised memory.
+pub fn wait_ready()
+{
+  while !READY.load(Ordering::Acquire) {
+    spin_loop_hint();
+  }
+}
+
+/// Sets up the calling hart's M-mode state: its trap vector and stack,
+/// trap delegation, counter access and PMP, and probes which paging modes
+/// it implements.
+///
+/// # Safety
+///
+/// Must run in M-mode, once per hart, before `enter_supervisor`.
+pub unsafe fn init_hart(hartid: usize)
+{
+  let stack = STACKS[hartid].0.as_ptr() as usize + STACK_SIZE;
+
+  asm!(
+    "csrw mtvec, {mtvec}",
+    "csrw mscratch, {stack}",
+    "csrw medeleg, {medeleg}",
+    "csrw mideleg, {mideleg}",
+    // Let S-mode read cycle, time and instret.
+    "csrw mcounteren, {counters}",
+    "csrw mie, {mie}",
+    "csrw mip, zero",
+    "csrw satp, zero",
+    mtvec = in(reg) _start_mtrap as usize,
+    stack = in(reg) stack,
+    medeleg = in(reg) MEDELEG,
+    mideleg = in(reg) MIDELEG,
+    counters = in(reg) 0b111,
+    mie = in(reg) MSIP,
+  );
+
+  // One NAPOT region covering all of memory, readable, writable and
+  // executable from S-mode and U-mode.
+  asm!(
+    "csrw pmpaddr0, {addr}",
+    "csrw pmpcfg0, {cfg}",
+    addr = in(reg) usize::MAX >> 10,
+    cfg = in(reg) 0x1f,
+  );
+
+  // S-mode cannot probe: a mode it tries would translate its own fetches.
+  if let Some(mode) = PagingMode::probe() {
+    PAGING_MODE[hartid].store(mode.satp_mode(), Ordering::Release);
+  }
+}
+
+/// The largest paging mode `hartid` accepted when `init_hart` probed it,
+/// or `None` if it has not been probed, as under OpenSBI.
+pub fn paging_mode(hartid: usize) -> Option<PagingMode>
+{
+  PagingMode::from_satp_mode(PAGING_MODE.get(hartid)?.load(Ordering::Acquire))
+}
+
+/// Drops the calling hart to S-mode at `entry`, with `hartid` in `a0` and
+/// `arg` in `a1`.
+///
+/// # Safety
+///
+/// `entry` must be the physical address of code expecting exactly that.
+pub unsafe fn enter_supervisor(hartid: usize, entry: usize, arg: usize) -> !
+{
+  STATE[hartid].store(STARTED, Ordering::Release);
+
+  asm!(
+    "csrc mstatus, {mpp}",
+    "csrs mstatus, {status}",
+    "csrw mepc, {entry}",
+    "mret",
+    mpp = in(reg) MSTATUS_MPP | MSTATUS_MPIE,
+    status = in(reg) MSTATUS_MPP_S | MSTATUS_FS_INITIAL,
+    entry = in(reg) entry,
+    in("a0") hartid,
+    in("a1") arg,
+    options(noreturn),
+  );
+}
+
+/// Parks the calling hart until another one starts it with `hart_start`.
+///
+/// # Safety
+///
+/// Must run in M-mode, after `init_hart`.
+pub unsafe fn park(hartid: usize) -> !
+{
+  // A hart stopping itself arrives here from its trap stack; reset it.
+  let stack = STACKS[hartid].0.as_ptr() as usize + STACK_SIZE;
+  asm!("csrw mscratch, {}", in(reg) stack);
+
+  STATE[hartid].store(STOPPED, Ordering::Release);
+  loop {
+    asm!("wfi");
+
+    if let Some(clint) = clint::CLINT.get() {
+      clint.clear_ipi(hartid);
+    }
+    if STATE[hartid].load(Ordering::Acquire) == START_PENDING {
+      let entry = START_ADDR[hartid].load(Ordering::Relaxed);
+      let arg = START_ARG[hartid].load(Ordering::Relaxed);
+      enter_supervisor(hartid, entry, arg);
+    }
+  }
+}
+
+/// M-mode trap entry, called by `_start_mtrap` with the frame it saved.
+#[export_name = "_start_mtrap_rust"]
+pub extern "C" fn start_mtrap_rust(frame: &mut TrapFrame)
+{
+  let hartid = current_hart();
+
+  match frame.trap() {
+    Trap::Exception(Exception::SupervisorEnvCall) => {
+      let ext = frame.arg(7);
+      let fid = frame.arg(6);
+      let args = [frame.arg(0), frame.arg(1), frame.arg(2), frame.arg(3), frame.arg(4), frame.arg(5)];
+
+      let (error, value) = match handle_ecall(hartid, ext, fid, args) {
+        Ok(value) => (0, value),
+        Err(e) => (e.code() as usize, 0),
+      };
+      frame.set_arg(0, error);
+      frame.set_arg(1, value);
+      frame.epc += 4;
+    }
+    Trap::Interrupt(7) => unsafe {
+      // Hand the timer to S-mode; it is rearmed through set_timer.
+      asm!("csrc mie, {}", "csrs mip, {}", in(reg) MTIP, in(reg) STIP);
+    },
+    Trap::Interrupt(3) => handle_ipi(hartid),
+    _ => loop {
+      // Anything else is a bug in the kernel or in this file, and there
+      // is no console here to report it on.
+      unsafe { asm!("wfi") };
+    },
+  }
+}
+
+fn current_hart() -> usize
+{
+  let hartid: usize;
+  unsafe {
+    asm!("csrr {}, mhartid", out(reg) hartid);
+  }
+
+  hartid
+}
+
+fn clint() -> SbiResult<&'static Clint>
+{
+  clint::CLINT.get().ok_or(SbiError::NotSupported)
+}
+
+fn handle_ecall(hartid: usize, ext: usize, fid: usize, args: [usize; 6]) -> SbiResult
+{
+  match ext {
+    sbi::EXT_BASE => handle_base(fid, args[0]),
+    sbi::EXT_TIME if fid == 0 => set_timer(hartid, args[0] as u64),
+    sbi::EXT_IPI if fid == 0 => send(args[0], args[1], WORK_IPI),
+    sbi::EXT_RFENCE => match fid {
+      rfence::REMOTE_FENCE_I => send(args[0], args[1], WORK_FENCE_I),
+      // The range and ASID only narrow what must be flushed; flushing
+      // everything is always correct.
+      rfence::REMOTE_SFENCE_VMA | rfence::REMOTE_SFENCE_VMA_ASID => {
+        send(args[0], args[1], WORK_SFENCE_VMA)
+      }
+      _ => Err(SbiError::NotSupported),
+    },
+    sbi::EXT_HSM => match fid {
+      hsm::HART_START => hart_start(args[0], args[1], args[2]),
+      hsm::HART_STOP => unsafe { park(hartid) },
+      hsm::HART_GET_STATUS => hart_status(args[0]),
+      _ => Err(SbiError::NotSupported),
+    },
+    sbi::EXT_SRST if fid == 0 => system_reset(args[0]),
+    _ => Err(SbiError::NotSupported),
+  }
+}
+
+fn handle_base(fid: usize, arg: usize) -> SbiResult
+{
+  let value = match fid {
+    base::GET_SPEC_VERSION => sbi::SPEC_VERSION,
+    base::GET_IMPL_ID => IMPL_ID,
+    base::GET_IMPL_VERSION => IMPL_VERSION,
+    base::PROBE_EXTENSION => match arg {
+      sbi::EXT_BASE | sbi::EXT_TIME | sbi::EXT_IPI | sbi::EXT_RFENCE | sbi::EXT_HSM => 1,
+      sbi::EXT_SRST => POWEROFF.get().is_some() as usize,
+      _ => 0,
+    },
+    base::GET_MVENDORID => read_id(0),
+    base::GET_MARCHID => read_id(1),
+    base::GET_MIMPID => read_id(2),
+    _ => return Err(SbiError::NotSupported),
+  };
+
+  Ok(value)
+}
+
+fn read_id(which: usize) -> usize
+{
+  let value: usize;
+  unsafe {
+    match which {
+      0 => asm!("csrr {}, mvendorid", out(reg) value),
+      1 => asm!("csrr {}, marchid", out(reg) value),
+      _ => asm!("csrr {}, mimpid", out(reg) value),
+    }
+  }
+
+  value
+}
+
+fn set_timer(hartid: usize, deadline: u64) -> SbiResult
+{
+  clint()?.set_mtimecmp(hartid, deadline);
+  unsafe {
+    asm!("csrc mip, {}", "csrs mie, {}", in(reg) STIP, in(reg) MTIP);
+  }
+
+  Ok(0)
+}
+
+/// The harts selected by an SBI hart mask. A base of `usize::MAX` selects
+/// every hart.
+fn harts(mask: usize, base: usize) -> impl Iterator<Item = usize>
+{
+  (0..MAX_HARTS).filter(move |&hart| {
+    base == usize::MAX || (hart >= base && hart - base < 64 && mask & (1 << (hart - base)) != 0)
+  })
+}
+
+/// Asks every started hart in the mask to do `work`, and waits for the
+/// fences to be done.
+fn send(mask: usize, base: usize, work: usize) -> SbiResult
+{
+  let clint = clint()?;
+  let targets = || harts(mask, base).filter(|&h| STATE[h].load(Ordering::Acquire) == STARTED);
+
+  for hart in targets() {
+    WORK[hart].fetch_or(work, Ordering::AcqRel);
+    clint.send_ipi(hart);
+  }
+
+  if work != WORK_IPI {
+    let me = current_hart();
+    for hart in targets() {
+      while WORK[hart].load(Ordering::Acquire) & work != 0 {
+        // Serve our own requests, and those of anyone waiting on us.
+        if hart == me || WORK[me].load(Ordering::Acquire) & !WORK_IPI != 0 {
+          handle_ipi(me);
+        }
+        spin_loop_hint();
+      }
+    }
+  }
+
+  Ok(0)
+}
+
+/// Does whatever work other harts have asked of this one.
+fn handle_ipi(hartid: usize)
+{
+  if let Some(clint) = clint::CLINT.get() {
+    clint.clear_ipi(hartid);
+  }
+
+  let work = WORK[hartid].load(Ordering::Acquire);
+  unsafe {
+    if work & WORK_FENCE_I != 0 {
+      asm!("fence.i");
+    }
+    if work & WORK_SFENCE_VMA != 0 {
+      asm!("sfence.vma");
+    }
+    if work & WORK_IPI != 0 {
+      asm!("csrs mip, {}", in(reg) SSIP);
+    }
+  }
+  WORK[hartid].fetch_and(!work, Ordering::AcqRel);
+}
+
+fn hart_start(hartid: usize, addr: usize, arg: usize) -> SbiResult
+{
+  if hartid >= MAX_HARTS {
+    return Err(SbiError::InvalidParam);
+  }
+
+  let clint = clint()?;
+
+  // Claim the hart before giving it an address, and only then let it see
+  // that it is wanted.
+  match STATE[hartid].compare_exchange(STOPPED, CLAIMED, Ordering::Acquire, Ordering::Acquire) {
+    Ok(_) => {}
+    Err(ABSENT) => return Err(SbiError::InvalidParam),
+    Err(_) => return Err(SbiError::AlreadyAvailable),
+  }
+  START_ADDR[hartid].store(addr, Ordering::Relaxed);
+  START_ARG[hartid].store(arg, Ordering::Relaxed);
+  STATE[hartid].store(START_PENDING, Ordering::Release);
+
+  clint.send_ipi(hartid);
+  Ok(0)
+}
+
+fn hart_status(hartid: usize) -> SbiResult
+{
+  let state = match STATE.get(hartid).map(|s| s.load(Ordering::Acquire)) {
+    Some(STARTED) => HartState::Started,
+    Some(STOPPED) => HartState::Stopped,
+    Some(START_PENDING) | Some(CLAIMED) => HartState::StartPending,
+    _ => return Err(SbiError::InvalidParam),
+  };
+
+  Ok(state as usize)
+}
+
+fn system_reset(kind: usize) -> SbiResult
+{
+  let syscon = match kind {
+    0 => POWEROFF.get(),
+    1 | 2 => REBOOT.get(),
+    _ => return Err(SbiError::InvalidParam),
+  };
+  let syscon = syscon.ok_or(SbiError::NotSupported)?;
+
+  unsafe {
+    ptr::write_volatile(syscon.addr as *mut u32, syscon.value);
+  }
+  loop {
+    unsafe { asm!("wfi") };
+  }
+}
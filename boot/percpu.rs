@@ -0,0 +1,254 @@
+//! Per-hart variables.
+//!
+//! Variables declared with `percpu!` are laid out in the `.percpu` section,
+//! which is only a template: each hart gets its own copy of the whole
+//! section when it comes up, and keeps the address of that copy in `tp`.
+//! A variable is then found at the same offset in every copy.
+//!
+//! A reference to the current hart's copy is only good for as long as the
+//! code holding it stays on that hart, so the accessors disable preemption
+//! until it is dropped.
+
+use core::cell::Cell;
+use core::ops::Deref;
+use core::ptr;
+
+use crate::irq;
+
+extern "C"
+{
+  // Boundaries of the .percpu template, in .data.
+  static _percpu_start: u8;
+  static _percpu_end: u8;
+
+  // The copies, one per hart up to _max_hart_id, in .bss.
+  static mut _percpu_areas: u8;
+}
+
+/// Declares per-hart variables.
+///
+/// ``` ignore
+/// percpu! {
+///   /// Interrupts taken on this hart.
+///   pub static IRQS: Cell<usize> = Cell::new(0);
+/// }
+///
+/// IRQS.with(|irqs| irqs.set(irqs.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! percpu {
+  ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+    $(
+      $(#[$attr])*
+      $vis static $name: $crate::percpu::PerCpu<$ty> = {
+        #[link_section = ".percpu"]
+        static TEMPLATE: $crate::percpu::Template<$ty> = $crate::percpu::Template::new($init);
+
+        unsafe { $crate::percpu::PerCpu::new(&TEMPLATE) }
+      };
+    )+
+  };
+}
+
+/// The initial value of a per-hart variable, which each hart copies.
+/// Only `percpu!` should create these.
+#[doc(hidden)]
+#[repr(transparent)]
+pub struct Template<T>(T);
+
+// The template itself is never accessed, only copied.
+unsafe impl<T> Sync for Template<T> {}
+
+impl<T> Template<T>
+{
+  #[doc(hidden)]
+  pub const fn new(value: T) -> Self
+  {
+    Template(value)
+  }
+}
+
+/// A per-hart variable, declared with `percpu!`.
+pub struct PerCpu<T: 'static>
+{
+  template: &'static Template<T>,
+}
+
+// Each hart only ever touches its own copy, except through `remote`, which
+// requires `T: Sync`.
+unsafe impl<T: 'static> Sync for PerCpu<T> {}
+
+impl<T: 'static> PerCpu<T>
+{
+  /// # Safety
+  ///
+  /// `template` must have been placed in the `.percpu` section.
+  #[doc(hidden)]
+  pub const unsafe fn new(template: &'static Template<T>) -> Self
+  {
+    Self { template }
+  }
+
+  fn offset(&self) -> usize
+  {
+    self.template as *const Template<T> as usize - template_start()
+  }
+
+  /// The address of the current hart's copy.
+  #[inline]
+  pub fn as_ptr(&self) -> *mut T
+  {
+    (area_base() + self.offset()) as *mut T
+  }
+
+  /// The current hart's copy, with preemption disabled until the returned
+  /// reference is dropped.
+  #[inline]
+  pub fn get(&self) -> PerCpuRef<'_, T>
+  {
+    preempt_disable();
+    PerCpuRef { value: unsafe { &*self.as_ptr() } }
+  }
+
+  /// Runs `f` on the current hart's copy, with preemption disabled.
+  #[inline]
+  pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R
+  {
+    f(&self.get())
+  }
+
+  /// The current hart's copy, without disabling preemption.
+  ///
+  /// # Safety
+  ///
+  /// The caller must not be moved to another hart while it holds the
+  /// reference, or must not mind reading another hart's copy.
+  #[inline]
+  pub unsafe fn get_unchecked(&self) -> &T
+  {
+    &*self.as_ptr()
+  }
+
+  /// The copy of hart `hartid`.
+  pub fn remote(&self, hartid: usize) -> &T
+    where T: Sync
+  {
+    unsafe { &*((area(hartid) + self.offset()) as *const T) }
+  }
+}
+
+/// A reference to the current hart's copy of a per-hart variable, which
+/// keeps preemption disabled while it lives.
+pub struct PerCpuRef<'a, T>
+{
+  value: &'a T,
+}
+
+impl<'a, T> Deref for PerCpuRef<'a, T>
+{
+  type Target = T;
+
+  #[inline]
+  fn deref(&self) -> &T
+  {
+    self.value
+  }
+}
+
+impl<'a, T> Drop for PerCpuRef<'a, T>
+{
+  #[inline]
+  fn drop(&mut self)
+  {
+    preempt_enable();
+  }
+}
+
+fn template_start() -> usize
+{
+  unsafe { &_percpu_start as *const u8 as usize }
+}
+
+/// The size of each hart's copy of the `.percpu` section.
+#[inline]
+pub fn area_size() -> usize
+{
+  unsafe { &_percpu_end as *const u8 as usize - template_start() }
+}
+
+/// The address of hart `hartid`'s copy of the `.percpu` section.
+#[inline]
+pub fn area(hartid: usize) -> usize
+{
+  unsafe { &_percpu_areas as *const u8 as usize + hartid * area_size() }
+}
+
+/// The address of the current hart's copy, from `tp`.
+#[inline]
+fn area_base() -> usize
+{
+  let base: usize;
+  unsafe {
+    asm!("mv {}, tp", out(reg) base);
+  }
+
+  base
+}
+
+/// Fills in hart `hartid`'s copy of the `.percpu` section from the
+/// template and points `tp` at it.
+///
+/// # Safety
+///
+/// Must run once on each hart, before anything per-hart is touched.
+pub(crate) unsafe fn init_hart(hartid: usize)
+{
+  let area = area(hartid);
+  ptr::copy_nonoverlapping(template_start() as *const u8, area as *mut u8, area_size());
+
+  asm!("mv tp, {}", in(reg) area);
+}
+
+crate::percpu! {
+  /// The number of `preempt_disable` calls not yet matched by
+  /// `preempt_enable` on this hart.
+  static PREEMPT: Cell<usize> = Cell::new(0);
+}
+
+/// Keeps the current code on this hart until the matching
+/// `preempt_enable`. Calls nest.
+#[inline]
+pub fn preempt_disable()
+{
+  // Masked, so that we cannot move between finding the counter and
+  // writing it back.
+  irq::free(|| {
+    let count = unsafe { PREEMPT.get_unchecked() };
+    count.set(count.get() + 1);
+  });
+}
+
+/// Undoes one `preempt_disable`.
+#[inline]
+pub fn preempt_enable()
+{
+  irq::free(|| {
+    let count = unsafe { PREEMPT.get_unchecked() };
+    debug_assert!(count.get() > 0, "unbalanced preempt_enable");
+    count.set(count.get() - 1);
+  });
+}
+
+/// The preemption nesting depth of this hart.
+#[inline]
+pub fn preempt_count() -> usize
+{
+  unsafe { PREEMPT.get_unchecked() }.get()
+}
+
+/// Returns true if the scheduler may move the current code elsewhere.
+#[inline]
+pub fn preemptible() -> bool
+{
+  preempt_count() == 0
+}
@@ -0,0 +1,318 @@
+//! The RISC-V Supervisor Binary Interface.
+//!
+//! S-mode asks the firmware for the things only M-mode can do (programming
+//! the timer, sending IPIs, starting harts, resetting the machine) with an
+//! `ecall`: the extension id goes in `a7`, the function id in `a6`, the
+//! arguments in `a0`..`a5`, and the error code and value come back in `a0`
+//! and `a1`. The calls here work the same whether the firmware is our own
+//! (see `machine`) or OpenSBI.
+
+use core::fmt::{self, Display};
+
+/// The Base extension.
+pub const EXT_BASE: usize = 0x10;
+/// The Timer extension, "TIME".
+pub const EXT_TIME: usize = 0x5449_4d45;
+/// The IPI extension, "sPI".
+pub const EXT_IPI: usize = 0x73_5049;
+/// The Remote Fence extension, "RFNC".
+pub const EXT_RFENCE: usize = 0x5246_4e43;
+/// The Hart State Management extension, "HSM".
+pub const EXT_HSM: usize = 0x48_534d;
+/// The System Reset extension, "SRST".
+pub const EXT_SRST: usize = 0x5352_5354;
+
+/// Functions of the Base extension.
+pub mod base
+{
+  /// Returns the implemented SBI version.
+  pub const GET_SPEC_VERSION: usize = 0;
+  /// Returns the id of the SBI implementation.
+  pub const GET_IMPL_ID: usize = 1;
+  /// Returns the version of the SBI implementation.
+  pub const GET_IMPL_VERSION: usize = 2;
+  /// Returns 1 if an extension is available.
+  pub const PROBE_EXTENSION: usize = 3;
+  /// Returns `mvendorid`.
+  pub const GET_MVENDORID: usize = 4;
+  /// Returns `marchid`.
+  pub const GET_MARCHID: usize = 5;
+  /// Returns `mimpid`.
+  pub const GET_MIMPID: usize = 6;
+}
+
+/// Functions of the Remote Fence extension.
+pub mod rfence
+{
+  /// `fence.i` on the given harts.
+  pub const REMOTE_FENCE_I: usize = 0;
+  /// `sfence.vma` on the given harts.
+  pub const REMOTE_SFENCE_VMA: usize = 1;
+  /// `sfence.vma` for one ASID on the given harts.
+  pub const REMOTE_SFENCE_VMA_ASID: usize = 2;
+}
+
+/// Functions of the Hart State Management extension.
+pub mod hsm
+{
+  /// Starts a stopped hart.
+  pub const HART_START: usize = 0;
+  /// Stops the calling hart.
+  pub const HART_STOP: usize = 1;
+  /// Returns the state of a hart.
+  pub const HART_GET_STATUS: usize = 2;
+}
+
+/// The SBI version we implement and expect: 0.3.
+pub const SPEC_VERSION: usize = 3;
+
+/// The ways an SBI call can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SbiError
+{
+  /// The call failed for an unspecified reason.
+  Failed,
+  /// The extension or function is not implemented.
+  NotSupported,
+  /// An argument is out of range.
+  InvalidParam,
+  /// The caller may not do this.
+  Denied,
+  /// An address argument is invalid.
+  InvalidAddress,
+  /// The resource is already available.
+  AlreadyAvailable,
+  /// The hart is already started.
+  AlreadyStarted,
+  /// The hart is already stopped.
+  AlreadyStopped,
+  /// An error code the specification does not define.
+  Unknown(isize),
+}
+
+impl SbiError
+{
+  /// Decodes a nonzero error code.
+  pub fn from_code(code: isize) -> Self
+  {
+    match code {
+      -1 => SbiError::Failed,
+      -2 => SbiError::NotSupported,
+      -3 => SbiError::InvalidParam,
+      -4 => SbiError::Denied,
+      -5 => SbiError::InvalidAddress,
+      -6 => SbiError::AlreadyAvailable,
+      -7 => SbiError::AlreadyStarted,
+      -8 => SbiError::AlreadyStopped,
+      code => SbiError::Unknown(code),
+    }
+  }
+
+  /// The error code returned in `a0`.
+  pub fn code(self) -> isize
+  {
+    match self {
+      SbiError::Failed => -1,
+      SbiError::NotSupported => -2,
+      SbiError::InvalidParam => -3,
+      SbiError::Denied => -4,
+      SbiError::InvalidAddress => -5,
+      SbiError::AlreadyAvailable => -6,
+      SbiError::AlreadyStarted => -7,
+      SbiError::AlreadyStopped => -8,
+      SbiError::Unknown(code) => code,
+    }
+  }
+}
+
+impl Display for SbiError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      SbiError::Failed => write!(f, "SBI call failed"),
+      SbiError::NotSupported => write!(f, "SBI call not supported"),
+      SbiError::InvalidParam => write!(f, "invalid SBI parameter"),
+      SbiError::Denied => write!(f, "SBI call denied"),
+      SbiError::InvalidAddress => write!(f, "invalid address passed to SBI"),
+      SbiError::AlreadyAvailable => write!(f, "already available"),
+      SbiError::AlreadyStarted => write!(f, "hart already started"),
+      SbiError::AlreadyStopped => write!(f, "hart already stopped"),
+      SbiError::Unknown(code) => write!(f, "unknown SBI error {}", code),
+    }
+  }
+}
+
+/// The result of an SBI call.
+pub type SbiResult<T = usize> = Result<T, SbiError>;
+
+/// The states a hart can be in, as reported by `hart_status`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum HartState
+{
+  /// Running in S-mode.
+  Started = 0,
+  /// Parked in the firmware.
+  Stopped = 1,
+  /// `hart_start` was called and the hart is on its way.
+  StartPending = 2,
+  /// `hart_stop` was called and the hart is on its way.
+  StopPending = 3,
+}
+
+impl HartState
+{
+  /// Decodes a state returned by `HART_GET_STATUS`.
+  pub fn from_value(value: usize) -> Option<Self>
+  {
+    match value {
+      0 => Some(HartState::Started),
+      1 => Some(HartState::Stopped),
+      2 => Some(HartState::StartPending),
+      3 => Some(HartState::StopPending),
+      _ => None,
+    }
+  }
+}
+
+/// The kinds of reset `system_reset` can do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetType
+{
+  /// Power the machine off.
+  Shutdown = 0,
+  /// Reset everything.
+  ColdReboot = 1,
+  /// Reset the harts but keep the rest of the machine's state.
+  WarmReboot = 2,
+}
+
+/// Makes an SBI call.
+#[inline]
+pub fn ecall(ext: usize, fid: usize, args: [usize; 6]) -> SbiResult
+{
+  let error: isize;
+  let value: usize;
+  unsafe {
+    asm!(
+      "ecall",
+      inlateout("a0") args[0] => error,
+      inlateout("a1") args[1] => value,
+      in("a2") args[2],
+      in("a3") args[3],
+      in("a4") args[4],
+      in("a5") args[5],
+      in("a6") fid,
+      in("a7") ext,
+    );
+  }
+
+  match error {
+    0 => Ok(value),
+    code => Err(SbiError::from_code(code)),
+  }
+}
+
+/// The SBI version the firmware implements, as `major << 24 | minor`.
+pub fn spec_version() -> usize
+{
+  // Only legacy firmware lacks this, and it never fails otherwise.
+  ecall(EXT_BASE, base::GET_SPEC_VERSION, [0; 6]).unwrap_or(0)
+}
+
+/// Returns true if the firmware implements extension `ext`.
+pub fn probe_extension(ext: usize) -> bool
+{
+  ecall(EXT_BASE, base::PROBE_EXTENSION, [ext, 0, 0, 0, 0, 0]).map_or(false, |v| v != 0)
+}
+
+/// Raises the supervisor timer interrupt of the calling hart once `time`
+/// reaches `deadline`, and clears the one pending now.
+pub fn set_timer(deadline: u64)
+{
+  // A nonexistent TIME extension leaves no way to program the timer at all.
+  ecall(EXT_TIME, 0, [deadline as usize, 0, 0, 0, 0, 0]).expect("SBI has no TIME extension");
+}
+
+/// The current value of the `time` CSR, which mirrors `mtime`.
+#[inline]
+pub fn time() -> u64
+{
+  let time: usize;
+  unsafe {
+    asm!("csrr {}, time", out(reg) time);
+  }
+
+  time as u64
+}
+
+/// Raises the supervisor software interrupt of every hart in `hart_mask`,
+/// where bit `n` stands for hart `hart_mask_base + n`.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()>
+{
+  ecall(EXT_IPI, 0, [hart_mask, hart_mask_base, 0, 0, 0, 0]).map(|_| ())
+}
+
+/// Runs `fence.i` on the harts in the mask.
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()>
+{
+  ecall(EXT_RFENCE, rfence::REMOTE_FENCE_I, [hart_mask, hart_mask_base, 0, 0, 0, 0]).map(|_| ())
+}
+
+/// Flushes the TLB entries for `start..start + size` on the harts in the
+/// mask.
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize)
+    -> SbiResult<()>
+{
+  let args = [hart_mask, hart_mask_base, start, size, 0, 0];
+  ecall(EXT_RFENCE, rfence::REMOTE_SFENCE_VMA, args).map(|_| ())
+}
+
+/// Flushes the TLB entries of `asid` for `start..start + size` on the
+/// harts in the mask.
+pub fn remote_sfence_vma_asid(
+  hart_mask: usize,
+  hart_mask_base: usize,
+  start: usize,
+  size: usize,
+  asid: usize,
+) -> SbiResult<()>
+{
+  let args = [hart_mask, hart_mask_base, start, size, asid, 0];
+  ecall(EXT_RFENCE, rfence::REMOTE_SFENCE_VMA_ASID, args).map(|_| ())
+}
+
+/// Starts the stopped hart `hartid` in S-mode at the physical address
+/// `start`, with its id in `a0`, `opaque` in `a1` and paging off.
+pub fn hart_start(hartid: usize, start: usize, opaque: usize) -> SbiResult<()>
+{
+  ecall(EXT_HSM, hsm::HART_START, [hartid, start, opaque, 0, 0, 0]).map(|_| ())
+}
+
+/// Stops the calling hart. Only returns on failure.
+pub fn hart_stop() -> SbiError
+{
+  match ecall(EXT_HSM, hsm::HART_STOP, [0; 6]) {
+    Ok(_) => SbiError::Failed,
+    Err(e) => e,
+  }
+}
+
+/// The state of hart `hartid`.
+pub fn hart_status(hartid: usize) -> SbiResult<HartState>
+{
+  let value = ecall(EXT_HSM, hsm::HART_GET_STATUS, [hartid, 0, 0, 0, 0, 0])?;
+  HartState::from_value(value).ok_or(SbiError::Failed)
+}
+
+/// Shuts down or reboots the machine. Only returns on failure.
+pub fn system_reset(kind: ResetType, reason: usize) -> SbiError
+{
+  match ecall(EXT_SRST, 0, [kind as usize, reason, 0, 0, 0, 0]) {
+    Ok(_) => SbiError::Failed,
+    Err(e) => e,
+  }
+}
+
@@ -0,0 +1,135 @@
+//! Bringing up the secondary harts.
+//!
+//! The boot hart runs `kmain` alone. Once the kernel is ready for company
+//! it calls `start_secondaries`, which starts every hart the firmware has
+//! parked through the SBI HSM extension. Each one comes up in
+//! `_start_secondary` on its own stack, sets up its per-hart data, and
+//! enters the kernel's `#[secondary_entry]` function.
+//!
+//! Every hart keeps its id in a per-hart variable; see `percpu`.
+
+use core::cell::Cell;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+
+use crate::percpu;
+use crate::sbi::{self, HartState};
+use crate::MAX_HARTS;
+
+crate::percpu! {
+  /// The id of the hart each copy belongs to.
+  static HART_ID: Cell<usize> = Cell::new(0);
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Which harts have entered the kernel.
+static ONLINE: [AtomicBool; MAX_HARTS] = [OFFLINE; MAX_HARTS];
+
+/// The number of harts which have entered the kernel.
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C"
+{
+  fn _start_secondary();
+}
+
+/// Sets up the calling hart's per-hart data and points `tp` at it, turns
+/// on the FPU and masks every supervisor interrupt.
+///
+/// # Safety
+///
+/// Must run once per hart, in S-mode, before anything calls `hart_id`.
+pub(crate) unsafe fn init_hart(hartid: usize)
+{
+  percpu::init_hart(hartid);
+  HART_ID.get_unchecked().set(hartid);
+
+  asm!(
+    // sstatus.FS = Initial
+    "csrs sstatus, {fs}",
+    "csrw sie, zero",
+    fs = in(reg) 1usize << 13,
+  );
+}
+
+/// Marks the calling hart as having entered the kernel.
+pub(crate) fn set_online()
+{
+  // A hart which was stopped and started again is already counted.
+  if !ONLINE[hart_id()].swap(true, Ordering::AcqRel) {
+    ONLINE_COUNT.fetch_add(1, Ordering::AcqRel);
+  }
+}
+
+/// The id of the hart this runs on.
+#[inline]
+pub fn hart_id() -> usize
+{
+  // Reading another hart's copy after a migration is no worse than the
+  // value going stale a moment after it is read.
+  unsafe { HART_ID.get_unchecked() }.get()
+}
+
+/// Returns true once hart `hartid` has entered the kernel.
+#[inline]
+pub fn is_online(hartid: usize) -> bool
+{
+  ONLINE.get(hartid).map_or(false, |online| online.load(Ordering::Acquire))
+}
+
+/// The ids of every hart which has entered the kernel.
+pub fn online() -> impl Iterator<Item = usize>
+{
+  (0..MAX_HARTS).filter(|&hart| is_online(hart))
+}
+
+/// The number of harts which have entered the kernel.
+#[inline]
+pub fn online_count() -> usize
+{
+  ONLINE_COUNT.load(Ordering::Acquire)
+}
+
+/// Starts every hart the firmware reports as stopped, and waits for each
+/// to enter the kernel. Returns the number of harts online afterwards.
+///
+/// The secondaries run with paging off until they turn it on themselves,
+/// so `_start_secondary` must be identity mapped.
+pub fn start_secondaries() -> usize
+{
+  let me = hart_id();
+  for hart in (0..MAX_HARTS).filter(|&hart| hart != me) {
+    if sbi::hart_status(hart) != Ok(HartState::Stopped) {
+      continue;
+    }
+    if sbi::hart_start(hart, _start_secondary as usize, 0).is_err() {
+      continue;
+    }
+
+    while !is_online(hart) {
+      spin_loop_hint();
+    }
+  }
+
+  online_count()
+}
+
+/// Rust entry point of the secondary harts (_start_secondary_rust)
+#[export_name = "_start_secondary_rust"]
+pub unsafe extern "C" fn start_secondary_rust(hartid: usize) -> !
+{
+  #[rustfmt::skip]
+  extern "Rust"
+  {
+    fn kmain_secondary(hartid: usize) -> !;
+
+    fn _setup_interrupts();
+  }
+
+  init_hart(hartid);
+  _setup_interrupts();
+  set_online();
+
+  kmain_secondary(hartid);
+}
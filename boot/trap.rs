@@ -1,21 +1,387 @@
-/// Registers saved in trap handler
-#[allow(missing_docs)]
+//! Trap frames and the decoding of trap causes.
+
+use core::fmt;
+use core::mem::size_of;
+
+use crate::percpu::Template;
+
+/// The ABI names of the integer registers, indexed by register number.
+pub const REG_NAMES: [&str; 32] = [
+  "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+  "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+  "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+  "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// The bit of `scause` (or `mcause`) which is set for interrupts and clear for exceptions.
+pub const INTERRUPT_BIT: usize = 1 << (size_of::<usize>() * 8 - 1);
+
+/// `sstatus.SPP`: set if the trap was taken from S-mode, clear from U-mode.
+pub const SSTATUS_SPP: usize = 1 << 8;
+/// `sstatus.SPIE`: whether interrupts were enabled before the trap.
+pub const SSTATUS_SPIE: usize = 1 << 5;
+/// `sstatus.FS` set to Dirty, so that `_start_trap` restores the FP registers.
+pub const SSTATUS_FS_DIRTY: usize = 3 << 13;
+
+/// Where `_start_trap` finds its way back into the kernel on a trap from
+/// U-mode. Each hart's copy is at the very start of its per-hart area (see
+/// `link.ld`), and only the assembly touches it.
 #[repr(C)]
-pub struct TrapFrame {
-    pub ra: usize,
-    pub t0: usize,
-    pub t1: usize,
-    pub t2: usize,
-    pub t3: usize,
-    pub t4: usize,
-    pub t5: usize,
-    pub t6: usize,
-    pub a0: usize,
-    pub a1: usize,
-    pub a2: usize,
-    pub a3: usize,
-    pub a4: usize,
-    pub a5: usize,
-    pub a6: usize,
-    pub a7: usize,
+pub struct UserScratch
+{
+  /// The top of the kernel stack of the thread running in U-mode.
+  kernel_sp: usize,
+  /// The user's stack pointer, held while switching stacks.
+  user_sp: usize,
+}
+
+#[used]
+#[link_section = ".percpu.trap"]
+static USER_SCRATCH: Template<UserScratch> = Template::new(UserScratch { kernel_sp: 0, user_sp: 0 });
+
+/// Registers saved by `_start_trap`, or by `_start_mtrap` in M-mode.
+///
+/// The layout is shared with `asm.S`, which addresses the fields by offset;
+/// keep the two in sync.
+#[repr(C)]
+#[derive(Clone)]
+pub struct TrapFrame
+{
+  /// The integer registers `x0`..`x31`, indexed by register number. `sp`
+  /// holds its value from before the trap; the `x0` slot is always zero.
+  pub regs: [usize; 32],
+  /// The floating point registers `f0`..`f31`. These are only saved when
+  /// the kernel is built with the D extension and the FPU state is dirty;
+  /// otherwise they are left untouched.
+  pub fregs: [u64; 32],
+  /// The pc of the trapping instruction (`sepc` or `mepc`). Handlers may move it,
+  /// e.g. past an `ecall`, and the trap returns there.
+  pub epc: usize,
+  /// `sstatus` or `mstatus` at the time of the trap.
+  pub status: usize,
+  /// `scause` or `mcause` at the time of the trap.
+  pub cause: usize,
+  /// `stval` or `mtval` at the time of the trap: the faulting address or instruction.
+  pub tval: usize,
+}
+
+impl TrapFrame
+{
+  /// A frame which enters U-mode at `pc` with the stack pointer `sp`,
+  /// interrupts enabled and every other register zero.
+  pub fn user(pc: usize, sp: usize) -> Self
+  {
+    let mut regs = [0; 32];
+    regs[2] = sp;
+
+    Self {
+      regs,
+      fregs: [0; 32],
+      epc: pc,
+      status: SSTATUS_SPIE | SSTATUS_FS_DIRTY,
+      cause: 0,
+      tval: 0,
+    }
+  }
+
+  /// Returns true if the trap was taken from U-mode.
+  #[inline]
+  pub fn from_user(&self) -> bool
+  {
+    self.status & SSTATUS_SPP == 0
+  }
+
+  /// The return address register.
+  #[inline]
+  pub fn ra(&self) -> usize
+  {
+    self.regs[1]
+  }
+
+  /// The stack pointer from before the trap.
+  #[inline]
+  pub fn sp(&self) -> usize
+  {
+    self.regs[2]
+  }
+
+  /// The argument register `a<n>`.
+  #[inline]
+  pub fn arg(&self, n: usize) -> usize
+  {
+    assert!(n < 8, "no such argument register");
+    self.regs[10 + n]
+  }
+
+  /// Sets the argument register `a<n>`, which is restored on return.
+  #[inline]
+  pub fn set_arg(&mut self, n: usize, value: usize)
+  {
+    assert!(n < 8, "no such argument register");
+    self.regs[10 + n] = value;
+  }
+
+  /// Decodes the cause of the trap.
+  #[inline]
+  pub fn trap(&self) -> Trap
+  {
+    Trap::from_cause(self.cause)
+  }
+}
+
+impl fmt::Display for TrapFrame
+{
+  /// Prints every integer register and trap CSR, four to a line.
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    let width = size_of::<usize>() * 2 + 2;
+
+    for (i, (name, value)) in REG_NAMES.iter().zip(self.regs.iter()).enumerate() {
+      write!(f, "{:>4}: {:#0w$x}", name, value, w = width)?;
+      if i % 4 == 3 {
+        writeln!(f)?;
+      } else {
+        write!(f, "  ")?;
+      }
+    }
+
+    writeln!(
+      f,
+      " epc: {:#0w$x}  status: {:#0w$x}  cause: {:#0w$x}  tval: {:#0w$x}",
+      self.epc, self.status, self.cause, self.tval,
+      w = width,
+    )
+  }
+}
+
+/// Drops into U-mode with the registers in `frame`, as if returning from
+/// a trap taken there.
+///
+/// # Safety
+///
+/// `frame` must sit at the very top of the calling thread's kernel stack,
+/// where `_start_trap` saves the frames of the traps U-mode takes later;
+/// everything else on that stack is abandoned. It must come from
+/// `TrapFrame::user`, or otherwise have `SSTATUS_SPP` clear, and the
+/// current address space must map the user memory it runs.
+pub unsafe fn enter_user(frame: *mut TrapFrame) -> !
+{
+  extern "C"
+  {
+    fn _enter_user(frame: *mut TrapFrame) -> !;
+  }
+
+  _enter_user(frame)
+}
+
+/// The cause of a trap, decoded from `scause` or `mcause`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trap
+{
+  /// An asynchronous interrupt, with its cause code.
+  Interrupt(usize),
+  /// A synchronous exception.
+  Exception(Exception),
+}
+
+impl Trap
+{
+  /// Decodes a value of `scause` or `mcause`.
+  pub fn from_cause(cause: usize) -> Self
+  {
+    let code = cause & !INTERRUPT_BIT;
+    if cause & INTERRUPT_BIT != 0 {
+      Trap::Interrupt(code)
+    } else {
+      Trap::Exception(Exception::from_code(code))
+    }
+  }
+}
+
+/// A synchronous exception, as defined by the RISC-V privileged specification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exception
+{
+  /// The target of a jump or branch was not suitably aligned.
+  InstructionMisaligned,
+  /// An instruction fetch failed a PMA or PMP check.
+  InstructionFault,
+  /// The instruction could not be decoded, or is not allowed here.
+  IllegalInstruction,
+  /// An `ebreak` instruction.
+  Breakpoint,
+  /// A load from a misaligned address.
+  LoadMisaligned,
+  /// A load failed a PMA or PMP check.
+  LoadFault,
+  /// A store or AMO to a misaligned address.
+  StoreMisaligned,
+  /// A store or AMO failed a PMA or PMP check.
+  StoreFault,
+  /// An `ecall` from U-mode.
+  UserEnvCall,
+  /// An `ecall` from S-mode.
+  SupervisorEnvCall,
+  /// An `ecall` from M-mode.
+  MachineEnvCall,
+  /// An instruction fetch from a page that is not mapped executable.
+  InstructionPageFault,
+  /// A load from a page that is not mapped readable.
+  LoadPageFault,
+  /// A store or AMO to a page that is not mapped writable.
+  StorePageFault,
+  /// A cause code the specification reserves or leaves to the platform.
+  Unknown(usize),
+}
+
+impl Exception
+{
+  /// Decodes the exception code of a cause register, without the interrupt bit.
+  pub fn from_code(code: usize) -> Self
+  {
+    match code {
+      0 => Exception::InstructionMisaligned,
+      1 => Exception::InstructionFault,
+      2 => Exception::IllegalInstruction,
+      3 => Exception::Breakpoint,
+      4 => Exception::LoadMisaligned,
+      5 => Exception::LoadFault,
+      6 => Exception::StoreMisaligned,
+      7 => Exception::StoreFault,
+      8 => Exception::UserEnvCall,
+      9 => Exception::SupervisorEnvCall,
+      11 => Exception::MachineEnvCall,
+      12 => Exception::InstructionPageFault,
+      13 => Exception::LoadPageFault,
+      15 => Exception::StorePageFault,
+      code => Exception::Unknown(code),
+    }
+  }
+
+  /// The exception code of this exception.
+  pub fn code(self) -> usize
+  {
+    match self {
+      Exception::InstructionMisaligned => 0,
+      Exception::InstructionFault => 1,
+      Exception::IllegalInstruction => 2,
+      Exception::Breakpoint => 3,
+      Exception::LoadMisaligned => 4,
+      Exception::LoadFault => 5,
+      Exception::StoreMisaligned => 6,
+      Exception::StoreFault => 7,
+      Exception::UserEnvCall => 8,
+      Exception::SupervisorEnvCall => 9,
+      Exception::MachineEnvCall => 11,
+      Exception::InstructionPageFault => 12,
+      Exception::LoadPageFault => 13,
+      Exception::StorePageFault => 15,
+      Exception::Unknown(code) => code,
+    }
+  }
+
+  /// Returns true if `tval` holds the faulting virtual address.
+  pub fn has_address(self) -> bool
+  {
+    matches!(
+      self,
+      Exception::InstructionMisaligned
+          | Exception::InstructionFault
+          | Exception::LoadMisaligned
+          | Exception::LoadFault
+          | Exception::StoreMisaligned
+          | Exception::StoreFault
+          | Exception::InstructionPageFault
+          | Exception::LoadPageFault
+          | Exception::StorePageFault
+    )
+  }
+
+  /// Returns true for the page faults raised by address translation.
+  pub fn is_page_fault(self) -> bool
+  {
+    matches!(
+      self,
+      Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault
+    )
+  }
+
+  /// Returns true for the `ecall` exceptions.
+  pub fn is_env_call(self) -> bool
+  {
+    matches!(
+      self,
+      Exception::UserEnvCall | Exception::SupervisorEnvCall | Exception::MachineEnvCall
+    )
+  }
+}
+
+impl fmt::Display for Exception
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      Exception::InstructionMisaligned => write!(f, "instruction address misaligned"),
+      Exception::InstructionFault => write!(f, "instruction access fault"),
+      Exception::IllegalInstruction => write!(f, "illegal instruction"),
+      Exception::Breakpoint => write!(f, "breakpoint"),
+      Exception::LoadMisaligned => write!(f, "load address misaligned"),
+      Exception::LoadFault => write!(f, "load access fault"),
+      Exception::StoreMisaligned => write!(f, "store address misaligned"),
+      Exception::StoreFault => write!(f, "store access fault"),
+      Exception::UserEnvCall => write!(f, "environment call from U-mode"),
+      Exception::SupervisorEnvCall => write!(f, "environment call from S-mode"),
+      Exception::MachineEnvCall => write!(f, "environment call from M-mode"),
+      Exception::InstructionPageFault => write!(f, "instruction page fault"),
+      Exception::LoadPageFault => write!(f, "load page fault"),
+      Exception::StorePageFault => write!(f, "store page fault"),
+      Exception::Unknown(code) => write!(f, "unknown exception {}", code),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn decode()
+  {
+    assert_eq!(Trap::from_cause(13), Trap::Exception(Exception::LoadPageFault));
+    assert_eq!(Trap::from_cause(INTERRUPT_BIT | 7), Trap::Interrupt(7));
+    assert_eq!(Exception::from_code(10), Exception::Unknown(10));
+
+    for code in 0..16 {
+      assert_eq!(Exception::from_code(code).code(), code);
+    }
+  }
+
+  #[test]
+  fn addresses()
+  {
+    assert!(Exception::StorePageFault.has_address());
+    assert!(Exception::StorePageFault.is_page_fault());
+    assert!(!Exception::IllegalInstruction.has_address());
+    assert!(Exception::UserEnvCall.is_env_call());
+  }
+
+  #[test]
+  fn layout()
+  {
+    // asm.S relies on these offsets.
+    let reg = size_of::<usize>();
+    assert_eq!(size_of::<TrapFrame>(), 32 * reg + 32 * 8 + 4 * reg);
+    assert_eq!(size_of::<TrapFrame>() % 16, 0);
+  }
+
+  #[test]
+  fn user_frame()
+  {
+    let frame = TrapFrame::user(0x1000, 0x8000);
+    assert!(frame.from_user());
+    assert_eq!(frame.sp(), 0x8000);
+    assert_eq!(frame.epc, 0x1000);
+    assert_ne!(frame.status & SSTATUS_SPIE, 0);
+  }
 }
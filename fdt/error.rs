@@ -0,0 +1,39 @@
+//! Errors raised while reading a device tree blob.
+
+use core::fmt::{self, Display};
+
+/// The ways in which a device tree blob can be malformed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FdtError
+{
+  /// The pointer to the blob was null.
+  NullPointer,
+  /// The blob does not start with the `0xd00dfeed` magic number.
+  BadMagic(u32),
+  /// The blob uses a format version older than we can read.
+  UnsupportedVersion(u32),
+  /// An offset or size in the header points outside of the blob.
+  BadHeader,
+  /// The structure block holds an unknown token.
+  BadToken(u32),
+  /// A node name, property name or value runs off the end of its block.
+  Truncated,
+  /// A name is not valid UTF-8.
+  BadString,
+}
+
+impl Display for FdtError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      FdtError::NullPointer => write!(f, "device tree pointer is null"),
+      FdtError::BadMagic(m) => write!(f, "bad device tree magic {:#x}", m),
+      FdtError::UnsupportedVersion(v) => write!(f, "unsupported device tree version {}", v),
+      FdtError::BadHeader => write!(f, "device tree header is inconsistent"),
+      FdtError::BadToken(t) => write!(f, "unknown structure token {:#x}", t),
+      FdtError::Truncated => write!(f, "device tree is truncated"),
+      FdtError::BadString => write!(f, "device tree string is not valid UTF-8"),
+    }
+  }
+}
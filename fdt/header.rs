@@ -0,0 +1,93 @@
+//! The header at the very start of a flattened device tree.
+
+use crate::{read_u32, FdtError};
+
+/// The magic number every blob starts with.
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// The oldest format version whose layout we understand.
+pub const FDT_COMPAT_VERSION: u32 = 16;
+
+/// The size of the version 17 header, in bytes.
+pub const HEADER_SIZE: usize = 40;
+
+/// The header of a flattened device tree. All fields are big-endian in the
+/// blob and are converted to native order here.
+#[derive(Copy, Clone, Debug)]
+pub struct Header
+{
+  /// Must be `FDT_MAGIC`.
+  pub magic: u32,
+  /// The size of the whole blob, in bytes.
+  pub total_size: u32,
+  /// The offset of the structure block.
+  pub off_dt_struct: u32,
+  /// The offset of the strings block.
+  pub off_dt_strings: u32,
+  /// The offset of the memory reservation block.
+  pub off_mem_rsvmap: u32,
+  /// The format version of the blob.
+  pub version: u32,
+  /// The oldest version this blob is backwards compatible with.
+  pub last_comp_version: u32,
+  /// The physical ID of the boot CPU.
+  pub boot_cpuid_phys: u32,
+  /// The size of the strings block, in bytes.
+  pub size_dt_strings: u32,
+  /// The size of the structure block, in bytes.
+  pub size_dt_struct: u32,
+}
+
+impl Header
+{
+  /// Reads the header from the start of `data` without checking it.
+  pub fn read(data: &[u8]) -> Result<Self, FdtError>
+  {
+    if data.len() < HEADER_SIZE {
+      return Err(FdtError::Truncated);
+    }
+
+    Ok(Header {
+      magic: read_u32(data, 0)?,
+      total_size: read_u32(data, 4)?,
+      off_dt_struct: read_u32(data, 8)?,
+      off_dt_strings: read_u32(data, 12)?,
+      off_mem_rsvmap: read_u32(data, 16)?,
+      version: read_u32(data, 20)?,
+      last_comp_version: read_u32(data, 24)?,
+      boot_cpuid_phys: read_u32(data, 28)?,
+      size_dt_strings: read_u32(data, 32)?,
+      size_dt_struct: read_u32(data, 36)?,
+    })
+  }
+
+  /// Checks the magic, the version and that every block lies inside a blob
+  /// of `len` bytes.
+  pub fn validate(&self, len: usize) -> Result<(), FdtError>
+  {
+    if self.magic != FDT_MAGIC {
+      return Err(FdtError::BadMagic(self.magic));
+    }
+
+    if self.last_comp_version > 17 || self.version < FDT_COMPAT_VERSION {
+      return Err(FdtError::UnsupportedVersion(self.version));
+    }
+
+    let total = self.total_size as usize;
+    let fits = |off: u32, size: u32| {
+      (off as usize).checked_add(size as usize).is_some_and(|end| end <= total)
+    };
+
+    if total > len
+        || total < HEADER_SIZE
+        || !fits(self.off_dt_struct, self.size_dt_struct)
+        || !fits(self.off_dt_strings, self.size_dt_strings)
+        || !fits(self.off_mem_rsvmap, 16)
+        || !self.off_dt_struct.is_multiple_of(4)
+        || !self.off_mem_rsvmap.is_multiple_of(8) {
+      return Err(FdtError::BadHeader);
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,688 @@
+//! Flattened device tree (FDT) reader for the Trident kernel.
+//!
+//! The blob handed to us by the firmware in `a1` is read in place; nothing
+//! here allocates. Drivers find their devices through `Fdt::find_node`,
+//! `Fdt::compatible` and `Fdt::find_phandle`, and read their MMIO ranges
+//! and interrupts from the resulting `Node`.
+#![deny(clippy::all)]
+#![warn(missing_docs)]
+#![allow(dead_code)]
+#![cfg_attr(not(test), no_std)]
+
+/////////////////////////////////
+/////////// Modules /////////////
+/////////////////////////////////
+
+pub mod error;
+pub mod header;
+pub mod node;
+pub mod property;
+pub mod token;
+
+// END "modules" ////////////////
+//-------------------------------
+
+use core::slice;
+
+pub use self::error::FdtError;
+pub use self::header::Header;
+pub use self::node::{CellSizes, Node, Nodes};
+pub use self::property::Property;
+pub use self::token::{Token, Tokens};
+
+/// Reads the big-endian `u32` at `off` in `data`.
+pub(crate) fn read_u32(data: &[u8], off: usize) -> Result<u32, FdtError>
+{
+  let bytes = data.get(off..off + 4).ok_or(FdtError::Truncated)?;
+  Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads the big-endian `u64` at `off` in `data`.
+pub(crate) fn read_u64(data: &[u8], off: usize) -> Result<u64, FdtError>
+{
+  Ok((read_u32(data, off)? as u64) << 32 | read_u32(data, off + 4)? as u64)
+}
+
+/// A range of physical memory described by the device tree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region
+{
+  /// The first byte of the range.
+  pub base: u64,
+  /// The length of the range in bytes.
+  pub size: u64,
+}
+
+impl Region
+{
+  /// One past the last byte of the range.
+  #[inline]
+  pub fn end(&self) -> u64
+  {
+    self.base.saturating_add(self.size)
+  }
+}
+
+/// A validated flattened device tree.
+#[derive(Copy, Clone)]
+pub struct Fdt<'a>
+{
+  data: &'a [u8],
+  header: Header,
+}
+
+impl<'a> Fdt<'a>
+{
+  /// Validates the header of the blob in `data`.
+  pub fn new(data: &'a [u8]) -> Result<Self, FdtError>
+  {
+    let header = Header::read(data)?;
+    header.validate(data.len())?;
+
+    Ok(Self {
+      data: &data[..header.total_size as usize],
+      header,
+    })
+  }
+
+  /// Validates the blob at `ptr`, taking its length from the header.
+  ///
+  /// # Safety
+  ///
+  /// `ptr` must point to a device tree blob which stays mapped and
+  /// unmodified for `'a`.
+  pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError>
+  {
+    if ptr.is_null() {
+      return Err(FdtError::NullPointer);
+    }
+
+    let header = Header::read(slice::from_raw_parts(ptr, header::HEADER_SIZE))?;
+    if header.magic != header::FDT_MAGIC {
+      return Err(FdtError::BadMagic(header.magic));
+    }
+
+    Self::new(slice::from_raw_parts(ptr, header.total_size as usize))
+  }
+
+  /// The header of the blob.
+  #[inline]
+  pub fn header(&self) -> &Header
+  {
+    &self.header
+  }
+
+  /// The whole blob.
+  #[inline]
+  pub fn as_bytes(&self) -> &'a [u8]
+  {
+    self.data
+  }
+
+  /// The size of the whole blob, in bytes.
+  #[inline]
+  pub fn total_size(&self) -> usize
+  {
+    self.data.len()
+  }
+
+  /// The physical range the blob itself occupies, which must not be handed
+  /// out as free memory.
+  pub fn blob_region(&self) -> Region
+  {
+    Region {
+      base: self.data.as_ptr() as u64,
+      size: self.data.len() as u64,
+    }
+  }
+
+  pub(crate) fn structs(&self) -> &'a [u8]
+  {
+    let off = self.header.off_dt_struct as usize;
+    &self.data[off..off + self.header.size_dt_struct as usize]
+  }
+
+  pub(crate) fn strings(&self) -> &'a [u8]
+  {
+    let off = self.header.off_dt_strings as usize;
+    &self.data[off..off + self.header.size_dt_strings as usize]
+  }
+
+  /// Walks every token of the structure block.
+  pub fn tokens(&self) -> Tokens<'a>
+  {
+    Tokens::new(self.structs(), self.strings())
+  }
+
+  /// Walks the tokens of the structure block from `offset` onwards.
+  pub(crate) fn tokens_at(&self, offset: usize) -> Tokens<'a>
+  {
+    Tokens::at(self.structs(), self.strings(), offset)
+  }
+
+  /// The entries of the memory reservation block.
+  pub fn mem_reservations(&self) -> MemReservations<'a>
+  {
+    MemReservations {
+      data: self.data,
+      offset: self.header.off_mem_rsvmap as usize,
+    }
+  }
+
+  /// The root node, or `None` if the structure block is malformed.
+  pub fn root(&self) -> Option<Node<'a>>
+  {
+    Node::at(*self, 0, CellSizes::default())
+  }
+
+  /// Every node of the tree, parents before children.
+  pub fn nodes(&self) -> Nodes<'a>
+  {
+    Nodes::new(*self)
+  }
+
+  /// Looks up a node by its path (`/soc/serial@10000000`), or by an alias
+  /// from `/aliases` if `path` does not start with a `/`.
+  ///
+  /// A path component without a unit address matches the first node with
+  /// that name, whatever its unit address.
+  pub fn find_node(&self, path: &str) -> Option<Node<'a>>
+  {
+    let path = if path.starts_with('/') {
+      path
+    } else {
+      self.alias(path)?
+    };
+
+    let mut node = self.root()?;
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+      node = node.children().find(|child| {
+        child.name() == part || (!part.contains('@') && child.unit_name() == part)
+      })?;
+    }
+
+    Some(node)
+  }
+
+  /// The path `/aliases` gives for `alias`.
+  pub fn alias(&self, alias: &str) -> Option<&'a str>
+  {
+    let aliases = self.root()?.children().find(|n| n.name() == "aliases")?;
+    aliases.property(alias)?.as_str().filter(|path| path.starts_with('/'))
+  }
+
+  /// Looks up the node whose `phandle` is `phandle`.
+  pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>>
+  {
+    self.nodes().find(|n| n.phandle() == Some(phandle))
+  }
+
+  /// Every enabled node which lists `compat` in its `compatible`.
+  pub fn compatible<'b>(&self, compat: &'b str) -> impl Iterator<Item = Node<'a>> + 'b
+    where
+        'a: 'b,
+  {
+    self.nodes().filter(move |n| n.is_compatible(compat) && n.is_enabled())
+  }
+
+  /// The first enabled node compatible with any of `compats`.
+  pub fn find_compatible(&self, compats: &[&str]) -> Option<Node<'a>>
+  {
+    self.nodes()
+        .find(|n| compats.iter().any(|c| n.is_compatible(c)) && n.is_enabled())
+  }
+
+  /// The parent of `node`, or `None` for the root.
+  pub fn parent(&self, node: &Node<'a>) -> Option<Node<'a>>
+  {
+    let mut nodes = self.nodes();
+    while let Some(n) = nodes.next() {
+      if n.offset() == node.offset() {
+        return nodes.parent();
+      }
+    }
+
+    None
+  }
+
+  /// The `/chosen` node, which holds the boot parameters.
+  pub fn chosen(&self) -> Option<Node<'a>>
+  {
+    self.find_node("/chosen")
+  }
+
+  /// The kernel command line from `/chosen`.
+  pub fn bootargs(&self) -> Option<&'a str>
+  {
+    self.chosen()?.property("bootargs")?.as_str()
+  }
+
+  /// The device named by `stdout-path` in `/chosen`, which the console
+  /// should be written to.
+  pub fn stdout(&self) -> Option<Node<'a>>
+  {
+    let path = self.chosen()?.property("stdout-path")?.as_str()?;
+    // Anything after a ':' holds options such as the baud rate.
+    self.find_node(path.split(':').next()?)
+  }
+
+  /// Where the bootloader put the initial ramdisk, from the
+  /// `linux,initrd-start` and `linux,initrd-end` properties of `/chosen`.
+  pub fn initrd(&self) -> Option<Region>
+  {
+    let chosen = self.chosen()?;
+    let start = chosen.property("linux,initrd-start")?.as_u64()?;
+    let end = chosen.property("linux,initrd-end")?.as_u64()?;
+    Some(Region { base: start, size: end.checked_sub(start)? })
+  }
+
+  /// The ranges listed in the `reg` of every `/memory` node.
+  pub fn memory(&self) -> impl Iterator<Item = Region> + Clone + 'a
+  {
+    self.root()
+        .into_iter()
+        .flat_map(|root| root.children())
+        .filter(|n| n.unit_name() == "memory" || n.device_type() == Some("memory"))
+        .flat_map(|n| n.reg().into_iter().flatten())
+  }
+
+  /// The ranges listed in the `reg` of every child of `/reserved-memory`.
+  ///
+  /// Children which only ask for a dynamically placed `size` have no fixed
+  /// range and are skipped.
+  pub fn reserved_memory(&self) -> impl Iterator<Item = Region> + Clone + 'a
+  {
+    self.find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|n| n.children())
+        .flat_map(|n| n.reg().into_iter().flatten())
+  }
+}
+
+/// Iterates over the memory reservation block.
+#[derive(Clone)]
+pub struct MemReservations<'a>
+{
+  data: &'a [u8],
+  offset: usize,
+}
+
+impl<'a> Iterator for MemReservations<'a>
+{
+  type Item = Region;
+
+  fn next(&mut self) -> Option<Region>
+  {
+    let base = read_u64(self.data, self.offset).ok()?;
+    let size = read_u64(self.data, self.offset + 8).ok()?;
+    if base == 0 && size == 0 {
+      return None;
+    }
+
+    self.offset += 16;
+    Some(Region { base, size })
+  }
+}
+
+/// Decodes the `(address, size)` pairs of a `reg` property.
+#[derive(Clone)]
+pub struct RegIter<'a>
+{
+  reg: &'a [u8],
+  address_cells: usize,
+  size_cells: usize,
+}
+
+impl<'a> RegIter<'a>
+{
+  /// Decodes `reg` with the given number of 32-bit cells per field.
+  pub fn new(reg: &'a [u8], address_cells: u32, size_cells: u32) -> Self
+  {
+    Self {
+      reg,
+      address_cells: address_cells as usize,
+      size_cells: size_cells as usize,
+    }
+  }
+}
+
+/// Reads a number made of `cells` big-endian 32-bit cells. Anything wider
+/// than 64 bits keeps only its low 64 bits.
+fn read_cells(data: &[u8], cells: usize) -> Option<u64>
+{
+  let mut value = 0u64;
+  for i in 0..cells {
+    value = value.checked_shl(32).unwrap_or(0) | read_u32(data, i * 4).ok()? as u64;
+  }
+
+  Some(value)
+}
+
+impl<'a> Iterator for RegIter<'a>
+{
+  type Item = Region;
+
+  fn next(&mut self) -> Option<Region>
+  {
+    let stride = (self.address_cells + self.size_cells) * 4;
+    if stride == 0 || self.reg.len() < stride {
+      return None;
+    }
+
+    let base = read_cells(self.reg, self.address_cells)?;
+    let size = read_cells(&self.reg[self.address_cells * 4..], self.size_cells)?;
+    self.reg = &self.reg[stride..];
+
+    Some(Region { base, size })
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests
+{
+  use super::*;
+
+  /// Builds small device tree blobs for the tests.
+  pub struct Builder
+  {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    rsvmap: Vec<(u64, u64)>,
+  }
+
+  impl Builder
+  {
+    pub fn new() -> Self
+    {
+      Self { structs: Vec::new(), strings: Vec::new(), rsvmap: Vec::new() }
+    }
+
+    fn pad(&mut self)
+    {
+      while !self.structs.len().is_multiple_of(4) {
+        self.structs.push(0);
+      }
+    }
+
+    pub fn begin(&mut self, name: &str) -> &mut Self
+    {
+      self.structs.extend_from_slice(&1u32.to_be_bytes());
+      self.structs.extend_from_slice(name.as_bytes());
+      self.structs.push(0);
+      self.pad();
+      self
+    }
+
+    pub fn end(&mut self) -> &mut Self
+    {
+      self.structs.extend_from_slice(&2u32.to_be_bytes());
+      self
+    }
+
+    pub fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self
+    {
+      let nameoff = self.strings.len() as u32;
+      self.strings.extend_from_slice(name.as_bytes());
+      self.strings.push(0);
+
+      self.structs.extend_from_slice(&3u32.to_be_bytes());
+      self.structs.extend_from_slice(&(value.len() as u32).to_be_bytes());
+      self.structs.extend_from_slice(&nameoff.to_be_bytes());
+      self.structs.extend_from_slice(value);
+      self.pad();
+      self
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Self
+    {
+      let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes().to_vec()).collect();
+      self.prop(name, &value)
+    }
+
+    pub fn prop_str(&mut self, name: &str, value: &str) -> &mut Self
+    {
+      let mut bytes = value.as_bytes().to_vec();
+      bytes.push(0);
+      self.prop(name, &bytes)
+    }
+
+    pub fn reserve(&mut self, base: u64, size: u64) -> &mut Self
+    {
+      self.rsvmap.push((base, size));
+      self
+    }
+
+    pub fn build(&mut self) -> Vec<u8>
+    {
+      self.structs.extend_from_slice(&9u32.to_be_bytes());
+
+      let rsv_off = header::HEADER_SIZE;
+      let rsv_len = (self.rsvmap.len() + 1) * 16;
+      let struct_off = rsv_off + rsv_len;
+      let strings_off = struct_off + self.structs.len();
+      let total = strings_off + self.strings.len();
+
+      let mut blob = Vec::new();
+      for v in &[
+        header::FDT_MAGIC,
+        total as u32,
+        struct_off as u32,
+        strings_off as u32,
+        rsv_off as u32,
+        17,
+        16,
+        0,
+        self.strings.len() as u32,
+        self.structs.len() as u32,
+      ] {
+        blob.extend_from_slice(&v.to_be_bytes());
+      }
+      for &(base, size) in self.rsvmap.iter().chain(core::iter::once(&(0, 0))) {
+        blob.extend_from_slice(&base.to_be_bytes());
+        blob.extend_from_slice(&size.to_be_bytes());
+      }
+      blob.extend_from_slice(&self.structs);
+      blob.extend_from_slice(&self.strings);
+      blob
+    }
+  }
+
+  /// A tree shaped like the one QEMU's `virt` machine produces.
+  pub fn virt() -> Vec<u8>
+  {
+    Builder::new()
+        .reserve(0x8000_0000, 0x20_0000)
+        .begin("")
+          .prop_cells("#address-cells", &[2])
+          .prop_cells("#size-cells", &[2])
+          .prop_str("compatible", "riscv-virtio")
+          .begin("memory@80000000")
+            .prop_str("device_type", "memory")
+            .prop_cells("reg", &[0, 0x8000_0000, 0, 0x800_0000])
+          .end()
+          .begin("reserved-memory")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .begin("mmode_resv0@80000000")
+              .prop_cells("reg", &[0, 0x8000_0000, 0, 0x4_0000])
+            .end()
+            .begin("dynamic")
+              .prop_cells("size", &[0, 0x1000])
+            .end()
+          .end()
+          .begin("chosen")
+            .prop_str("bootargs", "console=ttyS0")
+            .prop_str("stdout-path", "serial0:115200n8")
+            .prop_cells("linux,initrd-start", &[0x8400_0000])
+            .prop_cells("linux,initrd-end", &[0, 0x8410_0000])
+          .end()
+          .begin("aliases")
+            .prop_str("serial0", "/soc/serial@10000000")
+          .end()
+          .begin("soc")
+            .prop_cells("#address-cells", &[2])
+            .prop_cells("#size-cells", &[2])
+            .prop_cells("interrupt-parent", &[3])
+            .begin("serial@10000000")
+              .prop_cells("interrupts", &[10])
+              .prop_str("compatible", "ns16550a")
+              .prop_cells("reg", &[0, 0x1000_0000, 0, 0x100])
+            .end()
+            .begin("virtio_mmio@10001000")
+              .prop_cells("interrupts", &[1])
+              .prop_cells("interrupt-parent", &[3])
+              .prop_str("compatible", "virtio,mmio")
+              .prop_str("status", "disabled")
+              .prop_cells("reg", &[0, 0x1000_1000, 0, 0x1000])
+            .end()
+            .begin("plic@c000000")
+              .prop_cells("phandle", &[3])
+              .prop_cells("#interrupt-cells", &[1])
+              .prop("interrupt-controller", &[])
+              .prop("compatible", b"sifive,plic-1.0.0\0riscv,plic0\0")
+              .prop_cells("reg", &[0, 0xc00_0000, 0, 0x60_0000])
+            .end()
+          .end()
+        .end()
+        .build()
+  }
+
+  #[test]
+  fn header()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+
+    assert_eq!(fdt.total_size(), blob.len());
+    assert_eq!(fdt.header().version, 17);
+  }
+
+  #[test]
+  fn bad_magic()
+  {
+    let mut blob = virt();
+    blob[0] = 0;
+
+    assert!(matches!(Fdt::new(&blob), Err(FdtError::BadMagic(_))));
+  }
+
+  #[test]
+  fn truncated()
+  {
+    let blob = virt();
+
+    assert_eq!(Fdt::new(&blob[..20]).err(), Some(FdtError::Truncated));
+    assert_eq!(Fdt::new(&blob[..blob.len() - 1]).err(), Some(FdtError::BadHeader));
+  }
+
+  #[test]
+  fn memory()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+    let memory: Vec<Region> = fdt.memory().collect();
+
+    assert_eq!(memory, vec![Region { base: 0x8000_0000, size: 0x800_0000 }]);
+  }
+
+  #[test]
+  fn reserved()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+    let reserved: Vec<Region> = fdt.reserved_memory().collect();
+    let rsvmap: Vec<Region> = fdt.mem_reservations().collect();
+
+    assert_eq!(reserved, vec![Region { base: 0x8000_0000, size: 0x4_0000 }]);
+    assert_eq!(rsvmap, vec![Region { base: 0x8000_0000, size: 0x20_0000 }]);
+  }
+
+  #[test]
+  fn single_cells()
+  {
+    let blob = Builder::new()
+        .begin("")
+          .prop_cells("#address-cells", &[1])
+          .prop_cells("#size-cells", &[1])
+          .begin("memory")
+            .prop_cells("reg", &[0x8000_0000, 0x100_0000, 0x9000_0000, 0x100_0000])
+          .end()
+        .end()
+        .build();
+    let fdt = Fdt::new(&blob).unwrap();
+    let memory: Vec<Region> = fdt.memory().collect();
+
+    assert_eq!(memory, vec![
+      Region { base: 0x8000_0000, size: 0x100_0000 },
+      Region { base: 0x9000_0000, size: 0x100_0000 },
+    ]);
+  }
+
+  #[test]
+  fn initrd()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+
+    assert_eq!(fdt.initrd(), Some(Region { base: 0x8400_0000, size: 0x10_0000 }));
+  }
+
+  #[test]
+  fn find_node()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+    let serial = fdt.find_node("/soc/serial@10000000").unwrap();
+
+    assert_eq!(serial.name(), "serial@10000000");
+    assert_eq!(serial.unit_name(), "serial");
+    assert_eq!(serial.unit_address(), Some("10000000"));
+    assert_eq!(fdt.find_node("/soc/plic").unwrap().name(), "plic@c000000");
+    assert_eq!(fdt.find_node("serial0").unwrap().offset(), serial.offset());
+    assert_eq!(fdt.stdout().unwrap().offset(), serial.offset());
+    assert_eq!(fdt.bootargs(), Some("console=ttyS0"));
+    assert!(fdt.find_node("/soc/uart").is_none());
+    assert_eq!(fdt.parent(&serial).unwrap().name(), "soc");
+    assert_eq!(fdt.parent(&fdt.root().unwrap()).map(|n| n.offset()), None);
+  }
+
+  #[test]
+  fn reg()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+    let serial = fdt.find_node("/soc/serial").unwrap();
+    let reg: Vec<Region> = serial.reg().unwrap().collect();
+
+    assert_eq!(reg, vec![Region { base: 0x1000_0000, size: 0x100 }]);
+    assert_eq!(fdt.root().unwrap().cell_sizes(), CellSizes { address: 2, size: 2 });
+  }
+
+  #[test]
+  fn compatible()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+
+    assert_eq!(fdt.compatible("riscv,plic0").count(), 1);
+    assert_eq!(fdt.compatible("virtio,mmio").count(), 0);
+    assert_eq!(fdt.find_compatible(&["ns8250", "ns16550a"]).unwrap().name(), "serial@10000000");
+  }
+
+  #[test]
+  fn interrupts()
+  {
+    let blob = virt();
+    let fdt = Fdt::new(&blob).unwrap();
+    let plic = fdt.find_phandle(3).unwrap();
+    let serial = fdt.find_node("/soc/serial").unwrap();
+    let virtio = fdt.find_node("/soc/virtio_mmio").unwrap();
+
+    assert_eq!(plic.name(), "plic@c000000");
+    // Inherited from the `interrupt-parent` of /soc.
+    assert_eq!(serial.interrupt_parent().unwrap().offset(), plic.offset());
+    assert_eq!(serial.interrupts().unwrap().collect::<Vec<_>>(), vec![10]);
+    assert_eq!(virtio.interrupts().unwrap().collect::<Vec<_>>(), vec![1]);
+    assert!(!virtio.is_enabled());
+  }
+}
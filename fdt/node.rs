@@ -0,0 +1,367 @@
+//! Walks the nodes of the structure block.
+
+use crate::{Fdt, Property, RegIter, Token, Tokens};
+
+/// The deepest nesting of nodes `Nodes` can follow. Deeper trees end the walk.
+pub const MAX_DEPTH: usize = 16;
+
+/// The `#address-cells` and `#size-cells` a node gives its children.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CellSizes
+{
+  /// The number of cells in an address.
+  pub address: u32,
+  /// The number of cells in a size.
+  pub size: u32,
+}
+
+impl Default for CellSizes
+{
+  /// The sizes the specification assumes when the properties are missing.
+  fn default() -> Self
+  {
+    Self { address: 2, size: 1 }
+  }
+}
+
+/// A node of the device tree.
+#[derive(Copy, Clone)]
+pub struct Node<'a>
+{
+  fdt: Fdt<'a>,
+  /// The offset of the node's `FDT_BEGIN_NODE` token.
+  offset: usize,
+  /// The offset of the first token after the node's name.
+  body: usize,
+  name: &'a str,
+  /// The cell sizes of the parent, which apply to this node's `reg`.
+  parent_cells: CellSizes,
+}
+
+impl<'a> Node<'a>
+{
+  /// Reads the node whose `FDT_BEGIN_NODE` token is at `offset`.
+  pub(crate) fn at(fdt: Fdt<'a>, offset: usize, parent_cells: CellSizes) -> Option<Self>
+  {
+    let mut tokens = fdt.tokens_at(offset);
+    match tokens.next()?.ok()? {
+      Token::BeginNode(name) => Some(Self {
+        fdt,
+        offset,
+        body: tokens.offset(),
+        name,
+        parent_cells,
+      }),
+      _ => None,
+    }
+  }
+
+  /// The offset of this node in the structure block, which identifies it.
+  #[inline]
+  pub fn offset(&self) -> usize
+  {
+    self.offset
+  }
+
+  /// The full name of the node (`serial@10000000`). The root's name is empty.
+  #[inline]
+  pub fn name(&self) -> &'a str
+  {
+    self.name
+  }
+
+  /// The name without its unit address (`serial`).
+  pub fn unit_name(&self) -> &'a str
+  {
+    self.name.split('@').next().unwrap_or(self.name)
+  }
+
+  /// The unit address after the `@`, if there is one.
+  pub fn unit_address(&self) -> Option<&'a str>
+  {
+    self.name.split_once('@').map(|(_, unit)| unit)
+  }
+
+  /// The properties of this node, in the order they appear in the blob.
+  pub fn properties(&self) -> Properties<'a>
+  {
+    Properties {
+      tokens: self.fdt.tokens_at(self.body),
+      done: false,
+    }
+  }
+
+  /// Looks up a property of this node by name.
+  pub fn property(&self, name: &str) -> Option<Property<'a>>
+  {
+    self.properties().find(|p| p.name == name)
+  }
+
+  /// The direct children of this node.
+  pub fn children(&self) -> Children<'a>
+  {
+    Children {
+      fdt: self.fdt,
+      tokens: self.fdt.tokens_at(self.body),
+      cells: self.cell_sizes(),
+      depth: 0,
+      done: false,
+    }
+  }
+
+  /// The `#address-cells` and `#size-cells` this node gives its children.
+  pub fn cell_sizes(&self) -> CellSizes
+  {
+    let mut cells = CellSizes::default();
+    for prop in self.properties() {
+      match prop.name {
+        "#address-cells" => cells.address = prop.as_u32().unwrap_or(cells.address),
+        "#size-cells" => cells.size = prop.as_u32().unwrap_or(cells.size),
+        _ => {}
+      }
+    }
+
+    cells
+  }
+
+  /// The ranges of the `reg` property, decoded with the parent's cell sizes.
+  ///
+  /// The addresses are in the parent's address space; `ranges` are not
+  /// translated.
+  pub fn reg(&self) -> Option<RegIter<'a>>
+  {
+    let reg = self.property("reg")?;
+    Some(RegIter::new(reg.value, self.parent_cells.address, self.parent_cells.size))
+  }
+
+  /// Returns true if `compat` is one of the strings in `compatible`.
+  pub fn is_compatible(&self, compat: &str) -> bool
+  {
+    self.property("compatible")
+        .map(|p| p.strings().any(|s| s == compat))
+        .unwrap_or(false)
+  }
+
+  /// The value of `device_type`, if present.
+  pub fn device_type(&self) -> Option<&'a str>
+  {
+    self.property("device_type")?.as_str()
+  }
+
+  /// Returns false if `status` marks the node as unusable.
+  pub fn is_enabled(&self) -> bool
+  {
+    match self.property("status").and_then(|p| p.as_str()) {
+      None | Some("okay") | Some("ok") => true,
+      Some(_) => false,
+    }
+  }
+
+  /// The handle other nodes use to refer to this one.
+  pub fn phandle(&self) -> Option<u32>
+  {
+    self.property("phandle")
+        .or_else(|| self.property("linux,phandle"))?
+        .as_u32()
+  }
+
+  /// The number of cells in the interrupt specifiers of an interrupt
+  /// controller.
+  pub fn interrupt_cells(&self) -> Option<u32>
+  {
+    self.property("#interrupt-cells")?.as_u32()
+  }
+
+  /// The controller this node's interrupts are delivered to.
+  ///
+  /// This is the node named by `interrupt-parent`, or failing that the
+  /// nearest ancestor which is an interrupt controller, following any
+  /// `interrupt-parent` found on the way.
+  pub fn interrupt_parent(&self) -> Option<Node<'a>>
+  {
+    let mut node = *self;
+    loop {
+      let parent = match node.property("interrupt-parent").and_then(|p| p.as_u32()) {
+        Some(phandle) => self.fdt.find_phandle(phandle)?,
+        None => self.fdt.parent(&node)?,
+      };
+      if parent.interrupt_cells().is_some() {
+        return Some(parent);
+      }
+
+      node = parent;
+    }
+  }
+
+  /// The interrupts this node raises at its interrupt parent.
+  pub fn interrupts(&self) -> Option<Interrupts<'a>>
+  {
+    let interrupts = self.property("interrupts")?;
+    let cells = self.interrupt_parent()?.interrupt_cells()?;
+    Some(Interrupts {
+      data: interrupts.value,
+      cells: cells as usize,
+    })
+  }
+}
+
+/// Iterates over the properties of a single node.
+#[derive(Clone)]
+pub struct Properties<'a>
+{
+  tokens: Tokens<'a>,
+  done: bool,
+}
+
+impl<'a> Iterator for Properties<'a>
+{
+  type Item = Property<'a>;
+
+  fn next(&mut self) -> Option<Property<'a>>
+  {
+    if self.done {
+      return None;
+    }
+
+    // The properties of a node come before any of its children.
+    match self.tokens.next() {
+      Some(Ok(Token::Prop(name, value))) => Some(Property { name, value }),
+      _ => {
+        self.done = true;
+        None
+      }
+    }
+  }
+}
+
+/// Iterates over the direct children of a node.
+#[derive(Clone)]
+pub struct Children<'a>
+{
+  fdt: Fdt<'a>,
+  tokens: Tokens<'a>,
+  /// The cell sizes of the parent, handed on to each child.
+  cells: CellSizes,
+  depth: usize,
+  done: bool,
+}
+
+impl<'a> Iterator for Children<'a>
+{
+  type Item = Node<'a>;
+
+  fn next(&mut self) -> Option<Node<'a>>
+  {
+    while !self.done {
+      let offset = self.tokens.offset();
+      match self.tokens.next() {
+        Some(Ok(Token::BeginNode(_))) => {
+          self.depth += 1;
+          if self.depth == 1 {
+            return Node::at(self.fdt, offset, self.cells);
+          }
+        }
+        Some(Ok(Token::EndNode)) if self.depth > 0 => self.depth -= 1,
+        Some(Ok(Token::Prop(..))) => {}
+        // The end of the parent, or a malformed blob.
+        _ => self.done = true,
+      }
+    }
+
+    None
+  }
+}
+
+/// Iterates over every node of the tree, parents before children.
+#[derive(Clone)]
+pub struct Nodes<'a>
+{
+  fdt: Fdt<'a>,
+  tokens: Tokens<'a>,
+  /// The open nodes, from the root down to the last one returned.
+  path: [Option<Node<'a>>; MAX_DEPTH],
+  /// The cell sizes each open node gives its children.
+  cells: [CellSizes; MAX_DEPTH],
+  depth: usize,
+}
+
+impl<'a> Nodes<'a>
+{
+  pub(crate) fn new(fdt: Fdt<'a>) -> Self
+  {
+    Self {
+      fdt,
+      tokens: fdt.tokens(),
+      path: [None; MAX_DEPTH],
+      cells: [CellSizes::default(); MAX_DEPTH],
+      depth: 0,
+    }
+  }
+
+  /// The parent of the node returned last.
+  pub fn parent(&self) -> Option<Node<'a>>
+  {
+    self.path[self.depth.checked_sub(2)?]
+  }
+}
+
+impl<'a> Iterator for Nodes<'a>
+{
+  type Item = Node<'a>;
+
+  fn next(&mut self) -> Option<Node<'a>>
+  {
+    loop {
+      let offset = self.tokens.offset();
+      match self.tokens.next()?.ok()? {
+        Token::BeginNode(_) => {
+          if self.depth == MAX_DEPTH {
+            return None;
+          }
+
+          let parent_cells = match self.depth {
+            0 => CellSizes::default(),
+            d => self.cells[d - 1],
+          };
+          let node = Node::at(self.fdt, offset, parent_cells)?;
+          self.path[self.depth] = Some(node);
+          self.cells[self.depth] = node.cell_sizes();
+          self.depth += 1;
+          return Some(node);
+        }
+        Token::EndNode => {
+          self.depth = self.depth.checked_sub(1)?;
+          self.path[self.depth] = None;
+        }
+        Token::Prop(..) => {}
+      }
+    }
+  }
+}
+
+/// Iterates over the interrupt specifiers of an `interrupts` property,
+/// yielding the first cell of each. That cell is the interrupt number for
+/// the PLIC and the other controllers we drive.
+#[derive(Copy, Clone)]
+pub struct Interrupts<'a>
+{
+  data: &'a [u8],
+  cells: usize,
+}
+
+impl<'a> Iterator for Interrupts<'a>
+{
+  type Item = u32;
+
+  fn next(&mut self) -> Option<u32>
+  {
+    let stride = self.cells * 4;
+    if stride == 0 || self.data.len() < stride {
+      return None;
+    }
+
+    let irq = crate::read_u32(self.data, 0).ok()?;
+    self.data = &self.data[stride..];
+    Some(irq)
+  }
+}
@@ -0,0 +1,127 @@
+//! Decodes the values of device tree properties.
+
+use core::str;
+
+use crate::read_u32;
+
+/// A single property of a node.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Property<'a>
+{
+  /// The name of the property (`compatible`, `reg`, ...).
+  pub name: &'a str,
+  /// The raw, big-endian value of the property.
+  pub value: &'a [u8],
+}
+
+impl<'a> Property<'a>
+{
+  /// Reads the value as a single 32-bit cell.
+  pub fn as_u32(&self) -> Option<u32>
+  {
+    if self.value.len() != 4 {
+      return None;
+    }
+
+    read_u32(self.value, 0).ok()
+  }
+
+  /// Reads the value as either one or two cells, as is allowed for
+  /// properties like `clock-frequency`.
+  pub fn as_u64(&self) -> Option<u64>
+  {
+    match self.value.len() {
+      4 => self.as_u32().map(u64::from),
+      8 => Some((read_u32(self.value, 0).ok()? as u64) << 32 | read_u32(self.value, 4).ok()? as u64),
+      _ => None,
+    }
+  }
+
+  /// Reads the value as a single NUL-terminated string.
+  pub fn as_str(&self) -> Option<&'a str>
+  {
+    self.strings().next()
+  }
+
+  /// Reads the value as a list of NUL-terminated strings, as used by
+  /// `compatible`.
+  pub fn strings(&self) -> StrList<'a>
+  {
+    StrList { data: self.value }
+  }
+
+  /// Reads the value as a list of 32-bit cells.
+  pub fn cells(&self) -> Cells<'a>
+  {
+    Cells { data: self.value }
+  }
+}
+
+/// Iterates over a list of NUL-terminated strings. Entries which are not
+/// valid UTF-8 end the list.
+#[derive(Copy, Clone)]
+pub struct StrList<'a>
+{
+  data: &'a [u8],
+}
+
+impl<'a> Iterator for StrList<'a>
+{
+  type Item = &'a str;
+
+  fn next(&mut self) -> Option<&'a str>
+  {
+    let len = self.data.iter().position(|&b| b == 0)?;
+    let s = str::from_utf8(&self.data[..len]).ok()?;
+    self.data = &self.data[len + 1..];
+    Some(s)
+  }
+}
+
+/// Iterates over the 32-bit cells of a value. A trailing partial cell is
+/// ignored.
+#[derive(Copy, Clone)]
+pub struct Cells<'a>
+{
+  data: &'a [u8],
+}
+
+impl<'a> Iterator for Cells<'a>
+{
+  type Item = u32;
+
+  fn next(&mut self) -> Option<u32>
+  {
+    let cell = read_u32(self.data, 0).ok()?;
+    self.data = &self.data[4..];
+    Some(cell)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn numbers()
+  {
+    let one = Property { name: "a", value: &[0, 0, 0x10, 0] };
+    let two = Property { name: "b", value: &[0, 0, 0, 1, 0, 0, 0, 2] };
+
+    assert_eq!(one.as_u32(), Some(0x1000));
+    assert_eq!(one.as_u64(), Some(0x1000));
+    assert_eq!(two.as_u32(), None);
+    assert_eq!(two.as_u64(), Some(0x1_0000_0002));
+    assert_eq!(two.cells().collect::<Vec<_>>(), vec![1, 2]);
+  }
+
+  #[test]
+  fn strings()
+  {
+    let compat = Property { name: "compatible", value: b"sifive,plic-1.0.0\0riscv,plic0\0" };
+
+    assert_eq!(compat.as_str(), Some("sifive,plic-1.0.0"));
+    assert_eq!(compat.strings().collect::<Vec<_>>(), vec!["sifive,plic-1.0.0", "riscv,plic0"]);
+  }
+}
@@ -0,0 +1,130 @@
+//! Walks the tokens of the structure block.
+
+use core::str;
+
+use crate::{read_u32, FdtError};
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// A single item of the structure block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Token<'a>
+{
+  /// The start of a node, with its unit name (`memory@80000000`).
+  /// The root node has an empty name.
+  BeginNode(&'a str),
+  /// The end of the most recently begun node.
+  EndNode,
+  /// A property of the innermost open node.
+  Prop(&'a str, &'a [u8]),
+}
+
+/// An iterator over the tokens of the structure block, with `FDT_NOP`s
+/// skipped. It stops at `FDT_END`, or at the first malformed token, which
+/// is yielded as an error.
+#[derive(Clone)]
+pub struct Tokens<'a>
+{
+  structs: &'a [u8],
+  strings: &'a [u8],
+  offset: usize,
+  done: bool,
+}
+
+impl<'a> Tokens<'a>
+{
+  pub(crate) fn new(structs: &'a [u8], strings: &'a [u8]) -> Self
+  {
+    Self { structs, strings, offset: 0, done: false }
+  }
+
+  /// Starts walking again at `offset` into the structure block, which
+  /// must be the offset of a token.
+  pub(crate) fn at(structs: &'a [u8], strings: &'a [u8], offset: usize) -> Self
+  {
+    Self { structs, strings, offset, done: false }
+  }
+
+  /// The offset into the structure block of the next token.
+  #[inline]
+  pub fn offset(&self) -> usize
+  {
+    self.offset
+  }
+
+  fn string(&self, off: usize) -> Result<&'a str, FdtError>
+  {
+    let bytes = self.strings.get(off..).ok_or(FdtError::Truncated)?;
+    cstr(bytes)
+  }
+
+  fn next_token(&mut self) -> Result<Option<Token<'a>>, FdtError>
+  {
+    loop {
+      let token = read_u32(self.structs, self.offset)?;
+      self.offset += 4;
+
+      match token {
+        FDT_BEGIN_NODE => {
+          let rest = self.structs.get(self.offset..).ok_or(FdtError::Truncated)?;
+          let name = cstr(rest)?;
+          self.offset = align4(self.offset + name.len() + 1);
+          return Ok(Some(Token::BeginNode(name)));
+        }
+        FDT_END_NODE => return Ok(Some(Token::EndNode)),
+        FDT_PROP => {
+          let len = read_u32(self.structs, self.offset)? as usize;
+          let nameoff = read_u32(self.structs, self.offset + 4)? as usize;
+          let start = self.offset + 8;
+          let value = self.structs.get(start..start + len).ok_or(FdtError::Truncated)?;
+          self.offset = align4(start + len);
+          return Ok(Some(Token::Prop(self.string(nameoff)?, value)));
+        }
+        FDT_NOP => continue,
+        FDT_END => return Ok(None),
+        other => return Err(FdtError::BadToken(other)),
+      }
+    }
+  }
+}
+
+impl<'a> Iterator for Tokens<'a>
+{
+  type Item = Result<Token<'a>, FdtError>;
+
+  fn next(&mut self) -> Option<Self::Item>
+  {
+    if self.done {
+      return None;
+    }
+
+    match self.next_token() {
+      Ok(Some(token)) => Some(Ok(token)),
+      Ok(None) => {
+        self.done = true;
+        None
+      }
+      Err(e) => {
+        self.done = true;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+#[inline]
+fn align4(off: usize) -> usize
+{
+  (off + 3) & !3
+}
+
+/// Reads a NUL-terminated string from the start of `bytes`.
+fn cstr(bytes: &[u8]) -> Result<&str, FdtError>
+{
+  let len = bytes.iter().position(|&b| b == 0).ok_or(FdtError::Truncated)?;
+  str::from_utf8(&bytes[..len]).map_err(|_| FdtError::BadString)
+}
@@ -0,0 +1,353 @@
+//! Block devices.
+//!
+//! A file system on a disk reads and writes it through `BlockDevice`, in
+//! whole blocks, without caring whether a virtio disk or memory is behind
+//! it. `RamDisk` is the one in memory, for tests and for images loaded
+//! whole.
+
+use core::fmt::{self, Display};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use alloc::spin::Mutex;
+use alloc::Array;
+
+use crate::vfs::FsError;
+
+/// The ways a block request can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockError
+{
+  /// The blocks are past the end of the device.
+  OutOfRange,
+  /// A buffer is not a whole number of blocks.
+  Misaligned,
+  /// The device cannot be written.
+  ReadOnly,
+  /// The device reported an error.
+  Io,
+  /// The device does not support the request.
+  Unsupported,
+}
+
+impl Display for BlockError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      BlockError::OutOfRange => write!(f, "block out of range"),
+      BlockError::Misaligned => write!(f, "buffer is not a whole number of blocks"),
+      BlockError::ReadOnly => write!(f, "read-only device"),
+      BlockError::Io => write!(f, "device I/O error"),
+      BlockError::Unsupported => write!(f, "request not supported by the device"),
+    }
+  }
+}
+
+impl From<BlockError> for FsError
+{
+  fn from(e: BlockError) -> Self
+  {
+    match e {
+      BlockError::ReadOnly => FsError::ReadOnly,
+      BlockError::Unsupported => FsError::Unsupported,
+      _ => FsError::Io,
+    }
+  }
+}
+
+/// The result of a block request.
+pub type BlockResult<T> = Result<T, BlockError>;
+
+/// A device read and written in fixed-size blocks.
+///
+/// Requests name their first block and take buffers which are a whole
+/// number of blocks; the vectored forms fill or drain several buffers from
+/// consecutive blocks in one request, where the device can.
+pub trait BlockDevice: Send + Sync
+{
+  /// The size of a block in bytes.
+  fn block_size(&self) -> usize;
+
+  /// The number of blocks.
+  fn blocks(&self) -> u64;
+
+  /// Reads the blocks from `block` on into `buf`.
+  fn read(&self, block: u64, buf: &mut [u8]) -> BlockResult<()>;
+
+  /// Writes `buf` to the blocks from `block` on.
+  fn write(&self, block: u64, buf: &[u8]) -> BlockResult<()>;
+
+  /// Reads the blocks from `block` on into each of `bufs` in turn.
+  fn read_vectored(&self, block: u64, bufs: &mut [&mut [u8]]) -> BlockResult<()>
+  {
+    let mut block = block;
+    for buf in bufs.iter_mut() {
+      self.read(block, buf)?;
+      block += (buf.len() / self.block_size()) as u64;
+    }
+    Ok(())
+  }
+
+  /// Writes each of `bufs` in turn to the blocks from `block` on.
+  fn write_vectored(&self, block: u64, bufs: &[&[u8]]) -> BlockResult<()>
+  {
+    let mut block = block;
+    for buf in bufs {
+      self.write(block, buf)?;
+      block += (buf.len() / self.block_size()) as u64;
+    }
+    Ok(())
+  }
+
+  /// Makes the blocks written so far survive a power cut.
+  fn flush(&self) -> BlockResult<()>
+  {
+    Ok(())
+  }
+
+  /// Returns true if the device cannot be written.
+  fn is_read_only(&self) -> bool
+  {
+    false
+  }
+
+  /// The capacity in bytes.
+  fn size(&self) -> u64
+  {
+    self.blocks() * self.block_size() as u64
+  }
+}
+
+/// Checks that a request for `len` bytes from `block` is whole blocks on
+/// `device`, and returns how many.
+pub fn check_request(device: &(impl BlockDevice + ?Sized), block: u64, len: usize)
+    -> BlockResult<u64>
+{
+  let block_size = device.block_size();
+  if !len.is_multiple_of(block_size) {
+    return Err(BlockError::Misaligned);
+  }
+  let count = (len / block_size) as u64;
+  match block.checked_add(count) {
+    Some(end) if end <= device.blocks() => Ok(count),
+    _ => Err(BlockError::OutOfRange),
+  }
+}
+
+/// Reads `buf.len()` bytes from byte `offset` of `device`, which need not
+/// be on block boundaries.
+pub fn read_bytes(device: &(impl BlockDevice + ?Sized), offset: u64, buf: &mut [u8])
+    -> BlockResult<()>
+{
+  let block_size = device.block_size() as u64;
+  let mut scratch = Array::new();
+  let mut done = 0;
+  while done < buf.len() {
+    let pos = offset + done as u64;
+    let (block, start) = (pos / block_size, (pos % block_size) as usize);
+    let n = (block_size as usize - start).min(buf.len() - done);
+    let out = &mut buf[done..done + n];
+
+    if n == block_size as usize {
+      device.read(block, out)?;
+    } else {
+      scratch.resize(block_size as usize, 0);
+      device.read(block, &mut scratch)?;
+      out.copy_from_slice(&scratch[start..start + n]);
+    }
+    done += n;
+  }
+  Ok(())
+}
+
+/// Writes `buf` at byte `offset` of `device`, reading the blocks it only
+/// partly covers first.
+pub fn write_bytes(device: &(impl BlockDevice + ?Sized), offset: u64, buf: &[u8])
+    -> BlockResult<()>
+{
+  let block_size = device.block_size() as u64;
+  let mut scratch = Array::new();
+  let mut done = 0;
+  while done < buf.len() {
+    let pos = offset + done as u64;
+    let (block, start) = (pos / block_size, (pos % block_size) as usize);
+    let n = (block_size as usize - start).min(buf.len() - done);
+    let data = &buf[done..done + n];
+
+    if n == block_size as usize {
+      device.write(block, data)?;
+    } else {
+      scratch.resize(block_size as usize, 0);
+      device.read(block, &mut scratch)?;
+      scratch[start..start + n].copy_from_slice(data);
+      device.write(block, &scratch)?;
+    }
+    done += n;
+  }
+  Ok(())
+}
+
+/// A block device in memory.
+pub struct RamDisk
+{
+  block_size: usize,
+  data: Mutex<Array<u8>>,
+  read_only: AtomicBool,
+  reads: AtomicUsize,
+  writes: AtomicUsize,
+}
+
+impl RamDisk
+{
+  /// A zeroed disk of `blocks` blocks of `block_size` bytes.
+  pub fn new(block_size: usize, blocks: u64) -> Self
+  {
+    assert!(block_size != 0, "block size must not be 0");
+    let mut data = Array::new();
+    data.resize(block_size * blocks as usize, 0);
+    Self::from_data(block_size, data)
+  }
+
+  /// A disk holding `image`, padded with zeroes to a whole block.
+  pub fn from_image(block_size: usize, image: &[u8]) -> Self
+  {
+    assert!(block_size != 0, "block size must not be 0");
+    let mut data = Array::new();
+    data.extend(image.iter());
+    data.resize(image.len().div_ceil(block_size) * block_size, 0);
+    Self::from_data(block_size, data)
+  }
+
+  fn from_data(block_size: usize, data: Array<u8>) -> Self
+  {
+    Self {
+      block_size,
+      data: Mutex::new(data),
+      read_only: AtomicBool::new(false),
+      reads: AtomicUsize::new(0),
+      writes: AtomicUsize::new(0),
+    }
+  }
+
+  /// Refuses, or again allows, writes.
+  pub fn set_read_only(&self, read_only: bool)
+  {
+    self.read_only.store(read_only, Ordering::Relaxed);
+  }
+
+  /// The number of read requests served.
+  #[inline]
+  pub fn reads(&self) -> usize
+  {
+    self.reads.load(Ordering::Relaxed)
+  }
+
+  /// The number of write requests served.
+  #[inline]
+  pub fn writes(&self) -> usize
+  {
+    self.writes.load(Ordering::Relaxed)
+  }
+
+  /// Copies the disk's contents into `buf`, which must be its size.
+  pub fn copy_to(&self, buf: &mut [u8])
+  {
+    buf.copy_from_slice(&self.data.lock());
+  }
+}
+
+impl BlockDevice for RamDisk
+{
+  fn block_size(&self) -> usize
+  {
+    self.block_size
+  }
+
+  fn blocks(&self) -> u64
+  {
+    (self.data.lock().len() / self.block_size) as u64
+  }
+
+  fn read(&self, block: u64, buf: &mut [u8]) -> BlockResult<()>
+  {
+    check_request(self, block, buf.len())?;
+    let start = block as usize * self.block_size;
+    buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+    self.reads.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+  }
+
+  fn write(&self, block: u64, buf: &[u8]) -> BlockResult<()>
+  {
+    if self.is_read_only() {
+      return Err(BlockError::ReadOnly);
+    }
+    check_request(self, block, buf.len())?;
+    let start = block as usize * self.block_size;
+    self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+    self.writes.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+  }
+
+  fn is_read_only(&self) -> bool
+  {
+    self.read_only.load(Ordering::Relaxed)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn ram_disk()
+  {
+    let disk = RamDisk::new(512, 4);
+    assert_eq!(disk.size(), 2048);
+
+    let mut block = [0xa5u8; 512];
+    disk.write(2, &block).unwrap();
+    block = [0; 512];
+    disk.read(2, &mut block).unwrap();
+    assert!(block.iter().all(|&b| b == 0xa5));
+
+    assert_eq!(disk.read(4, &mut block), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read(u64::MAX, &mut block), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read(0, &mut block[..100]), Err(BlockError::Misaligned));
+    disk.set_read_only(true);
+    assert_eq!(disk.write(0, &block), Err(BlockError::ReadOnly));
+  }
+
+  #[test]
+  fn vectored()
+  {
+    let disk = RamDisk::from_image(4, b"abcdefghij");
+    assert_eq!(disk.blocks(), 3);
+
+    let (mut a, mut b) = ([0u8; 4], [0u8; 8]);
+    disk.read_vectored(0, &mut [&mut a, &mut b]).unwrap();
+    assert_eq!(&a, b"abcd");
+    assert_eq!(&b, b"efghij\0\0");
+
+    disk.write_vectored(1, &[b"EFGH", b"IJ\0\0"]).unwrap();
+    let mut all = [0u8; 12];
+    disk.copy_to(&mut all);
+    assert_eq!(&all, b"abcdEFGHIJ\0\0");
+  }
+
+  #[test]
+  fn bytes()
+  {
+    let disk = RamDisk::from_image(4, b"abcdefghijkl");
+    let mut buf = [0u8; 7];
+    read_bytes(&disk, 3, &mut buf).unwrap();
+    assert_eq!(&buf, b"defghij");
+
+    write_bytes(&disk, 2, b"CDEFGH").unwrap();
+    let mut all = [0u8; 12];
+    disk.copy_to(&mut all);
+    assert_eq!(&all, b"abCDEFGHijkl");
+    assert_eq!(read_bytes(&disk, 10, &mut buf), Err(BlockError::OutOfRange));
+  }
+}
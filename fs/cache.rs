@@ -0,0 +1,637 @@
+//! A cache of disk blocks.
+//!
+//! Buffers are keyed by device and block, and found through a `HashMap`;
+//! the same buffers are linked into a list from the most to the least
+//! recently used, and when the cache is full the least recently used one
+//! is reused. Writes only dirty a buffer. It reaches the disk when it is
+//! evicted, or when its device is synced.
+//!
+//! A file system sees the cache as a `CachedDevice`, which is a block
+//! device like any other.
+
+use alloc::collections::HashMap;
+use alloc::spin::Mutex;
+use alloc::{Array, Shared};
+
+use crate::block::{check_request, BlockDevice, BlockError, BlockResult};
+
+/// A device registered with a cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceId(pub usize);
+
+/// What a buffer holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct Key
+{
+  device: DeviceId,
+  block: u64,
+}
+
+/// How well a cache is doing.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats
+{
+  /// Blocks found in the cache.
+  pub hits: u64,
+  /// Blocks which had to be read from the device.
+  pub misses: u64,
+  /// Buffers reused for other blocks.
+  pub evictions: u64,
+  /// Dirty buffers written to their device.
+  pub writebacks: u64,
+}
+
+struct Buffer
+{
+  key: Key,
+  data: Array<u8>,
+  dirty: bool,
+  /// The more recently used neighbour.
+  prev: Option<usize>,
+  /// The less recently used neighbour.
+  next: Option<usize>,
+}
+
+struct Inner
+{
+  capacity: usize,
+  devices: Array<Option<Shared<dyn BlockDevice>>>,
+  map: HashMap<Key, usize>,
+  buffers: Array<Buffer>,
+  /// Buffers which hold no block.
+  free: Array<usize>,
+  /// The most recently used buffer.
+  head: Option<usize>,
+  /// The least recently used buffer.
+  tail: Option<usize>,
+  stats: CacheStats,
+}
+
+impl Inner
+{
+  fn device(&self, id: DeviceId) -> BlockResult<Shared<dyn BlockDevice>>
+  {
+    self.devices.get(id.0).cloned().flatten().ok_or(BlockError::Unsupported)
+  }
+
+  fn unlink(&mut self, index: usize)
+  {
+    let (prev, next) = (self.buffers[index].prev, self.buffers[index].next);
+    match prev {
+      Some(prev) => self.buffers[prev].next = next,
+      None => self.head = next,
+    }
+    match next {
+      Some(next) => self.buffers[next].prev = prev,
+      None => self.tail = prev,
+    }
+  }
+
+  fn push_front(&mut self, index: usize)
+  {
+    self.buffers[index].prev = None;
+    self.buffers[index].next = self.head;
+    match self.head {
+      Some(head) => self.buffers[head].prev = Some(index),
+      None => self.tail = Some(index),
+    }
+    self.head = Some(index);
+  }
+
+  /// Marks buffer `index` as the most recently used.
+  fn touch(&mut self, index: usize)
+  {
+    if self.head != Some(index) {
+      self.unlink(index);
+      self.push_front(index);
+    }
+  }
+
+  /// Writes buffer `index` to its device if it is dirty.
+  fn write_back(&mut self, index: usize) -> BlockResult<()>
+  {
+    if !self.buffers[index].dirty {
+      return Ok(());
+    }
+    let key = self.buffers[index].key;
+    self.device(key.device)?.write(key.block, &self.buffers[index].data)?;
+    self.buffers[index].dirty = false;
+    self.stats.writebacks += 1;
+    Ok(())
+  }
+
+  /// Writes back buffer `index` and takes it out of the cache.
+  fn evict(&mut self, index: usize) -> BlockResult<()>
+  {
+    self.write_back(index)?;
+    self.unlink(index);
+    let key = self.buffers[index].key;
+    self.map.remove(&key);
+    self.free.push(index);
+    Ok(())
+  }
+
+  /// The least recently used buffer which is not dirty.
+  fn lru_clean(&self) -> Option<usize>
+  {
+    let mut index = self.tail;
+    while let Some(i) = index {
+      if !self.buffers[i].dirty {
+        return Some(i);
+      }
+      index = self.buffers[i].prev;
+    }
+    None
+  }
+
+  /// A buffer of `size` bytes for `key`, evicting the least recently used
+  /// one if the cache is full. It is not yet in the map or the list.
+  fn claim(&mut self, key: Key, size: usize) -> BlockResult<usize>
+  {
+    if self.map.len() >= self.capacity {
+      let lru = self.tail.expect("a full cache has buffers");
+      if let Err(e) = self.evict(lru) {
+        // Keep the block, and try it last next time; a clean buffer can go
+        // instead.
+        self.touch(lru);
+        self.evict(self.lru_clean().ok_or(e)?)?;
+      }
+      self.stats.evictions += 1;
+    }
+
+    let index = match self.free.pop() {
+      Some(index) => index,
+      None => {
+        self.buffers.push(Buffer { key, data: Array::new(), dirty: false, prev: None, next: None });
+        self.buffers.len() - 1
+      }
+    };
+    let buffer = &mut self.buffers[index];
+    buffer.key = key;
+    buffer.dirty = false;
+    buffer.data.resize(size, 0);
+    Ok(index)
+  }
+
+  /// Puts the claimed buffer `index` in the cache.
+  fn insert(&mut self, index: usize)
+  {
+    let key = self.buffers[index].key;
+    self.map.insert(key, index);
+    self.push_front(index);
+  }
+
+  /// Writes back every dirty buffer of `device`, or of every device, in
+  /// block order.
+  fn write_back_all(&mut self, device: Option<DeviceId>) -> BlockResult<()>
+  {
+    let mut dirty: Array<(Key, usize)> = Array::new();
+    for (key, &index) in self.map.keys_values() {
+      if self.buffers[index].dirty && device.is_none_or(|device| key.device == device) {
+        dirty.push((*key, index));
+      }
+    }
+    dirty.sort_unstable_by_key(|&(key, _)| (key.device.0, key.block));
+
+    for &(_, index) in dirty.iter() {
+      self.write_back(index)?;
+    }
+    Ok(())
+  }
+}
+
+/// A cache of disk blocks, shared by any number of devices.
+pub struct BufferCache
+{
+  inner: Mutex<Inner>,
+}
+
+impl BufferCache
+{
+  /// A cache of at most `capacity` blocks.
+  pub fn new(capacity: usize) -> Self
+  {
+    assert!(capacity > 0, "a buffer cache needs room for a block");
+    Self {
+      inner: Mutex::new(Inner {
+        capacity,
+        devices: Array::new(),
+        map: HashMap::new(),
+        buffers: Array::new(),
+        free: Array::new(),
+        head: None,
+        tail: None,
+        stats: CacheStats::default(),
+      }),
+    }
+  }
+
+  /// The most blocks the cache holds.
+  pub fn capacity(&self) -> usize
+  {
+    self.inner.lock().capacity
+  }
+
+  /// Changes how many blocks the cache may hold, evicting the least
+  /// recently used ones if it now holds too many.
+  pub fn set_capacity(&self, capacity: usize) -> BlockResult<()>
+  {
+    assert!(capacity > 0, "a buffer cache needs room for a block");
+    let mut inner = self.inner.lock();
+    inner.capacity = capacity;
+    while inner.map.len() > capacity {
+      let lru = inner.tail.expect("a full cache has buffers");
+      inner.evict(lru)?;
+      inner.stats.evictions += 1;
+      inner.buffers[lru].data = Array::new();
+    }
+    Ok(())
+  }
+
+  /// The number of blocks cached.
+  pub fn len(&self) -> usize
+  {
+    self.inner.lock().map.len()
+  }
+
+  /// Returns true if no block is cached.
+  pub fn is_empty(&self) -> bool
+  {
+    self.len() == 0
+  }
+
+  /// The counts of hits, misses, evictions and write-backs so far.
+  pub fn stats(&self) -> CacheStats
+  {
+    self.inner.lock().stats
+  }
+
+  /// Starts caching the blocks of `device`.
+  pub fn register(&self, device: Shared<dyn BlockDevice>) -> DeviceId
+  {
+    let mut inner = self.inner.lock();
+    match inner.devices.iter().position(Option::is_none) {
+      Some(index) => {
+        inner.devices[index] = Some(device);
+        DeviceId(index)
+      }
+      None => {
+        inner.devices.push(Some(device));
+        DeviceId(inner.devices.len() - 1)
+      }
+    }
+  }
+
+  /// Writes back the dirty blocks of device `id`, drops all of its blocks
+  /// and forgets it.
+  pub fn unregister(&self, id: DeviceId) -> BlockResult<()>
+  {
+    let mut inner = self.inner.lock();
+    let device = inner.device(id)?;
+    inner.write_back_all(Some(id))?;
+
+    let mut index = inner.head;
+    while let Some(i) = index {
+      index = inner.buffers[i].next;
+      if inner.buffers[i].key.device == id {
+        inner.evict(i)?;
+      }
+    }
+    inner.devices[id.0] = None;
+    drop(inner);
+    device.flush()
+  }
+
+  /// Copies block `block` of device `id` into `buf`, which is one block
+  /// long, reading it from the device unless it is cached.
+  pub fn read(&self, id: DeviceId, block: u64, buf: &mut [u8]) -> BlockResult<()>
+  {
+    let mut inner = self.inner.lock();
+    let device = inner.device(id)?;
+    if buf.len() != device.block_size() {
+      return Err(BlockError::Misaligned);
+    }
+
+    let key = Key { device: id, block };
+    if let Some(&index) = inner.map.find(&key) {
+      inner.stats.hits += 1;
+      inner.touch(index);
+      buf.copy_from_slice(&inner.buffers[index].data);
+      return Ok(());
+    }
+
+    inner.stats.misses += 1;
+    let index = inner.claim(key, buf.len())?;
+    if let Err(e) = device.read(block, &mut inner.buffers[index].data) {
+      inner.free.push(index);
+      return Err(e);
+    }
+    inner.insert(index);
+    buf.copy_from_slice(&inner.buffers[index].data);
+    Ok(())
+  }
+
+  /// Copies `buf`, which is one block long, over block `block` of device
+  /// `id` in the cache. The device is written when the block is evicted or
+  /// synced.
+  pub fn write(&self, id: DeviceId, block: u64, buf: &[u8]) -> BlockResult<()>
+  {
+    let mut inner = self.inner.lock();
+    let device = inner.device(id)?;
+    if device.is_read_only() {
+      return Err(BlockError::ReadOnly);
+    }
+    if buf.len() != device.block_size() {
+      return Err(BlockError::Misaligned);
+    }
+
+    let key = Key { device: id, block };
+    let index = match inner.map.find(&key) {
+      Some(&index) => {
+        inner.stats.hits += 1;
+        inner.touch(index);
+        index
+      }
+      None => {
+        // The whole block is overwritten; there is nothing to read.
+        inner.stats.misses += 1;
+        let index = inner.claim(key, buf.len())?;
+        inner.insert(index);
+        index
+      }
+    };
+    let buffer = &mut inner.buffers[index];
+    buffer.data.copy_from_slice(buf);
+    buffer.dirty = true;
+    Ok(())
+  }
+
+  /// Writes back the dirty blocks of device `id`, and flushes it.
+  pub fn sync_device(&self, id: DeviceId) -> BlockResult<()>
+  {
+    let mut inner = self.inner.lock();
+    inner.write_back_all(Some(id))?;
+    let device = inner.device(id)?;
+    drop(inner);
+    device.flush()
+  }
+
+  /// Writes back every dirty block, and flushes every device.
+  pub fn sync(&self) -> BlockResult<()>
+  {
+    let mut inner = self.inner.lock();
+    inner.write_back_all(None)?;
+    let devices: Array<_> = inner.devices.iter().flatten().cloned().collect();
+    drop(inner);
+
+    for device in devices.iter() {
+      device.flush()?;
+    }
+    Ok(())
+  }
+}
+
+/// A device whose blocks go through a cache.
+///
+/// Dropping it writes back its dirty blocks, but has nowhere to report a
+/// failure; `flush` first to see one.
+pub struct CachedDevice
+{
+  cache: Shared<BufferCache>,
+  device: Shared<dyn BlockDevice>,
+  id: DeviceId,
+}
+
+impl CachedDevice
+{
+  /// Caches the blocks of `device` in `cache`.
+  pub fn new(cache: Shared<BufferCache>, device: Shared<dyn BlockDevice>) -> Self
+  {
+    let id = cache.register(device.clone());
+    Self { cache, device, id }
+  }
+
+  /// The cache the blocks go through.
+  #[inline]
+  pub fn cache(&self) -> &Shared<BufferCache>
+  {
+    &self.cache
+  }
+
+  /// The device underneath.
+  #[inline]
+  pub fn device(&self) -> &Shared<dyn BlockDevice>
+  {
+    &self.device
+  }
+}
+
+impl BlockDevice for CachedDevice
+{
+  fn block_size(&self) -> usize
+  {
+    self.device.block_size()
+  }
+
+  fn blocks(&self) -> u64
+  {
+    self.device.blocks()
+  }
+
+  fn read(&self, block: u64, buf: &mut [u8]) -> BlockResult<()>
+  {
+    check_request(self, block, buf.len())?;
+    for (i, chunk) in buf.chunks_mut(self.block_size()).enumerate() {
+      self.cache.read(self.id, block + i as u64, chunk)?;
+    }
+    Ok(())
+  }
+
+  fn write(&self, block: u64, buf: &[u8]) -> BlockResult<()>
+  {
+    check_request(self, block, buf.len())?;
+    for (i, chunk) in buf.chunks(self.block_size()).enumerate() {
+      self.cache.write(self.id, block + i as u64, chunk)?;
+    }
+    Ok(())
+  }
+
+  fn flush(&self) -> BlockResult<()>
+  {
+    self.cache.sync_device(self.id)
+  }
+
+  fn is_read_only(&self) -> bool
+  {
+    self.device.is_read_only()
+  }
+}
+
+impl Drop for CachedDevice
+{
+  fn drop(&mut self)
+  {
+    let _ = self.cache.unregister(self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::block::RamDisk;
+
+  fn setup(capacity: usize) -> (Shared<RamDisk>, CachedDevice)
+  {
+    let disk = Shared::new(RamDisk::new(16, 8));
+    let cached = CachedDevice::new(Shared::new(BufferCache::new(capacity)), disk.clone());
+    (disk, cached)
+  }
+
+  #[test]
+  fn hits()
+  {
+    let (disk, cached) = setup(4);
+    let mut block = [0u8; 16];
+    for _ in 0..3 {
+      cached.read(1, &mut block).unwrap();
+    }
+    assert_eq!(disk.reads(), 1);
+    let stats = cached.cache().stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+  }
+
+  #[test]
+  fn misaligned()
+  {
+    let cache = BufferCache::new(4);
+    let id = cache.register(Shared::new(RamDisk::new(16, 8)));
+    let mut block = [0u8; 16];
+    cache.read(id, 1, &mut block).unwrap();
+
+    // Whether or not the block is cached, the buffer must be one block.
+    for n in [1, 2] {
+      assert_eq!(cache.read(id, n, &mut [0; 8]), Err(BlockError::Misaligned));
+      assert_eq!(cache.read(id, n, &mut [0; 32]), Err(BlockError::Misaligned));
+    }
+    assert_eq!(cache.write(id, 1, &[0; 8]), Err(BlockError::Misaligned));
+  }
+
+  #[test]
+  fn write_back()
+  {
+    let (disk, cached) = setup(4);
+    cached.write(2, &[7u8; 32]).unwrap();
+    assert_eq!(disk.writes(), 0);
+
+    let mut block = [0u8; 16];
+    cached.read(3, &mut block).unwrap();
+    assert_eq!(block, [7; 16]);
+    assert_eq!(disk.reads(), 0);
+
+    cached.flush().unwrap();
+    assert_eq!(disk.writes(), 2);
+    disk.read(3, &mut block).unwrap();
+    assert_eq!(block, [7; 16]);
+
+    // Clean now; syncing again writes nothing.
+    cached.flush().unwrap();
+    assert_eq!(cached.cache().stats().writebacks, 2);
+  }
+
+  #[test]
+  fn lru()
+  {
+    let (disk, cached) = setup(2);
+    let mut block = [0u8; 16];
+    cached.write(0, &[1; 16]).unwrap();
+    cached.read(1, &mut block).unwrap();
+    // Block 0 is now the most recently used, so 1 goes first.
+    cached.read(0, &mut block).unwrap();
+    cached.read(2, &mut block).unwrap();
+    assert_eq!(cached.cache().len(), 2);
+    assert_eq!(cached.cache().stats().evictions, 1);
+    assert_eq!(disk.writes(), 0);
+
+    cached.read(3, &mut block).unwrap();
+    assert_eq!(disk.writes(), 1);
+    disk.read(0, &mut block).unwrap();
+    assert_eq!(block, [1; 16]);
+
+    let reads = disk.reads();
+    cached.read(3, &mut block).unwrap();
+    assert_eq!(disk.reads(), reads);
+  }
+
+  #[test]
+  fn capacity()
+  {
+    let (disk, cached) = setup(8);
+    cached.write(0, &[2; 8 * 16]).unwrap();
+    assert_eq!(cached.cache().len(), 8);
+
+    cached.cache().set_capacity(3).unwrap();
+    assert_eq!(cached.cache().len(), 3);
+    assert_eq!(disk.writes(), 5);
+    let mut block = [0u8; 16];
+    cached.read(7, &mut block).unwrap();
+    assert_eq!(block, [2; 16]);
+    assert_eq!(disk.reads(), 0);
+  }
+
+  #[test]
+  fn failed_write_back()
+  {
+    let cache = Shared::new(BufferCache::new(2));
+    let a = Shared::new(RamDisk::new(16, 2));
+    let b = Shared::new(RamDisk::new(16, 4));
+    let cached_a = CachedDevice::new(cache.clone(), a.clone());
+    let cached_b = CachedDevice::new(cache.clone(), b);
+
+    cached_a.write(0, &[1; 16]).unwrap();
+    a.set_read_only(true);
+    let mut block = [0u8; 16];
+    // The dirty block cannot be written, so the clean ones make way.
+    for i in 0..4 {
+      cached_b.read(i, &mut block).unwrap();
+    }
+    assert_eq!(cache.stats().evictions, 3);
+    assert_eq!(a.writes(), 0);
+
+    // With every buffer dirty the failure reaches the caller.
+    cached_b.write(3, &[2; 16]).unwrap();
+    assert_eq!(cached_a.read(1, &mut block), Err(BlockError::ReadOnly));
+    cached_a.read(0, &mut block).unwrap();
+    assert_eq!(block, [1; 16]);
+
+    a.set_read_only(false);
+    cache.sync().unwrap();
+    a.read(0, &mut block).unwrap();
+    assert_eq!(block, [1; 16]);
+  }
+
+  #[test]
+  fn devices()
+  {
+    let cache = Shared::new(BufferCache::new(4));
+    let a = Shared::new(RamDisk::new(16, 2));
+    let b = Shared::new(RamDisk::new(16, 2));
+    let cached_a = CachedDevice::new(cache.clone(), a.clone());
+    let cached_b = CachedDevice::new(cache.clone(), b.clone());
+
+    cached_a.write(0, &[1; 16]).unwrap();
+    cached_b.write(0, &[2; 16]).unwrap();
+    let mut block = [0u8; 16];
+    cached_a.read(0, &mut block).unwrap();
+    assert_eq!(block, [1; 16]);
+
+    drop(cached_b);
+    assert_eq!(cache.len(), 1);
+    b.read(0, &mut block).unwrap();
+    assert_eq!(block, [2; 16]);
+
+    cache.sync().unwrap();
+    a.read(0, &mut block).unwrap();
+    assert_eq!(block, [1; 16]);
+  }
+}
@@ -0,0 +1,1425 @@
+//! ext2 file systems on a block device.
+//!
+//! The volume is split into block groups, each with a bitmap of its
+//! blocks, a bitmap of its inodes and a table of the inodes themselves.
+//! Inodes are numbered from 1 across the groups, and those numbers are the
+//! VFS's; the root is inode 2. A file's blocks are found through its
+//! inode's block map, where a 0 is a hole which reads as zeroes, and a
+//! directory is a file of records naming inodes. Files keep their owners
+//! and permissions, and may have several names.
+//!
+//! There is no clock here, so times are left as they are found and new
+//! inodes have none. The free counts in the superblock are written back on
+//! `sync`; only the first copy of the superblock and of the descriptors is
+//! kept up to date, as Linux does.
+
+use core::convert::TryFrom;
+
+use alloc::spin::Mutex;
+use alloc::{Array, Shared, String};
+
+use crate::block::{read_bytes, write_bytes, BlockDevice};
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, FsResult, FsStat, Ino, Stat};
+
+pub mod dir;
+pub mod group;
+pub mod inode;
+pub mod superblock;
+
+use self::dir::{file_type, rec_size, Record, HEADER_SIZE};
+use self::group::Groups;
+use self::inode::{
+  mode, Inode, DIRECT_BLOCKS, FAST_SYMLINK_MAX, INDEX_FL, INDIRECT, LINK_MAX, ROOT_INO,
+};
+use self::superblock::{feature, Superblock, SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE};
+
+/// The magic number at the start of a block of extended attributes.
+const XATTR_MAGIC: u32 = 0xea02_0000;
+
+/// A record found in a directory.
+struct Found
+{
+  name: String,
+  ino: u32,
+  file_type: u8,
+  /// Where the record is on the device.
+  pos: u64,
+  /// Where the record after it is in the directory.
+  next: u64,
+}
+
+struct Inner
+{
+  device: Shared<dyn BlockDevice>,
+  sb: Superblock,
+  groups: Groups,
+}
+
+impl Inner
+{
+  #[inline]
+  fn device(&self) -> &dyn BlockDevice
+  {
+    &*self.device
+  }
+
+  fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<()>
+  {
+    Ok(read_bytes(self.device(), offset, buf)?)
+  }
+
+  fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<()>
+  {
+    Ok(write_bytes(self.device(), offset, buf)?)
+  }
+
+  #[inline]
+  fn block_size(&self) -> u64
+  {
+    self.sb.block_size() as u64
+  }
+
+  /// The number of block numbers an indirect block holds.
+  #[inline]
+  fn per_block(&self) -> u64
+  {
+    self.block_size() / 4
+  }
+
+  /// The largest a regular file can be: as much as its block map reaches,
+  /// while `i_blocks` can still count its blocks and the indirect ones.
+  fn max_size(&self) -> u64
+  {
+    let p = self.per_block();
+    let mapped = DIRECT_BLOCKS as u64 + p + p * p + p * p * p;
+    let countable = u32::MAX as u64 / (self.block_size() / 512);
+    let max = mapped.min(countable - countable / p - 3) * self.block_size();
+    match self.sb.rev_level() {
+      0 => max.min(i32::MAX as u64),
+      _ => max,
+    }
+  }
+
+  /// Reads the inode `ino`, whatever state it is in.
+  fn read_inode(&self, ino: u32) -> FsResult<Inode>
+  {
+    if ino == 0 || ino > self.sb.inodes_count() {
+      return Err(FsError::NotFound);
+    }
+    let mut bytes = [0; inode::INODE_SIZE];
+    self.read_at(self.groups.inode_offset(&self.sb, ino), &mut bytes)?;
+    Ok(Inode::from_bytes(&bytes))
+  }
+
+  fn write_inode(&self, ino: u32, inode: &Inode) -> FsResult<()>
+  {
+    self.write_at(self.groups.inode_offset(&self.sb, ino), inode.as_bytes())
+  }
+
+  /// The inode `ino`, if it is in use.
+  fn inode(&self, ino: Ino) -> FsResult<Inode>
+  {
+    let ino = u32::try_from(ino).map_err(|_| FsError::NotFound)?;
+    let inode = self.read_inode(ino)?;
+    if inode.links() == 0 || inode.mode() == 0 {
+      return Err(FsError::NotFound);
+    }
+    Ok(inode)
+  }
+
+  /// The inode `ino`, if it is a regular file.
+  fn file(&self, ino: Ino) -> FsResult<Inode>
+  {
+    let inode = self.inode(ino)?;
+    match inode.kind() {
+      FileType::File => Ok(inode),
+      FileType::Directory => Err(FsError::IsDirectory),
+      _ => Err(FsError::Invalid),
+    }
+  }
+
+  /// The inode `ino`, if it is a directory.
+  fn dir(&self, ino: Ino) -> FsResult<Inode>
+  {
+    let inode = self.inode(ino)?;
+    match inode.kind() {
+      FileType::Directory => Ok(inode),
+      _ => Err(FsError::NotDirectory),
+    }
+  }
+
+  /// Returns true if the symbolic link `inode` keeps its target in
+  /// `i_block`, which it does when it has no blocks of its own.
+  fn is_fast_symlink(&self, inode: &Inode) -> bool
+  {
+    let xattr_sectors = if inode.file_acl() != 0 { self.block_size() as u32 / 512 } else { 0 };
+    inode.kind() == FileType::Symlink && inode.sectors() == xattr_sectors
+  }
+
+  /// Where block `n` of a file is in its block map: the slot of `i_block`,
+  /// how many indirect blocks are below it, and the index of the block
+  /// under that slot.
+  fn path(&self, n: u64) -> FsResult<(usize, u32, u64)>
+  {
+    if n < DIRECT_BLOCKS as u64 {
+      return Ok((n as usize, 0, 0));
+    }
+    let p = self.per_block();
+    let (mut index, mut span) = (n - DIRECT_BLOCKS as u64, p);
+    for depth in 1..=3 {
+      if index < span {
+        return Ok((INDIRECT + depth as usize - 1, depth, index));
+      }
+      index -= span;
+      span *= p;
+    }
+    Err(FsError::NoSpace)
+  }
+
+  /// The block numbers in the indirect block `block`.
+  fn entries(&self, block: u32) -> FsResult<Array<u32>>
+  {
+    let mut bytes = Array::new();
+    bytes.resize(self.block_size() as usize, 0);
+    self.read_at(self.sb.block_offset(block), &mut bytes)?;
+    Ok(bytes.chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+  }
+
+  fn entry_offset(&self, block: u32, index: u64) -> u64
+  {
+    self.sb.block_offset(block) + index * 4
+  }
+
+  /// The block holding block `n` of `inode`, or 0 if it is a hole.
+  fn bmap(&self, inode: &Inode, n: u64) -> FsResult<u32>
+  {
+    let (slot, depth, index) = self.path(n)?;
+    let p = self.per_block();
+    let mut block = inode.block(slot);
+    for level in (0..depth).rev() {
+      if block == 0 {
+        break;
+      }
+      let mut bytes = [0; 4];
+      self.read_at(self.entry_offset(block, index / p.pow(level) % p), &mut bytes)?;
+      block = u32::from_le_bytes(bytes);
+    }
+    if block >= self.sb.blocks_count() {
+      return Err(FsError::Io);
+    }
+    Ok(block)
+  }
+
+  /// Takes a zeroed block for `inode`, which is `ino`, near its inode.
+  fn alloc_block(&mut self, ino: u32, inode: &mut Inode) -> FsResult<u32>
+  {
+    let goal = Groups::goal(&self.sb, ino);
+    let Inner { groups, sb, device } = self;
+    let block = groups.alloc_block(&**device, sb, goal)?;
+    let mut zeroes = Array::new();
+    zeroes.resize(self.block_size() as usize, 0);
+    self.write_at(self.sb.block_offset(block), &zeroes)?;
+    inode.set_sectors(inode.sectors() + self.block_size() as u32 / 512);
+    Ok(block)
+  }
+
+  /// Frees `block` of `inode`.
+  fn release(&mut self, inode: &mut Inode, block: u32) -> FsResult<()>
+  {
+    let Inner { groups, sb, device } = self;
+    groups.free_block(&**device, sb, block)?;
+    inode.set_sectors(inode.sectors().saturating_sub(self.block_size() as u32 / 512));
+    Ok(())
+  }
+
+  /// The block holding block `n` of `inode`, which is `ino`, taking it and
+  /// any indirect blocks it needs if it is a hole.
+  fn bmap_alloc(&mut self, ino: u32, inode: &mut Inode, n: u64) -> FsResult<u32>
+  {
+    let (slot, depth, index) = self.path(n)?;
+    let p = self.per_block();
+    let mut block = inode.block(slot);
+    if block == 0 {
+      block = self.alloc_block(ino, inode)?;
+      inode.set_block(slot, block);
+    }
+    for level in (0..depth).rev() {
+      let at = self.entry_offset(block, index / p.pow(level) % p);
+      let mut bytes = [0; 4];
+      self.read_at(at, &mut bytes)?;
+      block = u32::from_le_bytes(bytes);
+      if block == 0 {
+        block = self.alloc_block(ino, inode)?;
+        self.write_at(at, &block.to_le_bytes())?;
+      }
+    }
+    if block >= self.sb.blocks_count() {
+      return Err(FsError::Io);
+    }
+    Ok(block)
+  }
+
+  /// Frees the blocks under the indirect block `block`, `level` levels
+  /// above the data, from index `from` on. Returns true if that was all of
+  /// them, and `block` itself has been freed.
+  fn free_tree(&mut self, inode: &mut Inode, block: u32, level: u32, from: u64) -> FsResult<bool>
+  {
+    let span = self.per_block().pow(level - 1);
+    let mut entries = self.entries(block)?;
+    for (i, entry) in entries.iter_mut().enumerate() {
+      let first = i as u64 * span;
+      if *entry == 0 || first + span <= from {
+        continue;
+      }
+      let freed = match level {
+        1 => true,
+        _ => self.free_tree(inode, *entry, level - 1, from.saturating_sub(first))?,
+      };
+      if level == 1 {
+        self.release(inode, *entry)?;
+      }
+      if freed {
+        *entry = 0;
+      }
+    }
+
+    if from == 0 {
+      self.release(inode, block)?;
+      return Ok(true);
+    }
+    let bytes: Array<u8> = entries.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+    self.write_at(self.sb.block_offset(block), &bytes)?;
+    Ok(false)
+  }
+
+  /// Frees the blocks of `inode` from block `keep` on.
+  fn free_blocks(&mut self, inode: &mut Inode, keep: u64) -> FsResult<()>
+  {
+    for n in keep.min(DIRECT_BLOCKS as u64) as usize..DIRECT_BLOCKS {
+      let block = inode.block(n);
+      if block != 0 {
+        self.release(inode, block)?;
+        inode.set_block(n, 0);
+      }
+    }
+
+    let mut first = DIRECT_BLOCKS as u64;
+    for depth in 1..=3 {
+      let slot = INDIRECT + depth as usize - 1;
+      let (block, span) = (inode.block(slot), self.per_block().pow(depth));
+      let from = keep.saturating_sub(first);
+      if block != 0 && from < span && self.free_tree(inode, block, depth, from)? {
+        inode.set_block(slot, 0);
+      }
+      first += span;
+    }
+    Ok(())
+  }
+
+  /// Reads from `offset` in the data of `inode` into `buf`, which must not
+  /// reach past its end.
+  fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> FsResult<()>
+  {
+    let block_size = self.block_size();
+    let mut done = 0;
+    while done < buf.len() {
+      let pos = offset + done as u64;
+      let start = pos % block_size;
+      let n = ((block_size - start) as usize).min(buf.len() - done);
+      let out = &mut buf[done..done + n];
+      match self.bmap(inode, pos / block_size)? {
+        0 => out.fill(0),
+        block => self.read_at(self.sb.block_offset(block) + start, out)?,
+      }
+      done += n;
+    }
+    Ok(())
+  }
+
+  /// Writes `buf` at `offset` in the data of `inode`, which is `ino`,
+  /// taking blocks for it as need be. Returns how much was written, which
+  /// is short if the disk fills up.
+  fn write_data(&mut self, ino: u32, inode: &mut Inode, offset: u64, buf: &[u8])
+      -> FsResult<usize>
+  {
+    let block_size = self.block_size();
+    let mut done = 0;
+    while done < buf.len() {
+      let pos = offset + done as u64;
+      let start = pos % block_size;
+      let n = ((block_size - start) as usize).min(buf.len() - done);
+      let block = match self.bmap_alloc(ino, inode, pos / block_size) {
+        Ok(block) => block,
+        Err(FsError::NoSpace) if done > 0 => break,
+        Err(e) => return Err(e),
+      };
+      self.write_at(self.sb.block_offset(block) + start, &buf[done..done + n])?;
+      done += n;
+    }
+    Ok(done)
+  }
+
+  /// Sets the size of `inode`, marking the volume as having large files
+  /// if it is one.
+  fn set_size(&mut self, inode: &mut Inode, size: u64) -> FsResult<()>
+  {
+    let large = feature::RO_COMPAT_LARGE_FILE;
+    if size > i32::MAX as u64 && self.sb.feature_ro_compat() & large == 0 {
+      self.sb.add_ro_compat(large);
+      self.write_at(SUPERBLOCK_OFFSET, self.sb.as_bytes())?;
+    }
+    inode.set_size(size);
+    Ok(())
+  }
+
+  /// Calls `f` with each record in `dir` in turn, until it returns true,
+  /// and returns that one. Free records and `.` and `..` are skipped.
+  fn scan(&self, dir: &Inode, f: impl FnMut(&Found) -> bool) -> FsResult<Option<Found>>
+  {
+    self.scan_from(dir, 0, f)
+  }
+
+  /// Like `scan`, but only records starting at byte `start` of `dir` or
+  /// later. Each block is still walked from its first record, so `start`
+  /// need not be where a record starts any more.
+  fn scan_from(&self, dir: &Inode, start: u64, mut f: impl FnMut(&Found) -> bool)
+      -> FsResult<Option<Found>>
+  {
+    let block_size = self.block_size();
+    let mut buf = Array::new();
+    buf.resize(block_size as usize, 0);
+
+    for n in start / block_size..dir.size() / block_size {
+      let block = match self.bmap(dir, n)? {
+        0 => continue,
+        block => block,
+      };
+      let offset = self.sb.block_offset(block);
+      self.read_at(offset, &mut buf)?;
+
+      let mut at = 0;
+      while at < buf.len() {
+        let record = Record::parse(&buf, at)?;
+        let name = &buf[at + HEADER_SIZE..at + HEADER_SIZE + record.name_len];
+        let here = n * block_size + at as u64;
+        if here >= start && record.ino != 0 && name != b"." && name != b".." {
+          let found = Found {
+            name: dir::name(name),
+            ino: record.ino,
+            file_type: record.file_type,
+            pos: offset + at as u64,
+            next: here + record.rec_len as u64,
+          };
+          if f(&found) {
+            return Ok(Some(found));
+          }
+        }
+        at += record.rec_len;
+      }
+    }
+    Ok(None)
+  }
+
+  /// The record named `name` in `dir`.
+  fn find(&self, dir: &Inode, name: &str) -> FsResult<Found>
+  {
+    self.scan(dir, |found| found.name == name)?.ok_or(FsError::NotFound)
+  }
+
+  /// Returns true if `dir` has no entries but `.` and `..`.
+  fn is_empty(&self, dir: &Inode) -> FsResult<bool>
+  {
+    Ok(self.scan(dir, |_| true)?.is_none())
+  }
+
+  /// What the inode of `found` is.
+  fn kind(&self, found: &Found) -> FsResult<FileType>
+  {
+    match dir::kind(found.file_type) {
+      Some(kind) if self.sb.has_file_type() => Ok(kind),
+      _ => Ok(self.read_inode(found.ino)?.kind()),
+    }
+  }
+
+  /// Writes a record for `ino` named `name` at `at` in the directory block
+  /// `buf`.
+  fn put_record(&self, buf: &mut [u8], at: usize, record: Record, name: &str)
+  {
+    buf[at..at + HEADER_SIZE].copy_from_slice(&record.to_bytes(self.sb.has_file_type()));
+    buf[at + HEADER_SIZE..at + HEADER_SIZE + name.len()].copy_from_slice(name.as_bytes());
+  }
+
+  /// Adds a record naming `ino`, of `file_type`, as `name` to the directory
+  /// `dir_ino`, growing it if need be. `name` must not be taken.
+  fn add(&mut self, dir_ino: u32, name: &str, ino: u32, file_type: u8) -> FsResult<()>
+  {
+    let mut dir = self.read_inode(dir_ino)?;
+    let block_size = self.block_size();
+    let needed = rec_size(name.len());
+    let mut buf = Array::new();
+    buf.resize(block_size as usize, 0);
+    let name_len = name.len();
+
+    for n in 0..dir.size() / block_size {
+      let block = match self.bmap(&dir, n)? {
+        0 => continue,
+        block => block,
+      };
+      let offset = self.sb.block_offset(block);
+      self.read_at(offset, &mut buf)?;
+
+      let mut at = 0;
+      while at < buf.len() {
+        let record = Record::parse(&buf, at)?;
+        if record.slack() >= needed {
+          // A free record is taken whole; otherwise the new one takes
+          // the room after the name.
+          let (at, rec_len) = match record.ino {
+            0 => (at, record.rec_len),
+            _ => {
+              let used = rec_size(record.name_len);
+              buf[at + 4..at + 6].copy_from_slice(&(used as u16).to_le_bytes());
+              (at + used, record.rec_len - used)
+            }
+          };
+          self.put_record(&mut buf, at, Record { ino, rec_len, name_len, file_type }, name);
+          self.write_at(offset, &buf)?;
+          return self.changed_dir(dir_ino, &mut dir);
+        }
+        at += record.rec_len;
+      }
+    }
+
+    // There is no room, so the record goes in a new block.
+    let n = dir.size() / block_size;
+    let block = self.bmap_alloc(dir_ino, &mut dir, n)?;
+    buf.fill(0);
+    let rec_len = block_size as usize;
+    self.put_record(&mut buf, 0, Record { ino, rec_len, name_len, file_type }, name);
+    self.write_at(self.sb.block_offset(block), &buf)?;
+    dir.set_size((n + 1) * block_size);
+    self.changed_dir(dir_ino, &mut dir)
+  }
+
+  /// Writes back `dir`, which is `ino`, after a record was added: if it
+  /// was a hashed tree, it is now only a list.
+  fn changed_dir(&self, ino: u32, dir: &mut Inode) -> FsResult<()>
+  {
+    dir.set_flags(dir.flags() & !INDEX_FL);
+    self.write_inode(ino, dir)
+  }
+
+  /// Removes the record at `pos` in a directory, giving its room to the
+  /// record before it in its block, if there is one.
+  fn remove(&self, pos: u64) -> FsResult<()>
+  {
+    let block_size = self.block_size();
+    let (offset, target) = (pos - pos % block_size, (pos % block_size) as usize);
+    let mut buf = Array::new();
+    buf.resize(block_size as usize, 0);
+    self.read_at(offset, &mut buf)?;
+
+    let (mut at, mut prev) = (0, None);
+    while at < target {
+      let record = Record::parse(&buf, at)?;
+      prev = Some((at, record));
+      at += record.rec_len;
+    }
+    let record = Record::parse(&buf, at)?;
+    if at != target || record.ino == 0 {
+      return Err(FsError::Io);
+    }
+
+    match prev {
+      Some((prev_at, prev)) => {
+        let rec_len = (prev.rec_len + record.rec_len) as u16;
+        buf[prev_at + 4..prev_at + 6].copy_from_slice(&rec_len.to_le_bytes());
+      }
+      None => buf[at..at + 4].fill(0),
+    }
+    self.write_at(offset, &buf)
+  }
+
+  /// Points the record at `pos` in a directory at `ino`, of `file_type`.
+  fn retarget(&self, pos: u64, ino: u32, file_type: u8) -> FsResult<()>
+  {
+    self.write_at(pos, &ino.to_le_bytes())?;
+    if self.sb.has_file_type() {
+      self.write_at(pos + 7, &[file_type])?;
+    }
+    Ok(())
+  }
+
+  /// Adds `delta` to the links of `ino`.
+  fn link(&self, ino: u32, delta: i32) -> FsResult<()>
+  {
+    let mut inode = self.read_inode(ino)?;
+    inode.set_links(inode.links().wrapping_add_signed(delta));
+    self.write_inode(ino, &inode)
+  }
+
+  /// Takes an inode in the group of `near`, and writes it out empty with
+  /// `mode`.
+  fn new_inode(&mut self, near: u32, mode: u16) -> FsResult<(u32, Inode)>
+  {
+    let goal = Groups::group_of(&self.sb, near);
+    let is_dir = mode & mode::TYPE == mode::DIRECTORY;
+    let Inner { groups, sb, device } = self;
+    let ino = groups.alloc_inode(&**device, sb, goal, is_dir)?;
+
+    // The part after the first 128 bytes of a larger inode is cleared.
+    let mut zeroes = Array::new();
+    zeroes.resize(self.sb.inode_size() as usize, 0);
+    self.write_at(self.groups.inode_offset(&self.sb, ino), &zeroes)?;
+    let inode = Inode::new(mode);
+    self.write_inode(ino, &inode)?;
+    Ok((ino, inode))
+  }
+
+  /// Frees the blocks of `ino`, its attributes and the inode itself.
+  fn drop_inode(&mut self, ino: u32) -> FsResult<()>
+  {
+    let mut inode = self.read_inode(ino)?;
+    if !self.is_fast_symlink(&inode) {
+      self.free_blocks(&mut inode, 0)?;
+    }
+
+    // A block of attributes may be shared between inodes.
+    let xattr = inode.file_acl();
+    if xattr != 0 {
+      let mut header = [0; 8];
+      self.read_at(self.sb.block_offset(xattr), &mut header)?;
+      let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+      let refs = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+      if magic != XATTR_MAGIC {
+        return Err(FsError::Io);
+      }
+      if refs > 1 {
+        self.write_at(self.sb.block_offset(xattr) + 4, &(refs - 1).to_le_bytes())?;
+        inode.set_sectors(inode.sectors().saturating_sub(self.block_size() as u32 / 512));
+      } else {
+        self.release(&mut inode, xattr)?;
+      }
+      inode.set_file_acl(0);
+    }
+
+    // There is no clock to give it a time of deletion, which e2fsck wants
+    // of an inode with a mode, so the mode goes as well.
+    let is_dir = inode.kind() == FileType::Directory;
+    inode.set_size(0);
+    inode.set_mode(0);
+    inode.set_links(0);
+    self.write_inode(ino, &inode)?;
+    let Inner { groups, sb, device } = self;
+    groups.free_inode(&**device, sb, ino, is_dir)
+  }
+
+  /// Takes away a name of `ino`, and drops it if that was its last.
+  fn unlinked(&mut self, ino: u32) -> FsResult<()>
+  {
+    let inode = self.read_inode(ino)?;
+    match inode.links() {
+      0 | 1 => self.drop_inode(ino),
+      _ => self.link(ino, -1),
+    }
+  }
+
+  /// Fills the first block of the new directory `ino` with `.` and `..`,
+  /// which names `parent`.
+  fn make_dir(&mut self, ino: u32, dir: &mut Inode, parent: u32) -> FsResult<()>
+  {
+    let block = self.bmap_alloc(ino, dir, 0)?;
+    let block_size = self.block_size() as usize;
+    let mut buf = Array::new();
+    buf.resize(block_size, 0);
+    let kind = file_type::DIRECTORY;
+    let dot = Record { ino, rec_len: 12, name_len: 1, file_type: kind };
+    self.put_record(&mut buf, 0, dot, ".");
+    let dotdot = Record { ino: parent, rec_len: block_size - 12, name_len: 2, file_type: kind };
+    self.put_record(&mut buf, 12, dotdot, "..");
+    self.write_at(self.sb.block_offset(block), &buf)?;
+
+    dir.set_size(block_size as u64);
+    dir.set_links(2);
+    self.write_inode(ino, dir)
+  }
+
+  /// Points `..` in the directory `dir` at `parent`.
+  fn set_parent(&self, dir: &Inode, parent: u32) -> FsResult<()>
+  {
+    let block = self.bmap(dir, 0)?;
+    let mut buf = Array::new();
+    buf.resize(self.block_size() as usize, 0);
+    self.read_at(self.sb.block_offset(block), &mut buf)?;
+    let dot = Record::parse(&buf, 0)?;
+    let dotdot = Record::parse(&buf, dot.rec_len)?;
+    if block == 0 || &buf[dot.rec_len + HEADER_SIZE..][..dotdot.name_len] != b".." {
+      return Err(FsError::Io);
+    }
+    self.retarget(self.sb.block_offset(block) + dot.rec_len as u64, parent, dotdot.file_type)
+  }
+}
+
+/// An ext2 file system on a block device.
+pub struct Ext2
+{
+  read_only: bool,
+  inner: Mutex<Inner>,
+}
+
+impl Ext2
+{
+  /// The file system on `device`, or `Invalid` if it has none, or
+  /// `Unsupported` if it needs features this driver lacks. It is read only
+  /// if the device is, or if the volume has features which are only safe
+  /// to read.
+  pub fn new(device: Shared<dyn BlockDevice>) -> FsResult<Self>
+  {
+    let mut bytes = [0; SUPERBLOCK_SIZE];
+    read_bytes(&*device, SUPERBLOCK_OFFSET, &mut bytes)?;
+    let mut sb = Superblock::parse(&bytes)?;
+    if sb.block_offset(sb.blocks_count()) > device.size() {
+      return Err(FsError::Invalid);
+    }
+
+    // The counts in the superblock are only written now and then; the
+    // groups' are kept up to date.
+    let groups = Groups::new(&*device, &sb)?;
+    let (blocks, inodes) = (0..sb.groups()).map(|n| groups.get(n)).fold((0, 0), |sum, desc| {
+      (sum.0 + desc.free_blocks(), sum.1 + desc.free_inodes())
+    });
+    sb.set_free_blocks(blocks);
+    sb.set_free_inodes(inodes);
+
+    let read_only = device.is_read_only() || !sb.is_writable();
+    Ok(Self { read_only, inner: Mutex::new(Inner { device, sb, groups }) })
+  }
+
+  fn writable(&self) -> FsResult<()>
+  {
+    if self.read_only {
+      Err(FsError::ReadOnly)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl FileSystem for Ext2
+{
+  fn name(&self) -> &'static str
+  {
+    "ext2"
+  }
+
+  fn root(&self) -> Ino
+  {
+    ROOT_INO as Ino
+  }
+
+  fn stat(&self, ino: Ino) -> FsResult<Stat>
+  {
+    let inode = self.inner.lock().inode(ino)?;
+    Ok(Stat {
+      ino,
+      kind: inode.kind(),
+      mode: (inode.mode() & mode::PERMISSIONS) as u32,
+      uid: inode.uid(),
+      gid: inode.gid(),
+      size: inode.size(),
+      nlink: inode.links(),
+      mtime: inode.mtime(),
+    })
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> FsResult<Ino>
+  {
+    let inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    Ok(inner.find(&dir, name)?.ino as Ino)
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> FsResult<usize>
+  {
+    let inner = self.inner.lock();
+    let inode = inner.file(ino)?;
+    let size = inode.size();
+    if offset >= size {
+      return Ok(0);
+    }
+    let len = buf.len().min((size - offset) as usize);
+    inner.read_data(&inode, offset, &mut buf[..len])?;
+    Ok(len)
+  }
+
+  fn readdir(&self, dir: Ino, pos: usize) -> FsResult<Option<(DirEntry, usize)>>
+  {
+    let inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    // Positions are byte offsets of records in the directory.
+    let found = inner.scan_from(&dir, pos as u64, |_| true)?;
+
+    match found {
+      Some(found) => {
+        let kind = inner.kind(&found)?;
+        let next = found.next as usize;
+        Ok(Some((DirEntry { name: found.name, ino: found.ino as Ino, kind }, next)))
+      }
+      None => Ok(None),
+    }
+  }
+
+  fn readlink(&self, ino: Ino) -> FsResult<String>
+  {
+    let inner = self.inner.lock();
+    let inode = inner.inode(ino)?;
+    if inode.kind() != FileType::Symlink {
+      return Err(FsError::Invalid);
+    }
+
+    let size = inode.size() as usize;
+    if inner.is_fast_symlink(&inode) {
+      let target = inode.block_bytes().get(..size).ok_or(FsError::Io)?;
+      return Ok(dir::name(target));
+    }
+    if size > inner.block_size() as usize {
+      return Err(FsError::Io);
+    }
+    let mut target = Array::new();
+    target.resize(size, 0);
+    inner.read_data(&inode, 0, &mut target)?;
+    Ok(dir::name(&target))
+  }
+
+  fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> FsResult<usize>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let mut inode = inner.file(ino)?;
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let max = inner.max_size();
+    if offset >= max {
+      return Err(FsError::NoSpace);
+    }
+
+    let ino = ino as u32;
+    let len = buf.len().min((max - offset) as usize);
+    let written = inner.write_data(ino, &mut inode, offset, &buf[..len]);
+    // Blocks taken before the disk filled up are kept.
+    let end = offset + *written.as_ref().unwrap_or(&0) as u64;
+    if end > inode.size() {
+      inner.set_size(&mut inode, end)?;
+    }
+    inner.write_inode(ino, &inode)?;
+    written
+  }
+
+  fn truncate(&self, ino: Ino, new_size: u64) -> FsResult<()>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let mut inode = inner.file(ino)?;
+    if new_size > inner.max_size() {
+      return Err(FsError::NoSpace);
+    }
+
+    // Past the end of a file its last block is zeroes, so that it can
+    // grow into them, and into holes, without writing.
+    let block_size = inner.block_size();
+    if new_size < inode.size() {
+      let start = new_size % block_size;
+      if start != 0 {
+        let block = inner.bmap(&inode, new_size / block_size)?;
+        if block != 0 {
+          let mut zeroes = Array::new();
+          zeroes.resize((block_size - start) as usize, 0);
+          inner.write_at(inner.sb.block_offset(block) + start, &zeroes)?;
+        }
+      }
+      inner.free_blocks(&mut inode, new_size.div_ceil(block_size))?;
+    }
+    inner.set_size(&mut inode, new_size)?;
+    inner.write_inode(ino as u32, &inode)
+  }
+
+  fn create(&self, dir: Ino, name: &str, kind: FileType, mode: u32) -> FsResult<Ino>
+  {
+    self.writable()?;
+    dir::check_name(name)?;
+    let mut inner = self.inner.lock();
+    let parent = inner.dir(dir)?;
+    match inner.find(&parent, name) {
+      Ok(_) => return Err(FsError::Exists),
+      Err(FsError::NotFound) => {}
+      Err(e) => return Err(e),
+    }
+
+    let kind_bits = match kind {
+      FileType::File => mode::FILE,
+      FileType::Directory => mode::DIRECTORY,
+      _ => return Err(FsError::Invalid),
+    };
+    // A new directory's `..` links its parent.
+    if kind == FileType::Directory && parent.links() >= LINK_MAX {
+      return Err(FsError::LinkLimit);
+    }
+    let dir = dir as u32;
+    let (ino, mut inode) = inner.new_inode(dir, kind_bits | (mode as u16 & mode::PERMISSIONS))?;
+    let mut made = Ok(());
+    if kind == FileType::Directory {
+      made = inner.make_dir(ino, &mut inode, dir);
+    }
+    let made = made.and_then(|_| inner.add(dir, name, ino, dir::file_type(inode.mode())));
+    if let Err(e) = made {
+      inner.drop_inode(ino)?;
+      return Err(e);
+    }
+
+    if kind == FileType::Directory {
+      inner.link(dir, 1)?;
+    }
+    Ok(ino as Ino)
+  }
+
+  fn symlink(&self, dir: Ino, name: &str, target: &str) -> FsResult<Ino>
+  {
+    self.writable()?;
+    dir::check_name(name)?;
+    let mut inner = self.inner.lock();
+    let parent = inner.dir(dir)?;
+    match inner.find(&parent, name) {
+      Ok(_) => return Err(FsError::Exists),
+      Err(FsError::NotFound) => {}
+      Err(e) => return Err(e),
+    }
+    if target.len() > inner.block_size() as usize {
+      return Err(FsError::NameTooLong);
+    }
+
+    let dir = dir as u32;
+    let (ino, mut inode) = inner.new_inode(dir, mode::SYMLINK | 0o777)?;
+    let mut made = Ok(());
+    if target.len() < FAST_SYMLINK_MAX {
+      inode.set_block_bytes(target.as_bytes());
+    } else {
+      made = inner.write_data(ino, &mut inode, 0, target.as_bytes()).map(|_| ());
+    }
+    inode.set_size(target.len() as u64);
+    let made = made
+        .and_then(|_| inner.write_inode(ino, &inode))
+        .and_then(|_| inner.add(dir, name, ino, file_type::SYMLINK));
+    if let Err(e) = made {
+      inner.drop_inode(ino)?;
+      return Err(e);
+    }
+    Ok(ino as Ino)
+  }
+
+  fn unlink(&self, dir: Ino, name: &str) -> FsResult<()>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let parent = inner.dir(dir)?;
+    let found = inner.find(&parent, name)?;
+    if inner.kind(&found)? == FileType::Directory {
+      return Err(FsError::IsDirectory);
+    }
+
+    inner.remove(found.pos)?;
+    inner.unlinked(found.ino)
+  }
+
+  fn rmdir(&self, dir: Ino, name: &str) -> FsResult<()>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let parent = inner.dir(dir)?;
+    let found = inner.find(&parent, name)?;
+    let target = inner.dir(found.ino as Ino)?;
+    if !inner.is_empty(&target)? {
+      return Err(FsError::NotEmpty);
+    }
+
+    inner.remove(found.pos)?;
+    inner.drop_inode(found.ino)?;
+    inner.link(dir as u32, -1)
+  }
+
+  fn rename(&self, from_dir: Ino, from: &str, to_dir: Ino, to: &str) -> FsResult<()>
+  {
+    self.writable()?;
+    dir::check_name(to)?;
+    let mut inner = self.inner.lock();
+    let (from_parent, to_parent) = (inner.dir(from_dir)?, inner.dir(to_dir)?);
+    let source = inner.find(&from_parent, from)?;
+    let is_dir = inner.kind(&source)? == FileType::Directory;
+
+    let replaced = match inner.find(&to_parent, to) {
+      // Two names of one file: there is nothing to do.
+      Ok(old) if old.ino == source.ino => return Ok(()),
+      Ok(old) => {
+        let old_is_dir = inner.kind(&old)? == FileType::Directory;
+        match (is_dir, old_is_dir) {
+          (true, true) if !inner.is_empty(&inner.dir(old.ino as Ino)?)? => {
+            return Err(FsError::NotEmpty);
+          }
+          (true, false) => return Err(FsError::NotDirectory),
+          (false, true) => return Err(FsError::IsDirectory),
+          _ => {}
+        }
+        Some((old, old_is_dir))
+      }
+      Err(FsError::NotFound) => None,
+      Err(e) => return Err(e),
+    };
+    // A directory moved in links its new parent, unless it takes the place
+    // of one which did.
+    let moved_in = is_dir && from_dir != to_dir && !matches!(replaced, Some((_, true)));
+    if moved_in && to_parent.links() >= LINK_MAX {
+      return Err(FsError::LinkLimit);
+    }
+
+    // The new name is in place before the old one goes, so that the file
+    // always has one; replacing a record is one write.
+    let (from_dir, to_dir) = (from_dir as u32, to_dir as u32);
+    match &replaced {
+      Some((old, _)) => inner.retarget(old.pos, source.ino, source.file_type)?,
+      None => inner.add(to_dir, to, source.ino, source.file_type)?,
+    }
+    // Adding may have moved the old record along in its block.
+    let source = inner.find(&inner.dir(from_dir as Ino)?, from)?;
+    inner.remove(source.pos)?;
+
+    if let Some((old, old_is_dir)) = replaced {
+      if old_is_dir {
+        inner.drop_inode(old.ino)?;
+        inner.link(to_dir, -1)?;
+      } else {
+        inner.unlinked(old.ino)?;
+      }
+    }
+    if is_dir && from_dir != to_dir {
+      inner.set_parent(&inner.dir(source.ino as Ino)?, to_dir)?;
+      inner.link(from_dir, -1)?;
+      inner.link(to_dir, 1)?;
+    }
+    Ok(())
+  }
+
+  fn statfs(&self) -> FsResult<FsStat>
+  {
+    let inner = self.inner.lock();
+    let sb = &inner.sb;
+    Ok(FsStat {
+      block_size: sb.block_size() as u64,
+      blocks: sb.blocks_count() as u64,
+      free_blocks: sb.free_blocks() as u64,
+      inodes: sb.inodes_count() as u64,
+      free_inodes: sb.free_inodes() as u64,
+    })
+  }
+
+  fn sync(&self) -> FsResult<()>
+  {
+    let inner = self.inner.lock();
+    if !self.read_only {
+      inner.write_at(SUPERBLOCK_OFFSET, inner.sb.as_bytes())?;
+    }
+    Ok(inner.device.flush()?)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::block::RamDisk;
+  use crate::vfs::testing::{mount, names, read_at};
+  use crate::vfs::{OpenFlags, SeekFrom};
+
+  const BLOCKS_PER_GROUP: u32 = 1024;
+  const INODES_PER_GROUP: u32 = 64;
+
+  /// The blocks of an inode table: 64 inodes of 128 bytes.
+  fn table_blocks(block_size: u32) -> u32
+  {
+    INODES_PER_GROUP * 128 / block_size
+  }
+
+  /// Formats a disk of `blocks` blocks of `block_size` bytes, in groups of
+  /// 1024, with an empty root directory.
+  fn mkfs(block_size: u32, blocks: u32) -> Shared<RamDisk>
+  {
+    let disk = RamDisk::new(block_size as usize, blocks as u64);
+    // With 1 KiB blocks the superblock is in block 1, after the boot block.
+    let first_data = (block_size == 1024) as u32;
+    let table_blocks = table_blocks(block_size);
+    let groups = (blocks - first_data).div_ceil(BLOCKS_PER_GROUP);
+    let (mut free_blocks, mut free_inodes) = (0, 0);
+    let (mut root_table, mut root_dir) = (0, 0);
+
+    for group in 0..groups {
+      // The first group also has the superblock and the descriptors.
+      let first = first_data + group * BLOCKS_PER_GROUP;
+      let bitmap = if group == 0 { first + 2 } else { first };
+      let table = bitmap + 2;
+      let mut used = table + table_blocks - first;
+      if group == 0 {
+        root_table = table;
+        root_dir = table + table_blocks;
+        used += 1;
+      }
+      let count = BLOCKS_PER_GROUP.min(blocks - first);
+      let reserved = if group == 0 { 10 } else { 0 };
+
+      // Bits past the end of the group are set.
+      let mut bits = std::vec![0xffu8; block_size as usize];
+      (used..count).for_each(|bit| bits[bit as usize / 8] &= !(1 << (bit % 8)));
+      disk.write(bitmap as u64, &bits).unwrap();
+      let mut bits = std::vec![0xffu8; block_size as usize];
+      (reserved..INODES_PER_GROUP).for_each(|bit| bits[bit as usize / 8] &= !(1 << (bit % 8)));
+      disk.write(bitmap as u64 + 1, &bits).unwrap();
+
+      let mut desc = [0u8; 32];
+      desc[0..4].copy_from_slice(&bitmap.to_le_bytes());
+      desc[4..8].copy_from_slice(&(bitmap + 1).to_le_bytes());
+      desc[8..12].copy_from_slice(&table.to_le_bytes());
+      desc[12..14].copy_from_slice(&((count - used) as u16).to_le_bytes());
+      desc[14..16].copy_from_slice(&((INODES_PER_GROUP - reserved) as u16).to_le_bytes());
+      desc[16..18].copy_from_slice(&((group == 0) as u16).to_le_bytes());
+      let at = (first_data as u64 + 1) * block_size as u64 + group as u64 * 32;
+      write_bytes(&disk, at, &desc).unwrap();
+      free_blocks += count - used;
+      free_inodes += INODES_PER_GROUP - reserved;
+    }
+
+    let mut sb = [0u8; SUPERBLOCK_SIZE];
+    sb[0..4].copy_from_slice(&(groups * INODES_PER_GROUP).to_le_bytes());
+    sb[4..8].copy_from_slice(&blocks.to_le_bytes());
+    sb[12..16].copy_from_slice(&free_blocks.to_le_bytes());
+    sb[16..20].copy_from_slice(&free_inodes.to_le_bytes());
+    sb[20..24].copy_from_slice(&first_data.to_le_bytes());
+    sb[24..28].copy_from_slice(&(block_size / 1024).trailing_zeros().to_le_bytes());
+    sb[32..36].copy_from_slice(&BLOCKS_PER_GROUP.to_le_bytes());
+    sb[36..40].copy_from_slice(&BLOCKS_PER_GROUP.to_le_bytes());
+    sb[40..44].copy_from_slice(&INODES_PER_GROUP.to_le_bytes());
+    sb[56..58].copy_from_slice(&superblock::MAGIC.to_le_bytes());
+    sb[58..60].copy_from_slice(&1u16.to_le_bytes());
+    sb[76..80].copy_from_slice(&1u32.to_le_bytes());
+    sb[84..88].copy_from_slice(&11u32.to_le_bytes());
+    sb[88..90].copy_from_slice(&128u16.to_le_bytes());
+    sb[96..100].copy_from_slice(&feature::INCOMPAT_FILETYPE.to_le_bytes());
+    write_bytes(&disk, SUPERBLOCK_OFFSET, &sb).unwrap();
+
+    // The root directory, inode 2, is the second in the first table.
+    let mut root = Inode::new(mode::DIRECTORY | 0o755);
+    root.set_links(2);
+    root.set_size(block_size as u64);
+    root.set_sectors(block_size / 512);
+    root.set_block(0, root_dir);
+    write_bytes(&disk, root_table as u64 * block_size as u64 + 128, root.as_bytes()).unwrap();
+    let mut block = std::vec![0u8; block_size as usize];
+    let dir = file_type::DIRECTORY;
+    let dot = Record { ino: 2, rec_len: 12, name_len: 1, file_type: dir };
+    let dotdot = Record { ino: 2, rec_len: block_size as usize - 12, name_len: 2, file_type: dir };
+    block[..8].copy_from_slice(&dot.to_bytes(true));
+    block[8] = b'.';
+    block[12..20].copy_from_slice(&dotdot.to_bytes(true));
+    block[20..22].copy_from_slice(b"..");
+    disk.write(root_dir as u64, &block).unwrap();
+    Shared::new(disk)
+  }
+
+  #[test]
+  fn mount_volume()
+  {
+    let (fs, vfs) = mount(Ext2::new(mkfs(1024, 4096)));
+    let stat = fs.statfs().unwrap();
+    assert_eq!((stat.block_size, stat.blocks), (1024, 4096));
+    assert_eq!((stat.inodes, stat.free_inodes), (4 * 64, 4 * 64 - 10));
+    assert_eq!(vfs.stat("/").unwrap().nlink, 2);
+    assert!(names(&vfs, "/").is_empty());
+
+    let blank: Shared<dyn BlockDevice> = Shared::new(RamDisk::new(1024, 64));
+    assert_eq!(Ext2::new(blank).err(), Some(FsError::Invalid));
+
+    // An inode table which starts on the volume but runs off its end.
+    for table in &[4096 - table_blocks(1024) + 1, u32::MAX] {
+      let disk = mkfs(1024, 4096);
+      write_bytes(&*disk, 2048 + 3 * 32 + 8, &table.to_le_bytes()).unwrap();
+      assert_eq!(Ext2::new(disk).err(), Some(FsError::Invalid));
+    }
+  }
+
+  #[test]
+  fn triple_indirect()
+  {
+    let disk = mkfs(1024, 2048);
+    let (fs, vfs) = mount(Ext2::new(disk.clone()));
+    let free = fs.statfs().unwrap().free_blocks;
+    let tind = |fs: &Ext2, ino| fs.inner.lock().inode(ino).unwrap().block(INDIRECT + 2);
+
+    // With 256 numbers to a block, the last block through the doubly
+    // indirect block is 12 + 256 + 256 * 256 - 1; the write ends in the
+    // first through the triply indirect one, and the rest is a hole.
+    let far = (12 + 256 + 256 * 256) * 1024 - 2;
+    let file = vfs.open("/sparse", OpenFlags::WRITE | OpenFlags::CREATE, 0o640).unwrap();
+    file.seek(SeekFrom::Start(far)).unwrap();
+    assert_eq!(file.write(b"edge"), Ok(4));
+    let ino = vfs.stat("/sparse").unwrap().ino;
+    assert_eq!(vfs.stat("/sparse").unwrap().size, far + 4);
+    assert_eq!(read_at(&vfs, "/sparse", far - 1, 8), b"\0edge");
+    assert_eq!(read_at(&vfs, "/sparse", 1024 * 1024, 4), [0; 4]);
+    // Two data blocks, two indirect blocks above the first, three above
+    // the second.
+    assert_eq!(fs.statfs().unwrap().free_blocks, free - 7);
+    assert_ne!(tind(&fs, ino), 0);
+
+    // Cutting the second block off frees the triply indirect tree.
+    vfs.truncate("/sparse", far + 2).unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, free - 3);
+    assert_eq!(tind(&fs, ino), 0);
+    assert_eq!(read_at(&vfs, "/sparse", far - 1, 8), b"\0ed");
+
+    file.seek(SeekFrom::Start(far + 2)).unwrap();
+    assert_eq!(file.write(b"ge"), Ok(2));
+    vfs.sync().unwrap();
+
+    // Everything is on the disk, the counts too.
+    drop(file);
+    let (fs, vfs) = mount(Ext2::new(disk.clone()));
+    assert_eq!(read_at(&vfs, "/sparse", far - 1, 8), b"\0edge");
+    let mut sb = [0; SUPERBLOCK_SIZE];
+    read_bytes(&*disk, SUPERBLOCK_OFFSET, &mut sb).unwrap();
+    assert_eq!(Superblock::parse(&sb).unwrap().free_blocks() as u64, free - 7);
+    vfs.unlink("/sparse").unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, free);
+  }
+
+  #[test]
+  fn records_4k()
+  {
+    let (fs, vfs) = mount(Ext2::new(mkfs(4096, 4096)));
+    assert_eq!(fs.statfs().unwrap().block_size, 4096);
+    vfs.mkdir("/d", 0o755).unwrap();
+
+    // Names of 20 bytes take records of 28; after `.` and `..` the first
+    // block holds 145 of them.
+    for i in 0..150 {
+      vfs.open(&std::format!("/d/tool-with-name-{:03}", i), OpenFlags::CREATE, 0o755).unwrap();
+    }
+    assert_eq!(vfs.stat("/d").unwrap().size, 2 * 4096);
+
+    // No record crosses the end of a block, and the last of each reaches
+    // it.
+    let records = |fs: &Ext2| {
+      let inner = fs.inner.lock();
+      let root = inner.dir(ROOT_INO as Ino).unwrap();
+      let d = inner.dir(inner.find(&root, "d").unwrap().ino as Ino).unwrap();
+      let mut counts = std::vec::Vec::new();
+      for n in 0..2 {
+        let mut buf = std::vec![0u8; 4096];
+        inner.read_at(inner.sb.block_offset(inner.bmap(&d, n).unwrap()), &mut buf).unwrap();
+        let (mut at, mut count) = (0, 0);
+        while at < buf.len() {
+          at += Record::parse(&buf, at).unwrap().rec_len;
+          count += 1;
+        }
+        assert_eq!(at, 4096);
+        counts.push(count);
+      }
+      counts
+    };
+    assert_eq!(records(&fs), [2 + 145, 5]);
+
+    // Emptied, the second block is one free record, which is used again.
+    for i in 145..150 {
+      vfs.unlink(&std::format!("/d/tool-with-name-{:03}", i)).unwrap();
+    }
+    assert_eq!(records(&fs), [2 + 145, 1]);
+    for i in 0..3 {
+      vfs.open(&std::format!("/d/other-{}", i), OpenFlags::CREATE, 0o755).unwrap();
+    }
+    assert_eq!(records(&fs), [2 + 145, 3]);
+    assert_eq!(names(&vfs, "/d").len(), 148);
+    assert_eq!(vfs.stat("/d").unwrap().size, 2 * 4096);
+  }
+
+  #[test]
+  fn directories()
+  {
+    let (fs, vfs) = mount(Ext2::new(mkfs(1024, 4096)));
+    let free = fs.statfs().unwrap();
+    vfs.mkdir("/usr", 0o755).unwrap();
+    vfs.mkdir("/usr/bin", 0o700).unwrap();
+    assert_eq!(vfs.stat("/").unwrap().nlink, 3);
+    assert_eq!(vfs.stat("/usr").unwrap().nlink, 3);
+    assert_eq!(vfs.stat("/usr/bin").unwrap().mode, 0o700);
+
+    // Names of 20 bytes take records of 28, so a block holds 36 of them.
+    for i in 0..100 {
+      let path = std::format!("/usr/bin/tool-with-name-{:03}", i);
+      vfs.open(&path, OpenFlags::CREATE, 0o755).unwrap();
+    }
+    let mut listed = names(&vfs, "/usr/bin");
+    listed.sort();
+    assert_eq!(listed.len(), 100);
+    assert_eq!(listed[42], "tool-with-name-042");
+    assert_eq!(vfs.stat("/usr/bin").unwrap().size, 3 * 1024);
+
+    // Removed records give their room to the ones before, and it is used
+    // again.
+    for i in (0..100).step_by(2) {
+      vfs.unlink(&std::format!("/usr/bin/tool-with-name-{:03}", i)).unwrap();
+    }
+    for i in 0..50 {
+      vfs.open(&std::format!("/usr/bin/other-{:02}", i), OpenFlags::CREATE, 0o755).unwrap();
+    }
+    assert_eq!(names(&vfs, "/usr/bin").len(), 100);
+    assert_eq!(vfs.stat("/usr/bin").unwrap().size, 3 * 1024);
+
+    assert_eq!(vfs.rmdir("/usr"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.unlink("/usr/bin"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rmdir("/usr/bin/other-00"), Err(FsError::NotDirectory));
+    assert_eq!(vfs.mkdir("/usr/bin/other-00", 0o755), Err(FsError::Exists));
+
+    // Removing what was listed does not make the listing skip any.
+    let dir = vfs.open("/usr/bin", OpenFlags::READ, 0).unwrap();
+    let mut listed = 0;
+    while let Some(entry) = dir.readdir().unwrap() {
+      if entry.name != "." && entry.name != ".." {
+        vfs.unlink(&std::format!("/usr/bin/{}", entry.name)).unwrap();
+        listed += 1;
+      }
+    }
+    assert_eq!(listed, 100);
+    vfs.rmdir("/usr/bin").unwrap();
+    vfs.rmdir("/usr").unwrap();
+    assert!(names(&vfs, "/").is_empty());
+    assert_eq!(vfs.stat("/").unwrap().nlink, 2);
+    assert_eq!(fs.statfs().unwrap(), free);
+  }
+
+  #[test]
+  fn symlinks()
+  {
+    let (fs, vfs) = mount(Ext2::new(mkfs(1024, 2048)));
+    let free = fs.statfs().unwrap().free_blocks;
+    vfs.mkdir("/usr", 0o755).unwrap();
+    vfs.open("/usr/sh", OpenFlags::CREATE | OpenFlags::WRITE, 0o755).unwrap().write(b"#!").unwrap();
+
+    // Short targets are kept in the inode, long ones in a block.
+    let long = std::format!("/usr/{}", "../usr/".repeat(20)) + "sh";
+    vfs.symlink("/usr/sh", "/sh").unwrap();
+    vfs.symlink(&long, "/long").unwrap();
+    assert_eq!(vfs.readlink("/sh").unwrap(), "/usr/sh");
+    assert_eq!(vfs.readlink("/long").unwrap(), long.as_str());
+    assert_eq!(vfs.lstat("/long").unwrap().size, long.len() as u64);
+    assert_eq!(read_at(&vfs, "/long", 0, 4), b"#!");
+    assert_eq!(fs.statfs().unwrap().free_blocks, free - 3);
+
+    vfs.unlink("/sh").unwrap();
+    vfs.unlink("/long").unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, free - 2);
+  }
+
+  #[test]
+  fn rename()
+  {
+    let (fs, vfs) = mount(Ext2::new(mkfs(1024, 2048)));
+    let create = OpenFlags::WRITE | OpenFlags::CREATE;
+    vfs.open("/a", create, 0o644).unwrap().write(b"a").unwrap();
+    vfs.open("/b", create, 0o644).unwrap().write(b"b").unwrap();
+    vfs.mkdir("/d", 0o755).unwrap();
+    vfs.mkdir("/e", 0o755).unwrap();
+    vfs.open("/e/f", create, 0o644).unwrap();
+    let free = fs.statfs().unwrap();
+
+    // Replacing a file frees it.
+    vfs.rename("/a", "/b").unwrap();
+    assert_eq!(read_at(&vfs, "/b", 0, 4), b"a");
+    assert_eq!(vfs.stat("/a").err(), Some(FsError::NotFound));
+    assert_eq!(fs.statfs().unwrap().free_blocks, free.free_blocks + 1);
+    assert_eq!(fs.statfs().unwrap().free_inodes, free.free_inodes + 1);
+
+    // Inodes keep their numbers.
+    let ino = vfs.stat("/b").unwrap().ino;
+    vfs.rename("/b", "/d/c").unwrap();
+    assert_eq!(vfs.stat("/d/c").unwrap().ino, ino);
+    assert_eq!(names(&vfs, "/d"), ["c"]);
+
+    assert_eq!(vfs.rename("/d/c", "/e"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rename("/d", "/e"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.rename("/e", "/d/c"), Err(FsError::NotDirectory));
+
+    // A moved directory's `..` names its new parent, and the links of
+    // both parents follow.
+    vfs.rename("/e", "/d/e").unwrap();
+    assert_eq!(vfs.stat("/d/e/f").unwrap().kind, FileType::File);
+    assert_eq!(vfs.stat("/").unwrap().nlink, 3);
+    assert_eq!(vfs.stat("/d").unwrap().nlink, 3);
+    let inner = fs.inner.lock();
+    let e = inner.dir(vfs_ino(&inner, "e")).unwrap();
+    let mut buf = [0u8; 1024];
+    inner.read_at(inner.sb.block_offset(inner.bmap(&e, 0).unwrap()), &mut buf).unwrap();
+    assert_eq!(Record::parse(&buf, 12).unwrap().ino as Ino, vfs_ino(&inner, ""));
+  }
+
+  #[test]
+  fn link_limit()
+  {
+    let (fs, vfs) = mount(Ext2::new(mkfs(1024, 4096)));
+    vfs.mkdir("/d", 0o755).unwrap();
+    vfs.mkdir("/e", 0o755).unwrap();
+    vfs.mkdir("/e/f", 0o755).unwrap();
+    let d = vfs.stat("/d").unwrap().ino as u32;
+    fs.inner.lock().link(d, LINK_MAX as i32 - 3).unwrap();
+
+    vfs.mkdir("/d/a", 0o755).unwrap();
+    assert_eq!(vfs.stat("/d").unwrap().nlink, LINK_MAX);
+    assert_eq!(vfs.mkdir("/d/b", 0o755), Err(FsError::LinkLimit));
+    assert_eq!(vfs.rename("/e/f", "/d/f"), Err(FsError::LinkLimit));
+    assert_eq!(vfs.stat("/e/f").unwrap().kind, FileType::Directory);
+
+    // Files, and directories taking the place of one, add no links.
+    vfs.open("/d/file", OpenFlags::CREATE, 0o644).unwrap();
+    vfs.rename("/e/f", "/d/a").unwrap();
+    assert_eq!(vfs.stat("/d").unwrap().nlink, LINK_MAX);
+  }
+
+  /// The inode of `/d/<name>`, looked up without the VFS.
+  fn vfs_ino(inner: &Inner, name: &str) -> Ino
+  {
+    let root = inner.dir(ROOT_INO as Ino).unwrap();
+    let d = inner.find(&root, "d").unwrap().ino;
+    match name {
+      "" => d as Ino,
+      _ => inner.find(&inner.dir(d as Ino).unwrap(), name).unwrap().ino as Ino,
+    }
+  }
+
+  #[test]
+  fn read_only()
+  {
+    let disk = mkfs(1024, 2048);
+    let (_, vfs) = mount(Ext2::new(disk.clone()));
+    vfs.open("/x", OpenFlags::CREATE | OpenFlags::WRITE, 0o444).unwrap().write(b"x").unwrap();
+
+    disk.set_read_only(true);
+    let (_, vfs) = mount(Ext2::new(disk.clone()));
+    assert_eq!(vfs.open("/y", OpenFlags::CREATE, 0o644).err(), Some(FsError::ReadOnly));
+    assert_eq!(vfs.unlink("/x"), Err(FsError::ReadOnly));
+    assert_eq!(read_at(&vfs, "/x", 0, 4), b"x");
+
+    // So is a volume with features that can only be read.
+    disk.set_read_only(false);
+    let mut sb = [0; SUPERBLOCK_SIZE];
+    read_bytes(&*disk, SUPERBLOCK_OFFSET, &mut sb).unwrap();
+    sb[100] |= 0x40;
+    write_bytes(&*disk, SUPERBLOCK_OFFSET, &sb).unwrap();
+    let (_, vfs) = mount(Ext2::new(disk.clone()));
+    assert_eq!(vfs.unlink("/x"), Err(FsError::ReadOnly));
+  }
+}
@@ -0,0 +1,205 @@
+//! Directory entries.
+//!
+//! A directory's blocks are filled with records: an inode number, the
+//! length of the record, the length of the name, the type of the inode if
+//! the volume records it, and the name, padded to four bytes. A record may
+//! be longer than its name needs, up to where the next starts, and the
+//! last in a block runs to its end; a record with inode 0 is free.
+
+use alloc::String;
+
+use crate::vfs::{FileType, FsError, FsResult};
+
+/// The size of a record before its name.
+pub const HEADER_SIZE: usize = 8;
+
+/// The longest name a record can have.
+pub const NAME_MAX: usize = 255;
+
+/// The `file_type` of a record.
+pub mod file_type
+{
+  /// Not recorded.
+  pub const UNKNOWN: u8 = 0;
+  /// A regular file.
+  pub const FILE: u8 = 1;
+  /// A directory.
+  pub const DIRECTORY: u8 = 2;
+  /// A character device.
+  pub const CHAR_DEVICE: u8 = 3;
+  /// A block device.
+  pub const BLOCK_DEVICE: u8 = 4;
+  /// A fifo.
+  pub const FIFO: u8 = 5;
+  /// A socket.
+  pub const SOCKET: u8 = 6;
+  /// A symbolic link.
+  pub const SYMLINK: u8 = 7;
+}
+
+/// The `file_type` of an inode of `mode`.
+pub fn file_type(mode: u16) -> u8
+{
+  use super::inode::mode;
+  match mode & mode::TYPE {
+    mode::FILE => file_type::FILE,
+    mode::DIRECTORY => file_type::DIRECTORY,
+    mode::CHAR_DEVICE => file_type::CHAR_DEVICE,
+    mode::BLOCK_DEVICE => file_type::BLOCK_DEVICE,
+    mode::FIFO => file_type::FIFO,
+    mode::SOCKET => file_type::SOCKET,
+    mode::SYMLINK => file_type::SYMLINK,
+    _ => file_type::UNKNOWN,
+  }
+}
+
+/// What a record's `file_type` says its inode is, if it says.
+pub fn kind(file_type: u8) -> Option<FileType>
+{
+  match file_type {
+    file_type::UNKNOWN => None,
+    file_type::FILE => Some(FileType::File),
+    file_type::DIRECTORY => Some(FileType::Directory),
+    file_type::SYMLINK => Some(FileType::Symlink),
+    _ => Some(FileType::Other),
+  }
+}
+
+/// The size of a record for a name of `len` bytes.
+#[inline]
+pub fn rec_size(len: usize) -> usize
+{
+  (HEADER_SIZE + len).next_multiple_of(4)
+}
+
+/// The header of a record.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Record
+{
+  /// The inode it names, or 0 if it is free.
+  pub ino: u32,
+  /// Its length, up to the next record.
+  pub rec_len: usize,
+  /// The length of its name.
+  pub name_len: usize,
+  /// The type of its inode, or `UNKNOWN`.
+  pub file_type: u8,
+}
+
+impl Record
+{
+  /// The record at `at` in `block`, a directory block, or `Io` if it does
+  /// not fit in the block.
+  pub fn parse(block: &[u8], at: usize) -> FsResult<Self>
+  {
+    let header = block.get(at..at + HEADER_SIZE).ok_or(FsError::Io)?;
+    let rec_len = match u16::from_le_bytes([header[4], header[5]]) as usize {
+      // Blocks of 64 KiB need 17 bits.
+      0 | 0xffff if block.len() == 0x10000 => 0x10000,
+      len => len,
+    };
+    let record = Self {
+      ino: u32::from_le_bytes([header[0], header[1], header[2], header[3]]),
+      rec_len,
+      name_len: header[6] as usize,
+      file_type: header[7],
+    };
+    let valid = rec_len >= HEADER_SIZE
+        && rec_len.is_multiple_of(4)
+        && at + rec_len <= block.len()
+        && rec_size(record.name_len) <= rec_len;
+    if !valid {
+      return Err(FsError::Io);
+    }
+    Ok(record)
+  }
+
+  /// The header as it goes on the device. `file_type` is left out if the
+  /// volume does not record it.
+  pub fn to_bytes(&self, has_file_type: bool) -> [u8; HEADER_SIZE]
+  {
+    let rec_len = self.rec_len.min(0xffff) as u16;
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[0..4].copy_from_slice(&self.ino.to_le_bytes());
+    bytes[4..6].copy_from_slice(&rec_len.to_le_bytes());
+    bytes[6] = self.name_len as u8;
+    bytes[7] = if has_file_type { self.file_type } else { 0 };
+    bytes
+  }
+
+  /// The room left after the name, which another record can take.
+  #[inline]
+  pub fn slack(&self) -> usize
+  {
+    match self.ino {
+      0 => self.rec_len,
+      _ => self.rec_len - rec_size(self.name_len),
+    }
+  }
+}
+
+/// Decodes a name, which is any bytes but usually UTF-8.
+pub fn name(bytes: &[u8]) -> String
+{
+  let mut name = String::new();
+  for chunk in bytes.utf8_chunks() {
+    name.push_str(chunk.valid());
+    if !chunk.invalid().is_empty() {
+      name.push(char::REPLACEMENT_CHARACTER);
+    }
+  }
+  name
+}
+
+/// Checks that `name` can name a record.
+pub fn check_name(name: &str) -> FsResult<()>
+{
+  if name.len() > NAME_MAX {
+    return Err(FsError::NameTooLong);
+  }
+  if name.is_empty() || name.contains(&['/', '\0'][..]) {
+    return Err(FsError::Invalid);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn records()
+  {
+    let mut block = [0u8; 1024];
+    let dot = Record { ino: 2, rec_len: 12, name_len: 1, file_type: file_type::DIRECTORY };
+    block[..8].copy_from_slice(&dot.to_bytes(true));
+    block[8] = b'.';
+    let rest = Record { ino: 0, rec_len: 1012, name_len: 0, file_type: 0 };
+    block[12..20].copy_from_slice(&rest.to_bytes(true));
+
+    assert_eq!(Record::parse(&block, 0), Ok(dot));
+    assert_eq!(Record::parse(&block, 12), Ok(rest));
+    assert_eq!(Record::parse(&block, 12).unwrap().slack(), 1012);
+    assert_eq!(dot.slack(), 0);
+    assert_eq!(rec_size(1), 12);
+    assert_eq!(rec_size(4), 12);
+    assert_eq!(rec_size(5), 16);
+
+    // Records must stay in their block.
+    block[17] = 0x04;
+    assert_eq!(Record::parse(&block, 12), Err(FsError::Io));
+    assert_eq!(Record::parse(&block, 1020), Err(FsError::Io));
+    assert_eq!(dot.to_bytes(false)[7], 0);
+  }
+
+  #[test]
+  fn names()
+  {
+    assert_eq!(name(b"caf\xc3\xa9"), "café");
+    assert_eq!(name(b"caf\xe9"), "caf\u{fffd}");
+    assert_eq!(check_name(&"x".repeat(256)), Err(FsError::NameTooLong));
+    assert_eq!(check_name("a\0b"), Err(FsError::Invalid));
+    assert_eq!(check_name("日本語"), Ok(()));
+  }
+}
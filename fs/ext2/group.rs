@@ -0,0 +1,298 @@
+//! Block groups and their bitmaps.
+//!
+//! Each group has a descriptor, in the table after the superblock, which
+//! says where its block bitmap, inode bitmap and inode table are and how
+//! many of its blocks and inodes are free. Blocks and inodes are taken by
+//! setting their bits, looking in the group of the inode they are for
+//! first, and the counts in the descriptor and the superblock are kept
+//! with the bitmaps.
+
+use alloc::Array;
+
+use super::superblock::Superblock;
+use crate::block::{read_bytes, write_bytes, BlockDevice};
+use crate::vfs::{FsError, FsResult};
+
+/// The size of a group descriptor.
+pub const DESCRIPTOR_SIZE: usize = 32;
+
+fn u16_at(b: &[u8], at: usize) -> u32
+{
+  u16::from_le_bytes([b[at], b[at + 1]]) as u32
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32
+{
+  u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+/// The descriptor of a block group.
+#[derive(Copy, Clone)]
+pub struct Descriptor([u8; DESCRIPTOR_SIZE]);
+
+impl Descriptor
+{
+  fn set_u16(&mut self, at: usize, value: u32)
+  {
+    self.0[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes());
+  }
+
+  /// The block of the block bitmap.
+  pub fn block_bitmap(&self) -> u32
+  {
+    u32_at(&self.0, 0)
+  }
+
+  /// The block of the inode bitmap.
+  pub fn inode_bitmap(&self) -> u32
+  {
+    u32_at(&self.0, 4)
+  }
+
+  /// The first block of the inode table.
+  pub fn inode_table(&self) -> u32
+  {
+    u32_at(&self.0, 8)
+  }
+
+  /// The number of free blocks.
+  pub fn free_blocks(&self) -> u32
+  {
+    u16_at(&self.0, 12)
+  }
+
+  /// The number of free inodes.
+  pub fn free_inodes(&self) -> u32
+  {
+    u16_at(&self.0, 14)
+  }
+
+  /// The number of directories.
+  pub fn dirs(&self) -> u32
+  {
+    u16_at(&self.0, 16)
+  }
+}
+
+/// What a bitmap tracks.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Kind
+{
+  Block,
+  Inode,
+}
+
+/// The block groups of a volume.
+pub struct Groups
+{
+  descriptors: Array<Descriptor>,
+}
+
+impl Groups
+{
+  /// Reads the descriptors of the groups `sb` describes.
+  pub fn new(device: &dyn BlockDevice, sb: &Superblock) -> FsResult<Self>
+  {
+    let count = sb.groups() as usize;
+    let mut table = Array::new();
+    table.resize(count * DESCRIPTOR_SIZE, 0);
+    read_bytes(device, sb.block_offset(sb.descriptor_block()), &mut table)?;
+
+    let mut descriptors = Array::new();
+    for (group, raw) in table.chunks(DESCRIPTOR_SIZE).enumerate() {
+      let mut desc = Descriptor([0; DESCRIPTOR_SIZE]);
+      desc.0.copy_from_slice(raw);
+      // Everything a group points at must be on the volume.
+      let table_bytes = sb.inodes_per_group() as u64 * sb.inode_size() as u64;
+      let table_end = desc.inode_table() as u64 + table_bytes.div_ceil(sb.block_size() as u64);
+      let valid = [desc.block_bitmap(), desc.inode_bitmap(), desc.inode_table()]
+          .iter()
+          .all(|&block| (sb.first_data_block()..sb.blocks_count()).contains(&block))
+          && table_end <= sb.blocks_count() as u64
+          && desc.free_blocks() <= sb.blocks_in_group(group as u32)
+          && desc.free_inodes() <= sb.inodes_per_group();
+      if !valid {
+        return Err(FsError::Invalid);
+      }
+      descriptors.push(desc);
+    }
+    Ok(Self { descriptors })
+  }
+
+  /// The descriptor of `group`.
+  #[inline]
+  pub fn get(&self, group: u32) -> &Descriptor
+  {
+    &self.descriptors[group as usize]
+  }
+
+  /// Writes the descriptor of `group` back.
+  fn write(&self, device: &dyn BlockDevice, sb: &Superblock, group: u32) -> FsResult<()>
+  {
+    let at = sb.block_offset(sb.descriptor_block()) + (group as usize * DESCRIPTOR_SIZE) as u64;
+    Ok(write_bytes(device, at, &self.get(group).0)?)
+  }
+
+  /// The number of things the bitmap of `kind` in `group` has.
+  fn bits(sb: &Superblock, kind: Kind, group: u32) -> u32
+  {
+    match kind {
+      Kind::Block => sb.blocks_in_group(group),
+      Kind::Inode => sb.inodes_per_group(),
+    }
+  }
+
+  fn bitmap(&self, sb: &Superblock, kind: Kind, group: u32) -> u64
+  {
+    let desc = self.get(group);
+    let block = match kind {
+      Kind::Block => desc.block_bitmap(),
+      Kind::Inode => desc.inode_bitmap(),
+    };
+    sb.block_offset(block)
+  }
+
+  /// Adds `delta` to the free count of `kind` in `group` and in `sb`.
+  fn count(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, kind: Kind, group: u32,
+      delta: i32, dirs: i32) -> FsResult<()>
+  {
+    let desc = &mut self.descriptors[group as usize];
+    match kind {
+      Kind::Block => {
+        desc.set_u16(12, desc.free_blocks().wrapping_add_signed(delta));
+        sb.set_free_blocks(sb.free_blocks().wrapping_add_signed(delta));
+      }
+      Kind::Inode => {
+        desc.set_u16(14, desc.free_inodes().wrapping_add_signed(delta));
+        desc.set_u16(16, desc.dirs().wrapping_add_signed(dirs));
+        sb.set_free_inodes(sb.free_inodes().wrapping_add_signed(delta));
+      }
+    }
+    self.write(device, sb, group)
+  }
+
+  /// Sets the first clear bit in the bitmap of `kind` in one of the
+  /// groups, trying `goal` first, and returns the group and the bit.
+  fn take(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, kind: Kind, goal: u32,
+      dir: bool) -> FsResult<(u32, u32)>
+  {
+    let groups = sb.groups();
+    let mut bitmap = Array::new();
+    bitmap.resize(sb.block_size() as usize, 0);
+
+    for group in (0..groups).map(|n| (goal + n) % groups) {
+      let desc = self.get(group);
+      let free = match kind {
+        Kind::Block => desc.free_blocks(),
+        Kind::Inode => desc.free_inodes(),
+      };
+      if free == 0 {
+        continue;
+      }
+
+      let at = self.bitmap(sb, kind, group);
+      read_bytes(device, at, &mut bitmap)?;
+      let bits = Self::bits(sb, kind, group) as usize;
+      let found = (0..bits.div_ceil(8))
+          .filter(|&byte| bitmap[byte] != 0xff)
+          .map(|byte| byte * 8 + bitmap[byte].trailing_ones() as usize)
+          .find(|&bit| bit < bits);
+      // The count said there was one; believe the bitmap.
+      let bit = match found {
+        Some(bit) => bit,
+        None => continue,
+      };
+
+      let byte = bitmap[bit / 8] | 1 << (bit % 8);
+      write_bytes(device, at + (bit / 8) as u64, &[byte])?;
+      self.count(device, sb, kind, group, -1, dir as i32)?;
+      return Ok((group, bit as u32));
+    }
+    Err(FsError::NoSpace)
+  }
+
+  /// Clears `bit` in the bitmap of `kind` in `group`.
+  fn give(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, kind: Kind, group: u32,
+      bit: u32, dir: bool) -> FsResult<()>
+  {
+    let at = self.bitmap(sb, kind, group) + (bit / 8) as u64;
+    let mut byte = [0];
+    read_bytes(device, at, &mut byte)?;
+    let mask = 1 << (bit % 8);
+    // Freeing something already free means the volume is damaged.
+    if byte[0] & mask == 0 {
+      return Err(FsError::Io);
+    }
+    write_bytes(device, at, &[byte[0] & !mask])?;
+    self.count(device, sb, kind, group, 1, -(dir as i32))
+  }
+
+  /// Takes a free block, in the group of `goal` if it can.
+  pub fn alloc_block(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, goal: u32)
+      -> FsResult<u32>
+  {
+    let first = sb.first_data_block();
+    let goal = goal.saturating_sub(first) / sb.blocks_per_group();
+    let (group, bit) = self.take(device, sb, Kind::Block, goal, false)?;
+    Ok(first + group * sb.blocks_per_group() + bit)
+  }
+
+  /// Frees `block`.
+  pub fn free_block(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, block: u32)
+      -> FsResult<()>
+  {
+    let first = sb.first_data_block();
+    if !(first..sb.blocks_count()).contains(&block) {
+      return Err(FsError::Io);
+    }
+    let index = block - first;
+    let (group, bit) = (index / sb.blocks_per_group(), index % sb.blocks_per_group());
+    self.give(device, sb, Kind::Block, group, bit, false)
+  }
+
+  /// Takes a free inode, in `goal` if it can, for a directory if `dir`.
+  pub fn alloc_inode(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, goal: u32,
+      dir: bool) -> FsResult<u32>
+  {
+    let (group, bit) = self.take(device, sb, Kind::Inode, goal, dir)?;
+    let ino = group * sb.inodes_per_group() + bit + 1;
+    // The reserved inodes are marked in use, so this only fails on a
+    // damaged volume.
+    if ino < sb.first_ino() || ino > sb.inodes_count() {
+      return Err(FsError::Io);
+    }
+    Ok(ino)
+  }
+
+  /// Frees the inode `ino`, which was a directory if `dir`.
+  pub fn free_inode(&mut self, device: &dyn BlockDevice, sb: &mut Superblock, ino: u32, dir: bool)
+      -> FsResult<()>
+  {
+    if ino < sb.first_ino() || ino > sb.inodes_count() {
+      return Err(FsError::Io);
+    }
+    let (group, bit) = ((ino - 1) / sb.inodes_per_group(), (ino - 1) % sb.inodes_per_group());
+    self.give(device, sb, Kind::Inode, group, bit, dir)
+  }
+
+  /// The group `ino` is in.
+  #[inline]
+  pub fn group_of(sb: &Superblock, ino: u32) -> u32
+  {
+    (ino - 1) / sb.inodes_per_group()
+  }
+
+  /// Where the inode `ino` is on the device.
+  pub fn inode_offset(&self, sb: &Superblock, ino: u32) -> u64
+  {
+    let index = (ino - 1) % sb.inodes_per_group();
+    let table = self.get(Self::group_of(sb, ino)).inode_table();
+    sb.block_offset(table) + index as u64 * sb.inode_size() as u64
+  }
+
+  /// The first block of the group `ino` is in, where its data goes first.
+  pub fn goal(sb: &Superblock, ino: u32) -> u32
+  {
+    sb.first_data_block() + Self::group_of(sb, ino) * sb.blocks_per_group()
+  }
+}
@@ -0,0 +1,268 @@
+//! Inodes, as they are in the inode tables.
+//!
+//! Only the first 128 bytes, which every revision has, are used; the rest
+//! of a larger inode is left as it is. An inode's data is found through
+//! `i_block`: twelve direct blocks, then a block of block numbers, then
+//! one of blocks of them, then one more level again.
+
+use crate::vfs::FileType;
+
+/// The part of an inode that is read and written.
+pub const INODE_SIZE: usize = 128;
+
+/// The number of block numbers in `i_block`.
+pub const N_BLOCKS: usize = 15;
+/// The number of direct blocks.
+pub const DIRECT_BLOCKS: usize = 12;
+/// The slot of the indirect block, after which come the doubly and triply
+/// indirect ones.
+pub const INDIRECT: usize = 12;
+
+/// A symbolic link shorter than this is kept in `i_block`.
+pub const FAST_SYMLINK_MAX: usize = N_BLOCKS * 4;
+
+/// The inode of the root directory.
+pub const ROOT_INO: u32 = 2;
+
+/// The most links an inode may have, and so subdirectories a directory.
+pub const LINK_MAX: u32 = 32000;
+
+/// The type and permission bits of `i_mode`.
+pub mod mode
+{
+  /// The bits giving the type.
+  pub const TYPE: u16 = 0o170000;
+  /// A socket.
+  pub const SOCKET: u16 = 0o140000;
+  /// A symbolic link.
+  pub const SYMLINK: u16 = 0o120000;
+  /// A regular file.
+  pub const FILE: u16 = 0o100000;
+  /// A block device.
+  pub const BLOCK_DEVICE: u16 = 0o060000;
+  /// A directory.
+  pub const DIRECTORY: u16 = 0o040000;
+  /// A character device.
+  pub const CHAR_DEVICE: u16 = 0o020000;
+  /// A fifo.
+  pub const FIFO: u16 = 0o010000;
+  /// The permission bits, with setuid, setgid and sticky.
+  pub const PERMISSIONS: u16 = 0o7777;
+}
+
+/// The directory is a hashed tree; it must be cleared when the directory
+/// is changed by something that does not keep the hashes.
+pub const INDEX_FL: u32 = 0x1000;
+
+fn u16_at(b: &[u8], at: usize) -> u32
+{
+  u16::from_le_bytes([b[at], b[at + 1]]) as u32
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32
+{
+  u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+/// An inode.
+#[derive(Copy, Clone)]
+pub struct Inode([u8; INODE_SIZE]);
+
+impl Inode
+{
+  /// An inode with `mode` and one link, and nothing else.
+  pub fn new(mode: u16) -> Self
+  {
+    let mut inode = Self([0; INODE_SIZE]);
+    inode.set_mode(mode);
+    inode.set_links(1);
+    inode
+  }
+
+  /// The inode in `bytes`.
+  pub fn from_bytes(bytes: &[u8]) -> Self
+  {
+    let mut raw = [0; INODE_SIZE];
+    raw.copy_from_slice(&bytes[..INODE_SIZE]);
+    Self(raw)
+  }
+
+  /// The inode as it goes on the device.
+  #[inline]
+  pub fn as_bytes(&self) -> &[u8; INODE_SIZE]
+  {
+    &self.0
+  }
+
+  fn set_u16(&mut self, at: usize, value: u32)
+  {
+    self.0[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes());
+  }
+
+  fn set_u32(&mut self, at: usize, value: u32)
+  {
+    self.0[at..at + 4].copy_from_slice(&value.to_le_bytes());
+  }
+
+  /// The type and permission bits.
+  pub fn mode(&self) -> u16
+  {
+    u16_at(&self.0, 0) as u16
+  }
+
+  /// Sets the type and permission bits.
+  pub fn set_mode(&mut self, mode: u16)
+  {
+    self.set_u16(0, mode as u32);
+  }
+
+  /// What the inode is.
+  pub fn kind(&self) -> FileType
+  {
+    match self.mode() & mode::TYPE {
+      mode::FILE => FileType::File,
+      mode::DIRECTORY => FileType::Directory,
+      mode::SYMLINK => FileType::Symlink,
+      _ => FileType::Other,
+    }
+  }
+
+  /// The owner, with the high 16 bits Linux keeps apart.
+  pub fn uid(&self) -> u32
+  {
+    u16_at(&self.0, 2) | u16_at(&self.0, 120) << 16
+  }
+
+  /// The group, with the high 16 bits Linux keeps apart.
+  pub fn gid(&self) -> u32
+  {
+    u16_at(&self.0, 24) | u16_at(&self.0, 122) << 16
+  }
+
+  /// The size in bytes. Only regular files have the high half.
+  pub fn size(&self) -> u64
+  {
+    let high = match self.kind() {
+      FileType::File => u32_at(&self.0, 108),
+      _ => 0,
+    };
+    u32_at(&self.0, 4) as u64 | (high as u64) << 32
+  }
+
+  /// Sets the size in bytes.
+  pub fn set_size(&mut self, size: u64)
+  {
+    self.set_u32(4, size as u32);
+    if self.kind() == FileType::File {
+      self.set_u32(108, (size >> 32) as u32);
+    }
+  }
+
+  /// The modification time, in seconds since the epoch.
+  pub fn mtime(&self) -> u64
+  {
+    u32_at(&self.0, 16) as u64
+  }
+
+  /// The number of directory entries naming the inode.
+  pub fn links(&self) -> u32
+  {
+    u16_at(&self.0, 26)
+  }
+
+  /// Sets the number of directory entries naming the inode.
+  pub fn set_links(&mut self, links: u32)
+  {
+    self.set_u16(26, links);
+  }
+
+  /// The number of 512-byte sectors its blocks take, indirect ones and
+  /// the extended attribute block included.
+  pub fn sectors(&self) -> u32
+  {
+    u32_at(&self.0, 28)
+  }
+
+  /// Sets the number of 512-byte sectors its blocks take.
+  pub fn set_sectors(&mut self, sectors: u32)
+  {
+    self.set_u32(28, sectors);
+  }
+
+  /// The inode's flags.
+  pub fn flags(&self) -> u32
+  {
+    u32_at(&self.0, 32)
+  }
+
+  /// Sets the inode's flags.
+  pub fn set_flags(&mut self, flags: u32)
+  {
+    self.set_u32(32, flags);
+  }
+
+  /// Block number `n` of `i_block`.
+  pub fn block(&self, n: usize) -> u32
+  {
+    u32_at(&self.0, 40 + 4 * n)
+  }
+
+  /// Sets block number `n` of `i_block`.
+  pub fn set_block(&mut self, n: usize, block: u32)
+  {
+    self.set_u32(40 + 4 * n, block);
+  }
+
+  /// The bytes of `i_block`, where a short symbolic link keeps its target.
+  pub fn block_bytes(&self) -> &[u8]
+  {
+    &self.0[40..40 + FAST_SYMLINK_MAX]
+  }
+
+  /// Sets the bytes of `i_block`.
+  pub fn set_block_bytes(&mut self, bytes: &[u8])
+  {
+    self.0[40..40 + FAST_SYMLINK_MAX].fill(0);
+    self.0[40..40 + bytes.len()].copy_from_slice(bytes);
+  }
+
+  /// The block of extended attributes, or 0.
+  pub fn file_acl(&self) -> u32
+  {
+    u32_at(&self.0, 104)
+  }
+
+  /// Sets the block of extended attributes.
+  pub fn set_file_acl(&mut self, block: u32)
+  {
+    self.set_u32(104, block);
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn fields()
+  {
+    let mut inode = Inode::new(mode::FILE | 0o644);
+    assert_eq!(inode.kind(), FileType::File);
+    assert_eq!(inode.links(), 1);
+    inode.set_size(5 << 32 | 7);
+    assert_eq!(inode.size(), 5 << 32 | 7);
+    inode.set_block(14, 0x1234);
+    assert_eq!(inode.block(14), 0x1234);
+    assert_eq!(&inode.as_bytes()[96..100], &[0x34, 0x12, 0, 0]);
+
+    // Directories have no high half of their size.
+    let mut dir = Inode::from_bytes(inode.as_bytes());
+    dir.set_mode(mode::DIRECTORY | 0o755);
+    assert_eq!(dir.size(), 7);
+
+    let mut link = Inode::new(mode::SYMLINK | 0o777);
+    link.set_block_bytes(b"/usr/bin");
+    assert_eq!(&link.block_bytes()[..9], b"/usr/bin\0");
+  }
+}
@@ -0,0 +1,315 @@
+//! The superblock, 1 KiB into the volume.
+//!
+//! It gives the size of the blocks and of the block groups, how many
+//! inodes each group has and how big they are, the free counts, and the
+//! features the volume uses. A feature this driver does not know may make
+//! the volume unreadable (an incompatible one) or only unwritable (a
+//! read-only compatible one); the compatible ones can be ignored.
+
+use crate::vfs::{FsError, FsResult};
+
+/// Where the superblock is, in bytes.
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+/// The size of the superblock.
+pub const SUPERBLOCK_SIZE: usize = 1024;
+
+/// The magic number of ext2, and of ext3 and ext4 after it.
+pub const MAGIC: u16 = 0xef53;
+
+/// The first inode a file can have on a revision 0 volume.
+const GOOD_OLD_FIRST_INO: u32 = 11;
+/// The size of an inode on a revision 0 volume.
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+/// The largest block size, as a shift of 1 KiB.
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+/// Feature flags.
+pub mod feature
+{
+  /// Directories may be hashed trees, which still read as lists.
+  pub const COMPAT_DIR_INDEX: u32 = 0x0020;
+
+  /// Directory entries record the type of their inode.
+  pub const INCOMPAT_FILETYPE: u32 = 0x0002;
+  /// The incompatible features that are understood.
+  pub const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+  /// Only some groups have a copy of the superblock.
+  pub const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+  /// Regular files may be 2 GiB or more.
+  pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+  /// The read-only compatible features that are understood.
+  pub const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+}
+
+fn u16_at(b: &[u8], at: usize) -> u32
+{
+  u16::from_le_bytes([b[at], b[at + 1]]) as u32
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32
+{
+  u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+/// The superblock of a volume, as it is on the device.
+pub struct Superblock([u8; SUPERBLOCK_SIZE]);
+
+impl Superblock
+{
+  /// Reads the superblock from its bytes, or returns `Invalid` if they are
+  /// not an ext2 superblock, or `Unsupported` if the volume needs a
+  /// feature that is not understood.
+  pub fn parse(bytes: &[u8]) -> FsResult<Self>
+  {
+    if bytes.len() < SUPERBLOCK_SIZE || u16_at(bytes, 56) != MAGIC as u32 {
+      return Err(FsError::Invalid);
+    }
+    let mut raw = [0; SUPERBLOCK_SIZE];
+    raw.copy_from_slice(&bytes[..SUPERBLOCK_SIZE]);
+    let sb = Self(raw);
+
+    if u32_at(bytes, 24) > MAX_LOG_BLOCK_SIZE {
+      return Err(FsError::Invalid);
+    }
+    let bits = sb.block_size() * 8;
+    let inode_size = sb.inode_size();
+    let valid = (1..=bits).contains(&sb.blocks_per_group())
+        && (1..=bits).contains(&sb.inodes_per_group())
+        && sb.first_data_block() < sb.blocks_count()
+        && sb.first_data_block() == (sb.block_size() == 1024) as u32
+        && inode_size.is_power_of_two()
+        && (GOOD_OLD_INODE_SIZE..=sb.block_size()).contains(&inode_size)
+        && sb.first_ino() > 2
+        && sb.inodes_count() as u64 <= sb.groups() as u64 * sb.inodes_per_group() as u64;
+    if !valid {
+      return Err(FsError::Invalid);
+    }
+    if sb.feature_incompat() & !feature::INCOMPAT_SUPPORTED != 0 {
+      return Err(FsError::Unsupported);
+    }
+    Ok(sb)
+  }
+
+  /// The superblock as it goes on the device.
+  #[inline]
+  pub fn as_bytes(&self) -> &[u8; SUPERBLOCK_SIZE]
+  {
+    &self.0
+  }
+
+  fn set_u32(&mut self, at: usize, value: u32)
+  {
+    self.0[at..at + 4].copy_from_slice(&value.to_le_bytes());
+  }
+
+  /// The number of inodes.
+  pub fn inodes_count(&self) -> u32
+  {
+    u32_at(&self.0, 0)
+  }
+
+  /// The number of blocks, counting those before the first group.
+  pub fn blocks_count(&self) -> u32
+  {
+    u32_at(&self.0, 4)
+  }
+
+  /// The number of free blocks.
+  pub fn free_blocks(&self) -> u32
+  {
+    u32_at(&self.0, 12)
+  }
+
+  /// Sets the number of free blocks.
+  pub fn set_free_blocks(&mut self, free: u32)
+  {
+    self.set_u32(12, free);
+  }
+
+  /// The number of free inodes.
+  pub fn free_inodes(&self) -> u32
+  {
+    u32_at(&self.0, 16)
+  }
+
+  /// Sets the number of free inodes.
+  pub fn set_free_inodes(&mut self, free: u32)
+  {
+    self.set_u32(16, free);
+  }
+
+  /// The block the first group starts at: 1 for 1 KiB blocks, else 0.
+  pub fn first_data_block(&self) -> u32
+  {
+    u32_at(&self.0, 20)
+  }
+
+  /// The size of a block in bytes.
+  pub fn block_size(&self) -> u32
+  {
+    1024 << u32_at(&self.0, 24)
+  }
+
+  /// The number of blocks in each group.
+  pub fn blocks_per_group(&self) -> u32
+  {
+    u32_at(&self.0, 32)
+  }
+
+  /// The number of inodes in each group.
+  pub fn inodes_per_group(&self) -> u32
+  {
+    u32_at(&self.0, 40)
+  }
+
+  /// The revision: 0 for the original layout, 1 for dynamic inode sizes
+  /// and features.
+  pub fn rev_level(&self) -> u32
+  {
+    u32_at(&self.0, 76)
+  }
+
+  /// The first inode which is not reserved.
+  pub fn first_ino(&self) -> u32
+  {
+    match self.rev_level() {
+      0 => GOOD_OLD_FIRST_INO,
+      _ => u32_at(&self.0, 84),
+    }
+  }
+
+  /// The size of an inode in the inode tables.
+  pub fn inode_size(&self) -> u32
+  {
+    match self.rev_level() {
+      0 => GOOD_OLD_INODE_SIZE,
+      _ => u16_at(&self.0, 88),
+    }
+  }
+
+  /// The compatible features.
+  pub fn feature_compat(&self) -> u32
+  {
+    u32_at(&self.0, 92)
+  }
+
+  /// The incompatible features.
+  pub fn feature_incompat(&self) -> u32
+  {
+    u32_at(&self.0, 96)
+  }
+
+  /// The read-only compatible features.
+  pub fn feature_ro_compat(&self) -> u32
+  {
+    u32_at(&self.0, 100)
+  }
+
+  /// Adds read-only compatible features.
+  pub fn add_ro_compat(&mut self, features: u32)
+  {
+    self.set_u32(100, self.feature_ro_compat() | features);
+  }
+
+  /// Returns true if every feature is understood well enough to write.
+  pub fn is_writable(&self) -> bool
+  {
+    self.feature_ro_compat() & !feature::RO_COMPAT_SUPPORTED == 0
+  }
+
+  /// Returns true if directory entries record the type of their inode.
+  #[inline]
+  pub fn has_file_type(&self) -> bool
+  {
+    self.feature_incompat() & feature::INCOMPAT_FILETYPE != 0
+  }
+
+  /// The number of block groups.
+  pub fn groups(&self) -> u32
+  {
+    (self.blocks_count() - self.first_data_block()).div_ceil(self.blocks_per_group())
+  }
+
+  /// The number of blocks in `group`; the last may be short.
+  pub fn blocks_in_group(&self, group: u32) -> u32
+  {
+    let first = self.first_data_block() + group * self.blocks_per_group();
+    (self.blocks_count() - first).min(self.blocks_per_group())
+  }
+
+  /// The block the group descriptors start in, after the superblock.
+  #[inline]
+  pub fn descriptor_block(&self) -> u32
+  {
+    self.first_data_block() + 1
+  }
+
+  /// The byte offset of `block`.
+  #[inline]
+  pub fn block_offset(&self, block: u32) -> u64
+  {
+    block as u64 * self.block_size() as u64
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  /// A superblock like `mke2fs -t ext2 -b 1024` writes for 20 MiB.
+  fn ext2() -> [u8; SUPERBLOCK_SIZE]
+  {
+    let mut sb = [0u8; SUPERBLOCK_SIZE];
+    sb[0..4].copy_from_slice(&5136u32.to_le_bytes());
+    sb[4..8].copy_from_slice(&20480u32.to_le_bytes());
+    sb[20..24].copy_from_slice(&1u32.to_le_bytes());
+    sb[32..36].copy_from_slice(&8192u32.to_le_bytes());
+    sb[40..44].copy_from_slice(&1712u32.to_le_bytes());
+    sb[56..58].copy_from_slice(&MAGIC.to_le_bytes());
+    sb[76..80].copy_from_slice(&1u32.to_le_bytes());
+    sb[84..88].copy_from_slice(&11u32.to_le_bytes());
+    sb[88..90].copy_from_slice(&128u16.to_le_bytes());
+    sb[96..100].copy_from_slice(&feature::INCOMPAT_FILETYPE.to_le_bytes());
+    sb
+  }
+
+  #[test]
+  fn layout()
+  {
+    let sb = Superblock::parse(&ext2()).unwrap();
+    assert_eq!(sb.block_size(), 1024);
+    assert_eq!(sb.groups(), 3);
+    assert_eq!(sb.blocks_in_group(0), 8192);
+    assert_eq!(sb.blocks_in_group(2), 20480 - 1 - 2 * 8192);
+    assert_eq!(sb.descriptor_block(), 2);
+    assert_eq!(sb.block_offset(3), 3072);
+    assert!(sb.has_file_type() && sb.is_writable());
+  }
+
+  #[test]
+  fn invalid()
+  {
+    let mut bytes = ext2();
+    bytes[56] = 0;
+    assert_eq!(Superblock::parse(&bytes).err(), Some(FsError::Invalid));
+
+    // 4 KiB blocks start the first group at block 0.
+    let mut bytes = ext2();
+    bytes[24] = 2;
+    assert_eq!(Superblock::parse(&bytes).err(), Some(FsError::Invalid));
+
+    // Extents are ext4's.
+    let mut bytes = ext2();
+    bytes[96..100].copy_from_slice(&0x0042u32.to_le_bytes());
+    assert_eq!(Superblock::parse(&bytes).err(), Some(FsError::Unsupported));
+
+    // A journal is only compatible, but a checksummed volume can only be
+    // read.
+    let mut bytes = ext2();
+    bytes[92] = 0x04;
+    bytes[100..104].copy_from_slice(&0x0400u32.to_le_bytes());
+    assert!(!Superblock::parse(&bytes).unwrap().is_writable());
+  }
+}
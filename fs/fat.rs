@@ -0,0 +1,1168 @@
+//! FAT12, FAT16 and FAT32 file systems on a block device.
+//!
+//! A file is a chain of clusters through the FAT and a short directory
+//! entry giving its first cluster and size, with its long name, if it has
+//! one, in the entries before that. Inodes are numbered as their entries
+//! are first found, and follow an entry when it is renamed; the root, which
+//! has no entry, is `ROOT`. Names are matched whatever their case, and by
+//! their 8.3 alias as well. There are no links, owners or permissions: the
+//! read-only attribute is shown as a mode without write bits.
+
+use core::ops::Range;
+
+use alloc::collections::HashMap;
+use alloc::spin::Mutex;
+use alloc::{Array, Shared, String};
+
+use crate::block::{read_bytes, write_bytes, BlockDevice};
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, FsResult, FsStat, Ino, Stat};
+
+pub mod bpb;
+pub mod dir;
+pub mod table;
+
+use self::bpb::{Bpb, FatType, BOOT_SECTOR_SIZE};
+use self::dir::{attr, LongName, ShortEntry, DELETED, END, ENTRY_SIZE};
+use self::table::{Entry, Table};
+
+/// The number of the root directory.
+pub const ROOT: Ino = 1;
+
+/// The largest file an entry can describe.
+pub const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// The most entries a directory can have.
+pub const MAX_DIR_ENTRIES: usize = 65536;
+
+/// Where the entries of a directory are.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Dir
+{
+  /// The root directory of FAT12 and FAT16, between the FATs and the
+  /// clusters.
+  Root,
+  /// The chain of clusters from this one.
+  Chain(u32),
+}
+
+/// The entries of a directory, as runs of consecutive entries on the
+/// device: where each starts and how many it holds.
+struct Slots(Array<(u64, usize)>);
+
+impl Slots
+{
+  /// The number of entries.
+  fn len(&self) -> usize
+  {
+    self.0.iter().map(|&(_, count)| count).sum()
+  }
+
+  /// Where entry `index` is.
+  fn offset(&self, index: usize) -> FsResult<u64>
+  {
+    let mut index = index;
+    for &(offset, count) in self.0.iter() {
+      if index < count {
+        return Ok(offset + (index * ENTRY_SIZE) as u64);
+      }
+      index -= count;
+    }
+    Err(FsError::Io)
+  }
+}
+
+/// A file found in a directory.
+struct Found
+{
+  name: String,
+  entry: ShortEntry,
+  /// Where its short entry is on the device.
+  pos: u64,
+  /// The index of its short entry in the directory.
+  index: usize,
+  /// How many long-name entries come before it.
+  long: usize,
+}
+
+struct Inner
+{
+  device: Shared<dyn BlockDevice>,
+  bpb: Bpb,
+  table: Table,
+  /// Where the short entry of each inode handed out is, and back.
+  entries: HashMap<Ino, u64>,
+  inos: HashMap<u64, Ino>,
+  next_ino: Ino,
+}
+
+impl Inner
+{
+  #[inline]
+  fn device(&self) -> &dyn BlockDevice
+  {
+    &*self.device
+  }
+
+  fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<()>
+  {
+    Ok(read_bytes(self.device(), offset, buf)?)
+  }
+
+  fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<()>
+  {
+    Ok(write_bytes(self.device(), offset, buf)?)
+  }
+
+  /// The number of the inode whose short entry is at `pos`.
+  fn ino(&mut self, pos: u64) -> Ino
+  {
+    if let Some(&ino) = self.inos.find(&pos) {
+      return ino;
+    }
+    let ino = self.next_ino;
+    self.next_ino += 1;
+    self.inos.insert(pos, ino);
+    self.entries.insert(ino, pos);
+    ino
+  }
+
+  /// Forgets the inode whose entry at `pos` is gone.
+  fn forget(&mut self, pos: u64)
+  {
+    if let Some(&ino) = self.inos.find(&pos) {
+      self.inos.remove(&pos);
+      self.entries.remove(&ino);
+    }
+  }
+
+  /// Gives the inode whose entry was at `from` the one at `to`.
+  fn moved(&mut self, from: u64, to: u64)
+  {
+    if let Some(&ino) = self.inos.find(&from) {
+      self.inos.remove(&from);
+      self.inos.insert(to, ino);
+      self.entries.insert(ino, to);
+    }
+  }
+
+  /// The short entry of `ino`, and where it is. The root has none.
+  fn entry(&self, ino: Ino) -> FsResult<(u64, ShortEntry)>
+  {
+    if ino == ROOT {
+      return Err(FsError::IsDirectory);
+    }
+    let &pos = self.entries.find(&ino).ok_or(FsError::NotFound)?;
+    let mut bytes = [0; ENTRY_SIZE];
+    self.read_at(pos, &mut bytes)?;
+    Ok((pos, ShortEntry::from_bytes(&bytes)))
+  }
+
+  /// The entry of `ino`, if it is a regular file.
+  fn file(&self, ino: Ino) -> FsResult<(u64, ShortEntry)>
+  {
+    match self.entry(ino)? {
+      (_, entry) if entry.is_dir() => Err(FsError::IsDirectory),
+      found => Ok(found),
+    }
+  }
+
+  fn root_dir(&self) -> Dir
+  {
+    match self.bpb.fat_type {
+      FatType::Fat32 => Dir::Chain(self.bpb.root_cluster),
+      _ => Dir::Root,
+    }
+  }
+
+  /// The directory `ino`.
+  fn dir(&self, ino: Ino) -> FsResult<Dir>
+  {
+    if ino == ROOT {
+      return Ok(self.root_dir());
+    }
+    let (_, entry) = self.entry(ino)?;
+    Self::dir_of(&entry)
+  }
+
+  fn dir_of(entry: &ShortEntry) -> FsResult<Dir>
+  {
+    match entry.cluster() {
+      _ if !entry.is_dir() => Err(FsError::NotDirectory),
+      0 => Err(FsError::Io),
+      cluster => Ok(Dir::Chain(cluster)),
+    }
+  }
+
+  /// The cluster `..` names for a directory in `dir`: 0 for the root.
+  fn parent_cluster(&self, dir: Dir) -> u32
+  {
+    match dir {
+      Dir::Chain(cluster) if dir != self.root_dir() => cluster,
+      _ => 0,
+    }
+  }
+
+  fn slots(&self, dir: Dir) -> FsResult<Slots>
+  {
+    let mut runs: Array<(u64, usize)> = Array::new();
+    match dir {
+      Dir::Root => runs.push((self.bpb.root_dir_offset(), self.bpb.root_entries as usize)),
+      Dir::Chain(first) => {
+        let per_cluster = self.bpb.cluster_size() as usize / ENTRY_SIZE;
+        for &cluster in self.table.chain(self.device(), first)?.iter() {
+          let offset = self.bpb.cluster_offset(cluster);
+          match runs.last_mut() {
+            Some((start, count)) if *start + (*count * ENTRY_SIZE) as u64 == offset => {
+              *count += per_cluster;
+            }
+            _ => runs.push((offset, per_cluster)),
+          }
+        }
+      }
+    }
+    Ok(Slots(runs))
+  }
+
+  /// Calls `f` with each file in `dir` in turn, until it returns true, and
+  /// returns that one. The volume label and the `.` and `..` entries are
+  /// skipped.
+  fn scan(&self, dir: Dir, f: impl FnMut(&Found) -> bool) -> FsResult<Option<Found>>
+  {
+    self.scan_from(dir, 0, f)
+  }
+
+  /// Like `scan`, but starting at entry `start` of `dir`, which must not be
+  /// in the middle of a long name.
+  fn scan_from(&self, dir: Dir, start: usize, mut f: impl FnMut(&Found) -> bool)
+      -> FsResult<Option<Found>>
+  {
+    let mut long = LongName::default();
+    let mut buf = Array::new();
+    let mut index = 0;
+
+    for &(offset, count) in self.slots(dir)?.0.iter() {
+      let skip = start.saturating_sub(index).min(count);
+      index += skip;
+      if skip == count {
+        continue;
+      }
+      let offset = offset + (skip * ENTRY_SIZE) as u64;
+      buf.resize((count - skip) * ENTRY_SIZE, 0);
+      self.read_at(offset, &mut buf)?;
+
+      for (i, raw) in buf.chunks(ENTRY_SIZE).enumerate() {
+        let pos = offset + (i * ENTRY_SIZE) as u64;
+        index += 1;
+        match raw[0] {
+          END => return Ok(None),
+          DELETED => long.reset(),
+          _ if raw[11] & 0x3f == attr::LONG_NAME => long.push(raw),
+          _ => {
+            let entry = ShortEntry::from_bytes(raw);
+            let entries = long.entries();
+            let name = long.take(&entry);
+            if entry.attr() & attr::VOLUME_ID != 0 || raw[0] == b'.' {
+              continue;
+            }
+
+            let (name, entries) = match name {
+              Some(name) => (name, entries),
+              None => (entry.display_name(), 0),
+            };
+            let found = Found { name, entry, pos, index: index - 1, long: entries };
+            if f(&found) {
+              return Ok(Some(found));
+            }
+          }
+        }
+      }
+    }
+    Ok(None)
+  }
+
+  /// The file named `name` in `dir`, by its long name or its 8.3 alias.
+  fn find(&self, dir: Dir, name: &str) -> FsResult<Found>
+  {
+    let found = self.scan(dir, |found| {
+      dir::same_name(&found.name, name) || dir::same_name(&found.entry.display_name(), name)
+    })?;
+    found.ok_or(FsError::NotFound)
+  }
+
+  /// Returns true if the directory `dir` has no files.
+  fn is_empty(&self, dir: Dir) -> FsResult<bool>
+  {
+    Ok(self.scan(dir, |_| true)?.is_none())
+  }
+
+  /// Takes a free cluster, zeroed, linked after `prev` if there is one.
+  fn alloc_cluster(&mut self, prev: Option<u32>) -> FsResult<u32>
+  {
+    let cluster = self.table.alloc(&*self.device, prev)?;
+    let mut zeroes = Array::new();
+    zeroes.resize(self.bpb.cluster_size() as usize, 0);
+    self.write_at(self.bpb.cluster_offset(cluster), &zeroes)?;
+    Ok(cluster)
+  }
+
+  /// Adds clusters to `chain` until it has `count`.
+  fn grow(&mut self, chain: &mut Array<u32>, count: usize) -> FsResult<()>
+  {
+    while chain.len() < count {
+      let cluster = self.alloc_cluster(chain.last().copied())?;
+      chain.push(cluster);
+    }
+    Ok(())
+  }
+
+  /// Frees the chain of clusters from `first`.
+  fn free(&mut self, first: u32) -> FsResult<()>
+  {
+    let chain = self.table.chain(&*self.device, first)?;
+    self.table.free_chain(&*self.device, &chain)
+  }
+
+  /// Calls `f` with where on the device each piece of the `len` bytes from
+  /// `offset` in the clusters of `chain` is, and which of those bytes it
+  /// holds.
+  fn map(&self, chain: &[u32], offset: u64, len: usize,
+      mut f: impl FnMut(u64, Range<usize>) -> FsResult<()>) -> FsResult<()>
+  {
+    let cluster_size = self.bpb.cluster_size() as u64;
+    let mut done = 0;
+    while done < len {
+      let pos = offset + done as u64;
+      let start = pos % cluster_size;
+      let n = ((cluster_size - start) as usize).min(len - done);
+      let &cluster = chain.get((pos / cluster_size) as usize).ok_or(FsError::Io)?;
+      f(self.bpb.cluster_offset(cluster) + start, done..done + n)?;
+      done += n;
+    }
+    Ok(())
+  }
+
+  /// Zeroes the bytes from `from` to `to` in the clusters of `chain`.
+  fn zero(&self, chain: &[u32], from: u64, to: u64) -> FsResult<()>
+  {
+    if from >= to {
+      return Ok(());
+    }
+    let mut zeroes = Array::new();
+    zeroes.resize(self.bpb.cluster_size() as usize, 0);
+    self.map(chain, from, (to - from) as usize, |at, range| {
+      self.write_at(at, &zeroes[..range.len()])
+    })
+  }
+
+  /// The index of the first of `wanted` free entries in a row in `slots`.
+  fn free_run(&self, slots: &Slots, wanted: usize) -> FsResult<Option<usize>>
+  {
+    let mut buf = Array::new();
+    let mut index = 0;
+    let mut run = 0;
+    let mut ended = false;
+    for &(offset, count) in slots.0.iter() {
+      buf.resize(count * ENTRY_SIZE, 0);
+      self.read_at(offset, &mut buf)?;
+      for raw in buf.chunks(ENTRY_SIZE) {
+        // Everything after the end is free.
+        ended = ended || raw[0] == END;
+        run = if ended || raw[0] == DELETED { run + 1 } else { 0 };
+        index += 1;
+        if run == wanted {
+          return Ok(Some(index - wanted));
+        }
+      }
+    }
+    Ok(None)
+  }
+
+  /// Finds `wanted` free entries in a row in `dir`, growing it if it must,
+  /// and returns its entries and the index of the first.
+  fn free_slots(&mut self, dir: Dir, wanted: usize) -> FsResult<(Slots, usize)>
+  {
+    loop {
+      let slots = self.slots(dir)?;
+      if let Some(first) = self.free_run(&slots, wanted)? {
+        return Ok((slots, first));
+      }
+
+      let first = match dir {
+        Dir::Chain(first) if slots.len() < MAX_DIR_ENTRIES => first,
+        _ => return Err(FsError::NoSpace),
+      };
+      let last = self.table.chain(&*self.device, first)?.last().copied();
+      self.alloc_cluster(last)?;
+    }
+  }
+
+  /// Adds `entry` to `dir` as `name`, with long-name entries if it needs
+  /// them, and returns where the short entry is. `name` must not be taken.
+  fn add(&mut self, dir: Dir, name: &str, entry: ShortEntry) -> FsResult<u64>
+  {
+    dir::check_name(name)?;
+    let mut entry = entry;
+    let long = match dir::short_name(name) {
+      Some((short, case)) => {
+        entry.set_name(short, case);
+        Array::new()
+      }
+      None => {
+        let mut taken = Array::new();
+        self.scan(dir, |found| {
+          taken.push(found.entry.name());
+          false
+        })?;
+        let basis = dir::basis_name(name);
+        let short = (1..1_000_000)
+            .map(|n| dir::with_tail(&basis, n))
+            .find(|short| !taken.contains(short))
+            .ok_or(FsError::NoSpace)?;
+        entry.set_name(short, 0);
+        dir::long_entries(name, dir::checksum(&short))
+      }
+    };
+
+    let (slots, first) = self.free_slots(dir, long.len() + 1)?;
+    for (i, raw) in long.iter().enumerate() {
+      self.write_at(slots.offset(first + i)?, raw)?;
+    }
+    let pos = slots.offset(first + long.len())?;
+    self.write_at(pos, entry.as_bytes())?;
+    Ok(pos)
+  }
+
+  /// Marks the entries of `found`, in `dir`, deleted.
+  fn remove(&self, dir: Dir, found: &Found) -> FsResult<()>
+  {
+    let slots = self.slots(dir)?;
+    for index in found.index - found.long..=found.index {
+      self.write_at(slots.offset(index)?, &[DELETED])?;
+    }
+    Ok(())
+  }
+
+  /// Creates the directory `name` in `dir`, in the zeroed `cluster`.
+  fn make_dir(&mut self, dir: Dir, name: &str, cluster: u32) -> FsResult<u64>
+  {
+    let mut dot = ShortEntry::new(*b".          ", 0, attr::DIRECTORY);
+    dot.set_cluster(cluster);
+    let mut dotdot = ShortEntry::new(*b"..         ", 0, attr::DIRECTORY);
+    dotdot.set_cluster(self.parent_cluster(dir));
+    let offset = self.bpb.cluster_offset(cluster);
+    self.write_at(offset, dot.as_bytes())?;
+    self.write_at(offset + ENTRY_SIZE as u64, dotdot.as_bytes())?;
+
+    let mut entry = ShortEntry::new([b' '; 11], 0, attr::DIRECTORY);
+    entry.set_cluster(cluster);
+    self.add(dir, name, entry)
+  }
+}
+
+/// A FAT file system on a block device.
+pub struct Fat
+{
+  fat_type: FatType,
+  read_only: bool,
+  inner: Mutex<Inner>,
+}
+
+impl Fat
+{
+  /// The file system on `device`, or `Invalid` if it has none. It is read
+  /// only if the device is.
+  pub fn new(device: Shared<dyn BlockDevice>) -> FsResult<Self>
+  {
+    let mut boot = [0; BOOT_SECTOR_SIZE];
+    read_bytes(&*device, 0, &mut boot)?;
+    let bpb = Bpb::parse(&boot)?;
+    if bpb.total_sectors as u64 * bpb.bytes_per_sector as u64 > device.size() {
+      return Err(FsError::Invalid);
+    }
+
+    let table = Table::new(&*device, &bpb)?;
+    let inner = Inner {
+      table,
+      bpb,
+      entries: HashMap::new(),
+      inos: HashMap::new(),
+      next_ino: ROOT + 1,
+      device: device.clone(),
+    };
+    Ok(Self { fat_type: bpb.fat_type, read_only: device.is_read_only(), inner: Mutex::new(inner) })
+  }
+
+  /// Which of FAT12, FAT16 and FAT32 it is.
+  #[inline]
+  pub fn fat_type(&self) -> FatType
+  {
+    self.fat_type
+  }
+
+  fn writable(&self) -> FsResult<()>
+  {
+    if self.read_only {
+      Err(FsError::ReadOnly)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl FileSystem for Fat
+{
+  fn name(&self) -> &'static str
+  {
+    "fat"
+  }
+
+  fn root(&self) -> Ino
+  {
+    ROOT
+  }
+
+  fn stat(&self, ino: Ino) -> FsResult<Stat>
+  {
+    let inner = self.inner.lock();
+    let (entry, dir) = match ino {
+      ROOT => (None, inner.root_dir()),
+      _ => {
+        let (_, entry) = inner.entry(ino)?;
+        if !entry.is_dir() {
+          let mode = if entry.attr() & attr::READ_ONLY != 0 { 0o444 } else { 0o644 };
+          let (size, mtime) = (entry.size() as u64, entry.mtime());
+          let kind = FileType::File;
+          return Ok(Stat { ino, kind, mode, uid: 0, gid: 0, size, nlink: 1, mtime });
+        }
+        (Some(entry), Inner::dir_of(&entry)?)
+      }
+    };
+
+    let mut subdirs = 0;
+    inner.scan(dir, |found| {
+      subdirs += found.entry.is_dir() as u32;
+      false
+    })?;
+    let size = (inner.slots(dir)?.len() * ENTRY_SIZE) as u64;
+    let mtime = entry.map_or(0, |entry| entry.mtime());
+    let kind = FileType::Directory;
+    Ok(Stat { ino, kind, mode: 0o755, uid: 0, gid: 0, size, nlink: 2 + subdirs, mtime })
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> FsResult<Ino>
+  {
+    let mut inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    let found = inner.find(dir, name)?;
+    Ok(inner.ino(found.pos))
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> FsResult<usize>
+  {
+    let inner = self.inner.lock();
+    let (_, entry) = inner.file(ino)?;
+    let size = entry.size() as u64;
+    if offset >= size {
+      return Ok(0);
+    }
+
+    let len = buf.len().min((size - offset) as usize);
+    let chain = inner.table.chain(inner.device(), entry.cluster())?;
+    inner.map(&chain, offset, len, |at, range| inner.read_at(at, &mut buf[range]))?;
+    Ok(len)
+  }
+
+  fn readdir(&self, dir: Ino, pos: usize) -> FsResult<Option<(DirEntry, usize)>>
+  {
+    let mut inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    // Positions are entry indices, which stay put as others come and go.
+    let found = inner.scan_from(dir, pos, |_| true)?;
+
+    Ok(found.map(|found| {
+      let kind = if found.entry.is_dir() { FileType::Directory } else { FileType::File };
+      let next = found.index + 1;
+      (DirEntry { ino: inner.ino(found.pos), name: found.name, kind }, next)
+    }))
+  }
+
+  fn readlink(&self, _ino: Ino) -> FsResult<String>
+  {
+    Err(FsError::Invalid)
+  }
+
+  fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> FsResult<usize>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let (pos, mut entry) = inner.file(ino)?;
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    if offset >= MAX_FILE_SIZE {
+      return Err(FsError::NoSpace);
+    }
+
+    let cluster_size = inner.bpb.cluster_size() as u64;
+    let size = entry.size() as u64;
+    let mut chain = inner.table.chain(inner.device(), entry.cluster())?;
+    let had = chain.len() as u64 * cluster_size;
+
+    // Write what fits if the disk fills up.
+    let end = offset + (buf.len() as u64).min(MAX_FILE_SIZE - offset);
+    let grown = inner.grow(&mut chain, end.div_ceil(cluster_size) as usize);
+    entry.set_cluster(chain.first().copied().unwrap_or(0));
+    let end = end.min(chain.len() as u64 * cluster_size);
+    if end <= offset {
+      inner.write_at(pos, entry.as_bytes())?;
+      return Err(grown.err().unwrap_or(FsError::NoSpace));
+    }
+
+    // New clusters are zeroed, but the old last one may hold anything past
+    // the end.
+    inner.zero(&chain, size, offset.min(had))?;
+    let len = (end - offset) as usize;
+    inner.map(&chain, offset, len, |at, range| inner.write_at(at, &buf[range]))?;
+
+    entry.set_size(size.max(end) as u32);
+    entry.set_attr(entry.attr() | attr::ARCHIVE);
+    inner.write_at(pos, entry.as_bytes())?;
+    Ok(len)
+  }
+
+  fn truncate(&self, ino: Ino, new_size: u64) -> FsResult<()>
+  {
+    self.writable()?;
+    if new_size > MAX_FILE_SIZE {
+      return Err(FsError::NoSpace);
+    }
+    let mut inner = self.inner.lock();
+    let (pos, mut entry) = inner.file(ino)?;
+
+    let cluster_size = inner.bpb.cluster_size() as u64;
+    let size = entry.size() as u64;
+    let mut chain = inner.table.chain(inner.device(), entry.cluster())?;
+    let keep = new_size.div_ceil(cluster_size) as usize;
+    if keep < chain.len() {
+      // The file ends before the rest is freed, so a crash can only lose
+      // clusters.
+      let Inner { table, device, .. } = &mut *inner;
+      if keep > 0 {
+        table.set(&**device, chain[keep - 1], Entry::End)?;
+      }
+      table.free_chain(&**device, &chain[keep..])?;
+      entry.set_cluster(if keep > 0 { chain[0] } else { 0 });
+    } else {
+      let had = chain.len() as u64 * cluster_size;
+      let grown = inner.grow(&mut chain, keep);
+      entry.set_cluster(chain.first().copied().unwrap_or(0));
+      if grown.is_err() {
+        inner.write_at(pos, entry.as_bytes())?;
+        return grown;
+      }
+      inner.zero(&chain, size, new_size.min(had))?;
+    }
+
+    entry.set_size(new_size as u32);
+    entry.set_attr(entry.attr() | attr::ARCHIVE);
+    inner.write_at(pos, entry.as_bytes())
+  }
+
+  fn create(&self, dir: Ino, name: &str, kind: FileType, mode: u32) -> FsResult<Ino>
+  {
+    self.writable()?;
+    dir::check_name(name)?;
+    let mut inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    match inner.find(dir, name) {
+      Ok(_) => return Err(FsError::Exists),
+      Err(FsError::NotFound) => {}
+      Err(e) => return Err(e),
+    }
+
+    let pos = match kind {
+      FileType::File => {
+        let read_only = if mode & 0o222 == 0 { attr::READ_ONLY } else { 0 };
+        inner.add(dir, name, ShortEntry::new([b' '; 11], 0, attr::ARCHIVE | read_only))?
+      }
+      FileType::Directory => {
+        let cluster = inner.alloc_cluster(None)?;
+        let made = inner.make_dir(dir, name, cluster);
+        if made.is_err() {
+          inner.free(cluster)?;
+        }
+        made?
+      }
+      _ => return Err(FsError::Invalid),
+    };
+    Ok(inner.ino(pos))
+  }
+
+  fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> FsResult<Ino>
+  {
+    self.writable()?;
+    Err(FsError::Unsupported)
+  }
+
+  fn unlink(&self, dir: Ino, name: &str) -> FsResult<()>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    let found = inner.find(dir, name)?;
+    if found.entry.is_dir() {
+      return Err(FsError::IsDirectory);
+    }
+
+    inner.remove(dir, &found)?;
+    inner.forget(found.pos);
+    inner.free(found.entry.cluster())
+  }
+
+  fn rmdir(&self, dir: Ino, name: &str) -> FsResult<()>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let dir = inner.dir(dir)?;
+    let found = inner.find(dir, name)?;
+    if !inner.is_empty(Inner::dir_of(&found.entry)?)? {
+      return Err(FsError::NotEmpty);
+    }
+
+    inner.remove(dir, &found)?;
+    inner.forget(found.pos);
+    inner.free(found.entry.cluster())
+  }
+
+  fn rename(&self, from_dir: Ino, from: &str, to_dir: Ino, to: &str) -> FsResult<()>
+  {
+    self.writable()?;
+    let mut inner = self.inner.lock();
+    let (from_dir, to_dir) = (inner.dir(from_dir)?, inner.dir(to_dir)?);
+    let source = inner.find(from_dir, from)?;
+
+    let replaced = match inner.find(to_dir, to) {
+      // Only the case of the name can change.
+      Ok(old) if old.pos == source.pos && old.name == to => return Ok(()),
+      Ok(old) if old.pos == source.pos => None,
+      Ok(old) => {
+        match (source.entry.is_dir(), old.entry.is_dir()) {
+          (true, true) if !inner.is_empty(Inner::dir_of(&old.entry)?)? => {
+            return Err(FsError::NotEmpty);
+          }
+          (true, false) => return Err(FsError::NotDirectory),
+          (false, true) => return Err(FsError::IsDirectory),
+          _ => {}
+        }
+        Some(old)
+      }
+      Err(FsError::NotFound) => None,
+      Err(e) => return Err(e),
+    };
+
+    // The new entry goes in first, so that nothing is lost if there is no
+    // room for it.
+    let pos = inner.add(to_dir, to, source.entry)?;
+    if let Some(old) = replaced {
+      inner.remove(to_dir, &old)?;
+      inner.forget(old.pos);
+      inner.free(old.entry.cluster())?;
+    }
+    inner.remove(from_dir, &source)?;
+    inner.moved(source.pos, pos);
+
+    if source.entry.is_dir() && from_dir != to_dir {
+      let at = inner.bpb.cluster_offset(source.entry.cluster()) + ENTRY_SIZE as u64;
+      let mut bytes = [0; ENTRY_SIZE];
+      inner.read_at(at, &mut bytes)?;
+      let mut dotdot = ShortEntry::from_bytes(&bytes);
+      dotdot.set_cluster(inner.parent_cluster(to_dir));
+      inner.write_at(at, dotdot.as_bytes())?;
+    }
+    Ok(())
+  }
+
+  fn statfs(&self) -> FsResult<FsStat>
+  {
+    let mut inner = self.inner.lock();
+    let Inner { table, device, .. } = &mut *inner;
+    let free = table.free_count(&**device)?;
+    Ok(FsStat {
+      block_size: inner.bpb.cluster_size() as u64,
+      blocks: inner.bpb.clusters() as u64,
+      free_blocks: free as u64,
+      inodes: 0,
+      free_inodes: 0,
+    })
+  }
+
+  fn sync(&self) -> FsResult<()>
+  {
+    let inner = self.inner.lock();
+    if !self.read_only {
+      inner.table.write_fs_info(inner.device())?;
+    }
+    Ok(inner.device.flush()?)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::block::RamDisk;
+  use crate::vfs::testing::{mount, names, read_at};
+  use crate::vfs::OpenFlags;
+
+  /// Formats a disk of `sectors` 512-byte sectors, with a cluster a
+  /// sector, as FAT32 or as whichever of FAT12 and FAT16 fits.
+  fn mkfs(sectors: u32, fat32: bool) -> Shared<RamDisk>
+  {
+    let bits = match sectors {
+      _ if fat32 => 32,
+      s if s < 4085 => 12,
+      _ => 16,
+    };
+    let fat_sectors = ((sectors + 2) * bits).div_ceil(8 * 512);
+    let reserved: u16 = if fat32 { 32 } else { 1 };
+
+    let mut boot = [0u8; 512];
+    boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&reserved.to_le_bytes());
+    boot[16] = 2;
+    boot[21] = 0xf8;
+    boot[32..36].copy_from_slice(&sectors.to_le_bytes());
+    if fat32 {
+      boot[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+      boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+      boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+    } else {
+      boot[17..19].copy_from_slice(&64u16.to_le_bytes());
+      boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    }
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let disk = RamDisk::new(512, sectors as u64);
+    disk.write(0, &boot).unwrap();
+    // The first two entries hold the media byte; the FAT32 root ends in
+    // cluster 2.
+    let media: &[u8] = match bits {
+      12 => &[0xf8, 0xff, 0xff],
+      16 => &[0xf8, 0xff, 0xff, 0xff],
+      _ => &[0xf8, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f],
+    };
+    for n in 0..2 {
+      let at = (reserved as u64 + n * fat_sectors as u64) * 512;
+      write_bytes(&disk, at, media).unwrap();
+    }
+    if fat32 {
+      let mut info = [0u8; 512];
+      info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+      info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+      info[488..492].copy_from_slice(&u32::MAX.to_le_bytes());
+      info[492..496].copy_from_slice(&3u32.to_le_bytes());
+      info[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+      disk.write(1, &info).unwrap();
+    }
+    Shared::new(disk)
+  }
+
+  fn fat12() -> Shared<RamDisk>
+  {
+    mkfs(2048, false)
+  }
+
+  fn fat16() -> Shared<RamDisk>
+  {
+    mkfs(8192, false)
+  }
+
+  fn fat32() -> Shared<RamDisk>
+  {
+    mkfs(70000, true)
+  }
+
+  #[test]
+  fn types()
+  {
+    assert_eq!(Fat::new(fat12()).unwrap().fat_type(), FatType::Fat12);
+    assert_eq!(Fat::new(fat16()).unwrap().fat_type(), FatType::Fat16);
+    assert_eq!(Fat::new(fat32()).unwrap().fat_type(), FatType::Fat32);
+    let blank: Shared<dyn BlockDevice> = Shared::new(RamDisk::new(512, 64));
+    assert_eq!(Fat::new(blank).err(), Some(FsError::Invalid));
+  }
+
+  #[test]
+  fn chains()
+  {
+    for disk in [fat12(), fat16(), fat32()] {
+      let (fs, vfs) = mount(Fat::new(disk.clone()));
+      let free = fs.statfs().unwrap().free_blocks;
+      let data: std::vec::Vec<u8> = (0..3 * 512 + 100).map(|i| (i % 251) as u8).collect();
+      let create = OpenFlags::WRITE | OpenFlags::CREATE;
+      vfs.open("/data", create, 0o644).unwrap().write(&data).unwrap();
+      assert_eq!(fs.statfs().unwrap().free_blocks, free - 4);
+
+      // The clusters are linked alike in both FATs.
+      let ino = vfs.stat("/data").unwrap().ino;
+      {
+        let inner = fs.inner.lock();
+        let (_, entry) = inner.entry(ino).unwrap();
+        assert_eq!(inner.table.chain(inner.device(), entry.cluster()).unwrap().len(), 4);
+        let mut fats = [std::vec![0; 512], std::vec![0; 512]];
+        for (n, fat) in fats.iter_mut().enumerate() {
+          inner.read_at(inner.bpb.fat_offset(n as u32), fat).unwrap();
+        }
+        assert_eq!(fats[0], fats[1]);
+      }
+
+      // Truncating cuts the chain, and the free count is kept on FAT32.
+      vfs.truncate("/data", 600).unwrap();
+      assert_eq!(fs.statfs().unwrap().free_blocks, free - 2);
+      vfs.sync().unwrap();
+      let (fs, vfs) = mount(Fat::new(disk.clone()));
+      assert_eq!(fs.statfs().unwrap().free_blocks, free - 2);
+      assert_eq!(read_at(&vfs, "/data", 0, 1000), data[..600]);
+      vfs.unlink("/data").unwrap();
+      assert_eq!(fs.statfs().unwrap().free_blocks, free);
+    }
+  }
+
+  #[test]
+  fn fat12_packing()
+  {
+    let disk = fat12();
+    let fs = Fat::new(disk.clone()).unwrap();
+    let mut inner = fs.inner.lock();
+    let Inner { device, bpb, table, .. } = &mut *inner;
+
+    // Clusters 2 and 3 share the three bytes from 3, the odd one in the
+    // high bits; 4 and 5 the three after.
+    table.set(&**device, 2, Entry::Next(0x123)).unwrap();
+    table.set(&**device, 3, Entry::Next(0x456)).unwrap();
+    table.set(&**device, 4, Entry::End).unwrap();
+    for n in 0..2 {
+      let mut raw = [0; 6];
+      read_bytes(&*disk, bpb.fat_offset(n) + 3, &mut raw).unwrap();
+      assert_eq!(raw, [0x23, 0x61, 0x45, 0xff, 0x0f, 0x00]);
+    }
+
+    // Setting one entry leaves its neighbour as it was.
+    table.set(&**device, 3, Entry::Free).unwrap();
+    table.set(&**device, 5, Entry::Bad).unwrap();
+    assert_eq!(table.get(&**device, 2), Ok(Entry::Next(0x123)));
+    assert_eq!(table.get(&**device, 3), Ok(Entry::Free));
+    assert_eq!(table.get(&**device, 4), Ok(Entry::End));
+    assert_eq!(table.get(&**device, 5), Ok(Entry::Bad));
+  }
+
+  #[test]
+  fn corrupt_chains()
+  {
+    let (fs, vfs) = mount(Fat::new(fat16()));
+    let data = [1u8; 3 * 512];
+    vfs.open("/data", OpenFlags::WRITE | OpenFlags::CREATE, 0o644).unwrap().write(&data).unwrap();
+    let ino = vfs.stat("/data").unwrap().ino;
+    let set = |cluster, entry| {
+      let mut inner = fs.inner.lock();
+      let Inner { device, table, .. } = &mut *inner;
+      table.set(&**device, cluster, entry).unwrap();
+    };
+    let (chain, end) = {
+      let inner = fs.inner.lock();
+      let (_, entry) = inner.entry(ino).unwrap();
+      (inner.table.chain(inner.device(), entry.cluster()).unwrap(), inner.bpb.clusters() + 2)
+    };
+
+    // A loop, a free or bad cluster, or one past the last, ends the file.
+    let mut buf = [0u8; 3 * 512];
+    for entry in [Entry::Next(chain[0]), Entry::Free, Entry::Bad, Entry::Next(end)] {
+      set(chain[1], entry);
+      assert_eq!(fs.read(ino, 0, &mut buf), Err(FsError::Io));
+    }
+    set(chain[1], Entry::Next(chain[2]));
+    assert_eq!(fs.read(ino, 0, &mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+
+    // The entry goes, but nothing is freed from a chain which cannot be
+    // followed.
+    set(chain[1], Entry::Next(chain[0]));
+    let free = fs.statfs().unwrap().free_blocks;
+    assert_eq!(vfs.unlink("/data"), Err(FsError::Io));
+    assert_eq!(fs.statfs().unwrap().free_blocks, free);
+    assert_eq!(vfs.stat("/data").err(), Some(FsError::NotFound));
+  }
+
+  #[test]
+  fn checksum_mismatch()
+  {
+    let disk = fat16();
+    let (fs, vfs) = mount(Fat::new(disk.clone()));
+    vfs.open("/A long file name.txt", OpenFlags::CREATE, 0o644).unwrap();
+    let root = fs.inner.lock().bpb.root_dir_offset();
+
+    // A system which knows nothing of long names renames the short entry,
+    // so its long-name entries no longer match it.
+    let mut entries = [0u8; 3 * ENTRY_SIZE];
+    read_bytes(&*disk, root, &mut entries).unwrap();
+    assert_eq!([entries[11], entries[ENTRY_SIZE + 11]], [attr::LONG_NAME; 2]);
+    entries[2 * ENTRY_SIZE..][..11].copy_from_slice(b"OTHER   TXT");
+    write_bytes(&*disk, root, &entries).unwrap();
+
+    let (_, vfs) = mount(Fat::new(disk.clone()));
+    assert_eq!(names(&vfs, "/"), ["OTHER.TXT"]);
+    assert_eq!(vfs.stat("/A long file name.txt").err(), Some(FsError::NotFound));
+    assert_eq!(vfs.stat("/other.txt").unwrap().kind, FileType::File);
+
+    // The stale entries do not hide a name made after them.
+    vfs.open("/A long file name.txt", OpenFlags::CREATE, 0o644).unwrap();
+    let mut listed = names(&vfs, "/");
+    listed.sort();
+    assert_eq!(listed, ["A long file name.txt", "OTHER.TXT"]);
+  }
+
+  #[test]
+  fn long_names()
+  {
+    let disk = fat16();
+    let (_, vfs) = mount(Fat::new(disk.clone()));
+    let files = ["A long file name 2.txt", "A long file name.txt", "readme.txt", "日本語.text"];
+    for name in files {
+      vfs.open(&std::format!("/{}", name), OpenFlags::CREATE, 0o644).unwrap();
+    }
+    let mut listed = names(&vfs, "/");
+    listed.sort();
+    assert_eq!(listed, files);
+
+    // Names match whatever their case, and by their aliases.
+    let ino = vfs.stat("/a LONG file NAME 2.TXT").unwrap().ino;
+    assert_eq!(vfs.stat("/ALONGF~1.TXT").unwrap().ino, ino);
+    assert_eq!(vfs.stat("/README.TXT").unwrap().kind, FileType::File);
+    assert_eq!(vfs.stat("/___~1.TEX").unwrap().kind, FileType::File);
+
+    // "readme.txt" needs no long name; the others do.
+    let mut boot = [0; 512];
+    disk.read(0, &mut boot).unwrap();
+    let bpb = Bpb::parse(&boot).unwrap();
+    let mut root = std::vec![0u8; 64 * ENTRY_SIZE];
+    read_bytes(&*disk, bpb.root_dir_offset(), &mut root).unwrap();
+    let shorts: std::vec::Vec<_> = root
+        .chunks(ENTRY_SIZE)
+        .filter(|raw| raw[0] != 0 && raw[11] != attr::LONG_NAME)
+        .map(|raw| std::string::String::from_utf8_lossy(&raw[..11]).into_owned())
+        .collect();
+    assert_eq!(shorts, ["ALONGF~1TXT", "ALONGF~2TXT", "README  TXT", "___~1   TEX"]);
+    assert_eq!(root.chunks(ENTRY_SIZE).filter(|raw| raw[0] != 0).count(), 4 + 2 + 2 + 1);
+
+    let long = std::format!("/{}", "x".repeat(256));
+    assert_eq!(vfs.open(&long, OpenFlags::CREATE, 0).err(), Some(FsError::NameTooLong));
+    assert_eq!(vfs.mkdir("/a:b", 0o755), Err(FsError::Invalid));
+    assert_eq!(vfs.mkdir("/README.txt", 0o755), Err(FsError::Exists));
+  }
+
+  #[test]
+  fn directories()
+  {
+    let disk = fat32();
+    let (fs, vfs) = mount(Fat::new(disk.clone()));
+    let free = fs.statfs().unwrap().free_blocks;
+    vfs.mkdir("/usr", 0o755).unwrap();
+    vfs.mkdir("/usr/bin", 0o755).unwrap();
+    assert_eq!(vfs.stat("/usr").unwrap().nlink, 3);
+    assert_eq!(vfs.symlink("usr/bin", "/bin"), Err(FsError::Unsupported));
+
+    // Sixteen entries fill a cluster, so the directory grows.
+    for i in 0..40 {
+      vfs.open(&std::format!("/usr/bin/tool{}", i), OpenFlags::CREATE, 0o755).unwrap();
+    }
+    assert_eq!(names(&vfs, "/usr/bin").len(), 40);
+    assert_eq!(vfs.stat("/usr/bin").unwrap().size, 3 * 512);
+    assert_eq!(vfs.stat("/usr/bin/TOOL39").unwrap().kind, FileType::File);
+
+    assert_eq!(vfs.rmdir("/usr"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.unlink("/usr/bin"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rmdir("/usr/bin/tool0"), Err(FsError::NotDirectory));
+
+    // Removing what was listed does not make the listing skip any.
+    let dir = vfs.open("/usr/bin", OpenFlags::READ, 0).unwrap();
+    let mut listed = 0;
+    while let Some(entry) = dir.readdir().unwrap() {
+      if entry.name != "." && entry.name != ".." {
+        vfs.unlink(&std::format!("/usr/bin/{}", entry.name)).unwrap();
+        listed += 1;
+      }
+    }
+    assert_eq!(listed, 40);
+    vfs.rmdir("/usr/bin").unwrap();
+    vfs.rmdir("/usr").unwrap();
+    assert!(names(&vfs, "/").is_empty());
+    assert_eq!(fs.statfs().unwrap().free_blocks, free);
+
+    // The root of FAT12 and FAT16 cannot grow.
+    let (_, vfs) = mount(Fat::new(fat12()));
+    for i in 0..64 {
+      vfs.mkdir(&std::format!("/D{}", i), 0o755).unwrap();
+    }
+    assert_eq!(vfs.mkdir("/D64", 0o755), Err(FsError::NoSpace));
+  }
+
+  #[test]
+  fn rename()
+  {
+    let (fs, vfs) = mount(Fat::new(fat16()));
+    let create = OpenFlags::WRITE | OpenFlags::CREATE;
+    vfs.open("/a", create, 0o644).unwrap().write(b"a").unwrap();
+    vfs.open("/b", create, 0o644).unwrap().write(b"b").unwrap();
+    vfs.mkdir("/d", 0o755).unwrap();
+    vfs.mkdir("/e", 0o755).unwrap();
+    vfs.open("/e/f", create, 0o644).unwrap();
+    let free = fs.statfs().unwrap().free_blocks;
+
+    // Replacing a file frees it.
+    vfs.rename("/a", "/b").unwrap();
+    assert_eq!(read_at(&vfs, "/b", 0, 4), b"a");
+    assert_eq!(vfs.stat("/a").err(), Some(FsError::NotFound));
+    assert_eq!(fs.statfs().unwrap().free_blocks, free + 1);
+
+    // Open files follow their entries.
+    let file = vfs.open("/b", OpenFlags::WRITE | OpenFlags::APPEND, 0).unwrap();
+    vfs.rename("/b", "/d/Long name for c").unwrap();
+    file.write(b"!").unwrap();
+    assert_eq!(read_at(&vfs, "/d/long name for C", 0, 4), b"a!");
+    vfs.rename("/d/long name for c", "/d/c").unwrap();
+    vfs.rename("/d/c", "/d/C").unwrap();
+    assert_eq!(names(&vfs, "/d"), ["C"]);
+
+    assert_eq!(vfs.rename("/d/c", "/e"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rename("/d", "/e"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.rename("/e", "/d/c"), Err(FsError::NotDirectory));
+    vfs.unlink("/d/c").unwrap();
+    vfs.rename("/e", "/d/e").unwrap();
+    assert_eq!(vfs.stat("/d/e/f").unwrap().kind, FileType::File);
+
+    // The moved directory's `..` names its new parent.
+    let (d, e) = (vfs.stat("/d").unwrap().ino, vfs.stat("/d/e").unwrap().ino);
+    let inner = fs.inner.lock();
+    let (_, d) = inner.entry(d).unwrap();
+    let (_, e) = inner.entry(e).unwrap();
+    let mut dotdot = [0; ENTRY_SIZE];
+    inner.read_at(inner.bpb.cluster_offset(e.cluster()) + ENTRY_SIZE as u64, &mut dotdot).unwrap();
+    assert_eq!(ShortEntry::from_bytes(&dotdot).cluster(), d.cluster());
+  }
+
+  #[test]
+  fn read_only()
+  {
+    let disk = fat12();
+    let (_, vfs) = mount(Fat::new(disk.clone()));
+    vfs.open("/x", OpenFlags::CREATE, 0o444).unwrap();
+    assert_eq!(vfs.stat("/x").unwrap().mode, 0o444);
+
+    disk.set_read_only(true);
+    let (_, vfs) = mount(Fat::new(disk.clone()));
+    assert_eq!(vfs.open("/y", OpenFlags::CREATE, 0o644).err(), Some(FsError::ReadOnly));
+    assert_eq!(vfs.unlink("/x"), Err(FsError::ReadOnly));
+    assert_eq!(vfs.stat("/x").unwrap().size, 0);
+  }
+}
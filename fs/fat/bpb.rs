@@ -0,0 +1,318 @@
+//! The BIOS parameter block, at the start of the boot sector.
+//!
+//! It gives the layout of the volume: the reserved sectors, then the copies
+//! of the FAT, then on FAT12 and FAT16 the root directory, then the
+//! clusters. Which of the three a volume is depends only on how many
+//! clusters it has.
+
+use core::fmt::{self, Display};
+
+use crate::vfs::{FsError, FsResult};
+
+/// The size of the boot sector that is read.
+pub const BOOT_SECTOR_SIZE: usize = 512;
+
+/// The width of the entries in the FAT.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FatType
+{
+  /// 12-bit entries, for fewer than 4085 clusters.
+  Fat12,
+  /// 16-bit entries, for fewer than 65525 clusters.
+  Fat16,
+  /// 28-bit entries in 32 bits, with the root directory in clusters.
+  Fat32,
+}
+
+impl FatType
+{
+  /// The type of a volume of `clusters` clusters.
+  pub fn for_clusters(clusters: u32) -> Self
+  {
+    if clusters < 4085 {
+      FatType::Fat12
+    } else if clusters < 65525 {
+      FatType::Fat16
+    } else {
+      FatType::Fat32
+    }
+  }
+
+  /// The width of an entry in bits.
+  pub fn bits(self) -> u32
+  {
+    match self {
+      FatType::Fat12 => 12,
+      FatType::Fat16 => 16,
+      FatType::Fat32 => 32,
+    }
+  }
+}
+
+impl Display for FatType
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    let name = match self {
+      FatType::Fat12 => "FAT12",
+      FatType::Fat16 => "FAT16",
+      FatType::Fat32 => "FAT32",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// The layout of a volume.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bpb
+{
+  /// The width of the FAT entries.
+  pub fat_type: FatType,
+  /// The size of a sector in bytes.
+  pub bytes_per_sector: u32,
+  /// The size of a cluster in sectors.
+  pub sectors_per_cluster: u32,
+  /// The sectors before the first FAT, counting the boot sector.
+  pub reserved_sectors: u32,
+  /// The number of copies of the FAT.
+  pub fats: u32,
+  /// The number of entries in the root directory, on FAT12 and FAT16.
+  pub root_entries: u32,
+  /// The size of the volume in sectors.
+  pub total_sectors: u32,
+  /// The size of each FAT in sectors.
+  pub fat_sectors: u32,
+  /// The first cluster of the root directory, on FAT32.
+  pub root_cluster: u32,
+  /// The sector of the FSInfo block, on FAT32; 0 if there is none.
+  pub fs_info: u32,
+}
+
+fn u16_at(b: &[u8], at: usize) -> u32
+{
+  u16::from_le_bytes([b[at], b[at + 1]]) as u32
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32
+{
+  u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+impl Bpb
+{
+  /// Reads the layout from a boot sector, or returns `Invalid` if it does
+  /// not describe a FAT volume. The data area must start within the volume,
+  /// so the sectors before it can be counted in 32 bits.
+  pub fn parse(boot: &[u8]) -> FsResult<Self>
+  {
+    if boot.len() < BOOT_SECTOR_SIZE || boot[510..512] != [0x55, 0xaa] {
+      return Err(FsError::Invalid);
+    }
+
+    let bytes_per_sector = u16_at(boot, 11);
+    let sectors_per_cluster = boot[13] as u32;
+    let reserved_sectors = u16_at(boot, 14);
+    let fats = boot[16] as u32;
+    let root_entries = u16_at(boot, 17);
+    let total_sectors = match u16_at(boot, 19) {
+      0 => u32_at(boot, 32),
+      n => n,
+    };
+    let fat_sectors = match u16_at(boot, 22) {
+      0 => u32_at(boot, 36),
+      n => n,
+    };
+
+    let valid = matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors != 0
+        && fats != 0
+        && fat_sectors != 0;
+    if !valid {
+      return Err(FsError::Invalid);
+    }
+    let root_sectors = (root_entries as u64 * 32).div_ceil(bytes_per_sector as u64);
+    let data_sector = reserved_sectors as u64 + fats as u64 * fat_sectors as u64 + root_sectors;
+    if data_sector >= total_sectors as u64 {
+      return Err(FsError::Invalid);
+    }
+
+    let mut bpb = Self {
+      fat_type: FatType::Fat12,
+      bytes_per_sector,
+      sectors_per_cluster,
+      reserved_sectors,
+      fats,
+      root_entries,
+      total_sectors,
+      fat_sectors,
+      root_cluster: 0,
+      fs_info: 0,
+    };
+
+    bpb.fat_type = FatType::for_clusters(bpb.clusters());
+    let fat_bits = fat_sectors as u64 * bytes_per_sector as u64 * 8;
+    if (bpb.clusters() as u64 + 2) * bpb.fat_type.bits() as u64 > fat_bits {
+      return Err(FsError::Invalid);
+    }
+    if bpb.fat_type == FatType::Fat32 {
+      bpb.root_cluster = u32_at(boot, 44);
+      bpb.fs_info = u16_at(boot, 48);
+      if root_entries != 0 || bpb.root_cluster < 2 || bpb.root_cluster - 2 >= bpb.clusters() {
+        return Err(FsError::Invalid);
+      }
+    } else if root_entries == 0 {
+      return Err(FsError::Invalid);
+    }
+    Ok(bpb)
+  }
+
+  /// The size of a cluster in bytes.
+  #[inline]
+  pub fn cluster_size(&self) -> u32
+  {
+    self.bytes_per_sector * self.sectors_per_cluster
+  }
+
+  /// The byte offset of FAT `n`.
+  #[inline]
+  pub fn fat_offset(&self, n: u32) -> u64
+  {
+    let sector = self.reserved_sectors as u64 + n as u64 * self.fat_sectors as u64;
+    sector * self.bytes_per_sector as u64
+  }
+
+  /// The first sector of the FAT12 or FAT16 root directory.
+  #[inline]
+  pub fn root_dir_sector(&self) -> u32
+  {
+    self.reserved_sectors + self.fats * self.fat_sectors
+  }
+
+  /// The byte offset of the FAT12 or FAT16 root directory.
+  #[inline]
+  pub fn root_dir_offset(&self) -> u64
+  {
+    self.root_dir_sector() as u64 * self.bytes_per_sector as u64
+  }
+
+  /// The size of the FAT12 or FAT16 root directory in bytes.
+  #[inline]
+  pub fn root_dir_size(&self) -> u32
+  {
+    self.root_entries * 32
+  }
+
+  /// The first sector of cluster 2.
+  pub fn first_data_sector(&self) -> u32
+  {
+    let root_sectors = self.root_dir_size().div_ceil(self.bytes_per_sector);
+    self.root_dir_sector() + root_sectors
+  }
+
+  /// The number of clusters, which are numbered from 2.
+  pub fn clusters(&self) -> u32
+  {
+    (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster
+  }
+
+  /// The byte offset of `cluster`.
+  pub fn cluster_offset(&self, cluster: u32) -> u64
+  {
+    let sector = self.first_data_sector() as u64
+        + (cluster as u64 - 2) * self.sectors_per_cluster as u64;
+    sector * self.bytes_per_sector as u64
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  /// A FAT16 boot sector like `mkfs.vfat -F 16` writes for 32 MiB.
+  fn fat16() -> [u8; 512]
+  {
+    let mut boot = [0u8; 512];
+    boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot[13] = 4;
+    boot[14..16].copy_from_slice(&4u16.to_le_bytes());
+    boot[16] = 2;
+    boot[17..19].copy_from_slice(&512u16.to_le_bytes());
+    boot[19..21].copy_from_slice(&0u16.to_le_bytes());
+    boot[32..36].copy_from_slice(&65536u32.to_le_bytes());
+    boot[22..24].copy_from_slice(&64u16.to_le_bytes());
+    boot[510] = 0x55;
+    boot[511] = 0xaa;
+    boot
+  }
+
+  #[test]
+  fn layout()
+  {
+    let bpb = Bpb::parse(&fat16()).unwrap();
+    assert_eq!(bpb.fat_type, FatType::Fat16);
+    assert_eq!(bpb.cluster_size(), 2048);
+    assert_eq!(bpb.fat_offset(1), (4 + 64) * 512);
+    assert_eq!(bpb.root_dir_sector(), 4 + 2 * 64);
+    assert_eq!(bpb.first_data_sector(), 132 + 32);
+    assert_eq!(bpb.clusters(), (65536 - 164) / 4);
+    assert_eq!(bpb.cluster_offset(2), 164 * 512);
+  }
+
+  /// A FAT32 boot sector for 512 MiB in clusters of one sector.
+  fn fat32(root_cluster: u32) -> [u8; 512]
+  {
+    let mut boot = fat16();
+    boot[13] = 1;
+    boot[17..19].copy_from_slice(&0u16.to_le_bytes());
+    boot[22..24].copy_from_slice(&0u16.to_le_bytes());
+    boot[32..36].copy_from_slice(&(1u32 << 20).to_le_bytes());
+    boot[36..40].copy_from_slice(&8192u32.to_le_bytes());
+    boot[44..48].copy_from_slice(&root_cluster.to_le_bytes());
+    boot
+  }
+
+  #[test]
+  fn fat32_root()
+  {
+    let bpb = Bpb::parse(&fat32(2)).unwrap();
+    assert_eq!(bpb.fat_type, FatType::Fat32);
+    assert_eq!(bpb.clusters(), (1 << 20) - 4 - 2 * 8192);
+    let last = bpb.clusters() + 1;
+    assert_eq!(Bpb::parse(&fat32(last)).unwrap().root_cluster, last);
+
+    for root in &[0, 1, last + 1, u32::MAX] {
+      assert_eq!(Bpb::parse(&fat32(*root)), Err(FsError::Invalid));
+    }
+  }
+
+  #[test]
+  fn invalid()
+  {
+    let mut boot = fat16();
+    boot[511] = 0;
+    assert_eq!(Bpb::parse(&boot), Err(FsError::Invalid));
+
+    let mut boot = fat16();
+    boot[13] = 3;
+    assert_eq!(Bpb::parse(&boot), Err(FsError::Invalid));
+
+    // FAT32 has no fixed root directory.
+    let mut boot = fat16();
+    boot[32..36].copy_from_slice(&(1u32 << 20).to_le_bytes());
+    boot[13] = 1;
+    assert_eq!(Bpb::parse(&boot), Err(FsError::Invalid));
+
+    // FATs which would end past 32 bits of sectors.
+    let mut boot = fat32(2);
+    boot[16] = 255;
+    boot[36..40].copy_from_slice(&(u32::MAX / 16).to_le_bytes());
+    assert_eq!(Bpb::parse(&boot), Err(FsError::Invalid));
+    boot[14..16].copy_from_slice(&u16::MAX.to_le_bytes());
+    boot[16] = 1;
+    boot[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    boot[36..40].copy_from_slice(&(u32::MAX - 100).to_le_bytes());
+    assert_eq!(Bpb::parse(&boot), Err(FsError::Invalid));
+  }
+}
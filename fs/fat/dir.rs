@@ -0,0 +1,522 @@
+//! Directory entries.
+//!
+//! A directory is an array of 32-byte entries. Each file has a short entry
+//! holding its 8.3 name, attributes, first cluster and size; a name which
+//! does not fit 8.3 is kept in long-name entries just before it, 13 UTF-16
+//! units each, last part first, tied to the short entry by a checksum of
+//! its name.
+
+use alloc::{Array, String, StringWide};
+
+use crate::vfs::{FsError, FsResult, NAME_MAX};
+
+/// The size of an entry.
+pub const ENTRY_SIZE: usize = 32;
+
+/// The first byte of a deleted entry.
+pub const DELETED: u8 = 0xe5;
+
+/// The first byte of the entry after the last one in use.
+pub const END: u8 = 0x00;
+
+/// The UTF-16 units one long-name entry holds.
+pub const LFN_UNITS: usize = 13;
+
+/// The most long-name entries one name can take.
+pub const MAX_LFN_ENTRIES: usize = 20;
+
+/// The bit marking the long-name entry holding the end of the name, which
+/// comes first.
+pub const LAST_LFN: u8 = 0x40;
+
+/// Attribute bits.
+pub mod attr
+{
+  /// The file cannot be written.
+  pub const READ_ONLY: u8 = 0x01;
+  /// The file is not listed.
+  pub const HIDDEN: u8 = 0x02;
+  /// The file belongs to the system.
+  pub const SYSTEM: u8 = 0x04;
+  /// The entry is the volume label.
+  pub const VOLUME_ID: u8 = 0x08;
+  /// The entry is a directory.
+  pub const DIRECTORY: u8 = 0x10;
+  /// The file has changed since it was backed up.
+  pub const ARCHIVE: u8 = 0x20;
+  /// The entry holds part of a long name.
+  pub const LONG_NAME: u8 = READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID;
+}
+
+/// Bits of the case byte, for 8.3 names shown in lower case.
+pub mod case
+{
+  /// The name is in lower case.
+  pub const LOWER_BASE: u8 = 0x08;
+  /// The extension is in lower case.
+  pub const LOWER_EXT: u8 = 0x10;
+}
+
+/// The offsets of the UTF-16 units in a long-name entry.
+const LFN_OFFSETS: [usize; LFN_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// 1980-01-01, the earliest date an entry can hold.
+const EPOCH_DATE: u16 = 0x21;
+
+/// The characters, other than letters and digits, allowed in 8.3 names.
+const SHORT_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// The characters not allowed in any name.
+const FORBIDDEN: &str = "\"*/:<>?\\|";
+
+/// A short entry, kept as its bytes so that fields it does not know
+/// survive being written back.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ShortEntry([u8; ENTRY_SIZE]);
+
+fn u16_at(b: &[u8], at: usize) -> u16
+{
+  u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+impl ShortEntry
+{
+  /// An empty file or directory named `name`, dated 1980-01-01.
+  pub fn new(name: [u8; 11], case: u8, attr: u8) -> Self
+  {
+    let mut entry = Self([0; ENTRY_SIZE]);
+    entry.set_name(name, case);
+    entry.0[11] = attr;
+    for at in [16, 18, 24] {
+      entry.0[at..at + 2].copy_from_slice(&EPOCH_DATE.to_le_bytes());
+    }
+    entry
+  }
+
+  /// The entry in the first `ENTRY_SIZE` bytes of `bytes`.
+  pub fn from_bytes(bytes: &[u8]) -> Self
+  {
+    let mut entry = Self([0; ENTRY_SIZE]);
+    entry.0.copy_from_slice(&bytes[..ENTRY_SIZE]);
+    entry
+  }
+
+  /// The entry as it is on the disk.
+  #[inline]
+  pub fn as_bytes(&self) -> &[u8; ENTRY_SIZE]
+  {
+    &self.0
+  }
+
+  /// The 8.3 name, space-padded, without the dot.
+  pub fn name(&self) -> [u8; 11]
+  {
+    let mut name = [0; 11];
+    name.copy_from_slice(&self.0[..11]);
+    name
+  }
+
+  /// Renames the entry.
+  pub fn set_name(&mut self, name: [u8; 11], case: u8)
+  {
+    self.0[..11].copy_from_slice(&name);
+    self.0[12] = case;
+  }
+
+  /// The attribute bits.
+  #[inline]
+  pub fn attr(&self) -> u8
+  {
+    self.0[11]
+  }
+
+  /// Sets the attribute bits.
+  #[inline]
+  pub fn set_attr(&mut self, attr: u8)
+  {
+    self.0[11] = attr;
+  }
+
+  /// Returns true if the entry is a directory.
+  #[inline]
+  pub fn is_dir(&self) -> bool
+  {
+    self.attr() & attr::DIRECTORY != 0
+  }
+
+  /// The first cluster, or 0 if there is none.
+  pub fn cluster(&self) -> u32
+  {
+    (u16_at(&self.0, 20) as u32) << 16 | u16_at(&self.0, 26) as u32
+  }
+
+  /// Sets the first cluster.
+  pub fn set_cluster(&mut self, cluster: u32)
+  {
+    self.0[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    self.0[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+  }
+
+  /// The size in bytes; 0 for a directory.
+  pub fn size(&self) -> u32
+  {
+    u32::from_le_bytes([self.0[28], self.0[29], self.0[30], self.0[31]])
+  }
+
+  /// Sets the size.
+  pub fn set_size(&mut self, size: u32)
+  {
+    self.0[28..32].copy_from_slice(&size.to_le_bytes());
+  }
+
+  /// The time of the last write, in seconds since the epoch.
+  pub fn mtime(&self) -> u64
+  {
+    unix_time(u16_at(&self.0, 24), u16_at(&self.0, 22))
+  }
+
+  /// The name as it is shown, with the dot put back and the case applied.
+  pub fn display_name(&self) -> String
+  {
+    let name = self.name();
+    let case = self.0[12];
+    let (base, ext) = (trim(&name[..8]), trim(&name[8..]));
+
+    let mut s = String::new();
+    for (i, &b) in base.iter().enumerate() {
+      // 0xe5 starts a deleted entry, so a name starting with it has 0x05.
+      let b = if i == 0 && b == 0x05 { DELETED } else { b };
+      s.push(short_char(b, case & case::LOWER_BASE != 0));
+    }
+    if !ext.is_empty() {
+      s.push('.');
+      for &b in ext {
+        s.push(short_char(b, case & case::LOWER_EXT != 0));
+      }
+    }
+    s
+  }
+}
+
+fn trim(part: &[u8]) -> &[u8]
+{
+  let len = part.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+  &part[..len]
+}
+
+/// A byte of an 8.3 name as a character. The code page of anything past
+/// ASCII is not known, so it is shown as `?`.
+fn short_char(b: u8, lower: bool) -> char
+{
+  match b {
+    b if !b.is_ascii() => '?',
+    b if lower => b.to_ascii_lowercase() as char,
+    b => b as char,
+  }
+}
+
+/// Seconds since the epoch from a FAT date and time, in local time taken
+/// as UTC.
+fn unix_time(date: u16, time: u16) -> u64
+{
+  let year = 1980 + (date >> 9) as i64;
+  let month = ((date >> 5) & 0xf).clamp(1, 12) as i64;
+  let day = (date & 0x1f).max(1) as i64;
+
+  // Days from the epoch of the proleptic Gregorian calendar.
+  let y = if month <= 2 { year - 1 } else { year };
+  let era = y / 400;
+  let yoe = y - era * 400;
+  let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = era * 146097 + doe - 719468;
+
+  let (hours, minutes, seconds) = (time >> 11, (time >> 5) & 0x3f, (time & 0x1f) * 2);
+  let secs = hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64;
+  (days * 86400 + secs) as u64
+}
+
+/// The checksum of a short name, which its long-name entries hold.
+pub fn checksum(name: &[u8; 11]) -> u8
+{
+  name.iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// Checks that `name` can be given to a file.
+pub fn check_name(name: &str) -> FsResult<()>
+{
+  if name.encode_utf16().count() > NAME_MAX {
+    return Err(FsError::NameTooLong);
+  }
+  let bad = |c: char| c < ' ' || FORBIDDEN.contains(c);
+  if name.is_empty() || name.contains(bad) || name.ends_with(['.', ' ']) {
+    return Err(FsError::Invalid);
+  }
+  Ok(())
+}
+
+fn is_short_char(b: u8) -> bool
+{
+  b.is_ascii_alphanumeric() || SHORT_SPECIALS.contains(&b)
+}
+
+/// The 8.3 name and case bits of `name`, if it can be kept in a short
+/// entry alone.
+pub fn short_name(name: &str) -> Option<([u8; 11], u8)>
+{
+  let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+  let fits = (1..=8).contains(&base.len()) && ext.len() <= 3;
+  if !fits || !name.bytes().all(|b| b == b'.' || is_short_char(b)) || ext.contains('.') {
+    return None;
+  }
+
+  // Each part can be all lower case, but not mixed.
+  let lower = |part: &str, bit| {
+    let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+    match part.bytes().any(|b| b.is_ascii_lowercase()) {
+      true if upper => None,
+      true => Some(bit),
+      false => Some(0),
+    }
+  };
+  let case = lower(base, case::LOWER_BASE)? | lower(ext, case::LOWER_EXT)?;
+
+  let mut short = [b' '; 11];
+  for (to, b) in short.iter_mut().zip(base.bytes()) {
+    *to = b.to_ascii_uppercase();
+  }
+  for (to, b) in short[8..].iter_mut().zip(ext.bytes()) {
+    *to = b.to_ascii_uppercase();
+  }
+  // 0xe5 can only come from a code page, which this never writes.
+  Some((short, case))
+}
+
+/// The 8.3 name a long name is given before a `~N` tail is added: its
+/// letters in upper case, with anything not allowed made `_`.
+pub fn basis_name(name: &str) -> [u8; 11]
+{
+  let name = name.trim_start_matches(['.', ' ']);
+  let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+
+  let mut short = [b' '; 11];
+  basis_part(base, &mut short[..8]);
+  basis_part(ext, &mut short[8..]);
+  if short[0] == b' ' {
+    short[0] = b'_';
+  }
+  short
+}
+
+/// Fills `to` with as much of `part` as fits, made fit for an 8.3 name.
+fn basis_part(part: &str, to: &mut [u8])
+{
+  let bytes = part.chars().filter(|&c| c != ' ' && c != '.').map(|c| match c {
+    c if c.is_ascii() && is_short_char(c as u8) => c.to_ascii_uppercase() as u8,
+    _ => b'_',
+  });
+  for (to, b) in to.iter_mut().zip(bytes) {
+    *to = b;
+  }
+}
+
+/// `basis` with the tail `~n`, cutting the name short to make room.
+pub fn with_tail(basis: &[u8; 11], n: u32) -> [u8; 11]
+{
+  let mut digits = [0u8; 10];
+  let mut len = 0;
+  let mut n = n;
+  loop {
+    digits[len] = b'0' + (n % 10) as u8;
+    len += 1;
+    n /= 10;
+    if n == 0 {
+      break;
+    }
+  }
+
+  let base_len = trim(&basis[..8]).len().min(8 - len - 1);
+  let mut short = *basis;
+  short[base_len] = b'~';
+  for i in 0..len {
+    short[base_len + 1 + i] = digits[len - 1 - i];
+  }
+  short[base_len + 1 + len..8].fill(b' ');
+  short
+}
+
+/// Returns true if `a` and `b` name the same file, which they do whatever
+/// their case.
+pub fn same_name(a: &str, b: &str) -> bool
+{
+  a.chars().flat_map(char::to_uppercase).eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// The long-name entries holding `name`, in the order they go on the disk,
+/// for the short entry whose name has `checksum`.
+pub fn long_entries(name: &str, checksum: u8) -> Array<[u8; ENTRY_SIZE]>
+{
+  let units = StringWide::from(name);
+  let count = units.len().div_ceil(LFN_UNITS);
+
+  let mut entries = Array::new();
+  for ord in (1..=count).rev() {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[0] = ord as u8 | if ord == count { LAST_LFN } else { 0 };
+    entry[11] = attr::LONG_NAME;
+    entry[13] = checksum;
+
+    // The name ends with a 0 unless it fills the entry, then 0xffff.
+    for (k, &at) in LFN_OFFSETS.iter().enumerate() {
+      let i = (ord - 1) * LFN_UNITS + k;
+      let unit = match units.get(i) {
+        Some(&unit) => unit,
+        None if i == units.len() => 0,
+        None => 0xffff,
+      };
+      entry[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    entries.push(entry);
+  }
+  entries
+}
+
+/// Puts a long name back together from its entries, as they are read.
+pub struct LongName
+{
+  units: [u16; MAX_LFN_ENTRIES * LFN_UNITS],
+  /// How many entries the name takes, or 0 if none is being read.
+  count: usize,
+  /// The number of the entry expected next; 0 once they are all read.
+  next: usize,
+  checksum: u8,
+}
+
+impl Default for LongName
+{
+  fn default() -> Self
+  {
+    Self { units: [0; MAX_LFN_ENTRIES * LFN_UNITS], count: 0, next: 0, checksum: 0 }
+  }
+}
+
+impl LongName
+{
+  /// Forgets any entries read so far.
+  #[inline]
+  pub fn reset(&mut self)
+  {
+    self.count = 0;
+  }
+
+  /// How many entries the name being read takes, or 0 if none is.
+  #[inline]
+  pub fn entries(&self) -> usize
+  {
+    self.count
+  }
+
+  /// Adds the long-name entry `entry`. One out of order, or for another
+  /// short entry, throws away what was read.
+  pub fn push(&mut self, entry: &[u8])
+  {
+    let ord = (entry[0] & 0x1f) as usize;
+    if entry[0] & LAST_LFN != 0 {
+      if ord == 0 || ord > MAX_LFN_ENTRIES {
+        self.reset();
+        return;
+      }
+      self.count = ord;
+      self.checksum = entry[13];
+    } else if self.count == 0 || ord == 0 || ord != self.next || entry[13] != self.checksum {
+      self.reset();
+      return;
+    }
+
+    for (k, &at) in LFN_OFFSETS.iter().enumerate() {
+      self.units[(ord - 1) * LFN_UNITS + k] = u16_at(entry, at);
+    }
+    self.next = ord - 1;
+  }
+
+  /// The long name of the short entry `short`, if the entries read just
+  /// before it hold one.
+  pub fn take(&mut self, short: &ShortEntry) -> Option<String>
+  {
+    let complete = self.count != 0 && self.next == 0 && self.checksum == checksum(&short.name());
+    let count = self.count;
+    self.reset();
+    if !complete {
+      return None;
+    }
+
+    let units = &self.units[..count * LFN_UNITS];
+    let len = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+    let mut name = StringWide::new();
+    name.push_units(&units[..len]);
+    Some(name.to_string_lossy())
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn short_names()
+  {
+    assert_eq!(short_name("README.TXT"), Some((*b"README  TXT", 0)));
+    assert_eq!(short_name("readme.txt"), Some((*b"README  TXT", 0x18)));
+    assert_eq!(short_name("Makefile"), None);
+    assert_eq!(short_name("a.tar.gz"), None);
+    assert_eq!(short_name("toolongname"), None);
+    assert_eq!(short_name(".profile"), None);
+
+    let entry = ShortEntry::new(*b"README  TXT", case::LOWER_EXT, 0);
+    assert_eq!(entry.display_name(), "README.txt");
+    assert_eq!(entry.mtime(), 315532800);
+
+    assert_eq!(&basis_name("A long file name.text"), b"ALONGFILTEX");
+    assert_eq!(&basis_name(".profile"), b"PROFILE    ");
+    assert_eq!(&basis_name("é+x"), b"__X        ");
+    assert_eq!(&with_tail(b"ALONGFILTEX", 1), b"ALONGF~1TEX");
+    assert_eq!(&with_tail(b"AB      C  ", 123), b"AB~123  C  ");
+    assert!(same_name("Straße.TXT", "STRASSE.txt"));
+  }
+
+  #[test]
+  fn long_names()
+  {
+    let short = ShortEntry::new(*b"ALONGF~1TEX", 0, 0);
+    let sum = checksum(&short.name());
+    let name = "A long file name.text";
+    let entries = long_entries(name, sum);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0][0], LAST_LFN | 2);
+    assert_eq!(entries[1][0], 1);
+
+    let mut long = LongName::default();
+    for entry in entries.iter() {
+      long.push(entry);
+    }
+    assert_eq!(long.take(&short).unwrap(), name);
+
+    // Out of order, or for another entry, the entries are ignored.
+    long.push(&entries[1]);
+    assert_eq!(long.take(&short), None);
+    long.push(&entries[0]);
+    long.push(&entries[1]);
+    assert_eq!(long.take(&ShortEntry::new(*b"ALONGF~2TEX", 0, 0)), None);
+
+    // A name filling its entries has no terminator.
+    let name = "thirteen char";
+    let entries = long_entries(name, sum);
+    assert_eq!(entries.len(), 1);
+    long.push(&entries[0]);
+    assert_eq!(long.take(&short).unwrap(), name);
+
+    assert_eq!(check_name("a:b"), Err(FsError::Invalid));
+    assert_eq!(check_name("dot."), Err(FsError::Invalid));
+  }
+}
@@ -0,0 +1,310 @@
+//! The file allocation table.
+//!
+//! Each cluster has an entry in the FAT: 0 if it is free, or the next
+//! cluster of the file it belongs to, or a marker for the last. FAT12 packs
+//! two entries into three bytes; FAT32 entries are 28 bits, and the top
+//! four are left as they are found. Every copy of the FAT is written alike.
+
+use alloc::Array;
+
+use super::bpb::{Bpb, FatType};
+use crate::block::{read_bytes, write_bytes, BlockDevice};
+use crate::vfs::{FsError, FsResult};
+
+// FSInfo signatures, and where its fields are.
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_TRAIL: u32 = 0xaa55_0000;
+const FSINFO_FREE: usize = 488;
+const FSINFO_NEXT: usize = 492;
+
+fn u32_at(b: &[u8], at: usize) -> u32
+{
+  u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+/// What a FAT entry says of its cluster.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Entry
+{
+  /// The cluster is free.
+  Free,
+  /// The file goes on in this cluster.
+  Next(u32),
+  /// The file ends in the cluster.
+  End,
+  /// The cluster is bad, or the entry makes no sense.
+  Bad,
+}
+
+/// The file allocation table of a volume.
+pub struct Table
+{
+  bpb: Bpb,
+  /// Where the search for a free cluster starts.
+  next_free: u32,
+  /// The number of free clusters, once they have been counted.
+  free: Option<u32>,
+}
+
+impl Table
+{
+  /// The table of the volume `bpb` describes, on `device`.
+  pub fn new(device: &dyn BlockDevice, bpb: &Bpb) -> FsResult<Self>
+  {
+    let mut table = Self { bpb: *bpb, next_free: 2, free: None };
+    // Only the hint is taken from FSInfo; the count is only trusted once
+    // it has been made.
+    if let Some(info) = table.read_fs_info(device)? {
+      let next = u32_at(&info, FSINFO_NEXT);
+      if table.is_cluster(next) {
+        table.next_free = next;
+      }
+    }
+    Ok(table)
+  }
+
+  /// One past the number of the last cluster.
+  #[inline]
+  fn end(&self) -> u32
+  {
+    self.bpb.clusters() + 2
+  }
+
+  /// Returns true if `cluster` is a cluster of the volume.
+  #[inline]
+  pub fn is_cluster(&self, cluster: u32) -> bool
+  {
+    (2..self.end()).contains(&cluster)
+  }
+
+  #[inline]
+  fn bits(&self) -> u32
+  {
+    self.bpb.fat_type.bits()
+  }
+
+  /// Where the entry of `cluster` starts in each FAT.
+  fn offset(&self, cluster: u32) -> u64
+  {
+    cluster as u64 * self.bits() as u64 / 8
+  }
+
+  fn decode(&self, raw: u32) -> Entry
+  {
+    let (bad, end) = match self.bpb.fat_type {
+      FatType::Fat12 => (0xff7, 0xff8),
+      FatType::Fat16 => (0xfff7, 0xfff8),
+      FatType::Fat32 => (0x0fff_fff7, 0x0fff_fff8),
+    };
+    match raw {
+      0 => Entry::Free,
+      raw if raw >= end => Entry::End,
+      raw if raw != bad && self.is_cluster(raw) => Entry::Next(raw),
+      _ => Entry::Bad,
+    }
+  }
+
+  fn encode(&self, entry: Entry) -> u32
+  {
+    let mask = match self.bpb.fat_type {
+      FatType::Fat12 => 0xfff,
+      FatType::Fat16 => 0xffff,
+      FatType::Fat32 => 0x0fff_ffff,
+    };
+    match entry {
+      Entry::Free => 0,
+      Entry::Next(cluster) => cluster,
+      Entry::End => mask,
+      Entry::Bad => mask - 8,
+    }
+  }
+
+  /// The raw entry of `cluster`, in `bytes` from where it starts.
+  fn unpack(&self, cluster: u32, bytes: &[u8]) -> u32
+  {
+    match self.bpb.fat_type {
+      FatType::Fat12 => {
+        let pair = u16::from_le_bytes([bytes[0], bytes[1]]) as u32;
+        if cluster.is_multiple_of(2) { pair & 0xfff } else { pair >> 4 }
+      }
+      FatType::Fat16 => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+      FatType::Fat32 => u32_at(bytes, 0) & 0x0fff_ffff,
+    }
+  }
+
+  /// The entry of `cluster`.
+  pub fn get(&self, device: &dyn BlockDevice, cluster: u32) -> FsResult<Entry>
+  {
+    if !self.is_cluster(cluster) {
+      return Err(FsError::Io);
+    }
+    let mut buf = [0; 4];
+    let len = self.bits().div_ceil(8) as usize;
+    read_bytes(device, self.bpb.fat_offset(0) + self.offset(cluster), &mut buf[..len])?;
+    Ok(self.decode(self.unpack(cluster, &buf)))
+  }
+
+  /// Sets the entry of `cluster` in every FAT.
+  pub fn set(&mut self, device: &dyn BlockDevice, cluster: u32, entry: Entry) -> FsResult<()>
+  {
+    if !self.is_cluster(cluster) {
+      return Err(FsError::Io);
+    }
+    let value = self.encode(entry);
+    let at = self.offset(cluster);
+    let mut buf = [0; 4];
+    let len = self.bits().div_ceil(8) as usize;
+    read_bytes(device, self.bpb.fat_offset(0) + at, &mut buf[..len])?;
+
+    match self.bpb.fat_type {
+      FatType::Fat12 => {
+        // The entry shares a byte with its neighbour.
+        let pair = u16::from_le_bytes([buf[0], buf[1]]);
+        let pair = if cluster.is_multiple_of(2) {
+          (pair & 0xf000) | value as u16
+        } else {
+          (pair & 0x000f) | (value as u16) << 4
+        };
+        buf[..2].copy_from_slice(&pair.to_le_bytes());
+      }
+      FatType::Fat16 => buf[..2].copy_from_slice(&(value as u16).to_le_bytes()),
+      FatType::Fat32 => buf = ((u32::from_le_bytes(buf) & 0xf000_0000) | value).to_le_bytes(),
+    }
+    for n in 0..self.bpb.fats {
+      write_bytes(device, self.bpb.fat_offset(n) + at, &buf[..len])?;
+    }
+    Ok(())
+  }
+
+  /// Calls `f` with the raw entry of each cluster from `start` on, a run
+  /// of the FAT at a time, until it returns true; returns that cluster.
+  fn scan(&self, device: &dyn BlockDevice, start: u32, mut f: impl FnMut(u32) -> bool)
+      -> FsResult<Option<u32>>
+  {
+    // Three sectors hold a whole number of entries of any width.
+    let run_bytes = 3 * self.bpb.bytes_per_sector as usize;
+    let per_run = (run_bytes * 8 / self.bits() as usize) as u32;
+    let mut buf = Array::new();
+    buf.resize(run_bytes, 0);
+
+    let mut first = start - start % per_run;
+    while first < self.end() {
+      let count = per_run.min(self.end() - first);
+      let len = (self.offset(first + count - 1) - self.offset(first)) as usize + 4;
+      let len = len.min(run_bytes);
+      read_bytes(device, self.bpb.fat_offset(0) + self.offset(first), &mut buf[..len])?;
+
+      for i in first.max(start)..first + count {
+        let at = (self.offset(i) - self.offset(first)) as usize;
+        if f(self.unpack(i, &buf[at..])) {
+          return Ok(Some(i));
+        }
+      }
+      first += per_run;
+    }
+    Ok(None)
+  }
+
+  /// Takes a free cluster, marks it as the end of a file, and links it
+  /// after `prev` if there is one.
+  pub fn alloc(&mut self, device: &dyn BlockDevice, prev: Option<u32>) -> FsResult<u32>
+  {
+    if self.free == Some(0) {
+      return Err(FsError::NoSpace);
+    }
+    let cluster = match self.scan(device, self.next_free, |raw| raw == 0)? {
+      Some(cluster) => cluster,
+      None => self.scan(device, 2, |raw| raw == 0)?.ok_or(FsError::NoSpace)?,
+    };
+
+    self.set(device, cluster, Entry::End)?;
+    if let Some(prev) = prev {
+      self.set(device, prev, Entry::Next(cluster))?;
+    }
+    self.next_free = if cluster + 1 < self.end() { cluster + 1 } else { 2 };
+    if let Some(free) = &mut self.free {
+      *free -= 1;
+    }
+    Ok(cluster)
+  }
+
+  /// The clusters of the file starting at `first`, in order; none if
+  /// `first` is 0.
+  pub fn chain(&self, device: &dyn BlockDevice, first: u32) -> FsResult<Array<u32>>
+  {
+    let mut chain = Array::new();
+    let mut cluster = first;
+    while cluster != 0 {
+      // A chain longer than the volume loops.
+      if chain.len() >= self.bpb.clusters() as usize {
+        return Err(FsError::Io);
+      }
+      chain.push(cluster);
+      cluster = match self.get(device, cluster)? {
+        Entry::Next(next) => next,
+        Entry::End => 0,
+        Entry::Free | Entry::Bad => return Err(FsError::Io),
+      };
+    }
+    Ok(chain)
+  }
+
+  /// Frees the clusters of `chain`.
+  pub fn free_chain(&mut self, device: &dyn BlockDevice, chain: &[u32]) -> FsResult<()>
+  {
+    for &cluster in chain {
+      self.set(device, cluster, Entry::Free)?;
+      if let Some(free) = &mut self.free {
+        *free += 1;
+      }
+    }
+    if let Some(&first) = chain.first() {
+      self.next_free = self.next_free.min(first);
+    }
+    Ok(())
+  }
+
+  /// The number of free clusters, counting them the first time.
+  pub fn free_count(&mut self, device: &dyn BlockDevice) -> FsResult<u32>
+  {
+    if let Some(free) = self.free {
+      return Ok(free);
+    }
+    let mut free = 0;
+    self.scan(device, 2, |raw| {
+      free += (raw == 0) as u32;
+      false
+    })?;
+    self.free = Some(free);
+    Ok(free)
+  }
+
+  /// The FSInfo sector, if the volume has a valid one.
+  fn read_fs_info(&self, device: &dyn BlockDevice) -> FsResult<Option<[u8; 512]>>
+  {
+    if self.bpb.fat_type != FatType::Fat32 || self.bpb.fs_info == 0 {
+      return Ok(None);
+    }
+    let mut info = [0; 512];
+    read_bytes(device, self.bpb.fs_info as u64 * self.bpb.bytes_per_sector as u64, &mut info)?;
+    let valid = u32_at(&info, 0) == FSINFO_LEAD
+        && u32_at(&info, 484) == FSINFO_STRUCT
+        && u32_at(&info, 508) == FSINFO_TRAIL;
+    Ok(valid.then_some(info))
+  }
+
+  /// Records the free count and the next free cluster in FSInfo, where
+  /// other systems look for them.
+  pub fn write_fs_info(&self, device: &dyn BlockDevice) -> FsResult<()>
+  {
+    if let Some(mut info) = self.read_fs_info(device)? {
+      let free = self.free.unwrap_or(u32::MAX);
+      info[FSINFO_FREE..FSINFO_FREE + 4].copy_from_slice(&free.to_le_bytes());
+      info[FSINFO_NEXT..FSINFO_NEXT + 4].copy_from_slice(&self.next_free.to_le_bytes());
+      let at = self.bpb.fs_info as u64 * self.bpb.bytes_per_sector as u64;
+      write_bytes(device, at, &info)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,310 @@
+//! Initial RAM file system archives.
+//!
+//! The archive is read in place, in either of the formats Linux tooling
+//! produces for it: `newc` cpio (`find | cpio -o -H newc`) or ustar
+//! (`tar --format=ustar`). Its files are read only; directories are only
+//! implied by the paths of what is in them, unless listed themselves.
+
+use core::fmt::{self, Display};
+
+use crate::vfs::path::components;
+
+pub mod cpio;
+pub mod tar;
+
+/// The ways an archive can be unreadable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArchiveError
+{
+  /// The data is neither a newc cpio nor a ustar archive.
+  UnknownFormat,
+  /// The archive ends in the middle of the entry at this offset.
+  Truncated(usize),
+  /// The header at this offset is malformed.
+  BadHeader(usize),
+  /// The header at this offset fails its checksum.
+  BadChecksum(usize),
+}
+
+impl Display for ArchiveError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      ArchiveError::UnknownFormat => write!(f, "unknown archive format"),
+      ArchiveError::Truncated(off) => write!(f, "archive truncated at entry {:#x}", off),
+      ArchiveError::BadHeader(off) => write!(f, "bad archive header at {:#x}", off),
+      ArchiveError::BadChecksum(off) => write!(f, "bad archive checksum at {:#x}", off),
+    }
+  }
+}
+
+/// The formats an archive can be in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format
+{
+  /// `newc` cpio, with or without checksums.
+  Cpio,
+  /// POSIX ustar, or GNU tar in its ustar compatible form.
+  Tar,
+}
+
+/// What an entry is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind
+{
+  /// A regular file; its data is its contents.
+  File,
+  /// A directory.
+  Directory,
+  /// A symbolic link; its data is the path it points at.
+  Symlink,
+  /// Anything else: devices, fifos, hard links.
+  Other,
+}
+
+/// `st_mode` file type bits, which cpio stores in its mode.
+pub mod mode
+{
+  /// The file type bits.
+  pub const S_IFMT: u32 = 0o170_000;
+  /// A directory.
+  pub const S_IFDIR: u32 = 0o040_000;
+  /// A regular file.
+  pub const S_IFREG: u32 = 0o100_000;
+  /// A symbolic link.
+  pub const S_IFLNK: u32 = 0o120_000;
+}
+
+/// The path of an entry, relative to the root of the archive.
+///
+/// Archivers write paths as `./a/b`, `a/b` or `/a/b`, and tar may split
+/// them in two; paths are compared by their components so that all of
+/// these are the same.
+#[derive(Copy, Clone, Debug)]
+pub struct EntryPath<'a>
+{
+  prefix: &'a str,
+  name: &'a str,
+}
+
+impl<'a> EntryPath<'a>
+{
+  pub(crate) fn new(prefix: &'a str, name: &'a str) -> Self
+  {
+    Self { prefix, name }
+  }
+
+  /// The names making up the path, without empty or `.` components.
+  pub fn components(&self) -> impl Iterator<Item = &'a str> + Clone
+  {
+    components(self.prefix).chain(components(self.name))
+  }
+
+  /// The last component, or `None` for the root.
+  pub fn file_name(&self) -> Option<&'a str>
+  {
+    self.components().last()
+  }
+
+  /// Returns true if this is the path `path`.
+  pub fn matches(&self, path: &str) -> bool
+  {
+    self.components().eq(components(path))
+  }
+
+  /// Returns true if this is the path of something directly in `dir`.
+  pub fn is_child_of(&self, dir: &str) -> bool
+  {
+    let mut ours = self.components();
+    let inside = components(dir).all(|c| ours.next() == Some(c));
+    inside && ours.next().is_some() && ours.next().is_none()
+  }
+}
+
+impl Display for EntryPath<'_>
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    for (i, component) in self.components().enumerate() {
+      if i > 0 {
+        write!(f, "/")?;
+      }
+      write!(f, "{}", component)?;
+    }
+    Ok(())
+  }
+}
+
+/// A file, directory or link in an archive.
+#[derive(Copy, Clone, Debug)]
+pub struct Entry<'a>
+{
+  /// Where it is in the archive.
+  pub path: EntryPath<'a>,
+  /// What it is.
+  pub kind: Kind,
+  /// Its permission bits.
+  pub mode: u32,
+  /// Its owner.
+  pub uid: u32,
+  /// Its group.
+  pub gid: u32,
+  /// Its modification time, in seconds since the epoch.
+  pub mtime: u64,
+  /// Its contents, or for a symlink its target.
+  pub data: &'a [u8],
+}
+
+/// An archive read in place.
+#[derive(Copy, Clone, Debug)]
+pub struct Archive<'a>
+{
+  data: &'a [u8],
+  format: Format,
+}
+
+impl<'a> Archive<'a>
+{
+  /// Recognises the archive `data` holds by the magic of its first entry.
+  pub fn new(data: &'a [u8]) -> Result<Self, ArchiveError>
+  {
+    let format = if cpio::is_cpio(data) {
+      Format::Cpio
+    } else if tar::is_tar(data) {
+      Format::Tar
+    } else {
+      return Err(ArchiveError::UnknownFormat);
+    };
+
+    Ok(Self { data, format })
+  }
+
+  /// The format of the archive.
+  #[inline]
+  pub fn format(&self) -> Format
+  {
+    self.format
+  }
+
+  /// The whole archive.
+  #[inline]
+  pub fn as_bytes(&self) -> &'a [u8]
+  {
+    self.data
+  }
+
+  /// Every entry, in the order they were archived. An unreadable entry
+  /// is returned as an error and ends the iteration.
+  pub fn entries(&self) -> Entries<'a>
+  {
+    Entries { data: self.data, format: self.format, offset: 0, done: false }
+  }
+
+  /// Checks every entry can be read.
+  pub fn validate(&self) -> Result<(), ArchiveError>
+  {
+    self.entries().try_for_each(|entry| entry.map(|_| ()))
+  }
+
+  /// The entry at `path`, if it is in the readable part of the archive.
+  /// When a path was archived twice, the last one wins, as it would when
+  /// unpacking.
+  pub fn find(&self, path: &str) -> Option<Entry<'a>>
+  {
+    self.entries().map_while(Result::ok).filter(|entry| entry.path.matches(path)).last()
+  }
+
+  /// The entries directly in the directory `path`.
+  pub fn read_dir<'p>(&self, path: &'p str) -> impl Iterator<Item = Entry<'a>> + 'p
+  where
+    'a: 'p,
+  {
+    self.entries().map_while(Result::ok).filter(move |entry| entry.path.is_child_of(path))
+  }
+}
+
+/// Iterates over the entries of an archive.
+#[derive(Clone)]
+pub struct Entries<'a>
+{
+  data: &'a [u8],
+  format: Format,
+  offset: usize,
+  done: bool,
+}
+
+impl<'a> Iterator for Entries<'a>
+{
+  type Item = Result<Entry<'a>, ArchiveError>;
+
+  fn next(&mut self) -> Option<Self::Item>
+  {
+    if self.done {
+      return None;
+    }
+
+    let next = match self.format {
+      Format::Cpio => cpio::read_entry(self.data, self.offset),
+      Format::Tar => tar::read_entry(self.data, self.offset),
+    };
+    match next {
+      Ok(Some((entry, next))) => {
+        self.offset = next;
+        Some(Ok(entry))
+      }
+      Ok(None) => {
+        self.done = true;
+        None
+      }
+      Err(e) => {
+        self.done = true;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+/// `n` rounded up to a multiple of `align`, a power of two.
+fn align_up(n: usize, align: usize) -> usize
+{
+  (n + align - 1) & !(align - 1)
+}
+
+/// The `len` bytes at `off` in `data`, or `Truncated(entry)`.
+fn bytes(data: &[u8], off: usize, len: usize, entry: usize) -> Result<&[u8], ArchiveError>
+{
+  off.checked_add(len)
+      .and_then(|end| data.get(off..end))
+      .ok_or(ArchiveError::Truncated(entry))
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn paths()
+  {
+    let path = EntryPath::new("", "./bin/sh");
+    assert!(path.matches("/bin/sh"));
+    assert!(path.matches("bin//sh/"));
+    assert!(!path.matches("bin"));
+    assert!(path.is_child_of("/bin"));
+    assert!(!path.is_child_of("/"));
+    assert_eq!(path.file_name(), Some("sh"));
+
+    let split = EntryPath::new("usr/share", "doc/");
+    assert!(split.matches("/usr/share/doc"));
+    assert!(EntryPath::new("", "etc").is_child_of("."));
+    assert_eq!(format!("{}", split), "usr/share/doc");
+  }
+
+  #[test]
+  fn unknown()
+  {
+    assert_eq!(Archive::new(&[0; 1024]).err(), Some(ArchiveError::UnknownFormat));
+    assert_eq!(Archive::new(b"07070").err(), Some(ArchiveError::UnknownFormat));
+  }
+}
@@ -0,0 +1,181 @@
+//! `newc` cpio archives.
+//!
+//! Each entry is a 110 byte header of ASCII hex fields, the NUL terminated
+//! path, and the data, with the path and the data each padded to four
+//! bytes. The archive ends with an entry named `TRAILER!!!`.
+
+use core::str;
+
+use super::mode::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use super::{align_up, bytes, ArchiveError, Entry, EntryPath, Kind};
+
+/// The size of an entry's header.
+pub const HEADER_SIZE: usize = 110;
+
+/// The magic of an entry without a checksum.
+pub const MAGIC: &[u8; 6] = b"070701";
+/// The magic of an entry whose `check` field sums its data.
+pub const MAGIC_CRC: &[u8; 6] = b"070702";
+
+/// The path of the entry which ends the archive.
+pub const TRAILER: &str = "TRAILER!!!";
+
+// Header fields, in order after the magic.
+const MODE: usize = 1;
+const UID: usize = 2;
+const GID: usize = 3;
+const MTIME: usize = 5;
+const FILESIZE: usize = 6;
+const NAMESIZE: usize = 11;
+const CHECK: usize = 12;
+
+/// Returns true if `data` starts with a `newc` header.
+pub fn is_cpio(data: &[u8]) -> bool
+{
+  matches!(data.get(..6), Some(magic) if magic == MAGIC || magic == MAGIC_CRC)
+}
+
+/// Reads the eight hex digit field `index` of `header`.
+fn field(header: &[u8], index: usize, entry: usize) -> Result<u32, ArchiveError>
+{
+  let digits = &header[6 + index * 8..][..8];
+  str::from_utf8(digits)
+      .ok()
+      .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+      .ok_or(ArchiveError::BadHeader(entry))
+}
+
+/// Reads the entry at `off`, and returns it with the offset of the next;
+/// or `None` at the trailer or the end of the data.
+pub fn read_entry(data: &[u8], off: usize) -> Result<Option<(Entry<'_>, usize)>, ArchiveError>
+{
+  if off >= data.len() {
+    return Ok(None);
+  }
+
+  let header = bytes(data, off, HEADER_SIZE, off)?;
+  if !is_cpio(header) {
+    return Err(ArchiveError::BadHeader(off));
+  }
+
+  let namesize = field(header, NAMESIZE, off)? as usize;
+  let name = bytes(data, off + HEADER_SIZE, namesize, off)?;
+  let name = match name.split_last() {
+    Some((0, name)) => str::from_utf8(name).map_err(|_| ArchiveError::BadHeader(off))?,
+    _ => return Err(ArchiveError::BadHeader(off)),
+  };
+  if name == TRAILER {
+    return Ok(None);
+  }
+
+  let start = align_up(off + HEADER_SIZE + namesize, 4);
+  let size = field(header, FILESIZE, off)? as usize;
+  let contents = bytes(data, start, size, off)?;
+
+  if &header[..6] == MAGIC_CRC {
+    let sum = contents.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+    if sum != field(header, CHECK, off)? {
+      return Err(ArchiveError::BadChecksum(off));
+    }
+  }
+
+  let mode = field(header, MODE, off)?;
+  let kind = match mode & S_IFMT {
+    S_IFREG => Kind::File,
+    S_IFDIR => Kind::Directory,
+    S_IFLNK => Kind::Symlink,
+    _ => Kind::Other,
+  };
+  let entry = Entry {
+    path: EntryPath::new("", name),
+    kind,
+    mode: mode & !S_IFMT,
+    uid: field(header, UID, off)?,
+    gid: field(header, GID, off)?,
+    mtime: field(header, MTIME, off)? as u64,
+    data: contents,
+  };
+
+  Ok(Some((entry, align_up(start + size, 4))))
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::super::Archive;
+  use super::*;
+
+  /// Appends an entry as `cpio -H newc` would write it.
+  fn push(archive: &mut Vec<u8>, magic: &[u8; 6], name: &str, mode: u32, data: &[u8])
+  {
+    let check = match magic {
+      MAGIC_CRC => data.iter().map(|&b| b as u32).sum(),
+      _ => 0,
+    };
+    let fields = [1, mode, 1000, 100, 1, 1_600_000_000, data.len() as u32, 0, 0, 0, 0];
+
+    archive.extend_from_slice(magic);
+    for field in fields.iter().chain(&[name.len() as u32 + 1, check]) {
+      archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(align_up(archive.len(), 4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(align_up(archive.len(), 4), 0);
+  }
+
+  fn sample() -> Vec<u8>
+  {
+    let mut archive = Vec::new();
+    push(&mut archive, MAGIC, ".", S_IFDIR | 0o755, b"");
+    push(&mut archive, MAGIC, "etc", S_IFDIR | 0o755, b"");
+    push(&mut archive, MAGIC_CRC, "etc/motd", S_IFREG | 0o644, b"hello\n");
+    push(&mut archive, MAGIC, "init", S_IFREG | 0o755, b"\x7fELF");
+    push(&mut archive, MAGIC, "etc/issue", S_IFLNK | 0o777, b"motd");
+    push(&mut archive, MAGIC, TRAILER, 0, b"");
+    archive.resize(align_up(archive.len(), 512), 0);
+    archive
+  }
+
+  #[test]
+  fn entries()
+  {
+    let data = sample();
+    let archive = Archive::new(&data).unwrap();
+    assert!(archive.validate().is_ok());
+    assert_eq!(archive.entries().count(), 5);
+
+    let motd = archive.find("/etc/motd").unwrap();
+    assert_eq!(motd.kind, Kind::File);
+    assert_eq!(motd.mode, 0o644);
+    assert_eq!(motd.uid, 1000);
+    assert_eq!(motd.data, b"hello\n");
+
+    let issue = archive.find("etc/issue").unwrap();
+    assert_eq!(issue.kind, Kind::Symlink);
+    assert_eq!(issue.data, b"motd");
+
+    assert_eq!(archive.find("init").unwrap().data, b"\x7fELF");
+    assert!(archive.find("etc/passwd").is_none());
+
+    let etc: Vec<_> = archive.read_dir("/etc").filter_map(|e| e.path.file_name()).collect();
+    assert_eq!(etc, ["motd", "issue"]);
+    assert_eq!(archive.read_dir("/").count(), 2);
+  }
+
+  #[test]
+  fn corrupt()
+  {
+    let mut data = sample();
+    let motd = data.windows(8).position(|w| w == b"etc/motd").unwrap();
+    data[motd + 12] ^= 1;
+    let archive = Archive::new(&data).unwrap();
+    assert_eq!(archive.validate().err(), Some(ArchiveError::BadChecksum(motd - HEADER_SIZE)));
+    assert!(archive.find("init").is_none());
+
+    let data = sample();
+    let archive = Archive::new(&data[..200]).unwrap();
+    assert_eq!(archive.validate().err(), Some(ArchiveError::Truncated(112)));
+  }
+}
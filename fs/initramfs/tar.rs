@@ -0,0 +1,226 @@
+//! ustar archives.
+//!
+//! Each entry is a 512 byte header of NUL terminated strings and octal
+//! numbers, followed by its data padded to 512 bytes. The archive ends
+//! with a block of zeroes. pax extended headers and GNU long name records
+//! are skipped, so paths must fit in ustar's own 255 bytes.
+
+use core::str;
+
+use super::{align_up, bytes, ArchiveError, Entry, EntryPath, Kind};
+
+/// The size of a header, and of the blocks data is padded to.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The magic of a POSIX ustar header, with its version.
+pub const MAGIC: &[u8; 8] = b"ustar\x0000";
+/// The magic of a GNU tar header, with its version.
+pub const MAGIC_GNU: &[u8; 8] = b"ustar  \0";
+
+// Header fields, as (offset, length).
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const UID: (usize, usize) = (108, 8);
+const GID: (usize, usize) = (116, 8);
+const SIZE: (usize, usize) = (124, 12);
+const MTIME: (usize, usize) = (136, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPEFLAG: usize = 156;
+const LINKNAME: (usize, usize) = (157, 100);
+const MAGIC_FIELD: (usize, usize) = (257, 8);
+const PREFIX: (usize, usize) = (345, 155);
+
+/// Returns true if `data` starts with a ustar or GNU tar header.
+pub fn is_tar(data: &[u8]) -> bool
+{
+  data.len() >= BLOCK_SIZE && &data[MAGIC_FIELD.0..][..5] == b"ustar"
+}
+
+/// The field `(off, len)` of `header`, up to its first NUL.
+fn string(header: &[u8], (off, len): (usize, usize), entry: usize) -> Result<&str, ArchiveError>
+{
+  let field = &header[off..off + len];
+  let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+  str::from_utf8(&field[..end]).map_err(|_| ArchiveError::BadHeader(entry))
+}
+
+/// The octal number in field `(off, len)` of `header`, which may be padded
+/// with spaces and ended by a space or NUL.
+fn octal(header: &[u8], (off, len): (usize, usize), entry: usize) -> Result<u64, ArchiveError>
+{
+  let digits = header[off..off + len]
+      .iter()
+      .skip_while(|&&b| b == b' ')
+      .take_while(|&&b| b != b' ' && b != 0);
+
+  let mut n = 0u64;
+  for &digit in digits {
+    if !(b'0'..=b'7').contains(&digit) || n >> 61 != 0 {
+      return Err(ArchiveError::BadHeader(entry));
+    }
+    n = n << 3 | (digit - b'0') as u64;
+  }
+
+  Ok(n)
+}
+
+/// The sum of the bytes of `header`, with the checksum field as spaces.
+fn checksum(header: &[u8]) -> u64
+{
+  let (off, len) = CHECKSUM;
+  let spaces = b' ' as u64 * len as u64;
+  header[..off].iter().chain(&header[off + len..]).map(|&b| b as u64).sum::<u64>() + spaces
+}
+
+/// Reads the entry at `off`, and returns it with the offset of the next;
+/// or `None` at the end of the archive.
+pub fn read_entry(data: &[u8], mut off: usize) -> Result<Option<(Entry<'_>, usize)>, ArchiveError>
+{
+  loop {
+    if off >= data.len() {
+      return Ok(None);
+    }
+
+    let header = bytes(data, off, BLOCK_SIZE, off)?;
+    if header.iter().all(|&b| b == 0) {
+      return Ok(None);
+    }
+    if !is_tar(header) {
+      return Err(ArchiveError::BadHeader(off));
+    }
+    if octal(header, CHECKSUM, off)? != checksum(header) {
+      return Err(ArchiveError::BadChecksum(off));
+    }
+
+    let typeflag = header[TYPEFLAG];
+    // Links, devices and directories have no data, whatever size they give.
+    let size = match typeflag {
+      b'1'..=b'6' => 0,
+      _ => octal(header, SIZE, off)? as usize,
+    };
+    let start = off + BLOCK_SIZE;
+    let contents = bytes(data, start, size, off)?;
+    let next = start + align_up(size, BLOCK_SIZE);
+
+    let kind = match typeflag {
+      b'0' | b'\0' | b'7' => Kind::File,
+      b'5' => Kind::Directory,
+      b'2' => Kind::Symlink,
+      // pax headers and GNU long names describe the next entry.
+      b'x' | b'g' | b'L' | b'K' => {
+        off = next;
+        continue;
+      }
+      _ => Kind::Other,
+    };
+
+    // GNU tar keeps other fields where ustar has the prefix.
+    let prefix = if &header[MAGIC_FIELD.0..][..MAGIC_FIELD.1] == MAGIC {
+      string(header, PREFIX, off)?
+    } else {
+      ""
+    };
+    let data = match kind {
+      Kind::Symlink => string(header, LINKNAME, off)?.as_bytes(),
+      _ => contents,
+    };
+    let entry = Entry {
+      path: EntryPath::new(prefix, string(header, NAME, off)?),
+      kind,
+      mode: octal(header, MODE, off)? as u32 & 0o7777,
+      uid: octal(header, UID, off)? as u32,
+      gid: octal(header, GID, off)? as u32,
+      mtime: octal(header, MTIME, off)?,
+      data,
+    };
+
+    return Ok(Some((entry, next)));
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::super::Archive;
+  use super::*;
+
+  fn put(header: &mut [u8], (off, len): (usize, usize), value: &[u8])
+  {
+    assert!(value.len() <= len);
+    header[off..off + value.len()].copy_from_slice(value);
+  }
+
+  /// Appends an entry as `tar --format=ustar` would write it.
+  fn push(archive: &mut Vec<u8>, prefix: &str, name: &str, typeflag: u8, data: &[u8])
+  {
+    let mut header = [0; BLOCK_SIZE];
+    put(&mut header, NAME, name.as_bytes());
+    put(&mut header, MODE, b"0000644\0");
+    put(&mut header, UID, b"0001750\0");
+    put(&mut header, GID, b"0000144\0");
+    put(&mut header, SIZE, format!("{:011o}\0", data.len()).as_bytes());
+    put(&mut header, MTIME, b"13727410000\0");
+    header[TYPEFLAG] = typeflag;
+    put(&mut header, MAGIC_FIELD, MAGIC);
+    put(&mut header, PREFIX, prefix.as_bytes());
+    if typeflag == b'2' {
+      put(&mut header, LINKNAME, data);
+    }
+    let sum = format!("{:06o}\0 ", checksum(&header));
+    put(&mut header, CHECKSUM, sum.as_bytes());
+
+    archive.extend_from_slice(&header);
+    if typeflag != b'2' {
+      archive.extend_from_slice(data);
+      archive.resize(align_up(archive.len(), BLOCK_SIZE), 0);
+    }
+  }
+
+  fn sample() -> Vec<u8>
+  {
+    let mut archive = Vec::new();
+    push(&mut archive, "", "./bin/", b'5', b"");
+    push(&mut archive, "", "./bin/init", b'0', &[0x7f; 700]);
+    push(&mut archive, "", "PaxHeaders/sh", b'x', b"30 path=bin/a-very-long-name\n");
+    push(&mut archive, "./usr/share", "doc/readme", b'0', b"read me\n");
+    push(&mut archive, "", "./bin/sh", b'2', b"init");
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    archive
+  }
+
+  #[test]
+  fn entries()
+  {
+    let data = sample();
+    let archive = Archive::new(&data).unwrap();
+    assert!(archive.validate().is_ok());
+    assert_eq!(archive.entries().count(), 4);
+
+    let init = archive.find("/bin/init").unwrap();
+    assert_eq!(init.kind, Kind::File);
+    assert_eq!(init.data.len(), 700);
+    assert_eq!((init.mode, init.uid, init.gid), (0o644, 1000, 100));
+    assert_eq!(init.mtime, 1_600_000_000);
+
+    let readme = archive.find("usr/share/doc/readme").unwrap();
+    assert_eq!(readme.data, b"read me\n");
+    assert_eq!(archive.find("bin/sh").unwrap().data, b"init");
+    assert_eq!(archive.find("/bin").unwrap().kind, Kind::Directory);
+
+    let bin: Vec<_> = archive.read_dir("bin").filter_map(|e| e.path.file_name()).collect();
+    assert_eq!(bin, ["init", "sh"]);
+  }
+
+  #[test]
+  fn corrupt()
+  {
+    let mut data = sample();
+    data[BLOCK_SIZE + 1] ^= 1;
+    let archive = Archive::new(&data).unwrap();
+    assert_eq!(archive.validate().err(), Some(ArchiveError::BadChecksum(BLOCK_SIZE)));
+
+    let data = sample();
+    let archive = Archive::new(&data[..BLOCK_SIZE + 600]).unwrap();
+    assert_eq!(archive.validate().err(), Some(ArchiveError::Truncated(BLOCK_SIZE)));
+  }
+}
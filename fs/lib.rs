@@ -0,0 +1,32 @@
+//! File systems for the Trident kernel.
+//!
+//! Everything here works on plain byte slices and traits, so it can be
+//! tested on the host; the kernel supplies the memory and the devices.
+#![deny(clippy::all)]
+#![warn(missing_docs)]
+#![cfg_attr(not(test), no_std)]
+
+extern crate t_alloc as alloc;
+
+/////////////////////////////////
+/////////// Modules /////////////
+/////////////////////////////////
+
+pub mod block;
+pub mod cache;
+pub mod ext2;
+pub mod fat;
+pub mod initramfs;
+pub mod tmpfs;
+pub mod vfs;
+
+// END "modules" ////////////////
+//-------------------------------
+
+pub use self::block::{BlockDevice, BlockError, BlockResult, RamDisk};
+pub use self::cache::{BufferCache, CachedDevice};
+pub use self::ext2::Ext2;
+pub use self::fat::Fat;
+pub use self::initramfs::{Archive, ArchiveError};
+pub use self::tmpfs::Tmpfs;
+pub use self::vfs::{File, FileSystem, FsError, FsResult, Vfs};
@@ -0,0 +1,818 @@
+//! A file system in memory.
+//!
+//! Files keep their contents in whole pages from a page allocator, by
+//! default the kernel's frame allocator, taken as they are first written.
+//! Holes left by truncating or writing past the end read as zeroes and
+//! take no pages. The size limit counts pages of contents; a file system
+//! can hold as many inodes as it can pages. Everything is freed when the
+//! file system is dropped, and a file's contents when its name is removed.
+
+use core::ptr::NonNull;
+use core::slice;
+
+use alloc::alloc::page::{PageAlloc, PAGE_SIZE};
+use alloc::alloc::{AllocRef, Layout};
+use alloc::collections::HashMap;
+use alloc::spin::Mutex;
+use alloc::{Array, String};
+
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, FsResult, FsStat, Ino, Stat};
+
+/// The number of the root directory.
+pub const ROOT: Ino = 1;
+
+/// A page of a file's contents, zeroed when it is taken.
+struct Page<A: AllocRef>
+{
+  ptr: NonNull<u8>,
+  alloc: A,
+}
+
+unsafe impl<A: AllocRef + Send> Send for Page<A> {}
+
+impl<A: AllocRef + Clone> Page<A>
+{
+  fn new(alloc: &A) -> Option<Self>
+  {
+    let ptr = unsafe { alloc.zalloc(1)? };
+    Some(Self { ptr, alloc: alloc.clone() })
+  }
+
+  fn bytes(&self) -> &[u8]
+  {
+    unsafe { slice::from_raw_parts(self.ptr.as_ptr(), PAGE_SIZE) }
+  }
+
+  fn bytes_mut(&mut self) -> &mut [u8]
+  {
+    unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), PAGE_SIZE) }
+  }
+}
+
+impl<A: AllocRef> Drop for Page<A>
+{
+  fn drop(&mut self)
+  {
+    unsafe { self.alloc.dealloc(self.ptr.as_ptr(), Layout::from_size(1)) }
+  }
+}
+
+/// A directory's entries. Each keeps its slot until it is removed, and a
+/// listing walks the slots, so entries which stay are listed once however
+/// the directory changes in between.
+struct Dir
+{
+  /// The slot of each entry, by name.
+  names: HashMap<String, usize>,
+  /// The entries' names and inodes; `None` for a free slot.
+  slots: Array<Option<(String, Ino)>>,
+  /// Free slots, taken before new ones.
+  free: Array<usize>,
+}
+
+impl Dir
+{
+  fn new() -> Self
+  {
+    Self { names: HashMap::new(), slots: Array::new(), free: Array::new() }
+  }
+
+  fn len(&self) -> usize
+  {
+    self.names.len()
+  }
+
+  fn contains(&self, name: &str) -> bool
+  {
+    self.names.contains(name)
+  }
+
+  fn find(&self, name: &str) -> Option<Ino>
+  {
+    let &slot = self.names.find(name)?;
+    self.slots[slot].as_ref().map(|&(_, ino)| ino)
+  }
+
+  /// Names `ino` as `name`, in place of what `name` was.
+  fn insert(&mut self, name: &str, ino: Ino)
+  {
+    if let Some(&slot) = self.names.find(name) {
+      self.slots[slot] = Some((String::from(name), ino));
+      return;
+    }
+
+    let slot = match self.free.pop() {
+      Some(slot) => slot,
+      None => {
+        self.slots.push(None);
+        self.slots.len() - 1
+      }
+    };
+    self.slots[slot] = Some((String::from(name), ino));
+    self.names.insert(String::from(name), slot);
+  }
+
+  fn remove(&mut self, name: &str)
+  {
+    if let Some(&slot) = self.names.find(name) {
+      self.names.remove(name);
+      self.slots[slot] = None;
+      self.free.push(slot);
+    }
+  }
+
+  fn inos(&self) -> impl Iterator<Item = Ino> + '_
+  {
+    self.slots.iter().filter_map(|slot| slot.as_ref().map(|&(_, ino)| ino))
+  }
+
+  /// The first entry at or after the slot `pos`, and its slot.
+  fn next(&self, pos: usize) -> Option<(usize, &str, Ino)>
+  {
+    let mut slots = self.slots.get(pos..)?.iter().enumerate();
+    slots.find_map(|(i, slot)| slot.as_ref().map(|(name, ino)| (pos + i, name.as_str(), *ino)))
+  }
+}
+
+/// What an inode holds.
+enum Data<A: AllocRef>
+{
+  /// A file's size, and its pages; `None` for a hole.
+  File(u64, Array<Option<Page<A>>>),
+  /// A directory's entries.
+  Directory(Dir),
+  /// A link's target.
+  Symlink(String),
+}
+
+struct Node<A: AllocRef>
+{
+  mode: u32,
+  data: Data<A>,
+}
+
+impl<A: AllocRef> Node<A>
+{
+  fn kind(&self) -> FileType
+  {
+    match self.data {
+      Data::File(..) => FileType::File,
+      Data::Directory(_) => FileType::Directory,
+      Data::Symlink(_) => FileType::Symlink,
+    }
+  }
+
+  /// How many pages the contents take.
+  fn pages(&self) -> usize
+  {
+    match &self.data {
+      Data::File(_, pages) => pages.iter().filter(|page| page.is_some()).count(),
+      _ => 0,
+    }
+  }
+
+  fn file_mut(&mut self) -> FsResult<(&mut u64, &mut Array<Option<Page<A>>>)>
+  {
+    match &mut self.data {
+      Data::File(size, pages) => Ok((size, pages)),
+      Data::Directory(_) => Err(FsError::IsDirectory),
+      Data::Symlink(_) => Err(FsError::Invalid),
+    }
+  }
+}
+
+struct Inner<A: AllocRef>
+{
+  nodes: HashMap<Ino, Node<A>>,
+  next_ino: Ino,
+  /// Pages of contents in use.
+  used: usize,
+}
+
+impl<A: AllocRef> Inner<A>
+{
+  fn node(&self, ino: Ino) -> FsResult<&Node<A>>
+  {
+    self.nodes.find(&ino).ok_or(FsError::NotFound)
+  }
+
+  fn node_mut(&mut self, ino: Ino) -> FsResult<&mut Node<A>>
+  {
+    self.nodes.find_mut(&ino).ok_or(FsError::NotFound)
+  }
+
+  fn dir(&self, ino: Ino) -> FsResult<&Dir>
+  {
+    match &self.node(ino)?.data {
+      Data::Directory(entries) => Ok(entries),
+      _ => Err(FsError::NotDirectory),
+    }
+  }
+
+  fn dir_mut(&mut self, ino: Ino) -> FsResult<&mut Dir>
+  {
+    match &mut self.node_mut(ino)?.data {
+      Data::Directory(entries) => Ok(entries),
+      _ => Err(FsError::NotDirectory),
+    }
+  }
+
+  /// The entry `name` in `dir`.
+  fn entry(&self, dir: Ino, name: &str) -> FsResult<Ino>
+  {
+    self.dir(dir)?.find(name).ok_or(FsError::NotFound)
+  }
+
+  /// Adds `node` to the directory `dir` as `name`.
+  fn link(&mut self, dir: Ino, name: &str, node: Node<A>) -> FsResult<Ino>
+  {
+    let ino = self.next_ino;
+    self.dir_mut(dir)?.insert(name, ino);
+    self.nodes.insert(ino, node);
+    self.next_ino += 1;
+    Ok(ino)
+  }
+
+  /// Frees `ino` and its contents, once no directory names it.
+  fn free(&mut self, ino: Ino)
+  {
+    if let Some(node) = self.nodes.find(&ino) {
+      self.used -= node.pages();
+      self.nodes.remove(&ino);
+    }
+  }
+}
+
+/// A file system in memory, with its contents in pages from `A`.
+pub struct Tmpfs<A: AllocRef + Clone = PageAlloc>
+{
+  alloc: A,
+  /// The most pages of contents, and inodes, it can hold.
+  limit: usize,
+  inner: Mutex<Inner<A>>,
+}
+
+impl Tmpfs<PageAlloc>
+{
+  /// An empty file system holding up to `limit` bytes, rounded down to
+  /// whole pages, in frames from the kernel.
+  pub fn new(limit: usize) -> Self
+  {
+    Self::new_with(limit, PageAlloc)
+  }
+}
+
+impl<A: AllocRef + Clone> Tmpfs<A>
+{
+  /// An empty file system holding up to `limit` bytes, rounded down to
+  /// whole pages, in pages from `alloc`.
+  pub fn new_with(limit: usize, alloc: A) -> Self
+  {
+    let root = Node { mode: 0o1777, data: Data::Directory(Dir::new()) };
+    let mut nodes = HashMap::new();
+    nodes.insert(ROOT, root);
+
+    let inner = Inner { nodes, next_ino: ROOT + 1, used: 0 };
+    Self { alloc, limit: limit / PAGE_SIZE, inner: Mutex::new(inner) }
+  }
+
+  /// The most it can hold, in bytes.
+  #[inline]
+  pub fn limit(&self) -> usize
+  {
+    self.limit * PAGE_SIZE
+  }
+
+  /// How much it holds, in bytes of the pages it has taken.
+  pub fn usage(&self) -> usize
+  {
+    self.inner.lock().used * PAGE_SIZE
+  }
+
+  /// Creates `name` in `dir`, if there is room for another inode.
+  fn add(&self, dir: Ino, name: &str, node: Node<A>) -> FsResult<Ino>
+  {
+    let mut inner = self.inner.lock();
+    if inner.dir(dir)?.contains(name) {
+      return Err(FsError::Exists);
+    }
+    if inner.nodes.len() >= self.limit {
+      return Err(FsError::NoSpace);
+    }
+    inner.link(dir, name, node)
+  }
+}
+
+impl<A: AllocRef + Clone + Send + Sync> FileSystem for Tmpfs<A>
+{
+  fn name(&self) -> &'static str
+  {
+    "tmpfs"
+  }
+
+  fn root(&self) -> Ino
+  {
+    ROOT
+  }
+
+  fn stat(&self, ino: Ino) -> FsResult<Stat>
+  {
+    let inner = self.inner.lock();
+    let node = inner.node(ino)?;
+    let (size, nlink) = match &node.data {
+      Data::File(size, _) => (*size, 1),
+      Data::Symlink(target) => (target.len() as u64, 1),
+      Data::Directory(entries) => {
+        let subdirs = entries
+            .inos()
+            .filter(|&ino| matches!(inner.node(ino), Ok(n) if n.kind() == FileType::Directory))
+            .count();
+        (entries.len() as u64, 2 + subdirs as u32)
+      }
+    };
+
+    let kind = node.kind();
+    Ok(Stat { ino, kind, mode: node.mode, uid: 0, gid: 0, size, nlink, mtime: 0 })
+  }
+
+  fn lookup(&self, dir: Ino, name: &str) -> FsResult<Ino>
+  {
+    self.inner.lock().entry(dir, name)
+  }
+
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> FsResult<usize>
+  {
+    let mut inner = self.inner.lock();
+    let (&mut size, pages) = inner.node_mut(ino)?.file_mut()?;
+    if offset >= size {
+      return Ok(0);
+    }
+
+    let len = buf.len().min((size - offset) as usize);
+    let mut done = 0;
+    while done < len {
+      let pos = offset as usize + done;
+      let start = pos % PAGE_SIZE;
+      let n = (PAGE_SIZE - start).min(len - done);
+      let out = &mut buf[done..done + n];
+      match pages.get(pos / PAGE_SIZE) {
+        Some(Some(page)) => out.copy_from_slice(&page.bytes()[start..start + n]),
+        _ => out.fill(0),
+      }
+      done += n;
+    }
+
+    Ok(len)
+  }
+
+  fn readdir(&self, dir: Ino, pos: usize) -> FsResult<Option<(DirEntry, usize)>>
+  {
+    let inner = self.inner.lock();
+    let entry = match inner.dir(dir)?.next(pos) {
+      Some((slot, name, ino)) => {
+        let kind = inner.node(ino)?.kind();
+        Some((DirEntry { name: String::from(name), ino, kind }, slot + 1))
+      }
+      None => None,
+    };
+    Ok(entry)
+  }
+
+  fn readlink(&self, ino: Ino) -> FsResult<String>
+  {
+    match &self.inner.lock().node(ino)?.data {
+      Data::Symlink(target) => Ok(String::from(target.as_str())),
+      _ => Err(FsError::Invalid),
+    }
+  }
+
+  fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> FsResult<usize>
+  {
+    let mut inner = self.inner.lock();
+    let Inner { nodes, used, .. } = &mut *inner;
+    let (size, pages) = nodes.find_mut(&ino).ok_or(FsError::NotFound)?.file_mut()?;
+
+    // Nothing is written past the limit, even as holes.
+    let end = (offset as usize).saturating_add(buf.len()).min(self.limit());
+    let mut done = 0;
+    while (offset as usize) + done < end {
+      let pos = offset as usize + done;
+      let (index, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+      let n = (PAGE_SIZE - start).min(end - pos);
+
+      if pages.len() <= index {
+        pages.resize_with(index + 1, || None);
+      }
+      if pages[index].is_none() {
+        if *used >= self.limit {
+          break;
+        }
+        match Page::new(&self.alloc) {
+          Some(page) => pages[index] = Some(page),
+          None => break,
+        }
+        *used += 1;
+      }
+      if let Some(page) = &mut pages[index] {
+        page.bytes_mut()[start..start + n].copy_from_slice(&buf[done..done + n]);
+      }
+      done += n;
+    }
+
+    if done == 0 && !buf.is_empty() {
+      return Err(FsError::NoSpace);
+    }
+    *size = (*size).max(offset + done as u64);
+    Ok(done)
+  }
+
+  fn truncate(&self, ino: Ino, new_size: u64) -> FsResult<()>
+  {
+    if new_size > self.limit() as u64 {
+      return Err(FsError::NoSpace);
+    }
+
+    let mut inner = self.inner.lock();
+    let Inner { nodes, used, .. } = &mut *inner;
+    let (size, pages) = nodes.find_mut(&ino).ok_or(FsError::NotFound)?.file_mut()?;
+
+    let keep = (new_size as usize).div_ceil(PAGE_SIZE);
+    while pages.len() > keep {
+      if let Some(Some(_)) = pages.pop() {
+        *used -= 1;
+      }
+    }
+    // What was past the end must read as zeroes if the file grows again.
+    let tail = new_size as usize % PAGE_SIZE;
+    if new_size < *size && tail != 0 {
+      if let Some(Some(page)) = pages.get_mut(keep - 1) {
+        page.bytes_mut()[tail..].fill(0);
+      }
+    }
+
+    *size = new_size;
+    Ok(())
+  }
+
+  fn create(&self, dir: Ino, name: &str, kind: FileType, mode: u32) -> FsResult<Ino>
+  {
+    let data = match kind {
+      FileType::File => Data::File(0, Array::new()),
+      FileType::Directory => Data::Directory(Dir::new()),
+      _ => return Err(FsError::Invalid),
+    };
+    self.add(dir, name, Node { mode, data })
+  }
+
+  fn symlink(&self, dir: Ino, name: &str, target: &str) -> FsResult<Ino>
+  {
+    self.add(dir, name, Node { mode: 0o777, data: Data::Symlink(String::from(target)) })
+  }
+
+  fn unlink(&self, dir: Ino, name: &str) -> FsResult<()>
+  {
+    let mut inner = self.inner.lock();
+    let ino = inner.entry(dir, name)?;
+    if inner.node(ino)?.kind() == FileType::Directory {
+      return Err(FsError::IsDirectory);
+    }
+
+    inner.dir_mut(dir)?.remove(name);
+    inner.free(ino);
+    Ok(())
+  }
+
+  fn rmdir(&self, dir: Ino, name: &str) -> FsResult<()>
+  {
+    let mut inner = self.inner.lock();
+    let ino = inner.entry(dir, name)?;
+    if inner.dir(ino)?.len() > 0 {
+      return Err(FsError::NotEmpty);
+    }
+
+    inner.dir_mut(dir)?.remove(name);
+    inner.free(ino);
+    Ok(())
+  }
+
+  fn rename(&self, from_dir: Ino, from: &str, to_dir: Ino, to: &str) -> FsResult<()>
+  {
+    let mut inner = self.inner.lock();
+    let ino = inner.entry(from_dir, from)?;
+    let moving_dir = inner.node(ino)?.kind() == FileType::Directory;
+
+    match inner.entry(to_dir, to) {
+      Ok(old) if old == ino => return Ok(()),
+      Ok(old) => {
+        match (moving_dir, &inner.node(old)?.data) {
+          (true, Data::Directory(entries)) if entries.len() > 0 => return Err(FsError::NotEmpty),
+          (true, Data::Directory(_)) | (false, Data::File(..)) | (false, Data::Symlink(_)) => {}
+          (true, _) => return Err(FsError::NotDirectory),
+          (false, Data::Directory(_)) => return Err(FsError::IsDirectory),
+        }
+        inner.free(old);
+      }
+      Err(FsError::NotFound) => {}
+      Err(e) => return Err(e),
+    }
+
+    inner.dir_mut(from_dir)?.remove(from);
+    inner.dir_mut(to_dir)?.insert(to, ino);
+    Ok(())
+  }
+
+  fn statfs(&self) -> FsResult<FsStat>
+  {
+    let inner = self.inner.lock();
+    Ok(FsStat {
+      block_size: PAGE_SIZE as u64,
+      blocks: self.limit as u64,
+      free_blocks: (self.limit - inner.used) as u64,
+      inodes: self.limit as u64,
+      free_inodes: self.limit.saturating_sub(inner.nodes.len()) as u64,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::vfs::testing::read_at;
+  use crate::vfs::{OpenFlags, SeekFrom, Vfs};
+  use alloc::Shared;
+
+  /// Pages from the host's heap, in place of the frame allocator.
+  #[derive(Copy, Clone)]
+  struct HostPages;
+
+  impl HostPages
+  {
+    fn layout(pages: usize) -> std::alloc::Layout
+    {
+      std::alloc::Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+  }
+
+  unsafe impl AllocRef for HostPages
+  {
+    unsafe fn alloc(&self, layout: Layout) -> Option<NonNull<u8>>
+    {
+      NonNull::new(std::alloc::alloc(Self::layout(layout.size())))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+      std::alloc::dealloc(ptr, Self::layout(layout.size()))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, layout: Layout)
+        -> Option<NonNull<u8>>
+    {
+      let new_size = Self::layout(layout.size()).size();
+      NonNull::new(std::alloc::realloc(ptr, Self::layout(old_size), new_size))
+    }
+
+    unsafe fn zalloc(&self, pages: usize) -> Option<NonNull<u8>>
+    {
+      NonNull::new(std::alloc::alloc_zeroed(Self::layout(pages)))
+    }
+  }
+
+  /// Host pages, but only as many as are left in the shared count.
+  #[derive(Clone)]
+  struct ScarcePages(std::sync::Arc<core::sync::atomic::AtomicUsize>);
+
+  unsafe impl AllocRef for ScarcePages
+  {
+    unsafe fn alloc(&self, layout: Layout) -> Option<NonNull<u8>>
+    {
+      HostPages.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+      HostPages.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, old_size: usize, layout: Layout)
+        -> Option<NonNull<u8>>
+    {
+      HostPages.realloc(ptr, old_size, layout)
+    }
+
+    unsafe fn zalloc(&self, pages: usize) -> Option<NonNull<u8>>
+    {
+      use core::sync::atomic::Ordering;
+      self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(pages)).ok()?;
+      HostPages.zalloc(pages)
+    }
+  }
+
+  fn tmpfs(pages: usize) -> Shared<Tmpfs<HostPages>>
+  {
+    Shared::new(Tmpfs::new_with(pages * PAGE_SIZE, HostPages))
+  }
+
+  fn vfs(fs: &Shared<Tmpfs<HostPages>>) -> Vfs
+  {
+    Vfs::new(fs.clone())
+  }
+
+  #[test]
+  fn files()
+  {
+    let fs = tmpfs(16);
+    let vfs = vfs(&fs);
+    let create = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+
+    let file = vfs.open("/motd", create, 0o644).unwrap();
+    assert_eq!(file.write(b"hello, "), Ok(7));
+    assert_eq!(file.write(b"world\n"), Ok(6));
+    assert_eq!(read_at(&vfs, "/motd", 0, 64), b"hello, world\n");
+    assert_eq!(read_at(&vfs, "motd", 7, 5), b"world");
+    assert_eq!(vfs.stat("/motd").unwrap().size, 13);
+    assert_eq!(vfs.stat("/motd").unwrap().mode, 0o644);
+    assert_eq!(fs.usage(), PAGE_SIZE);
+
+    // Across a page boundary, then a hole which takes no pages.
+    file.seek(SeekFrom::Start(PAGE_SIZE as u64 - 2)).unwrap();
+    assert_eq!(file.write(b"edge"), Ok(4));
+    assert_eq!(read_at(&vfs, "/motd", PAGE_SIZE as u64 - 3, 8), b"\0edge");
+    file.seek(SeekFrom::Start(5 * PAGE_SIZE as u64)).unwrap();
+    assert_eq!(file.write(b"!"), Ok(1));
+    assert_eq!(fs.usage(), 3 * PAGE_SIZE);
+    assert_eq!(read_at(&vfs, "/motd", 3 * PAGE_SIZE as u64, 4), [0; 4]);
+
+    let append = vfs.open("/motd", OpenFlags::WRITE | OpenFlags::APPEND, 0).unwrap();
+    assert_eq!(append.write(b"?"), Ok(1));
+    assert_eq!(vfs.stat("/motd").unwrap().size, 5 * PAGE_SIZE as u64 + 2);
+
+    // Truncating frees whole pages and zeroes the rest of the last one.
+    vfs.truncate("/motd", 5).unwrap();
+    assert_eq!(fs.usage(), PAGE_SIZE);
+    vfs.truncate("/motd", 13).unwrap();
+    assert_eq!(read_at(&vfs, "/motd", 0, 64), b"hello\0\0\0\0\0\0\0\0");
+    vfs.open("/motd", OpenFlags::WRITE | OpenFlags::TRUNCATE, 0).unwrap();
+    assert_eq!(vfs.stat("/motd").unwrap().size, 0);
+    assert_eq!(fs.usage(), 0);
+
+    let exclusive = create | OpenFlags::EXCLUSIVE;
+    assert_eq!(vfs.open("/motd", exclusive, 0).err(), Some(FsError::Exists));
+    assert_eq!(vfs.open("/none/motd", create, 0).err(), Some(FsError::NotFound));
+  }
+
+  #[test]
+  fn directories()
+  {
+    let fs = tmpfs(16);
+    let vfs = vfs(&fs);
+    vfs.mkdir("/usr", 0o755).unwrap();
+    vfs.mkdir("/usr/bin", 0o755).unwrap();
+    vfs.open("/usr/bin/sh", OpenFlags::CREATE, 0o755).unwrap();
+    vfs.symlink("usr/bin", "/bin").unwrap();
+
+    assert_eq!(vfs.mkdir("/usr", 0o755), Err(FsError::Exists));
+    assert_eq!(vfs.mkdir("/usr/bin/sh/x", 0o755), Err(FsError::NotDirectory));
+    assert_eq!(vfs.stat("/bin/sh").unwrap().kind, FileType::File);
+    assert_eq!(vfs.readlink("/bin").unwrap().as_str(), "usr/bin");
+    assert_eq!(vfs.stat("/usr").unwrap().nlink, 3);
+
+    let usr = vfs.open("/usr", OpenFlags::READ, 0).unwrap();
+    let mut names = std::vec::Vec::new();
+    while let Some(entry) = usr.readdir().unwrap() {
+      names.push((std::string::String::from(entry.name.as_str()), entry.kind));
+    }
+    assert_eq!(names.len(), 3);
+    assert!(names.contains(&("bin".into(), FileType::Directory)));
+
+    assert_eq!(vfs.rmdir("/usr"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.unlink("/usr/bin"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rmdir("/bin"), Err(FsError::NotDirectory));
+    vfs.unlink("/bin/sh").unwrap();
+    vfs.unlink("/bin").unwrap();
+    vfs.rmdir("/usr/bin").unwrap();
+    vfs.rmdir("/usr").unwrap();
+    assert_eq!(vfs.stat("/usr").err(), Some(FsError::NotFound));
+    assert!(vfs.open("/", OpenFlags::READ, 0).unwrap().readdir().unwrap().is_some());
+    assert_eq!(fs.statfs().unwrap().free_inodes, 15);
+  }
+
+  #[test]
+  fn listing()
+  {
+    let fs = tmpfs(16);
+    let vfs = vfs(&fs);
+    for name in &["a", "b", "c", "d", "e", "f"] {
+      vfs.open(&std::format!("/{}", name), OpenFlags::CREATE, 0).unwrap();
+    }
+
+    // Entries which stay are listed once, whatever comes and goes between.
+    let dir = vfs.open("/", OpenFlags::READ, 0).unwrap();
+    let mut names = std::vec::Vec::new();
+    for _ in 0..4 {
+      names.push(std::string::String::from(dir.readdir().unwrap().unwrap().name.as_str()));
+    }
+    vfs.unlink("/a").unwrap();
+    vfs.unlink("/f").unwrap();
+    vfs.open("/g", OpenFlags::CREATE, 0).unwrap();
+    while let Some(entry) = dir.readdir().unwrap() {
+      names.push(std::string::String::from(entry.name.as_str()));
+    }
+    assert_eq!(names, [".", "..", "a", "b", "c", "d", "e", "g"]);
+  }
+
+  #[test]
+  fn rename()
+  {
+    let fs = tmpfs(16);
+    let vfs = vfs(&fs);
+    let create = OpenFlags::WRITE | OpenFlags::CREATE;
+    vfs.open("/a", create, 0).unwrap().write(b"a").unwrap();
+    vfs.open("/b", create, 0).unwrap().write(b"b").unwrap();
+    vfs.mkdir("/d", 0o755).unwrap();
+    vfs.mkdir("/e", 0o755).unwrap();
+    vfs.open("/e/f", create, 0).unwrap();
+
+    // Replacing a file frees it.
+    vfs.rename("/a", "/b").unwrap();
+    assert_eq!(read_at(&vfs, "/b", 0, 4), b"a");
+    assert_eq!(vfs.stat("/a").err(), Some(FsError::NotFound));
+    assert_eq!(fs.usage(), PAGE_SIZE);
+
+    vfs.rename("/b", "/d/c").unwrap();
+    assert_eq!(read_at(&vfs, "/d/c", 0, 4), b"a");
+    vfs.rename("/d/c", "/d/c").unwrap();
+
+    assert_eq!(vfs.rename("/d/c", "/e"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rename("/d", "/e"), Err(FsError::NotEmpty));
+    assert_eq!(vfs.rename("/e", "/d/c"), Err(FsError::NotDirectory));
+    assert_eq!(vfs.rename("/e", "/e/g"), Err(FsError::Invalid));
+    vfs.unlink("/d/c").unwrap();
+    vfs.rename("/e", "/d").unwrap();
+    assert_eq!(vfs.stat("/d/f").unwrap().kind, FileType::File);
+    assert_eq!(vfs.stat("/e").err(), Some(FsError::NotFound));
+  }
+
+  #[test]
+  fn limit()
+  {
+    let fs = tmpfs(4);
+    let vfs = vfs(&fs);
+    assert_eq!(fs.limit(), 4 * PAGE_SIZE);
+
+    let file = vfs.open("/big", OpenFlags::WRITE | OpenFlags::CREATE, 0).unwrap();
+    let data = std::vec![7; 5 * PAGE_SIZE];
+    assert_eq!(file.write(&data), Ok(4 * PAGE_SIZE));
+    assert_eq!(file.write(b"x"), Err(FsError::NoSpace));
+    assert_eq!(fs.usage(), 4 * PAGE_SIZE);
+    assert_eq!(vfs.truncate("/big", 5 * PAGE_SIZE as u64), Err(FsError::NoSpace));
+
+    let stat = fs.statfs().unwrap();
+    assert_eq!((stat.blocks, stat.free_blocks), (4, 0));
+    assert_eq!(vfs.statfs("/").unwrap(), stat);
+
+    // Inodes are limited too.
+    vfs.mkdir("/a", 0).unwrap();
+    vfs.mkdir("/b", 0).unwrap();
+    assert_eq!(vfs.mkdir("/c", 0), Err(FsError::NoSpace));
+
+    vfs.unlink("/big").unwrap();
+    assert_eq!(fs.usage(), 0);
+    assert_eq!(fs.statfs().unwrap().free_blocks, 4);
+  }
+
+  #[test]
+  fn out_of_pages()
+  {
+    let left = std::sync::Arc::new(core::sync::atomic::AtomicUsize::new(2));
+    let fs = Shared::new(Tmpfs::new_with(16 * PAGE_SIZE, ScarcePages(left)));
+    let vfs = Vfs::new(fs.clone());
+
+    // What fit before the allocator ran out is written, and only then
+    // is running out an error.
+    let file = vfs.open("/big", OpenFlags::WRITE | OpenFlags::CREATE, 0).unwrap();
+    assert_eq!(file.write(&std::vec![7; 3 * PAGE_SIZE]), Ok(2 * PAGE_SIZE));
+    assert_eq!(file.write(b"x"), Err(FsError::NoSpace));
+    assert_eq!(file.stat().unwrap().size, 2 * PAGE_SIZE as u64);
+    assert_eq!(read_at(&vfs, "/big", 2 * PAGE_SIZE as u64 - 1, 2), [7]);
+  }
+
+  #[test]
+  fn mounted()
+  {
+    let root = tmpfs(16);
+    let vfs = vfs(&root);
+    vfs.mkdir("/tmp", 0o1777).unwrap();
+    let tmp = tmpfs(2);
+    vfs.mount("/tmp", tmp.clone()).unwrap();
+
+    vfs.open("/tmp/x", OpenFlags::WRITE | OpenFlags::CREATE, 0).unwrap().write(b"x").unwrap();
+    assert_eq!((root.usage(), tmp.usage()), (0, PAGE_SIZE));
+    assert_eq!(vfs.statfs("/tmp/x").unwrap().blocks, 2);
+    assert_eq!(vfs.rename("/tmp/x", "/x"), Err(FsError::CrossDevice));
+
+    vfs.unmount("/tmp").unwrap();
+    assert_eq!(vfs.stat("/tmp/x").err(), Some(FsError::NotFound));
+  }
+}
@@ -0,0 +1,851 @@
+//! The virtual file system.
+//!
+//! Every file system, whatever backs it, is a `FileSystem`: a set of
+//! numbered inodes which it can look up by name in a directory, read,
+//! write and list. A `Vfs` joins file systems into one tree through its
+//! mount table, and walks paths through that tree, following `.`, `..`
+//! and symbolic links, to a `Dentry`. Opening a dentry gives a `File`,
+//! which reads, writes, seeks and lists from an offset of its own.
+
+use core::fmt::{self, Display};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::collections::HashMap;
+use alloc::spin::Mutex;
+use alloc::{Shared, String};
+
+pub mod file;
+pub mod node;
+pub mod path;
+#[cfg(test)]
+pub mod testing;
+
+pub use self::file::{File, OpenFlags, SeekFrom};
+pub use self::node::{Dentry, Inode};
+
+/// The number of an inode on its file system.
+pub type Ino = u64;
+
+/// The longest name a directory entry can have, in bytes.
+pub const NAME_MAX: usize = 255;
+/// The most symbolic links one path walk will follow.
+pub const MAX_SYMLINKS: usize = 40;
+
+/// The ways a file system operation can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsError
+{
+  /// Nothing is at the path.
+  NotFound,
+  /// A directory was needed, and something else was found.
+  NotDirectory,
+  /// Something other than a directory was needed.
+  IsDirectory,
+  /// Something is already at the path.
+  Exists,
+  /// The directory still has entries.
+  NotEmpty,
+  /// The file system cannot be changed.
+  ReadOnly,
+  /// The file was not opened for this.
+  PermissionDenied,
+  /// The path cannot name what it was used for, like `/` to `mkdir`.
+  InvalidPath,
+  /// A name in the path is longer than `NAME_MAX`.
+  NameTooLong,
+  /// More than `MAX_SYMLINKS` links were followed, or a link was found
+  /// where one is not allowed.
+  TooManyLinks,
+  /// The file already has as many links as the file system allows.
+  LinkLimit,
+  /// The paths are on different mounts.
+  CrossDevice,
+  /// The file system or mount point is in use.
+  Busy,
+  /// The file system is full.
+  NoSpace,
+  /// An argument is out of range.
+  Invalid,
+  /// The device behind the file system failed.
+  Io,
+  /// The file system does not do this.
+  Unsupported,
+}
+
+impl Display for FsError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    let msg = match self {
+      FsError::NotFound => "no such file or directory",
+      FsError::NotDirectory => "not a directory",
+      FsError::IsDirectory => "is a directory",
+      FsError::Exists => "file exists",
+      FsError::NotEmpty => "directory not empty",
+      FsError::ReadOnly => "read-only file system",
+      FsError::PermissionDenied => "permission denied",
+      FsError::InvalidPath => "invalid path",
+      FsError::NameTooLong => "file name too long",
+      FsError::TooManyLinks => "too many levels of symbolic links",
+      FsError::LinkLimit => "too many links",
+      FsError::CrossDevice => "cross-device link",
+      FsError::Busy => "device or resource busy",
+      FsError::NoSpace => "no space left on device",
+      FsError::Invalid => "invalid argument",
+      FsError::Io => "input/output error",
+      FsError::Unsupported => "operation not supported",
+    };
+    write!(f, "{}", msg)
+  }
+}
+
+/// The result of a file system operation.
+pub type FsResult<T> = Result<T, FsError>;
+
+/// What a file is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType
+{
+  /// A regular file.
+  File,
+  /// A directory.
+  Directory,
+  /// A symbolic link.
+  Symlink,
+  /// A device, fifo or socket.
+  Other,
+}
+
+/// The metadata of a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Stat
+{
+  /// Its number on its file system.
+  pub ino: Ino,
+  /// What it is.
+  pub kind: FileType,
+  /// Its permission bits.
+  pub mode: u32,
+  /// Its owner.
+  pub uid: u32,
+  /// Its group.
+  pub gid: u32,
+  /// Its size in bytes; for a symbolic link, the length of its target.
+  pub size: u64,
+  /// How many directory entries name it.
+  pub nlink: u32,
+  /// Its modification time, in seconds since the epoch.
+  pub mtime: u64,
+}
+
+/// How much of a file system is used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsStat
+{
+  /// The size of its blocks, in bytes.
+  pub block_size: u64,
+  /// How many blocks it has.
+  pub blocks: u64,
+  /// How many of those are free.
+  pub free_blocks: u64,
+  /// How many inodes it can have.
+  pub inodes: u64,
+  /// How many more it can have.
+  pub free_inodes: u64,
+}
+
+/// An entry of a directory.
+#[derive(Debug, PartialEq)]
+pub struct DirEntry
+{
+  /// Its name in the directory.
+  pub name: String,
+  /// The inode it names.
+  pub ino: Ino,
+  /// What that inode is.
+  pub kind: FileType,
+}
+
+/// A file system, as the VFS sees it.
+///
+/// Inodes are named by number; the VFS never holds a backend's own
+/// objects. Directories list only their real entries, without `.` and
+/// `..`. Everything that changes the file system fails with `ReadOnly`
+/// unless the backend does it.
+pub trait FileSystem: Send + Sync
+{
+  /// The name of the kind of file system, like `tmpfs`.
+  fn name(&self) -> &'static str;
+
+  /// The number of the root directory.
+  fn root(&self) -> Ino;
+
+  /// The metadata of `ino`.
+  fn stat(&self, ino: Ino) -> FsResult<Stat>;
+
+  /// The inode named `name` in the directory `dir`. `name` is never `.`,
+  /// `..` or empty.
+  fn lookup(&self, dir: Ino, name: &str) -> FsResult<Ino>;
+
+  /// Reads from `offset` in the regular file `ino` into `buf`. Returns how
+  /// much was read, which is 0 at or past the end.
+  fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> FsResult<usize>;
+
+  /// The entry at `pos` in the directory `dir` and the position of the one
+  /// after it, or `None` past the last. A listing starts at 0; other
+  /// positions only come from earlier calls.
+  fn readdir(&self, dir: Ino, pos: usize) -> FsResult<Option<(DirEntry, usize)>>;
+
+  /// The target of the symbolic link `ino`.
+  fn readlink(&self, ino: Ino) -> FsResult<String>;
+
+  /// Writes `buf` at `offset` in the regular file `ino`, growing it if
+  /// need be. Returns how much was written.
+  fn write(&self, _ino: Ino, _offset: u64, _buf: &[u8]) -> FsResult<usize>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// Sets the size of the regular file `ino`, with zeroes if it grows.
+  fn truncate(&self, _ino: Ino, _size: u64) -> FsResult<()>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// Creates an empty file or directory named `name` in `dir`. `kind` is
+  /// `File` or `Directory`.
+  fn create(&self, _dir: Ino, _name: &str, _kind: FileType, _mode: u32) -> FsResult<Ino>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// Creates a symbolic link named `name` in `dir`, pointing at `target`.
+  fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> FsResult<Ino>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// Removes the entry `name` from `dir`, which is not a directory.
+  fn unlink(&self, _dir: Ino, _name: &str) -> FsResult<()>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// Removes the empty directory `name` from `dir`.
+  fn rmdir(&self, _dir: Ino, _name: &str) -> FsResult<()>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// Moves the entry `from` in `from_dir` to `to` in `to_dir`, replacing
+  /// what is there as POSIX `rename` does.
+  fn rename(&self, _from_dir: Ino, _from: &str, _to_dir: Ino, _to: &str) -> FsResult<()>
+  {
+    Err(FsError::ReadOnly)
+  }
+
+  /// How much of the file system is used.
+  fn statfs(&self) -> FsResult<FsStat>
+  {
+    Ok(FsStat::default())
+  }
+
+  /// Writes anything buffered back to the device.
+  fn sync(&self) -> FsResult<()>
+  {
+    Ok(())
+  }
+}
+
+/// Identifies a mount in a `Vfs`. The root file system is mount 0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MountId(pub usize);
+
+/// A file system mounted somewhere in the tree.
+struct Mount
+{
+  fs: Shared<dyn FileSystem>,
+  /// The directory it covers, or `None` for the root.
+  point: Option<(MountId, Ino)>,
+}
+
+impl Mount
+{
+  fn root(&self, id: MountId) -> Inode
+  {
+    Inode::new(id, self.fs.clone(), self.fs.root())
+  }
+}
+
+/// A tree of mounted file systems.
+pub struct Vfs
+{
+  mounts: Mutex<HashMap<MountId, Mount>>,
+  next_id: AtomicUsize,
+  root: Shared<Dentry>,
+}
+
+impl Vfs
+{
+  /// A tree with `root` mounted at `/`.
+  pub fn new(root: Shared<dyn FileSystem>) -> Self
+  {
+    let id = MountId(0);
+    let mount = Mount { fs: root, point: None };
+    let dentry = Shared::new(Dentry::new("", mount.root(id), None));
+
+    let mut mounts = HashMap::new();
+    mounts.insert(id, mount);
+    Self { mounts: Mutex::new(mounts), next_id: AtomicUsize::new(1), root: dentry }
+  }
+
+  /// The root directory.
+  #[inline]
+  pub fn root(&self) -> &Shared<Dentry>
+  {
+    &self.root
+  }
+
+  /// Mounts `fs` over the directory at `path`, hiding what is in it until
+  /// it is unmounted.
+  pub fn mount(&self, path: &str, fs: Shared<dyn FileSystem>) -> FsResult<MountId>
+  {
+    let dentry = self.lookup(path)?;
+    let inode = dentry.inode();
+    inode.require_dir()?;
+    let point = (inode.mount(), inode.ino());
+
+    // A covered directory cannot be reached, but the root of what covers it,
+    // or the root of the tree, can. The lookup may have raced with another
+    // mount on the same directory, so check again under the lock.
+    let mut mounts = self.mounts.lock();
+    let covered = mounts.keys_values().any(|(_, m)| m.point == Some(point));
+    if covered || !matches!(dentry.parent(), Some(parent) if parent.inode().mount() == point.0) {
+      return Err(FsError::Busy);
+    }
+
+    let id = MountId(self.next_id.fetch_add(1, Ordering::Relaxed));
+    mounts.insert(id, Mount { fs, point: Some(point) });
+    Ok(id)
+  }
+
+  /// Unmounts the file system mounted at `path`. Files already open on it
+  /// keep it alive until they are closed.
+  pub fn unmount(&self, path: &str) -> FsResult<()>
+  {
+    let dentry = self.lookup(path)?;
+    let inode = dentry.inode();
+
+    let mut mounts = self.mounts.lock();
+    match mounts.find(&inode.mount()) {
+      Some(mount) if mount.point.is_some() && mount.fs.root() == inode.ino() => {}
+      _ => return Err(FsError::Invalid),
+    }
+    if mounts.keys_values().any(|(_, m)| matches!(m.point, Some((id, _)) if id == inode.mount())) {
+      return Err(FsError::Busy);
+    }
+
+    mounts.remove(&inode.mount());
+    Ok(())
+  }
+
+  /// The number of file systems mounted, including the root.
+  pub fn mount_count(&self) -> usize
+  {
+    self.mounts.lock().len()
+  }
+
+  /// Writes back everything buffered on every mounted file system.
+  pub fn sync(&self) -> FsResult<()>
+  {
+    let mounts = self.mounts.lock();
+    for (_, mount) in mounts.keys_values() {
+      mount.fs.sync()?;
+    }
+    Ok(())
+  }
+
+  /// What `path` names, following symbolic links.
+  #[inline]
+  pub fn lookup(&self, path: &str) -> FsResult<Shared<Dentry>>
+  {
+    self.lookup_at(&self.root, path, true)
+  }
+
+  /// What `path` names, relative to `dir` unless it is absolute. A symbolic
+  /// link at the end of the path is followed if `follow` is set, or if the
+  /// path ends in `/`.
+  pub fn lookup_at(&self, dir: &Shared<Dentry>, path: &str, follow: bool)
+      -> FsResult<Shared<Dentry>>
+  {
+    if path.is_empty() {
+      return Err(FsError::NotFound);
+    }
+    let trailing = path.ends_with('/');
+    let mut links = 0;
+
+    let dentry = self.walk(dir, path, follow || trailing, &mut links)?;
+    if trailing {
+      dentry.inode().require_dir()?;
+    }
+    Ok(dentry)
+  }
+
+  /// Walks `path` from `dir`, counting symbolic links followed in `links`.
+  fn walk(&self, dir: &Shared<Dentry>, path: &str, follow: bool, links: &mut usize)
+      -> FsResult<Shared<Dentry>>
+  {
+    let mut dentry = if path::is_absolute(path) { self.root.clone() } else { dir.clone() };
+
+    let mut names = path::components(path).peekable();
+    while let Some(name) = names.next() {
+      dentry.inode().require_dir()?;
+      if name == ".." {
+        dentry = dentry.parent().cloned().unwrap_or(dentry);
+        continue;
+      }
+
+      let child = self.child(&dentry, name)?;
+      let last = names.peek().is_none();
+      dentry = if child.inode().kind()? == FileType::Symlink && (follow || !last) {
+        *links += 1;
+        if *links > MAX_SYMLINKS {
+          return Err(FsError::TooManyLinks);
+        }
+        // Relative targets are relative to the directory holding the link.
+        let target = child.inode().fs().readlink(child.inode().ino())?;
+        self.walk(&dentry, target.as_str(), true, links)?
+      } else {
+        child
+      };
+    }
+
+    Ok(dentry)
+  }
+
+  /// The entry `name` of the directory `dir`, or the root of what is
+  /// mounted on it.
+  fn child(&self, dir: &Shared<Dentry>, name: &str) -> FsResult<Shared<Dentry>>
+  {
+    if name.len() > NAME_MAX {
+      return Err(FsError::NameTooLong);
+    }
+
+    let inode = dir.inode();
+    let ino = inode.fs().lookup(inode.ino(), name)?;
+    let child = self.covering(inode.mount(), ino)
+        .unwrap_or_else(|| Inode::new(inode.mount(), inode.fs().clone(), ino));
+    Ok(Shared::new(Dentry::new(name, child, Some(dir.clone()))))
+  }
+
+  /// The root of the file system mounted on `ino` of `mount`, if any.
+  fn covering(&self, mount: MountId, ino: Ino) -> Option<Inode>
+  {
+    let mounts = self.mounts.lock();
+    let found = mounts.keys_values().find(|(_, m)| m.point == Some((mount, ino)));
+    found.map(|(&id, m)| m.root(id))
+  }
+
+  /// Returns true if something is mounted on `inode`.
+  fn is_mount_point(&self, inode: &Inode) -> bool
+  {
+    self.covering(inode.mount(), inode.ino()).is_some()
+  }
+
+  /// The directory `path` is in, and its last name.
+  fn parent<'p>(&self, path: &'p str) -> FsResult<(Shared<Dentry>, &'p str)>
+  {
+    let (dir, name) = path::split_last(path).ok_or(FsError::InvalidPath)?;
+    if name.len() > NAME_MAX {
+      return Err(FsError::NameTooLong);
+    }
+
+    let dir = self.lookup(dir)?;
+    dir.inode().require_dir()?;
+    Ok((dir, name))
+  }
+
+  /// Opens the file at `path`. With `CREATE`, a regular file with the
+  /// permission bits `mode` is created if nothing is there.
+  pub fn open(&self, path: &str, flags: OpenFlags, mode: u32) -> FsResult<File>
+  {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let dentry = match self.lookup_at(&self.root, path, follow) {
+      Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+        return Err(FsError::Exists);
+      }
+      Ok(dentry) => dentry,
+      Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+        let (dir, name) = self.parent(path)?;
+        let inode = dir.inode();
+        let ino = inode.fs().create(inode.ino(), name, FileType::File, mode)?;
+        let child = Inode::new(inode.mount(), inode.fs().clone(), ino);
+        Shared::new(Dentry::new(name, child, Some(dir.clone())))
+      }
+      Err(e) => return Err(e),
+    };
+
+    let inode = dentry.inode();
+    let writing = flags.contains(OpenFlags::WRITE);
+    match inode.kind()? {
+      FileType::Symlink => return Err(FsError::TooManyLinks),
+      FileType::Directory if writing => return Err(FsError::IsDirectory),
+      FileType::Directory => {}
+      _ if flags.contains(OpenFlags::DIRECTORY) => return Err(FsError::NotDirectory),
+      FileType::File if writing && flags.contains(OpenFlags::TRUNCATE) => {
+        inode.fs().truncate(inode.ino(), 0)?;
+      }
+      _ => {}
+    }
+
+    Ok(File::new(dentry, flags))
+  }
+
+  /// The metadata of what `path` names, following symbolic links.
+  pub fn stat(&self, path: &str) -> FsResult<Stat>
+  {
+    self.lookup(path)?.inode().stat()
+  }
+
+  /// The metadata of what `path` names, or of the link if it is one.
+  pub fn lstat(&self, path: &str) -> FsResult<Stat>
+  {
+    self.lookup_at(&self.root, path, false)?.inode().stat()
+  }
+
+  /// The target of the symbolic link at `path`.
+  pub fn readlink(&self, path: &str) -> FsResult<String>
+  {
+    let dentry = self.lookup_at(&self.root, path, false)?;
+    let inode = dentry.inode();
+    match inode.kind()? {
+      FileType::Symlink => inode.fs().readlink(inode.ino()),
+      _ => Err(FsError::Invalid),
+    }
+  }
+
+  /// Creates the directory `path`.
+  pub fn mkdir(&self, path: &str, mode: u32) -> FsResult<()>
+  {
+    let (dir, name) = self.parent(path)?;
+    let inode = dir.inode();
+    inode.fs().create(inode.ino(), name, FileType::Directory, mode).map(|_| ())
+  }
+
+  /// Creates a symbolic link at `path`, pointing at `target`.
+  pub fn symlink(&self, target: &str, path: &str) -> FsResult<()>
+  {
+    if target.is_empty() {
+      return Err(FsError::NotFound);
+    }
+    let (dir, name) = self.parent(path)?;
+    let inode = dir.inode();
+    inode.fs().symlink(inode.ino(), name, target).map(|_| ())
+  }
+
+  /// Removes the file or link at `path`.
+  pub fn unlink(&self, path: &str) -> FsResult<()>
+  {
+    let (dir, name) = self.parent(path)?;
+    if self.child(&dir, name)?.inode().kind()? == FileType::Directory {
+      return Err(FsError::IsDirectory);
+    }
+    let inode = dir.inode();
+    inode.fs().unlink(inode.ino(), name)
+  }
+
+  /// Removes the empty directory at `path`.
+  pub fn rmdir(&self, path: &str) -> FsResult<()>
+  {
+    let (dir, name) = self.parent(path)?;
+    let target = self.child(&dir, name)?;
+    if target.inode().mount() != dir.inode().mount() {
+      return Err(FsError::Busy);
+    }
+    target.inode().require_dir()?;
+    let inode = dir.inode();
+    inode.fs().rmdir(inode.ino(), name)
+  }
+
+  /// Moves what is at `from` to `to`, which must be on the same mount.
+  pub fn rename(&self, from: &str, to: &str) -> FsResult<()>
+  {
+    let (from_dir, from_name) = self.parent(from)?;
+    let (to_dir, to_name) = self.parent(to)?;
+    let source = self.child(&from_dir, from_name)?;
+
+    let mount = from_dir.inode().mount();
+    if to_dir.inode().mount() != mount {
+      return Err(FsError::CrossDevice);
+    }
+    if source.inode().mount() != mount {
+      return Err(FsError::Busy);
+    }
+    match self.child(&to_dir, to_name) {
+      Ok(target) if target.inode().mount() != mount || self.is_mount_point(target.inode()) => {
+        return Err(FsError::Busy);
+      }
+      Ok(_) | Err(FsError::NotFound) => {}
+      Err(e) => return Err(e),
+    }
+
+    // A directory cannot be moved into itself.
+    let mut dir = Some(&to_dir);
+    while let Some(d) = dir {
+      if d.inode().same(source.inode()) {
+        return Err(FsError::Invalid);
+      }
+      dir = d.parent();
+    }
+
+    from_dir.inode().fs().rename(from_dir.inode().ino(), from_name, to_dir.inode().ino(), to_name)
+  }
+
+  /// Sets the size of the regular file at `path`.
+  pub fn truncate(&self, path: &str, size: u64) -> FsResult<()>
+  {
+    let dentry = self.lookup(path)?;
+    let inode = dentry.inode();
+    match inode.kind()? {
+      FileType::Directory => Err(FsError::IsDirectory),
+      FileType::File => inode.fs().truncate(inode.ino(), size),
+      _ => Err(FsError::Invalid),
+    }
+  }
+
+  /// How much of the file system holding `path` is used.
+  pub fn statfs(&self, path: &str) -> FsResult<FsStat>
+  {
+    self.lookup(path)?.inode().fs().statfs()
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  /// A read only file system from a table of `(ino, parent, name, kind, data)`,
+  /// where the data of a link is its target. The root is inode 1.
+  struct Table(&'static [(Ino, Ino, &'static str, FileType, &'static str)]);
+
+  impl Table
+  {
+    fn get(&self, ino: Ino) -> FsResult<(Ino, Ino, &'static str, FileType, &'static str)>
+    {
+      self.0.iter().copied().find(|e| e.0 == ino).ok_or(FsError::NotFound)
+    }
+  }
+
+  impl FileSystem for Table
+  {
+    fn name(&self) -> &'static str
+    {
+      "table"
+    }
+
+    fn root(&self) -> Ino
+    {
+      1
+    }
+
+    fn stat(&self, ino: Ino) -> FsResult<Stat>
+    {
+      let (ino, _, _, kind, data) = self.get(ino)?;
+      let size = data.len() as u64;
+      Ok(Stat { ino, kind, mode: 0o755, uid: 0, gid: 0, size, nlink: 1, mtime: 0 })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> FsResult<Ino>
+    {
+      let entry = self.0.iter().find(|e| e.0 != 1 && e.1 == dir && e.2 == name);
+      entry.map(|e| e.0).ok_or(FsError::NotFound)
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> FsResult<usize>
+    {
+      let data = self.get(ino)?.4.as_bytes();
+      let data = data.get(offset as usize..).unwrap_or(&[]);
+      let len = data.len().min(buf.len());
+      buf[..len].copy_from_slice(&data[..len]);
+      Ok(len)
+    }
+
+    fn readdir(&self, dir: Ino, pos: usize) -> FsResult<Option<(DirEntry, usize)>>
+    {
+      let mut entries = self.0.iter().filter(|e| e.0 != 1 && e.1 == dir);
+      let entry = entries.nth(pos);
+      Ok(entry.map(|e| (DirEntry { name: String::from(e.2), ino: e.0, kind: e.3 }, pos + 1)))
+    }
+
+    fn readlink(&self, ino: Ino) -> FsResult<String>
+    {
+      Ok(String::from(self.get(ino)?.4))
+    }
+  }
+
+  const ROOT: &[(Ino, Ino, &str, FileType, &str)] = &[
+    (1, 1, "", FileType::Directory, ""),
+    (2, 1, "bin", FileType::Directory, ""),
+    (3, 2, "sh", FileType::File, "#!sh"),
+    (4, 1, "etc", FileType::Directory, ""),
+    (5, 4, "motd", FileType::File, "hello, world\n"),
+    (6, 1, "lib", FileType::Symlink, "bin"),
+    (7, 4, "shell", FileType::Symlink, "../bin/sh"),
+    (8, 4, "abs", FileType::Symlink, "/etc/motd"),
+    (9, 1, "loop", FileType::Symlink, "loop/a"),
+    (10, 1, "mnt", FileType::Directory, ""),
+  ];
+
+  const DISK: &[(Ino, Ino, &str, FileType, &str)] = &[
+    (1, 1, "", FileType::Directory, ""),
+    (2, 1, "data", FileType::File, "on disk"),
+    (3, 1, "up", FileType::Symlink, "../etc/motd"),
+  ];
+
+  fn vfs() -> Vfs
+  {
+    Vfs::new(Shared::new(Table(ROOT)))
+  }
+
+  fn read_all(file: &File) -> std::string::String
+  {
+    let mut out = std::vec::Vec::new();
+    let mut buf = [0; 4];
+    while let Ok(n @ 1..=4) = file.read(&mut buf) {
+      out.extend_from_slice(&buf[..n]);
+    }
+    std::string::String::from_utf8(out).unwrap()
+  }
+
+  #[test]
+  fn lookup()
+  {
+    let vfs = vfs();
+    assert_eq!(vfs.lookup("/").unwrap().inode().ino(), 1);
+    assert_eq!(vfs.lookup("/bin/sh").unwrap().inode().ino(), 3);
+    assert_eq!(vfs.lookup("//etc/./motd").unwrap().inode().ino(), 5);
+    assert_eq!(vfs.lookup("/etc/../bin/./sh").unwrap().path().as_str(), "/bin/sh");
+    assert_eq!(vfs.lookup("/../..").unwrap().path().as_str(), "/");
+
+    assert_eq!(vfs.lookup("/bin/ls").err(), Some(FsError::NotFound));
+    assert_eq!(vfs.lookup("/bin/sh/x").err(), Some(FsError::NotDirectory));
+    assert_eq!(vfs.lookup("/bin/sh/").err(), Some(FsError::NotDirectory));
+    assert_eq!(vfs.lookup("/bin/sh/..").err(), Some(FsError::NotDirectory));
+    assert_eq!(vfs.lookup("").err(), Some(FsError::NotFound));
+
+    let long = "x".repeat(NAME_MAX + 1);
+    assert_eq!(vfs.lookup(&long).err(), Some(FsError::NameTooLong));
+
+    let etc = vfs.lookup("/etc").unwrap();
+    assert_eq!(vfs.lookup_at(&etc, "motd", true).unwrap().inode().ino(), 5);
+    assert_eq!(vfs.lookup_at(&etc, "../bin", true).unwrap().inode().ino(), 2);
+  }
+
+  #[test]
+  fn symlinks()
+  {
+    let vfs = vfs();
+    assert_eq!(vfs.lookup("/lib/sh").unwrap().inode().ino(), 3);
+    assert_eq!(vfs.lookup("/etc/shell").unwrap().inode().ino(), 3);
+    assert_eq!(vfs.lookup("/etc/abs").unwrap().inode().ino(), 5);
+    assert_eq!(vfs.lstat("/etc/abs").unwrap().kind, FileType::Symlink);
+    assert_eq!(vfs.stat("/etc/abs").unwrap().kind, FileType::File);
+    assert_eq!(vfs.readlink("/etc/shell").unwrap().as_str(), "../bin/sh");
+    assert_eq!(vfs.readlink("/etc/motd").err(), Some(FsError::Invalid));
+
+    // `..` goes back the way the walk came, through the link.
+    assert_eq!(vfs.lookup("/lib/..").unwrap().path().as_str(), "/");
+    assert_eq!(vfs.lookup("/lib").unwrap().path().as_str(), "/bin");
+    assert_eq!(vfs.lookup_at(vfs.root(), "lib/", false).unwrap().inode().ino(), 2);
+
+    assert_eq!(vfs.lookup("/loop").err(), Some(FsError::TooManyLinks));
+    assert_eq!(vfs.lstat("/loop").unwrap().ino, 9);
+  }
+
+  #[test]
+  fn files()
+  {
+    let vfs = vfs();
+    let motd = vfs.open("/etc/abs", OpenFlags::READ, 0).unwrap();
+    assert_eq!(read_all(&motd), "hello, world\n");
+    assert_eq!(motd.read(&mut [0; 4]), Ok(0));
+
+    assert_eq!(motd.seek(SeekFrom::End(-6)), Ok(7));
+    assert_eq!(read_all(&motd), "world\n");
+    assert_eq!(motd.seek(SeekFrom::Start(2)), Ok(2));
+    assert_eq!(motd.seek(SeekFrom::Current(3)), Ok(5));
+    assert_eq!(motd.seek(SeekFrom::Current(-6)), Err(FsError::Invalid));
+    assert_eq!(motd.stat().unwrap().size, 13);
+    assert_eq!(motd.write(b"x"), Err(FsError::PermissionDenied));
+
+    let flags = OpenFlags::READ | OpenFlags::WRITE;
+    let rw = vfs.open("/etc/motd", flags, 0).unwrap();
+    assert_eq!(rw.write(b"x"), Err(FsError::ReadOnly));
+    assert_eq!(vfs.open("/etc", flags, 0).err(), Some(FsError::IsDirectory));
+    assert_eq!(vfs.open("/etc/abs", OpenFlags::NO_FOLLOW, 0).err(), Some(FsError::TooManyLinks));
+    assert_eq!(vfs.open("/etc/motd", OpenFlags::DIRECTORY, 0).err(), Some(FsError::NotDirectory));
+    assert_eq!(vfs.open("/etc/new", OpenFlags::CREATE, 0).err(), Some(FsError::ReadOnly));
+    let exclusive = OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+    assert_eq!(vfs.open("/etc/motd", exclusive, 0).err(), Some(FsError::Exists));
+
+    assert_eq!(vfs.mkdir("/tmp", 0o755), Err(FsError::ReadOnly));
+    assert_eq!(vfs.mkdir("/", 0o755), Err(FsError::InvalidPath));
+    assert_eq!(vfs.unlink("/etc"), Err(FsError::IsDirectory));
+    assert_eq!(vfs.rmdir("/etc/motd"), Err(FsError::NotDirectory));
+  }
+
+  #[test]
+  fn readdir()
+  {
+    let vfs = vfs();
+    let etc = vfs.open("/etc", OpenFlags::READ, 0).unwrap();
+    let mut names = std::vec::Vec::new();
+    while let Some(entry) = etc.readdir().unwrap() {
+      names.push((std::string::String::from(entry.name.as_str()), entry.ino));
+    }
+    let names: std::vec::Vec<_> = names.iter().map(|(n, i)| (n.as_str(), *i)).collect();
+    assert_eq!(names, [(".", 4), ("..", 1), ("motd", 5), ("shell", 7), ("abs", 8)]);
+
+    assert_eq!(etc.read(&mut [0; 4]), Err(FsError::IsDirectory));
+    assert_eq!(etc.seek(SeekFrom::Start(2)), Ok(2));
+    assert_eq!(etc.readdir().unwrap().unwrap().name, "motd");
+
+    let motd = vfs.open("/etc/motd", OpenFlags::READ, 0).unwrap();
+    assert_eq!(motd.readdir().err(), Some(FsError::NotDirectory));
+  }
+
+  #[test]
+  fn mounts()
+  {
+    let vfs = vfs();
+    let id = vfs.mount("/mnt", Shared::new(Table(DISK))).unwrap();
+    assert_eq!(vfs.mount_count(), 2);
+
+    let data = vfs.lookup("/mnt/data").unwrap();
+    assert_eq!(data.inode().mount(), id);
+    assert_eq!(data.inode().fs().name(), "table");
+    assert_eq!(data.path().as_str(), "/mnt/data");
+    let file = vfs.open("/mnt/data", OpenFlags::READ, 0).unwrap();
+    assert_eq!(read_all(&file), "on disk");
+
+    // `..` from the mounted root leaves the mount.
+    assert_eq!(vfs.lookup("/mnt/..").unwrap().inode().ino(), 1);
+    assert_eq!(vfs.lookup("/mnt/up").unwrap().inode().ino(), 5);
+    assert_eq!(vfs.lookup("/mnt").unwrap().inode().mount(), id);
+
+    assert_eq!(vfs.mount("/mnt", Shared::new(Table(DISK))).err(), Some(FsError::Busy));
+    assert_eq!(vfs.mount("/", Shared::new(Table(DISK))).err(), Some(FsError::Busy));
+    assert_eq!(vfs.mount("/etc/motd", Shared::new(Table(DISK))).err(), Some(FsError::NotDirectory));
+    assert_eq!(vfs.rmdir("/mnt"), Err(FsError::Busy));
+    assert_eq!(vfs.rename("/mnt/data", "/etc/data"), Err(FsError::CrossDevice));
+    assert_eq!(vfs.unmount("/etc"), Err(FsError::Invalid));
+
+    vfs.unmount("/mnt").unwrap();
+    assert_eq!(vfs.mount_count(), 1);
+    assert_eq!(vfs.lookup("/mnt/data").err(), Some(FsError::NotFound));
+    // The open file keeps the unmounted file system.
+    assert_eq!(file.seek(SeekFrom::Start(3)), Ok(3));
+    assert_eq!(read_all(&file), "disk");
+  }
+}
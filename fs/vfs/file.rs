@@ -0,0 +1,213 @@
+//! Open files.
+
+use core::fmt;
+use core::ops::BitOr;
+
+use alloc::spin::Mutex;
+use alloc::{Shared, String};
+
+use super::{DirEntry, Dentry, FileType, FsError, FsResult, Stat};
+
+/// How a file is opened.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags
+{
+  /// The file can be read.
+  pub const READ: Self = Self(1 << 0);
+  /// The file can be written.
+  pub const WRITE: Self = Self(1 << 1);
+  /// Every write goes to the end of the file.
+  pub const APPEND: Self = Self(1 << 2);
+  /// A regular file is created if nothing is at the path.
+  pub const CREATE: Self = Self(1 << 3);
+  /// With `CREATE`, fail if something is already at the path.
+  pub const EXCLUSIVE: Self = Self(1 << 4);
+  /// A regular file opened for writing is emptied.
+  pub const TRUNCATE: Self = Self(1 << 5);
+  /// Fail unless the path is a directory.
+  pub const DIRECTORY: Self = Self(1 << 6);
+  /// Fail if the path is a symbolic link, rather than following it.
+  pub const NO_FOLLOW: Self = Self(1 << 7);
+
+  /// No flags.
+  #[inline]
+  pub const fn empty() -> Self
+  {
+    Self(0)
+  }
+
+  /// The flags as bits.
+  #[inline]
+  pub const fn bits(self) -> u32
+  {
+    self.0
+  }
+
+  /// Returns true if every flag in `other` is set.
+  #[inline]
+  pub const fn contains(self, other: Self) -> bool
+  {
+    self.0 & other.0 == other.0
+  }
+}
+
+impl BitOr for OpenFlags
+{
+  type Output = Self;
+
+  #[inline]
+  fn bitor(self, other: Self) -> Self
+  {
+    Self(self.0 | other.0)
+  }
+}
+
+/// Where a seek is relative to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom
+{
+  /// The start of the file.
+  Start(u64),
+  /// The current offset.
+  Current(i64),
+  /// The end of the file.
+  End(i64),
+}
+
+/// An open file: a dentry, with the flags it was opened with and an offset
+/// of its own. For a directory the offset is a position in its listing, not
+/// bytes.
+pub struct File
+{
+  dentry: Shared<Dentry>,
+  flags: OpenFlags,
+  offset: Mutex<u64>,
+}
+
+impl File
+{
+  pub(crate) fn new(dentry: Shared<Dentry>, flags: OpenFlags) -> Self
+  {
+    Self { dentry, flags, offset: Mutex::new(0) }
+  }
+
+  /// The dentry the file was opened through.
+  #[inline]
+  pub fn dentry(&self) -> &Shared<Dentry>
+  {
+    &self.dentry
+  }
+
+  /// The flags the file was opened with.
+  #[inline]
+  pub fn flags(&self) -> OpenFlags
+  {
+    self.flags
+  }
+
+  /// The file's metadata.
+  #[inline]
+  pub fn stat(&self) -> FsResult<Stat>
+  {
+    self.dentry.inode().stat()
+  }
+
+  /// Reads from the offset into `buf`, and moves the offset past what was
+  /// read. Returns 0 at the end of the file.
+  pub fn read(&self, buf: &mut [u8]) -> FsResult<usize>
+  {
+    if !self.flags.contains(OpenFlags::READ) {
+      return Err(FsError::PermissionDenied);
+    }
+    let inode = self.dentry.inode();
+    if inode.kind()? == FileType::Directory {
+      return Err(FsError::IsDirectory);
+    }
+
+    let mut offset = self.offset.lock();
+    let read = inode.fs().read(inode.ino(), *offset, buf)?;
+    *offset += read as u64;
+    Ok(read)
+  }
+
+  /// Writes `buf` at the offset, or at the end of the file if it was opened
+  /// with `APPEND`, and moves the offset past what was written.
+  pub fn write(&self, buf: &[u8]) -> FsResult<usize>
+  {
+    if !self.flags.contains(OpenFlags::WRITE) {
+      return Err(FsError::PermissionDenied);
+    }
+    let inode = self.dentry.inode();
+
+    let mut offset = self.offset.lock();
+    if self.flags.contains(OpenFlags::APPEND) {
+      *offset = inode.stat()?.size;
+    }
+    let written = inode.fs().write(inode.ino(), *offset, buf)?;
+    *offset += written as u64;
+    Ok(written)
+  }
+
+  /// Moves the offset, and returns where it now is. It may be moved past
+  /// the end of the file, but not before its start.
+  pub fn seek(&self, pos: SeekFrom) -> FsResult<u64>
+  {
+    let mut offset = self.offset.lock();
+    let (base, delta) = match pos {
+      SeekFrom::Start(to) => (to, 0),
+      SeekFrom::Current(delta) => (*offset, delta),
+      SeekFrom::End(delta) => (self.stat()?.size, delta),
+    };
+
+    let to = if delta < 0 {
+      base.checked_sub(delta.unsigned_abs())
+    } else {
+      base.checked_add(delta as u64)
+    };
+    *offset = to.ok_or(FsError::Invalid)?;
+    Ok(*offset)
+  }
+
+  /// Sets the file's size, keeping the offset where it is.
+  pub fn truncate(&self, size: u64) -> FsResult<()>
+  {
+    if !self.flags.contains(OpenFlags::WRITE) {
+      return Err(FsError::PermissionDenied);
+    }
+    let inode = self.dentry.inode();
+    inode.fs().truncate(inode.ino(), size)
+  }
+
+  /// The next entry of a directory, or `None` after the last. `.` and `..`
+  /// come first, whatever the file system lists.
+  pub fn readdir(&self) -> FsResult<Option<DirEntry>>
+  {
+    let inode = self.dentry.inode();
+    inode.require_dir()?;
+
+    let mut offset = self.offset.lock();
+    let (entry, next) = match *offset {
+      0 => (DirEntry { name: String::from("."), ino: inode.ino(), kind: FileType::Directory }, 1),
+      1 => {
+        let parent = self.dentry.parent().map_or(inode, |parent| parent.inode());
+        (DirEntry { name: String::from(".."), ino: parent.ino(), kind: FileType::Directory }, 2)
+      }
+      pos => match inode.fs().readdir(inode.ino(), pos as usize - 2)? {
+        Some((entry, next)) => (entry, next as u64 + 2),
+        None => return Ok(None),
+      },
+    };
+    *offset = next;
+    Ok(Some(entry))
+  }
+}
+
+impl fmt::Debug for File
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "File({:?}, {:?})", self.dentry, self.flags)
+  }
+}
@@ -0,0 +1,155 @@
+//! Inodes and dentries.
+//!
+//! An `Inode` is a file as its file system knows it: a number, meaningful
+//! only to the file system on that mount. A `Dentry` is an inode as a path
+//! walk reached it, with the name it was found by and the directory it was
+//! found in, which is what `..` goes back to.
+
+use core::fmt;
+
+use alloc::{Array, Shared, String};
+
+use super::{FileSystem, FileType, FsError, FsResult, Ino, MountId, Stat};
+
+/// A file on a mounted file system.
+#[derive(Clone)]
+pub struct Inode
+{
+  mount: MountId,
+  fs: Shared<dyn FileSystem>,
+  ino: Ino,
+}
+
+impl Inode
+{
+  pub(crate) fn new(mount: MountId, fs: Shared<dyn FileSystem>, ino: Ino) -> Self
+  {
+    Self { mount, fs, ino }
+  }
+
+  /// The mount the file is on.
+  #[inline]
+  pub fn mount(&self) -> MountId
+  {
+    self.mount
+  }
+
+  /// The file system the file is on.
+  #[inline]
+  pub fn fs(&self) -> &Shared<dyn FileSystem>
+  {
+    &self.fs
+  }
+
+  /// The file's number on its file system.
+  #[inline]
+  pub fn ino(&self) -> Ino
+  {
+    self.ino
+  }
+
+  /// Returns true if `other` is the same file.
+  #[inline]
+  pub fn same(&self, other: &Inode) -> bool
+  {
+    self.mount == other.mount && self.ino == other.ino
+  }
+
+  /// The file's metadata.
+  #[inline]
+  pub fn stat(&self) -> FsResult<Stat>
+  {
+    self.fs.stat(self.ino)
+  }
+
+  /// What the file is.
+  #[inline]
+  pub fn kind(&self) -> FsResult<FileType>
+  {
+    self.stat().map(|stat| stat.kind)
+  }
+
+  /// Fails with `NotDirectory` unless the file is a directory.
+  pub fn require_dir(&self) -> FsResult<()>
+  {
+    match self.kind()? {
+      FileType::Directory => Ok(()),
+      _ => Err(FsError::NotDirectory),
+    }
+  }
+}
+
+impl fmt::Debug for Inode
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "Inode({}:{} on {})", self.mount.0, self.ino, self.fs.name())
+  }
+}
+
+/// A file reached by a path walk.
+pub struct Dentry
+{
+  name: String,
+  inode: Inode,
+  parent: Option<Shared<Dentry>>,
+}
+
+impl Dentry
+{
+  pub(crate) fn new(name: &str, inode: Inode, parent: Option<Shared<Dentry>>) -> Self
+  {
+    Self { name: String::from(name), inode, parent }
+  }
+
+  /// The name the file was found by; empty for the root.
+  #[inline]
+  pub fn name(&self) -> &str
+  {
+    self.name.as_str()
+  }
+
+  /// The file.
+  #[inline]
+  pub fn inode(&self) -> &Inode
+  {
+    &self.inode
+  }
+
+  /// The directory the file was found in, or `None` for the root.
+  #[inline]
+  pub fn parent(&self) -> Option<&Shared<Dentry>>
+  {
+    self.parent.as_ref()
+  }
+
+  /// The absolute path the file was reached by, with `..` and symbolic
+  /// links resolved.
+  pub fn path(&self) -> String
+  {
+    let mut names = Array::new();
+    let mut dentry = self;
+    while let Some(parent) = dentry.parent() {
+      names.push(dentry.name());
+      dentry = parent;
+    }
+
+    let mut path = String::new();
+    for name in names.iter().rev() {
+      path.push('/');
+      path.push_str(name);
+    }
+    if path.is_empty() {
+      path.push('/');
+    }
+    path
+  }
+}
+
+impl fmt::Debug for Dentry
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "Dentry({:?}, {:?})", self.path(), self.inode)
+  }
+}
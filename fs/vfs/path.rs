@@ -0,0 +1,58 @@
+//! Splitting paths into names.
+//!
+//! Paths are `/` separated names. Empty names, as in `a//b` or a trailing
+//! `/`, and `.` name the directory they are in and are dropped; `..` is
+//! kept, since what it means depends on how the directory was reached.
+
+/// Returns true if `path` starts at the root rather than at a directory.
+#[inline]
+pub fn is_absolute(path: &str) -> bool
+{
+  path.starts_with('/')
+}
+
+/// The names making up `path`, without empty or `.` components.
+pub fn components(path: &str) -> impl Iterator<Item = &str> + Clone
+{
+  path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+/// Splits `path` into the path of the directory it is in and its last
+/// name, or `None` if it has no last name: it is empty, the root, or ends
+/// in `.` or `..`. The directory is `.` for a single relative name.
+pub fn split_last(path: &str) -> Option<(&str, &str)>
+{
+  let path = path.trim_end_matches('/');
+  let (dir, name) = match path.rfind('/') {
+    Some(0) => ("/", &path[1..]),
+    Some(i) => (&path[..i], &path[i + 1..]),
+    None => (".", path),
+  };
+
+  match name {
+    "" | "." | ".." => None,
+    name => Some((dir, name)),
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn split()
+  {
+    assert!(components("//usr/./bin/").eq(["usr", "bin"].iter().copied()));
+    assert!(components("../a").eq(["..", "a"].iter().copied()));
+    assert_eq!(components("/").count(), 0);
+
+    assert_eq!(split_last("/usr/bin/"), Some(("/usr", "bin")));
+    assert_eq!(split_last("/usr"), Some(("/", "usr")));
+    assert_eq!(split_last("a/b"), Some(("a", "b")));
+    assert_eq!(split_last("motd"), Some((".", "motd")));
+    assert_eq!(split_last("/"), None);
+    assert_eq!(split_last("a/.."), None);
+    assert_eq!(split_last(""), None);
+  }
+}
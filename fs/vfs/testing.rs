@@ -0,0 +1,40 @@
+//! Helpers for the tests of file systems.
+
+use std::string::String;
+use std::vec::Vec;
+
+use alloc::Shared;
+
+use super::{FileSystem, FsResult, OpenFlags, SeekFrom, Vfs};
+
+/// Mounts `fs`, which must have been made, at the root of a new tree.
+pub fn mount<F: FileSystem + 'static>(fs: FsResult<F>) -> (Shared<F>, Vfs)
+{
+  let fs = Shared::new(fs.unwrap());
+  let vfs = Vfs::new(fs.clone());
+  (fs, vfs)
+}
+
+/// Up to `len` bytes of the file `path` from `offset`.
+pub fn read_at(vfs: &Vfs, path: &str, offset: u64, len: usize) -> Vec<u8>
+{
+  let file = vfs.open(path, OpenFlags::READ, 0).unwrap();
+  file.seek(SeekFrom::Start(offset)).unwrap();
+  let mut buf = std::vec![0; len];
+  let n = file.read(&mut buf).unwrap();
+  buf.truncate(n);
+  buf
+}
+
+/// The names in the directory `path`, without `.` and `..`, as listed.
+pub fn names(vfs: &Vfs, path: &str) -> Vec<String>
+{
+  let dir = vfs.open(path, OpenFlags::READ, 0).unwrap();
+  let mut names = Vec::new();
+  while let Some(entry) = dir.readdir().unwrap() {
+    if entry.name != "." && entry.name != ".." {
+      names.push(entry.name.as_str().into());
+    }
+  }
+  names
+}
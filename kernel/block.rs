@@ -0,0 +1,68 @@
+//! The disks the drivers have found.
+//!
+//! A driver registers each block device it brings up, and it is known by
+//! its index from then on. File systems read them through one buffer
+//! cache, allowed a share of the frames free at boot.
+
+use fs::block::BlockDevice;
+use fs::cache::{BufferCache, CachedDevice};
+use system::alloc::alloc::frame::FRAMES;
+use system::alloc::spin::{Mutex, Once};
+use system::alloc::Shared;
+
+/// The most disks that can be registered.
+pub const MAX_DISKS: usize = 16;
+
+/// The buffer cache may hold a block for one in this many of the frames
+/// free at boot.
+pub const CACHE_SHARE: usize = 8;
+
+/// A registered disk.
+pub type Disk = Shared<dyn BlockDevice>;
+
+const NO_DISK: Option<Disk> = None;
+
+static DISKS: Mutex<[Option<Disk>; MAX_DISKS]> = Mutex::new([NO_DISK; MAX_DISKS]);
+
+static CACHE: Once<Shared<BufferCache>> = Once::new();
+
+/// Sets up the buffer cache. The frame allocator must be set up.
+pub fn init()
+{
+  let free = FRAMES.lock().as_ref().map_or(0, |frames| frames.free_frames());
+  CACHE.call_once(|| Shared::new(BufferCache::new((free / CACHE_SHARE).max(1))));
+}
+
+/// The buffer cache `init` set up.
+#[inline]
+pub fn cache() -> &'static Shared<BufferCache>
+{
+  CACHE.get().expect("the buffer cache is not initialised")
+}
+
+/// Adds `disk`, and returns its index, or `None` if there are too many.
+pub fn register(disk: Disk) -> Option<usize>
+{
+  let mut disks = DISKS.lock();
+  let index = disks.iter().position(Option::is_none)?;
+  disks[index] = Some(disk);
+  Some(index)
+}
+
+/// The disk at `index`.
+pub fn get(index: usize) -> Option<Disk>
+{
+  DISKS.lock().get(index)?.clone()
+}
+
+/// The number of disks registered.
+pub fn count() -> usize
+{
+  DISKS.lock().iter().flatten().count()
+}
+
+/// The disk at `index`, read and written through the buffer cache.
+pub fn cached(index: usize) -> Option<CachedDevice>
+{
+  Some(CachedDevice::new(cache().clone(), get(index)?))
+}
@@ -0,0 +1,6 @@
+//! Device drivers.
+
+pub mod dma;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
@@ -0,0 +1,90 @@
+//! Memory for devices to read and write.
+//!
+//! Devices see physical addresses, and the kernel identity maps RAM, so
+//! memory for DMA is physically contiguous frames whose virtual address is
+//! the one to give the device. There is no IOMMU to program, and the
+//! devices on QEMU's `virt` machine are cache coherent.
+
+use core::ptr;
+use core::slice;
+
+use system::alloc::alloc::frame::FRAMES;
+use system::alloc::alloc::page::PAGE_SIZE;
+
+/// Zeroed, physically contiguous pages, freed when dropped.
+pub struct Dma
+{
+  addr: usize,
+  pages: usize,
+}
+
+impl Dma
+{
+  /// Takes `pages` contiguous frames and zeroes them, or returns `None` if
+  /// there is no such run free.
+  pub fn new(pages: usize) -> Option<Self>
+  {
+    let addr = FRAMES.lock().as_mut()?.alloc_contiguous(pages)?;
+    unsafe {
+      ptr::write_bytes(addr as *mut u8, 0, pages * PAGE_SIZE);
+    }
+    Some(Self { addr, pages })
+  }
+
+  /// Pages enough for `bytes` bytes.
+  #[inline]
+  pub fn for_bytes(bytes: usize) -> Option<Self>
+  {
+    Self::new(bytes.div_ceil(PAGE_SIZE).max(1))
+  }
+
+  /// The address of the first byte, for the CPU and the device alike.
+  #[inline]
+  pub fn addr(&self) -> usize
+  {
+    self.addr
+  }
+
+  /// The size in bytes.
+  #[inline]
+  pub fn len(&self) -> usize
+  {
+    self.pages * PAGE_SIZE
+  }
+
+  /// Always false; there is at least one page.
+  #[inline]
+  pub fn is_empty(&self) -> bool
+  {
+    false
+  }
+
+  /// A pointer to the first byte.
+  #[inline]
+  pub fn as_ptr(&self) -> *mut u8
+  {
+    self.addr as *mut u8
+  }
+
+  /// The memory, as the CPU last saw it.
+  pub fn as_slice(&self) -> &[u8]
+  {
+    unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
+  }
+
+  /// The memory, to fill in before handing it to a device.
+  pub fn as_mut_slice(&mut self) -> &mut [u8]
+  {
+    unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.len()) }
+  }
+}
+
+impl Drop for Dma
+{
+  fn drop(&mut self)
+  {
+    if let Some(frames) = FRAMES.lock().as_mut() {
+      frames.free_contiguous(self.addr, self.pages);
+    }
+  }
+}
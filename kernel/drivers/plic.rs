@@ -0,0 +1,258 @@
+//! Driver for the RISC-V Platform-Level Interrupt Controller.
+//!
+//! The PLIC gathers the external interrupt sources and routes them to
+//! contexts, one per hart and privilege level. Each context has its own
+//! enable bits and priority threshold, and raises the external interrupt
+//! of its hart while a source it has enabled is pending above its threshold.
+//! The handler then claims the source, runs whatever was registered for it
+//! with `boot::irq::register_external`, and completes it.
+
+use core::ptr;
+
+use boot::irq::{self, Handler, Interrupt, IrqError};
+use boot::MAX_HARTS;
+use fdt::Fdt;
+use system::alloc::spin::Once;
+
+/// Compatible strings of the controllers this driver handles.
+pub const COMPATIBLE: [&str; 2] = ["sifive,plic-1.0.0", "riscv,plic0"];
+
+/// The highest priority a source may be given.
+pub const MAX_PRIORITY: u32 = 7;
+
+const PRIORITY: usize = 0x0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+/// The controller, once `init` has found it.
+pub static PLIC: Once<Plic> = Once::new();
+
+/// A PLIC mapped at a fixed base address.
+pub struct Plic
+{
+  base: usize,
+  /// The number of sources, not counting source 0.
+  sources: u32,
+  /// The context which delivers to each hart at the privilege level we
+  /// handle interrupts in.
+  contexts: [Option<usize>; MAX_HARTS],
+}
+
+impl Plic
+{
+  /// A PLIC at `base` with `sources` sources, whose contexts are laid out
+  /// as on QEMU's `virt` machine: M-mode and S-mode for each hart in turn.
+  pub fn new(base: usize, sources: u32, interrupt: Interrupt) -> Self
+  {
+    let mut contexts = [None; MAX_HARTS];
+    let offset = if interrupt == Interrupt::MachineExternal { 0 } else { 1 };
+    for (hart, context) in contexts.iter_mut().enumerate() {
+      *context = Some(2 * hart + offset);
+    }
+
+    Self { base, sources, contexts }
+  }
+
+  /// Finds the PLIC in the device tree and works out which of its contexts
+  /// raise `interrupt` on each hart, from `interrupts-extended`.
+  ///
+  /// Returns the controller together with the MMIO range it occupies.
+  pub fn from_fdt(fdt: &Fdt, interrupt: Interrupt) -> Option<(Self, (usize, usize))>
+  {
+    let node = fdt.find_compatible(&COMPATIBLE)?;
+    let reg = node.reg()?.next()?;
+    let sources = node.property("riscv,ndev")?.as_u32()?;
+    let mut plic = Self::new(reg.base as usize, sources, interrupt);
+
+    // Pairs of (hart interrupt controller, interrupt it raises there), one
+    // for each context.
+    if let Some(prop) = node.property("interrupts-extended") {
+      plic.contexts = [None; MAX_HARTS];
+
+      let mut cells = prop.cells();
+      let mut context = 0;
+      while let (Some(phandle), Some(code)) = (cells.next(), cells.next()) {
+        let hart = fdt
+            .find_phandle(phandle)
+            .and_then(|intc| fdt.parent(&intc))
+            .and_then(|cpu| cpu.reg()?.next())
+            .map(|reg| reg.base as usize);
+        if let Some(hart) = hart {
+          if code as usize == interrupt.code() && hart < MAX_HARTS {
+            plic.contexts[hart] = Some(context);
+          }
+        }
+        context += 1;
+      }
+    }
+
+    Some((plic, (reg.base as usize, reg.size as usize)))
+  }
+
+  /// The number of interrupt sources.
+  #[inline]
+  pub fn sources(&self) -> u32
+  {
+    self.sources
+  }
+
+  fn reg(&self, offset: usize) -> *mut u32
+  {
+    (self.base + offset) as *mut u32
+  }
+
+  fn context(&self, hart: usize) -> usize
+  {
+    self.contexts
+        .get(hart)
+        .copied()
+        .flatten()
+        .expect("no PLIC context for this hart")
+  }
+
+  fn check(&self, source: u32)
+  {
+    assert!(source != 0 && source <= self.sources, "invalid PLIC source {}", source);
+  }
+
+  /// Sets the priority of `source`, from 0 (never raised) to `MAX_PRIORITY`.
+  pub fn set_priority(&self, source: u32, priority: u32)
+  {
+    self.check(source);
+    unsafe {
+      ptr::write_volatile(self.reg(PRIORITY + 4 * source as usize), priority & MAX_PRIORITY);
+    }
+  }
+
+  /// The priority of `source`.
+  pub fn priority(&self, source: u32) -> u32
+  {
+    self.check(source);
+    unsafe { ptr::read_volatile(self.reg(PRIORITY + 4 * source as usize)) }
+  }
+
+  /// Returns true if `source` is waiting to be claimed.
+  pub fn is_pending(&self, source: u32) -> bool
+  {
+    self.check(source);
+    let word = unsafe { ptr::read_volatile(self.reg(PENDING + 4 * (source as usize / 32))) };
+    word & (1 << (source % 32)) != 0
+  }
+
+  fn enable_reg(&self, hart: usize, source: u32) -> *mut u32
+  {
+    self.reg(ENABLE + ENABLE_STRIDE * self.context(hart) + 4 * (source as usize / 32))
+  }
+
+  /// Lets `source` interrupt `hart`.
+  pub fn enable(&self, hart: usize, source: u32)
+  {
+    self.check(source);
+    let reg = self.enable_reg(hart, source);
+    unsafe {
+      ptr::write_volatile(reg, ptr::read_volatile(reg) | 1 << (source % 32));
+    }
+  }
+
+  /// Stops `source` from interrupting `hart`.
+  pub fn disable(&self, hart: usize, source: u32)
+  {
+    self.check(source);
+    let reg = self.enable_reg(hart, source);
+    unsafe {
+      ptr::write_volatile(reg, ptr::read_volatile(reg) & !(1 << (source % 32)));
+    }
+  }
+
+  /// Sets the priority a source must exceed to interrupt `hart`.
+  pub fn set_threshold(&self, hart: usize, threshold: u32)
+  {
+    let context = CONTEXT + CONTEXT_STRIDE * self.context(hart);
+    unsafe {
+      ptr::write_volatile(self.reg(context + THRESHOLD), threshold & MAX_PRIORITY);
+    }
+  }
+
+  /// Claims the highest priority pending source for `hart`, if any.
+  pub fn claim(&self, hart: usize) -> Option<u32>
+  {
+    let context = CONTEXT + CONTEXT_STRIDE * self.context(hart);
+    match unsafe { ptr::read_volatile(self.reg(context + CLAIM)) } {
+      0 => None,
+      source => Some(source),
+    }
+  }
+
+  /// Tells the PLIC that `hart` has finished with a claimed `source`,
+  /// which may then be raised again.
+  pub fn complete(&self, hart: usize, source: u32)
+  {
+    let context = CONTEXT + CONTEXT_STRIDE * self.context(hart);
+    unsafe {
+      ptr::write_volatile(self.reg(context + CLAIM), source);
+    }
+  }
+
+  /// Registers `handler` for `source`, gives the source `priority` and
+  /// routes it to `hart`.
+  pub fn attach(&self, hart: usize, source: u32, priority: u32, handler: Handler, ctx: *mut ())
+      -> Result<(), IrqError>
+  {
+    if source == 0 || source > self.sources {
+      return Err(IrqError::InvalidSource(source));
+    }
+
+    irq::register_external(source, handler, ctx)?;
+    self.set_priority(source, priority);
+    self.enable(hart, source);
+    Ok(())
+  }
+
+  /// Stops routing `source` to `hart` and removes its handler.
+  pub fn detach(&self, hart: usize, source: u32)
+  {
+    self.disable(hart, source);
+    irq::unregister_external(source);
+  }
+}
+
+/// Services the external interrupt of the current hart. `ctx` is the `Plic`.
+fn handle_external(ctx: *mut ())
+{
+  let plic = unsafe { &*(ctx as *const Plic) };
+  let hart = boot::hart_id();
+
+  while let Some(source) = plic.claim(hart) {
+    if !irq::dispatch_external(source) {
+      // Nobody wants it; keep it from firing again.
+      plic.disable(hart, source);
+    }
+    plic.complete(hart, source);
+  }
+}
+
+/// Finds the PLIC in the device tree and returns the MMIO range it
+/// occupies, so that it can be mapped before `enable` is called.
+pub fn init(fdt: &Fdt) -> Option<(usize, usize)>
+{
+  let (plic, mmio) = Plic::from_fdt(fdt, Interrupt::SupervisorExternal)?;
+  PLIC.call_once(|| plic);
+  Some(mmio)
+}
+
+/// Lets every source through on the current hart and hooks the PLIC into
+/// the hart's external interrupt.
+pub fn enable()
+{
+  let plic = PLIC.get().expect("PLIC not initialised");
+  plic.set_threshold(boot::hart_id(), 0);
+
+  // A second hart finds the handler already in place.
+  let _ = irq::register(Interrupt::SupervisorExternal, handle_external, plic as *const Plic as *mut ());
+  irq::enable(Interrupt::SupervisorExternal);
+}
@@ -0,0 +1,100 @@
+//! Interrupt handling for the console UART.
+//!
+//! The interrupt handler only moves received bytes into a buffer; the
+//! `console` thread echoes them. Printing takes locks which threads hold
+//! with interrupts enabled, so the handler must not print.
+
+use boot::irq;
+use system::alloc::spin;
+use system::alloc::uart::Uart;
+use system::console::print;
+
+use crate::sched::{self, ThreadId};
+
+/// How many received bytes can wait to be read; more are dropped.
+pub const RX_BUFFER_SIZE: usize = 256;
+
+/// The bytes received and not yet read, and the thread reading them.
+struct RxBuffer
+{
+  bytes: [u8; RX_BUFFER_SIZE],
+  head: usize,
+  len: usize,
+  reader: Option<ThreadId>,
+}
+
+impl RxBuffer
+{
+  fn push(&mut self, byte: u8)
+  {
+    if self.len < RX_BUFFER_SIZE {
+      self.bytes[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+      self.len += 1;
+    }
+  }
+
+  fn pop(&mut self) -> Option<u8>
+  {
+    if self.len == 0 {
+      return None;
+    }
+    let byte = self.bytes[self.head];
+    self.head = (self.head + 1) % RX_BUFFER_SIZE;
+    self.len -= 1;
+    Some(byte)
+  }
+}
+
+/// Only taken with interrupts masked.
+static RX: spin::Mutex<RxBuffer> = spin::Mutex::new(RxBuffer {
+  bytes: [0; RX_BUFFER_SIZE],
+  head: 0,
+  len: 0,
+  reader: None,
+});
+
+/// Moves every byte waiting in the console UART's receive buffer into
+/// `RX` and wakes its reader. `ctx` is the base address of the UART.
+pub fn handle_rx(ctx: *mut ())
+{
+  // Reading needs no lock: writers only touch the transmit register.
+  let uart = Uart::new(ctx as usize);
+  let reader = {
+    let mut rx = RX.lock();
+    while let Some(byte) = uart.read() {
+      rx.push(byte);
+    }
+    rx.reader
+  };
+
+  if let Some(reader) = reader {
+    sched::unpark(reader);
+  }
+}
+
+/// Takes the next received byte, waiting for one. Only one thread may read.
+pub fn read_byte() -> u8
+{
+  loop {
+    let byte = irq::free(|| {
+      let mut rx = RX.lock();
+      rx.reader = Some(sched::current());
+      rx.pop()
+    });
+    match byte {
+      Some(byte) => return byte,
+      None => sched::park(),
+    }
+  }
+}
+
+/// Echoes what is typed on the console. The entry of the `console` thread.
+pub fn echo(_arg: usize) -> usize
+{
+  loop {
+    match read_byte() {
+      b'\r' => print!("\n"),
+      byte => print!("{}", byte as char),
+    }
+  }
+}
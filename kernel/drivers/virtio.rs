@@ -0,0 +1,398 @@
+//! Virtio devices on the MMIO transport.
+//!
+//! `init` finds the `virtio,mmio` nodes in the device tree before paging is
+//! set up, so that their registers can be mapped; `probe` then reads them
+//! to learn which kind of device sits in each slot. A driver `take`s the
+//! transport of a device it handles, and builds a `Device` from it, which
+//! negotiates features, sets up its virtqueues, and waits for requests to
+//! complete either by polling or by sleeping until the device interrupts.
+
+use core::fmt::{self, Display};
+use core::hint;
+
+use boot::irq::{self, IrqError};
+use fdt::Fdt;
+use system::alloc::spin::Mutex;
+use system::alloc::{Array, Shared};
+use system::console::println;
+
+use crate::drivers::plic::PLIC;
+use crate::sched;
+
+pub mod blk;
+pub mod mmio;
+pub mod queue;
+
+pub use self::mmio::Transport;
+pub use self::queue::{Buffer, VirtQueue, QUEUE_SIZE};
+
+/// The compatible string of virtio-mmio device nodes.
+pub const COMPATIBLE: &str = "virtio,mmio";
+
+/// The most virtio-mmio slots that are looked at.
+pub const MAX_DEVICES: usize = 8;
+
+/// The priority device interrupts are given at the PLIC.
+const IRQ_PRIORITY: u32 = 1;
+
+/// Device IDs.
+pub mod id
+{
+  /// A network card.
+  pub const NET: u32 = 1;
+  /// A block device.
+  pub const BLOCK: u32 = 2;
+  /// A console.
+  pub const CONSOLE: u32 = 3;
+  /// A source of entropy.
+  pub const ENTROPY: u32 = 4;
+
+  /// What kind of device `id` is, for messages.
+  pub fn name(id: u32) -> &'static str
+  {
+    match id {
+      NET => "network",
+      BLOCK => "block",
+      CONSOLE => "console",
+      ENTROPY => "entropy",
+      _ => "unknown",
+    }
+  }
+}
+
+/// Feature bits which are not specific to a kind of device.
+pub mod feature
+{
+  /// Descriptors may point to tables of further descriptors.
+  pub const RING_INDIRECT_DESC: u64 = 1 << 28;
+  /// The rings carry event indices to suppress notifications.
+  pub const RING_EVENT_IDX: u64 = 1 << 29;
+  /// The device follows the virtio 1.x specification, not the legacy one.
+  pub const VERSION_1: u64 = 1 << 32;
+  /// The device's accesses go through an IOMMU.
+  pub const ACCESS_PLATFORM: u64 = 1 << 33;
+}
+
+/// Device status bits.
+pub mod status
+{
+  /// The driver has found the device.
+  pub const ACKNOWLEDGE: u32 = 1;
+  /// The driver knows how to drive it.
+  pub const DRIVER: u32 = 2;
+  /// The driver is ready, and the device may be used.
+  pub const DRIVER_OK: u32 = 4;
+  /// The device has accepted the features.
+  pub const FEATURES_OK: u32 = 8;
+  /// The device has hit an error it cannot recover from without a reset.
+  pub const DEVICE_NEEDS_RESET: u32 = 64;
+  /// The driver has given up on the device.
+  pub const FAILED: u32 = 128;
+}
+
+/// The ways driving a virtio device can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VirtioError
+{
+  /// The registers do not start with the virtio magic number.
+  BadMagic,
+  /// The transport is neither version 1 nor version 2.
+  UnsupportedVersion(u32),
+  /// The slot is empty.
+  NoDevice,
+  /// The device would not accept the features, lacks one it must have, or
+  /// has limits too small to work within.
+  FeaturesRejected,
+  /// The queue does not exist or is already in use.
+  QueueUnavailable(u16),
+  /// There is no memory for the queues.
+  NoMemory,
+  /// There are not enough free descriptors for the request.
+  QueueFull,
+  /// A request with no buffers, or for a queue that does not exist.
+  Invalid,
+  /// The device reported an error for a request.
+  Io,
+  /// The device's interrupt could not be handled.
+  Irq(IrqError),
+}
+
+impl Display for VirtioError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      VirtioError::BadMagic => write!(f, "not a virtio-mmio device"),
+      VirtioError::UnsupportedVersion(v) => write!(f, "unsupported virtio-mmio version {}", v),
+      VirtioError::NoDevice => write!(f, "no device"),
+      VirtioError::FeaturesRejected => write!(f, "features rejected"),
+      VirtioError::QueueUnavailable(q) => write!(f, "virtqueue {} unavailable", q),
+      VirtioError::NoMemory => write!(f, "out of memory for virtqueues"),
+      VirtioError::QueueFull => write!(f, "virtqueue full"),
+      VirtioError::Invalid => write!(f, "invalid request"),
+      VirtioError::Io => write!(f, "device I/O error"),
+      VirtioError::Irq(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<IrqError> for VirtioError
+{
+  fn from(e: IrqError) -> Self
+  {
+    VirtioError::Irq(e)
+  }
+}
+
+/// A slot found in the device tree.
+struct Slot
+{
+  base: usize,
+  irq: Option<u32>,
+  /// The device in it, until a driver takes it.
+  transport: Option<Transport>,
+}
+
+const NO_SLOT: Option<Slot> = None;
+
+static SLOTS: Mutex<[Option<Slot>; MAX_DEVICES]> = Mutex::new([NO_SLOT; MAX_DEVICES]);
+
+/// Records the enabled virtio-mmio nodes in the device tree, and returns
+/// the MMIO range which covers all of them, to be mapped before `probe`.
+pub fn init(fdt: &Fdt) -> Option<(usize, usize)>
+{
+  let mut slots = SLOTS.lock();
+  let mut range: Option<(usize, usize)> = None;
+
+  let nodes = fdt.compatible(COMPATIBLE).filter(|node| node.is_enabled());
+  for (slot, node) in slots.iter_mut().zip(nodes) {
+    let reg = match node.reg().and_then(|mut reg| reg.next()) {
+      Some(reg) => reg,
+      None => continue,
+    };
+    let (base, end) = (reg.base as usize, (reg.base + reg.size) as usize);
+    let irq = node.interrupts().and_then(|mut irqs| irqs.next());
+    *slot = Some(Slot { base, irq, transport: None });
+
+    range = Some(match range {
+      Some((start, limit)) => (start.min(base), limit.max(end)),
+      None => (base, end),
+    });
+  }
+
+  range.map(|(start, end)| (start, end - start))
+}
+
+/// Looks at the slots `init` found, once they are mapped, and reports the
+/// devices in them.
+pub fn probe()
+{
+  let mut slots = SLOTS.lock();
+  for slot in slots.iter_mut().flatten() {
+    match unsafe { Transport::new(slot.base, slot.irq) } {
+      Ok(transport) => {
+        println!(
+          "virtio: {} device at {:#x}, version {}",
+          id::name(transport.device_id()),
+          slot.base,
+          transport.version()
+        );
+        slot.transport = Some(transport);
+      }
+      Err(VirtioError::NoDevice) => {}
+      Err(e) => println!("virtio: slot at {:#x}: {}", slot.base, e),
+    }
+  }
+}
+
+/// Takes the first device of kind `device_id` which no driver has taken.
+pub fn take(device_id: u32) -> Option<Transport>
+{
+  SLOTS
+      .lock()
+      .iter_mut()
+      .flatten()
+      .find(|slot| slot.transport.as_ref().is_some_and(|t| t.device_id() == device_id))
+      .and_then(|slot| slot.transport.take())
+}
+
+/// How a thread waits for the device to finish a request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Completion
+{
+  /// Spin on the used ring, with the device's interrupts suppressed.
+  Polled,
+  /// Sleep until the device interrupts. Callers which cannot block, such
+  /// as interrupt handlers and code run before the scheduler, still poll.
+  Interrupt,
+}
+
+/// A device with its features negotiated and its queues set up.
+pub struct Device
+{
+  transport: Transport,
+  features: u64,
+  queues: Mutex<Array<VirtQueue>>,
+  completion: Completion,
+  /// The hart its interrupt is routed to, once attached.
+  irq_hart: Option<usize>,
+}
+
+impl Device
+{
+  /// Brings up the device on `transport` with the features it shares with
+  /// `supported`, and `queues` virtqueues. `Interrupt` completion falls
+  /// back to `Polled` if the device has no interrupt.
+  pub fn new(transport: Transport, supported: u64, queues: u16, completion: Completion)
+      -> Result<Shared<Self>, VirtioError>
+  {
+    let completion = match (completion, transport.irq(), PLIC.get()) {
+      (Completion::Interrupt, Some(_), Some(_)) => Completion::Interrupt,
+      _ => Completion::Polled,
+    };
+    let mut device = Self {
+      transport,
+      features: 0,
+      queues: Mutex::new(Array::new()),
+      completion,
+      irq_hart: None,
+    };
+    // From here on, dropping the device resets it.
+    device.features = device.transport.negotiate(supported)?;
+
+    for index in 0..queues {
+      let max = (device.transport.queue_max(index) as usize).min(QUEUE_SIZE);
+      if max == 0 {
+        return Err(VirtioError::QueueUnavailable(index));
+      }
+      // The largest power of two that fits.
+      let size = 1 << (usize::BITS - 1 - max.leading_zeros());
+
+      let mut queue = VirtQueue::new(index, size as u16)?;
+      queue.set_interrupts(completion == Completion::Interrupt);
+      device.transport.setup_queue(&queue)?;
+      device.queues.get_mut().push(queue);
+    }
+
+    let mut device = Shared::new(device);
+    if let (Completion::Interrupt, Some(source)) = (completion, device.transport.irq()) {
+      let hart = boot::hart_id();
+      let ctx = &*device as *const Self as *mut ();
+      PLIC.get().unwrap().attach(hart, source, IRQ_PRIORITY, handle_irq, ctx)?;
+      Shared::get_mut(&mut device).expect("new device is shared").irq_hart = Some(hart);
+    }
+
+    device.transport.driver_ok();
+    Ok(device)
+  }
+
+  /// The transport, for reading the device configuration.
+  #[inline]
+  pub fn transport(&self) -> &Transport
+  {
+    &self.transport
+  }
+
+  /// The features agreed on.
+  #[inline]
+  pub fn features(&self) -> u64
+  {
+    self.features
+  }
+
+  /// How requests are waited for.
+  #[inline]
+  pub fn completion(&self) -> Completion
+  {
+    self.completion
+  }
+
+  /// The number of descriptors in queue `queue`, as set up with the
+  /// device, or `None` if there is no such queue.
+  pub fn queue_size(&self, queue: u16) -> Option<u16>
+  {
+    self.queues.lock().get(queue as usize).map(VirtQueue::size)
+  }
+
+  /// Puts a request in queue `queue`, made of the buffers in `out` for the
+  /// device to read and then those in `inn` for it to write, and waits for
+  /// the device to finish with it. Returns the number of bytes written.
+  ///
+  /// If the queue has too few free descriptors, it waits for some. The
+  /// buffers must stay put until it returns, which borrowing them ensures
+  /// for the caller's memory.
+  pub fn submit(&self, queue: u16, out: &[Buffer], inn: &[Buffer]) -> Result<u32, VirtioError>
+  {
+    let sleep = self.completion == Completion::Interrupt && sched::can_block();
+
+    let head = loop {
+      let added = irq::free(|| {
+        let mut queues = self.queues.lock();
+        let vq = queues.get_mut(queue as usize).ok_or(VirtioError::Invalid)?;
+        let head = vq.add(out, inn)?;
+        if sleep {
+          vq.set_waiter(head, sched::current());
+        }
+        if vq.should_notify() {
+          self.transport.notify(queue);
+        }
+        Ok(head)
+      });
+
+      // Descriptors come free as the other requests in the queue finish.
+      match added {
+        Err(VirtioError::QueueFull) if sleep => sched::yield_now(),
+        Err(VirtioError::QueueFull) => hint::spin_loop(),
+        added => break added?,
+      }
+    };
+
+    loop {
+      let written = irq::free(|| {
+        let mut queues = self.queues.lock();
+        let vq = &mut queues[queue as usize];
+        vq.collect(sched::unpark);
+        vq.take(head)
+      });
+      if let Some(written) = written {
+        return Ok(written);
+      }
+
+      // An unpark left over from an earlier request only costs a turn
+      // around the loop.
+      if sleep {
+        sched::park();
+      } else {
+        hint::spin_loop();
+      }
+    }
+  }
+}
+
+impl Drop for Device
+{
+  fn drop(&mut self)
+  {
+    if let (Some(hart), Some(source)) = (self.irq_hart, self.transport.irq()) {
+      if let Some(plic) = PLIC.get() {
+        plic.detach(hart, source);
+      }
+    }
+    // The device must stop using the queues before their memory is freed.
+    self.transport.reset();
+  }
+}
+
+/// Handles a device's interrupt. `ctx` is the `Device`.
+fn handle_irq(ctx: *mut ())
+{
+  let device = unsafe { &*(ctx as *const Device) };
+  let pending = device.transport.interrupt_status();
+  device.transport.ack_interrupt(pending);
+
+  if pending & mmio::INT_USED_BUFFER != 0 {
+    for queue in device.queues.lock().iter_mut() {
+      queue.collect(sched::unpark);
+    }
+  }
+}
@@ -0,0 +1,301 @@
+//! Virtio block devices.
+//!
+//! Each request is a header naming the operation and the first sector, the
+//! data buffers, and a status byte the device writes last. The sectors are
+//! always 512 bytes, whatever block size the device prefers; a request
+//! covers whole blocks of that size.
+
+use core::mem::size_of;
+use core::ptr;
+
+use fs::block::{check_request, BlockDevice, BlockError, BlockResult};
+use system::alloc::Shared;
+use system::console::println;
+
+use super::{id, Buffer, Completion, Device, Transport, VirtioError};
+use crate::block;
+
+/// The unit requests address the device in.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The most data buffers in one request.
+pub const MAX_SEGMENTS: usize = 32;
+
+/// Feature bits of block devices.
+pub mod feature
+{
+  /// `size_max` is the most bytes in one buffer.
+  pub const SIZE_MAX: u64 = 1 << 1;
+  /// `seg_max` is the most buffers in one request.
+  pub const SEG_MAX: u64 = 1 << 2;
+  /// The device cannot be written.
+  pub const RO: u64 = 1 << 5;
+  /// `blk_size` is the block size the device prefers.
+  pub const BLK_SIZE: u64 = 1 << 6;
+  /// The device has a write cache, emptied by a flush request.
+  pub const FLUSH: u64 = 1 << 9;
+}
+
+// Configuration offsets.
+const CAPACITY: usize = 0;
+const SIZE_MAX: usize = 8;
+const SEG_MAX: usize = 12;
+const BLK_SIZE: usize = 20;
+
+// Request types.
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// Status values.
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// Every feature the driver knows what to do with.
+const SUPPORTED: u64 = feature::SIZE_MAX
+    | feature::SEG_MAX
+    | feature::RO
+    | feature::BLK_SIZE
+    | feature::FLUSH;
+
+/// The header which starts every request.
+#[repr(C)]
+struct Header
+{
+  kind: u32,
+  reserved: u32,
+  sector: u64,
+}
+
+/// A virtio block device.
+pub struct VirtioBlk
+{
+  device: Shared<Device>,
+  block_size: usize,
+  blocks: u64,
+  read_only: bool,
+  flush: bool,
+  /// The most data buffers in one request.
+  segments: usize,
+  /// The most bytes in one buffer, in whole blocks.
+  segment_size: usize,
+}
+
+impl VirtioBlk
+{
+  /// Brings up the block device on `transport`.
+  pub fn new(transport: Transport) -> Result<Self, VirtioError>
+  {
+    if transport.device_id() != id::BLOCK {
+      return Err(VirtioError::NoDevice);
+    }
+    let device = Device::new(transport, SUPPORTED, 1, Completion::Interrupt)?;
+    let features = device.features();
+
+    let block_size = match config_u32(&device, BLK_SIZE) {
+      size if features & feature::BLK_SIZE != 0 && is_block_size(size as usize) => size as usize,
+      _ => SECTOR_SIZE,
+    };
+    let sectors = config_u64(&device, CAPACITY);
+
+    // Two descriptors go to the header and the status, of those in the
+    // queue the device agreed to.
+    let queue_size = device.queue_size(0).ok_or(VirtioError::QueueUnavailable(0))? as usize;
+    if queue_size < 3 {
+      return Err(VirtioError::FeaturesRejected);
+    }
+    let mut segments = MAX_SEGMENTS.min(queue_size - 2);
+    if features & feature::SEG_MAX != 0 {
+      segments = segments.min(config_u32(&device, SEG_MAX).max(1) as usize);
+    }
+    let mut segment_size = u32::MAX as usize;
+    if features & feature::SIZE_MAX != 0 {
+      segment_size = config_u32(&device, SIZE_MAX) as usize;
+    }
+    // Each request moves whole blocks, so every buffer must hold one.
+    if segment_size < block_size {
+      return Err(VirtioError::FeaturesRejected);
+    }
+    let segment_size = segment_size / block_size * block_size;
+
+    Ok(Self {
+      block_size,
+      blocks: sectors / (block_size / SECTOR_SIZE) as u64,
+      read_only: features & feature::RO != 0,
+      flush: features & feature::FLUSH != 0,
+      segments,
+      segment_size,
+      device,
+    })
+  }
+
+  /// Sends one request of type `kind` from `sector` with the data in
+  /// `data`, which the device reads for `T_OUT` and writes otherwise.
+  fn request(&self, kind: u32, sector: u64, data: &[Buffer]) -> BlockResult<()>
+  {
+    let header = Header { kind, reserved: 0, sector };
+    let mut status = 0xffu8;
+    let header_buf = Buffer {
+      addr: &header as *const Header as usize,
+      len: size_of::<Header>() as u32,
+    };
+    let status_buf = Buffer { addr: &mut status as *mut u8 as usize, len: 1 };
+
+    let mut out = [header_buf; MAX_SEGMENTS + 1];
+    let mut inn = [status_buf; MAX_SEGMENTS + 1];
+    let (outs, ins) = if kind == T_OUT {
+      out[1..=data.len()].copy_from_slice(data);
+      (data.len() + 1, 1)
+    } else {
+      inn[..data.len()].copy_from_slice(data);
+      inn[data.len()] = status_buf;
+      (1, data.len() + 1)
+    };
+
+    self.device.submit(0, &out[..outs], &inn[..ins]).map_err(|_| BlockError::Io)?;
+    match unsafe { ptr::read_volatile(&status) } {
+      S_OK => Ok(()),
+      S_UNSUPP => Err(BlockError::Unsupported),
+      _ => Err(BlockError::Io),
+    }
+  }
+
+  /// Moves the blocks from `block` on to or from `bufs`, as few requests as
+  /// the device's limits allow.
+  fn transfer(&self, kind: u32, block: u64, bufs: impl Iterator<Item = (usize, usize)>)
+      -> BlockResult<()>
+  {
+    let sectors_per_block = (self.block_size / SECTOR_SIZE) as u64;
+    let mut sector = block * sectors_per_block;
+    let mut segs = [Buffer { addr: 0, len: 0 }; MAX_SEGMENTS];
+    let mut count = 0;
+    let mut bytes = 0;
+
+    for (addr, len) in bufs {
+      let mut offset = 0;
+      while offset < len {
+        let piece = (len - offset).min(self.segment_size);
+        segs[count] = Buffer { addr: addr + offset, len: piece as u32 };
+        count += 1;
+        bytes += piece;
+        offset += piece;
+
+        if count == self.segments {
+          self.request(kind, sector, &segs[..count])?;
+          sector += (bytes / SECTOR_SIZE) as u64;
+          count = 0;
+          bytes = 0;
+        }
+      }
+    }
+
+    if count != 0 {
+      self.request(kind, sector, &segs[..count])?;
+    }
+    Ok(())
+  }
+}
+
+fn is_block_size(size: usize) -> bool
+{
+  size >= SECTOR_SIZE && size.is_power_of_two()
+}
+
+fn config_u32(device: &Device, offset: usize) -> u32
+{
+  let mut bytes = [0; 4];
+  device.transport().read_config(offset, &mut bytes);
+  u32::from_le_bytes(bytes)
+}
+
+fn config_u64(device: &Device, offset: usize) -> u64
+{
+  let mut bytes = [0; 8];
+  device.transport().read_config(offset, &mut bytes);
+  u64::from_le_bytes(bytes)
+}
+
+impl BlockDevice for VirtioBlk
+{
+  fn block_size(&self) -> usize
+  {
+    self.block_size
+  }
+
+  fn blocks(&self) -> u64
+  {
+    self.blocks
+  }
+
+  fn read(&self, block: u64, buf: &mut [u8]) -> BlockResult<()>
+  {
+    self.read_vectored(block, &mut [buf])
+  }
+
+  fn write(&self, block: u64, buf: &[u8]) -> BlockResult<()>
+  {
+    self.write_vectored(block, &[buf])
+  }
+
+  fn read_vectored(&self, block: u64, bufs: &mut [&mut [u8]]) -> BlockResult<()>
+  {
+    let len = bufs.iter().map(|buf| buf.len()).sum();
+    check_request(self, block, len)?;
+    if bufs.iter().any(|buf| !buf.len().is_multiple_of(self.block_size)) {
+      return Err(BlockError::Misaligned);
+    }
+    self.transfer(T_IN, block, bufs.iter_mut().map(|buf| (buf.as_mut_ptr() as usize, buf.len())))
+  }
+
+  fn write_vectored(&self, block: u64, bufs: &[&[u8]]) -> BlockResult<()>
+  {
+    if self.read_only {
+      return Err(BlockError::ReadOnly);
+    }
+    let len = bufs.iter().map(|buf| buf.len()).sum();
+    check_request(self, block, len)?;
+    if bufs.iter().any(|buf| !buf.len().is_multiple_of(self.block_size)) {
+      return Err(BlockError::Misaligned);
+    }
+    self.transfer(T_OUT, block, bufs.iter().map(|buf| (buf.as_ptr() as usize, buf.len())))
+  }
+
+  fn flush(&self) -> BlockResult<()>
+  {
+    // Without a write cache every write is already on the disk.
+    if self.flush {
+      self.request(T_FLUSH, 0, &[])
+    } else {
+      Ok(())
+    }
+  }
+
+  fn is_read_only(&self) -> bool
+  {
+    self.read_only
+  }
+}
+
+/// Brings up every virtio block device `probe` found, and registers them
+/// as disks.
+pub fn init()
+{
+  while let Some(transport) = super::take(id::BLOCK) {
+    let base = transport.base();
+    match VirtioBlk::new(transport) {
+      Ok(disk) => {
+        println!(
+          "virtio-blk at {:#x}: {} blocks of {} bytes{}",
+          base,
+          disk.blocks(),
+          disk.block_size(),
+          if disk.is_read_only() { ", read-only" } else { "" }
+        );
+        if block::register(Shared::new(disk)).is_none() {
+          println!("virtio-blk at {:#x}: too many disks", base);
+        }
+      }
+      Err(e) => println!("virtio-blk at {:#x}: {}", base, e),
+    }
+  }
+}
@@ -0,0 +1,316 @@
+//! The virtio-mmio transport: a device's registers in a page of MMIO.
+//!
+//! Version 2 is the one the virtio 1.x specification describes; version 1
+//! is the legacy interface QEMU still offers by default, which places each
+//! queue by page frame number and knows nothing of `FEATURES_OK`.
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use system::alloc::alloc::page::PAGE_SIZE;
+
+use super::queue::VirtQueue;
+use super::{feature, status, VirtioError};
+
+/// "virt", little endian.
+pub const MAGIC: u32 = 0x7472_6976;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// The device has used buffers.
+pub const INT_USED_BUFFER: u32 = 1 << 0;
+/// The device's configuration has changed.
+pub const INT_CONFIG_CHANGE: u32 = 1 << 1;
+
+/// A virtio-mmio device.
+#[derive(Debug)]
+pub struct Transport
+{
+  base: usize,
+  version: u32,
+  device_id: u32,
+  vendor_id: u32,
+  irq: Option<u32>,
+}
+
+impl Transport
+{
+  /// The device at `base`, raising external interrupt `irq`. Returns
+  /// `NoDevice` for an empty slot, which QEMU fills with device ID 0.
+  ///
+  /// # Safety
+  /// `base` must be the mapped registers of a virtio-mmio device, which
+  /// nothing else drives.
+  pub unsafe fn new(base: usize, irq: Option<u32>) -> Result<Self, VirtioError>
+  {
+    let mut transport = Self { base, version: 0, device_id: 0, vendor_id: 0, irq };
+    if transport.read(MAGIC_VALUE) != MAGIC {
+      return Err(VirtioError::BadMagic);
+    }
+    transport.version = transport.read(VERSION);
+    if transport.version != 1 && transport.version != 2 {
+      return Err(VirtioError::UnsupportedVersion(transport.version));
+    }
+    transport.device_id = transport.read(DEVICE_ID);
+    if transport.device_id == 0 {
+      return Err(VirtioError::NoDevice);
+    }
+    transport.vendor_id = transport.read(VENDOR_ID);
+    Ok(transport)
+  }
+
+  /// The address of the registers.
+  #[inline]
+  pub fn base(&self) -> usize
+  {
+    self.base
+  }
+
+  /// 1 for a legacy device, 2 for a modern one.
+  #[inline]
+  pub fn version(&self) -> u32
+  {
+    self.version
+  }
+
+  /// What kind of device it is; one of `virtio::id`.
+  #[inline]
+  pub fn device_id(&self) -> u32
+  {
+    self.device_id
+  }
+
+  /// Who made it.
+  #[inline]
+  pub fn vendor_id(&self) -> u32
+  {
+    self.vendor_id
+  }
+
+  /// The external interrupt it raises, if the device tree says.
+  #[inline]
+  pub fn irq(&self) -> Option<u32>
+  {
+    self.irq
+  }
+
+  fn read(&self, offset: usize) -> u32
+  {
+    unsafe { ptr::read_volatile((self.base + offset) as *const u32) }
+  }
+
+  fn write(&self, offset: usize, value: u32)
+  {
+    unsafe { ptr::write_volatile((self.base + offset) as *mut u32, value) }
+  }
+
+  /// The device status, as `virtio::status` bits.
+  #[inline]
+  pub fn status(&self) -> u32
+  {
+    self.read(STATUS)
+  }
+
+  fn add_status(&self, bits: u32)
+  {
+    self.write(STATUS, self.status() | bits);
+  }
+
+  /// Resets the device, which forgets its queues and features and stops
+  /// touching their memory.
+  pub fn reset(&self)
+  {
+    self.write(STATUS, 0);
+    while self.status() != 0 {
+      core::hint::spin_loop();
+    }
+  }
+
+  /// Tells the device the driver has given up on it.
+  #[inline]
+  pub fn fail(&self)
+  {
+    self.add_status(status::FAILED);
+  }
+
+  /// Tells the device the driver is ready to use it.
+  #[inline]
+  pub fn driver_ok(&self)
+  {
+    self.add_status(status::DRIVER_OK);
+  }
+
+  /// Every feature the device offers.
+  pub fn device_features(&self) -> u64
+  {
+    self.write(DEVICE_FEATURES_SEL, 0);
+    let low = self.read(DEVICE_FEATURES);
+    self.write(DEVICE_FEATURES_SEL, 1);
+    let high = self.read(DEVICE_FEATURES);
+    (high as u64) << 32 | low as u64
+  }
+
+  /// Resets the device and agrees on the features both it and the driver
+  /// support, of `supported`. Returns them.
+  ///
+  /// A modern device must offer `VERSION_1`, and may reject the features;
+  /// a legacy device never offers it, and must take what it is given.
+  pub fn negotiate(&self, supported: u64) -> Result<u64, VirtioError>
+  {
+    self.reset();
+    self.add_status(status::ACKNOWLEDGE);
+    self.add_status(status::DRIVER);
+
+    let offered = self.device_features();
+    let mut features = offered & supported;
+    if self.version == 2 {
+      if offered & feature::VERSION_1 == 0 {
+        self.fail();
+        return Err(VirtioError::FeaturesRejected);
+      }
+      features |= feature::VERSION_1;
+    } else {
+      features &= !feature::VERSION_1;
+    }
+
+    self.write(DRIVER_FEATURES_SEL, 0);
+    self.write(DRIVER_FEATURES, features as u32);
+    self.write(DRIVER_FEATURES_SEL, 1);
+    self.write(DRIVER_FEATURES, (features >> 32) as u32);
+
+    if self.version == 2 {
+      self.add_status(status::FEATURES_OK);
+      if self.status() & status::FEATURES_OK == 0 {
+        self.fail();
+        return Err(VirtioError::FeaturesRejected);
+      }
+    } else {
+      self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+    }
+    Ok(features)
+  }
+
+  /// The most descriptors queue `index` may have; 0 if there is no such
+  /// queue.
+  pub fn queue_max(&self, index: u16) -> u16
+  {
+    self.write(QUEUE_SEL, index as u32);
+    self.read(QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+  }
+
+  /// Tells the device where `queue` is, and that it may be used.
+  pub fn setup_queue(&self, queue: &VirtQueue) -> Result<(), VirtioError>
+  {
+    let index = queue.index();
+    let max = self.queue_max(index);
+    if max == 0 || queue.size() > max {
+      return Err(VirtioError::QueueUnavailable(index));
+    }
+
+    if self.version == 2 {
+      if self.read(QUEUE_READY) != 0 {
+        return Err(VirtioError::QueueUnavailable(index));
+      }
+      self.write(QUEUE_NUM, queue.size() as u32);
+      self.write_addr(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, queue.desc_addr());
+      self.write_addr(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, queue.avail_addr());
+      self.write_addr(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, queue.used_addr());
+      self.write(QUEUE_READY, 1);
+    } else {
+      if self.read(QUEUE_PFN) != 0 {
+        return Err(VirtioError::QueueUnavailable(index));
+      }
+      self.write(QUEUE_NUM, queue.size() as u32);
+      self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+      self.write(QUEUE_PFN, (queue.desc_addr() / PAGE_SIZE) as u32);
+    }
+    Ok(())
+  }
+
+  fn write_addr(&self, low: usize, high: usize, addr: usize)
+  {
+    self.write(low, addr as u32);
+    self.write(high, (addr as u64 >> 32) as u32);
+  }
+
+  /// Tells the device there are new buffers in queue `index`.
+  pub fn notify(&self, index: u16)
+  {
+    // The rings must be written before the device is told to look.
+    fence(Ordering::SeqCst);
+    self.write(QUEUE_NOTIFY, index as u32);
+  }
+
+  /// Why the device interrupted, as `INT_*` bits.
+  #[inline]
+  pub fn interrupt_status(&self) -> u32
+  {
+    self.read(INTERRUPT_STATUS)
+  }
+
+  /// Acknowledges the interrupts in `bits`.
+  #[inline]
+  pub fn ack_interrupt(&self, bits: u32)
+  {
+    self.write(INTERRUPT_ACK, bits);
+  }
+
+  /// Reads `buf.len()` bytes of the device configuration from `offset`,
+  /// all from one generation of it.
+  pub fn read_config(&self, offset: usize, buf: &mut [u8])
+  {
+    loop {
+      let generation = self.config_generation();
+      for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile((self.base + CONFIG + offset + i) as *const u8) };
+      }
+      if self.config_generation() == generation {
+        return;
+      }
+    }
+  }
+
+  /// Writes `buf` into the device configuration at `offset`.
+  pub fn write_config(&self, offset: usize, buf: &[u8])
+  {
+    for (i, &byte) in buf.iter().enumerate() {
+      unsafe { ptr::write_volatile((self.base + CONFIG + offset + i) as *mut u8, byte) };
+    }
+  }
+
+  fn config_generation(&self) -> u32
+  {
+    // Legacy devices have no generation counter, and read as 0.
+    if self.version == 2 {
+      self.read(CONFIG_GENERATION)
+    } else {
+      0
+    }
+  }
+}
@@ -0,0 +1,540 @@
+//! Split virtqueues.
+//!
+//! A queue is three rings in memory shared with the device: the descriptor
+//! table, each entry naming one buffer and the one chained after it; the
+//! available ring, where the driver puts the heads of chains for the device
+//! to process; and the used ring, where the device puts them back with the
+//! number of bytes it wrote. The layout is the legacy one, with the used
+//! ring on a page boundary, which modern devices accept as well.
+
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use system::alloc::alloc::page::PAGE_SIZE;
+
+use super::VirtioError;
+use crate::drivers::dma::Dma;
+use crate::sched::ThreadId;
+
+/// The number of descriptors in each queue, if the device allows as many.
+pub const QUEUE_SIZE: usize = 128;
+
+/// The descriptor is followed by the one in `next`.
+pub const DESC_F_NEXT: u16 = 1;
+/// The device writes the buffer, rather than reading it.
+pub const DESC_F_WRITE: u16 = 2;
+/// The driver does not want to be interrupted when buffers are used.
+pub const AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// The device does not need to be notified when buffers are made available.
+pub const USED_F_NO_NOTIFY: u16 = 1;
+
+/// An entry of the descriptor table.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Descriptor
+{
+  /// The physical address of the buffer.
+  pub addr: u64,
+  /// The length of the buffer in bytes.
+  pub len: u32,
+  /// `DESC_F_*` flags.
+  pub flags: u16,
+  /// The next descriptor in the chain, with `DESC_F_NEXT`.
+  pub next: u16,
+}
+
+/// An entry of the used ring.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UsedElem
+{
+  /// The head of the chain the device has finished with.
+  pub id: u32,
+  /// The number of bytes it wrote into the chain.
+  pub len: u32,
+}
+
+/// A buffer to hand to a device: a physical address and a length.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Buffer
+{
+  /// The physical address of the first byte.
+  pub addr: usize,
+  /// The length in bytes.
+  pub len: u32,
+}
+
+impl Buffer
+{
+  /// The buffer `bytes` occupies. RAM is identity mapped, so its address
+  /// is the one the device needs.
+  #[inline]
+  pub fn from_slice(bytes: &[u8]) -> Self
+  {
+    Self { addr: bytes.as_ptr() as usize, len: bytes.len() as u32 }
+  }
+}
+
+/// Where each ring of a queue of `size` descriptors is, relative to the
+/// descriptor table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RingLayout
+{
+  /// The number of descriptors.
+  pub size: u16,
+  /// The offset of the available ring.
+  pub avail: usize,
+  /// The offset of the used ring.
+  pub used: usize,
+  /// The bytes the whole queue occupies.
+  pub total: usize,
+}
+
+impl RingLayout
+{
+  /// The layout of a queue of `size` descriptors; `size` must be a power
+  /// of two.
+  pub fn new(size: u16) -> Self
+  {
+    assert!(size.is_power_of_two(), "virtqueue size {} is not a power of two", size);
+    let n = size as usize;
+    let avail = n * size_of::<Descriptor>();
+    // flags, idx, ring and used_event.
+    let used = align_up(avail + 2 * (3 + n), PAGE_SIZE);
+    // flags, idx, ring and avail_event.
+    let total = used + 2 * 3 + n * size_of::<UsedElem>();
+    Self { size, avail, used, total }
+  }
+}
+
+fn align_up(x: usize, align: usize) -> usize
+{
+  (x + align - 1) & !(align - 1)
+}
+
+/// What the driver knows about a chain it has made available.
+#[derive(Copy, Clone, Debug, Default)]
+struct Chain
+{
+  /// The number of descriptors in it; 0 if the head is free.
+  len: u16,
+  /// The bytes the device wrote, once it has used the chain.
+  used: Option<u32>,
+  /// The thread to wake when the device uses it.
+  waiter: Option<ThreadId>,
+}
+
+/// A split virtqueue, and the bookkeeping for the chains in it.
+pub struct VirtQueue
+{
+  index: u16,
+  layout: RingLayout,
+  base: usize,
+  free_head: u16,
+  num_free: u16,
+  /// The available index the driver publishes next.
+  avail_idx: u16,
+  /// The used index up to which entries have been collected.
+  last_used: u16,
+  chains: [Chain; QUEUE_SIZE],
+  _memory: Option<Dma>,
+}
+
+// The rings are only touched through `&mut self`, and the device.
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue
+{
+  /// Queue `index` of `size` descriptors, in pages taken for it.
+  pub fn new(index: u16, size: u16) -> Result<Self, VirtioError>
+  {
+    let layout = RingLayout::new(size);
+    let memory = Dma::for_bytes(layout.total).ok_or(VirtioError::NoMemory)?;
+    let mut queue = unsafe { Self::from_raw(index, size, memory.addr()) };
+    queue._memory = Some(memory);
+    Ok(queue)
+  }
+
+  /// Queue `index` of `size` descriptors, in the zeroed memory at `base`.
+  ///
+  /// # Safety
+  /// `base` must be page aligned and hold `RingLayout::new(size).total`
+  /// zeroed bytes, which outlive the queue and nothing else uses.
+  pub unsafe fn from_raw(index: u16, size: u16, base: usize) -> Self
+  {
+    assert!(size as usize <= QUEUE_SIZE, "virtqueue size {} is too large", size);
+    let layout = RingLayout::new(size);
+    let mut queue = Self {
+      index,
+      layout,
+      base,
+      free_head: 0,
+      num_free: size,
+      avail_idx: 0,
+      last_used: 0,
+      chains: [Chain::default(); QUEUE_SIZE],
+      _memory: None,
+    };
+
+    for i in 0..size {
+      queue.desc(i).next = i.wrapping_add(1);
+    }
+    queue
+  }
+
+  /// The queue's index on its device.
+  #[inline]
+  pub fn index(&self) -> u16
+  {
+    self.index
+  }
+
+  /// The number of descriptors.
+  #[inline]
+  pub fn size(&self) -> u16
+  {
+    self.layout.size
+  }
+
+  /// The number of descriptors not in a chain.
+  #[inline]
+  pub fn num_free(&self) -> u16
+  {
+    self.num_free
+  }
+
+  /// The physical address of the descriptor table.
+  #[inline]
+  pub fn desc_addr(&self) -> usize
+  {
+    self.base
+  }
+
+  /// The physical address of the available ring.
+  #[inline]
+  pub fn avail_addr(&self) -> usize
+  {
+    self.base + self.layout.avail
+  }
+
+  /// The physical address of the used ring.
+  #[inline]
+  pub fn used_addr(&self) -> usize
+  {
+    self.base + self.layout.used
+  }
+
+  fn desc(&mut self, i: u16) -> &mut Descriptor
+  {
+    debug_assert!(i < self.layout.size);
+    unsafe { &mut *(self.base as *mut Descriptor).add(i as usize) }
+  }
+
+  /// Field `i` of the available ring, counting flags and idx.
+  fn avail(&self, i: usize) -> *mut u16
+  {
+    unsafe { (self.avail_addr() as *mut u16).add(i) }
+  }
+
+  fn used_flags(&self) -> u16
+  {
+    unsafe { ptr::read_volatile(self.used_addr() as *const u16) }
+  }
+
+  fn used_idx(&self) -> u16
+  {
+    unsafe { ptr::read_volatile((self.used_addr() as *const u16).add(1)) }
+  }
+
+  fn used_elem(&self, slot: u16) -> UsedElem
+  {
+    let ring = (self.used_addr() + 4) as *const UsedElem;
+    unsafe { ptr::read_volatile(ring.add((slot % self.layout.size) as usize)) }
+  }
+
+  /// Makes a chain of the buffers in `out`, for the device to read,
+  /// followed by those in `inn`, for it to write, and puts it in the
+  /// available ring. Returns its head, which names it until `take`.
+  pub fn add(&mut self, out: &[Buffer], inn: &[Buffer]) -> Result<u16, VirtioError>
+  {
+    let count = out.len() + inn.len();
+    if count == 0 || count > self.layout.size as usize {
+      return Err(VirtioError::Invalid);
+    }
+    if count > self.num_free as usize {
+      return Err(VirtioError::QueueFull);
+    }
+
+    let head = self.free_head;
+    let mut last = head;
+    let buffers = out.iter().map(|b| (b, 0)).chain(inn.iter().map(|b| (b, DESC_F_WRITE)));
+    for (i, (buffer, flags)) in buffers.enumerate() {
+      let desc = self.desc(last);
+      desc.addr = buffer.addr as u64;
+      desc.len = buffer.len;
+      desc.flags = if i + 1 < count { flags | DESC_F_NEXT } else { flags };
+      if i + 1 < count {
+        last = desc.next;
+      }
+    }
+    self.free_head = self.desc(last).next;
+    self.num_free -= count as u16;
+    self.chains[head as usize] = Chain { len: count as u16, used: None, waiter: None };
+
+    let slot = (self.avail_idx % self.layout.size) as usize;
+    unsafe {
+      ptr::write_volatile(self.avail(2 + slot), head);
+    }
+    // The device must see the entry before the index that publishes it.
+    fence(Ordering::SeqCst);
+    self.avail_idx = self.avail_idx.wrapping_add(1);
+    unsafe {
+      ptr::write_volatile(self.avail(1), self.avail_idx);
+    }
+    Ok(head)
+  }
+
+  /// Returns true if the device wants to be notified of new buffers.
+  pub fn should_notify(&self) -> bool
+  {
+    // The index must be published before the flag is read.
+    fence(Ordering::SeqCst);
+    self.used_flags() & USED_F_NO_NOTIFY == 0
+  }
+
+  /// Asks the device to interrupt, or not, when it uses buffers. It may
+  /// interrupt anyway; the request only saves work.
+  pub fn set_interrupts(&mut self, enabled: bool)
+  {
+    let flags = if enabled { 0 } else { AVAIL_F_NO_INTERRUPT };
+    unsafe {
+      ptr::write_volatile(self.avail(0), flags);
+    }
+  }
+
+  /// Has `waiter` woken when the device uses the chain at `head`.
+  pub fn set_waiter(&mut self, head: u16, waiter: ThreadId)
+  {
+    self.chains[head as usize].waiter = Some(waiter);
+  }
+
+  /// Records every chain the device has used since the last call, and
+  /// passes the waiters of those chains to `wake`. Returns how many there
+  /// were.
+  pub fn collect(&mut self, mut wake: impl FnMut(ThreadId)) -> usize
+  {
+    let used = self.used_idx();
+    // The entries are read after the index that publishes them.
+    fence(Ordering::SeqCst);
+
+    let mut count = 0;
+    while self.last_used != used {
+      let elem = self.used_elem(self.last_used);
+      self.last_used = self.last_used.wrapping_add(1);
+      count += 1;
+
+      let chain = match self.chains.get_mut(elem.id as usize) {
+        Some(chain) if chain.len != 0 => chain,
+        // A device which hands back what it was never given is broken;
+        // there is nothing to record.
+        _ => continue,
+      };
+      chain.used = Some(elem.len);
+      if let Some(waiter) = chain.waiter.take() {
+        wake(waiter);
+      }
+    }
+    count
+  }
+
+  /// Returns true if the device has used the chain at `head`, once it has
+  /// been collected.
+  #[inline]
+  pub fn is_used(&self, head: u16) -> bool
+  {
+    self.chains[head as usize].used.is_some()
+  }
+
+  /// Frees the chain at `head` if the device has used it, and returns the
+  /// number of bytes it wrote.
+  pub fn take(&mut self, head: u16) -> Option<u32>
+  {
+    let chain = self.chains[head as usize];
+    let written = chain.used?;
+    self.chains[head as usize] = Chain::default();
+
+    let mut last = head;
+    for _ in 1..chain.len {
+      last = self.desc(last).next;
+    }
+    let free_head = self.free_head;
+    let desc = self.desc(last);
+    desc.flags = 0;
+    desc.next = free_head;
+    self.free_head = head;
+    self.num_free += chain.len;
+    Some(written)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[repr(C, align(4096))]
+  #[derive(Copy, Clone)]
+  struct Page([u8; PAGE_SIZE]);
+
+  /// Zeroed, page aligned memory for a queue.
+  struct Rings(Box<[Page]>);
+
+  impl Rings
+  {
+    fn new(size: u16) -> Self
+    {
+      let pages = RingLayout::new(size).total.div_ceil(PAGE_SIZE);
+      Self(vec![Page([0; PAGE_SIZE]); pages].into_boxed_slice())
+    }
+
+    fn base(&mut self) -> usize
+    {
+      self.0.as_mut_ptr() as usize
+    }
+  }
+
+  /// Plays the device: takes the next available chain, and puts it in the
+  /// used ring as having had `written` bytes written.
+  fn device_use(queue: &VirtQueue, next: &mut u16, written: u32) -> Option<Descriptor>
+  {
+    unsafe {
+      let avail_idx = ptr::read_volatile(queue.avail(1));
+      if *next == avail_idx {
+        return None;
+      }
+      let slot = (*next % queue.size()) as usize;
+      let head = ptr::read_volatile(queue.avail(2 + slot));
+      *next = next.wrapping_add(1);
+
+      let used = queue.used_addr() as *mut u16;
+      let idx = ptr::read_volatile(used.add(1));
+      let ring = (queue.used_addr() + 4) as *mut UsedElem;
+      let elem = UsedElem { id: head as u32, len: written };
+      ptr::write_volatile(ring.add((idx % queue.size()) as usize), elem);
+      ptr::write_volatile(used.add(1), idx.wrapping_add(1));
+      Some(*(queue.desc_addr() as *const Descriptor).add(head as usize))
+    }
+  }
+
+  #[test]
+  fn layout()
+  {
+    let layout = RingLayout::new(128);
+    assert_eq!(layout.avail, 2048);
+    assert_eq!(layout.used, PAGE_SIZE);
+    assert_eq!(layout.total, PAGE_SIZE + 6 + 128 * 8);
+  }
+
+  #[test]
+  fn chains()
+  {
+    let mut rings = Rings::new(8);
+    let mut queue = unsafe { VirtQueue::from_raw(0, 8, rings.base()) };
+    let header = [0u8; 16];
+    let data = [0u8; 512];
+    let status = [0u8; 1];
+
+    let out = [Buffer::from_slice(&header)];
+    let inn = [Buffer::from_slice(&data), Buffer::from_slice(&status)];
+    let head = queue.add(&out, &inn).unwrap();
+    assert_eq!(queue.num_free(), 5);
+
+    let mut desc = unsafe { *(queue.desc_addr() as *const Descriptor).add(head as usize) };
+    let mut seen = [(0, 0); 3];
+    for (i, entry) in seen.iter_mut().enumerate() {
+      *entry = (desc.len, desc.flags);
+      if i < 2 {
+        desc = unsafe { *(queue.desc_addr() as *const Descriptor).add(desc.next as usize) };
+      }
+    }
+    assert_eq!(seen, [
+      (16, DESC_F_NEXT),
+      (512, DESC_F_NEXT | DESC_F_WRITE),
+      (1, DESC_F_WRITE)
+    ]);
+
+    let mut next = 0;
+    assert_eq!(queue.take(head), None);
+    assert!(device_use(&queue, &mut next, 513).is_some());
+    assert_eq!(queue.collect(|_| {}), 1);
+    assert_eq!(queue.take(head), Some(513));
+    assert_eq!(queue.take(head), None);
+    assert_eq!(queue.num_free(), 8);
+  }
+
+  #[test]
+  fn full()
+  {
+    let mut rings = Rings::new(4);
+    let mut queue = unsafe { VirtQueue::from_raw(0, 4, rings.base()) };
+    let bytes = [0u8; 4];
+    let buffer = Buffer::from_slice(&bytes);
+
+    assert_eq!(queue.add(&[], &[]), Err(VirtioError::Invalid));
+    assert_eq!(queue.add(&[buffer; 5], &[]), Err(VirtioError::Invalid));
+    let a = queue.add(&[buffer; 3], &[]).unwrap();
+    assert_eq!(queue.add(&[buffer; 2], &[]), Err(VirtioError::QueueFull));
+    let b = queue.add(&[buffer], &[]).unwrap();
+    assert_eq!(queue.num_free(), 0);
+
+    // Descriptors freed out of order are reused.
+    let mut next = 0;
+    device_use(&queue, &mut next, 0);
+    device_use(&queue, &mut next, 0);
+    queue.collect(|_| {});
+    assert_eq!(queue.take(b), Some(0));
+    assert_eq!(queue.add(&[buffer; 2], &[]), Err(VirtioError::QueueFull));
+    assert_eq!(queue.take(a), Some(0));
+    queue.add(&[buffer; 4], &[]).unwrap();
+  }
+
+  #[test]
+  fn wraps()
+  {
+    let mut rings = Rings::new(4);
+    let mut queue = unsafe { VirtQueue::from_raw(0, 4, rings.base()) };
+    let bytes = [0u8; 4];
+    let buffer = Buffer::from_slice(&bytes);
+
+    let mut next = 0;
+    for i in 0..10u32 {
+      let head = queue.add(&[buffer], &[buffer]).unwrap();
+      queue.set_waiter(head, ThreadId(i as usize));
+      device_use(&queue, &mut next, i);
+
+      let mut woken = None;
+      assert_eq!(queue.collect(|id| woken = Some(id)), 1);
+      assert_eq!(woken, Some(ThreadId(i as usize)));
+      assert_eq!(queue.take(head), Some(i));
+    }
+    assert_eq!(queue.num_free(), 4);
+  }
+
+  #[test]
+  fn notify_and_interrupts()
+  {
+    let mut rings = Rings::new(4);
+    let mut queue = unsafe { VirtQueue::from_raw(0, 4, rings.base()) };
+    assert!(queue.should_notify());
+    unsafe {
+      ptr::write_volatile(queue.used_addr() as *mut u16, USED_F_NO_NOTIFY);
+    }
+    assert!(!queue.should_notify());
+
+    queue.set_interrupts(false);
+    assert_eq!(unsafe { ptr::read_volatile(queue.avail(0)) }, AVAIL_F_NO_INTERRUPT);
+    queue.set_interrupts(true);
+    assert_eq!(unsafe { ptr::read_volatile(queue.avail(0)) }, 0);
+  }
+}
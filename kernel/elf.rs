@@ -0,0 +1,355 @@
+//! ELF64 executables.
+//!
+//! Only what loading a static RISC-V executable needs: the file header and
+//! the program headers, checked against the file they come from so that
+//! every offset and address handed out afterwards is in range. Section
+//! headers are never looked at.
+
+use core::fmt::{self, Display};
+
+/// The size of the ELF64 file header.
+pub const HEADER_SIZE: usize = 64;
+
+/// The size of an ELF64 program header.
+pub const PHDR_SIZE: usize = 56;
+
+/// `e_machine` for RISC-V.
+pub const EM_RISCV: u16 = 243;
+
+/// `e_type` of an executable linked at a fixed address.
+pub const ET_EXEC: u16 = 2;
+
+/// Program header type: a segment to load.
+pub const PT_LOAD: u32 = 1;
+/// Program header type: the path of a dynamic linker.
+pub const PT_INTERP: u32 = 3;
+
+/// Segment flag: executable.
+pub const PF_X: u32 = 1 << 0;
+/// Segment flag: writable.
+pub const PF_W: u32 = 1 << 1;
+/// Segment flag: readable.
+pub const PF_R: u32 = 1 << 2;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const VERSION: u8 = 1;
+
+/// Why an image is not an executable we can load.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ElfError
+{
+  /// The image ends before a header or segment it describes.
+  Truncated,
+  /// The image does not start with the ELF magic.
+  BadMagic,
+  /// The image is not 64 bit, little endian, ELF version 1.
+  Unsupported,
+  /// The image is not for RISC-V.
+  WrongMachine(u16),
+  /// The image is not a fixed address executable.
+  NotExecutable(u16),
+  /// The image needs a dynamic linker.
+  Dynamic,
+  /// The program header at this index is inconsistent.
+  BadSegment(usize),
+  /// The entry point is not in an executable segment.
+  BadEntry(u64),
+}
+
+impl Display for ElfError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      ElfError::Truncated => write!(f, "truncated ELF image"),
+      ElfError::BadMagic => write!(f, "not an ELF image"),
+      ElfError::Unsupported => write!(f, "not a little endian ELF64 image"),
+      ElfError::WrongMachine(machine) => write!(f, "ELF image for machine {}", machine),
+      ElfError::NotExecutable(kind) => write!(f, "ELF image of type {} is not executable", kind),
+      ElfError::Dynamic => write!(f, "ELF image is dynamically linked"),
+      ElfError::BadSegment(index) => write!(f, "bad ELF program header {}", index),
+      ElfError::BadEntry(entry) => write!(f, "ELF entry point {:#x} is not executable", entry),
+    }
+  }
+}
+
+fn read_u16(data: &[u8], off: usize) -> Result<u16, ElfError>
+{
+  let bytes = data.get(off..off + 2).ok_or(ElfError::Truncated)?;
+  Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32, ElfError>
+{
+  let bytes = data.get(off..off + 4).ok_or(ElfError::Truncated)?;
+  Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], off: usize) -> Result<u64, ElfError>
+{
+  Ok(read_u32(data, off)? as u64 | (read_u32(data, off + 4)? as u64) << 32)
+}
+
+/// A program header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Segment
+{
+  /// `p_type`: one of the `PT_*` constants, or another.
+  pub kind: u32,
+  /// `p_flags`: the `PF_*` permissions.
+  pub flags: u32,
+  /// Where the segment's contents start in the image.
+  pub offset: u64,
+  /// Where the segment is loaded.
+  pub vaddr: u64,
+  /// How many bytes of it the image holds.
+  pub filesz: u64,
+  /// How many bytes it takes in memory; the rest past `filesz` is zeroed.
+  pub memsz: u64,
+  /// The alignment `vaddr` and `offset` agree to.
+  pub align: u64,
+}
+
+impl Segment
+{
+  fn parse(data: &[u8], off: usize) -> Result<Self, ElfError>
+  {
+    Ok(Self {
+      kind: read_u32(data, off)?,
+      flags: read_u32(data, off + 4)?,
+      offset: read_u64(data, off + 8)?,
+      vaddr: read_u64(data, off + 16)?,
+      filesz: read_u64(data, off + 32)?,
+      memsz: read_u64(data, off + 40)?,
+      align: read_u64(data, off + 48)?,
+    })
+  }
+
+  /// Returns true if the segment is loaded.
+  #[inline]
+  pub fn is_load(&self) -> bool
+  {
+    self.kind == PT_LOAD
+  }
+
+  /// The end of the segment in memory.
+  #[inline]
+  pub fn end(&self) -> u64
+  {
+    self.vaddr + self.memsz
+  }
+
+  /// Returns true if `vaddr` is in the segment.
+  #[inline]
+  pub fn contains(&self, vaddr: u64) -> bool
+  {
+    self.vaddr <= vaddr && vaddr < self.end()
+  }
+
+  /// Checks the segment fits in an image of `len` bytes and in the
+  /// address space, and that its alignment is consistent.
+  fn check(&self, len: usize) -> bool
+  {
+    let in_image = self.offset.checked_add(self.filesz).map_or(false, |end| end <= len as u64);
+    let aligned = match self.align {
+      0 | 1 => true,
+      align => align.is_power_of_two() && self.vaddr % align == self.offset % align,
+    };
+
+    in_image && aligned && self.filesz <= self.memsz && self.vaddr.checked_add(self.memsz).is_some()
+  }
+}
+
+/// A checked ELF64 executable image.
+#[derive(Copy, Clone, Debug)]
+pub struct Elf<'a>
+{
+  data: &'a [u8],
+  entry: u64,
+  phoff: usize,
+  phnum: usize,
+}
+
+impl<'a> Elf<'a>
+{
+  /// Checks that `data` is a static RISC-V executable, with every segment
+  /// inside it and its entry point in an executable segment.
+  pub fn parse(data: &'a [u8]) -> Result<Self, ElfError>
+  {
+    let ident = data.get(..16).ok_or(ElfError::Truncated)?;
+    if ident[..4] != MAGIC {
+      return Err(ElfError::BadMagic);
+    }
+    if ident[4] != CLASS_64 || ident[5] != DATA_LSB || ident[6] != VERSION {
+      return Err(ElfError::Unsupported);
+    }
+    if data.len() < HEADER_SIZE {
+      return Err(ElfError::Truncated);
+    }
+
+    let kind = read_u16(data, 16)?;
+    if kind != ET_EXEC {
+      return Err(ElfError::NotExecutable(kind));
+    }
+    let machine = read_u16(data, 18)?;
+    if machine != EM_RISCV {
+      return Err(ElfError::WrongMachine(machine));
+    }
+    if read_u16(data, 54)? as usize != PHDR_SIZE {
+      return Err(ElfError::Unsupported);
+    }
+
+    let phoff = read_u64(data, 32)?;
+    let phnum = read_u16(data, 56)? as usize;
+    let table_end = phoff.checked_add((phnum * PHDR_SIZE) as u64);
+    if table_end.map_or(true, |end| end > data.len() as u64) {
+      return Err(ElfError::Truncated);
+    }
+
+    let elf = Self { data, entry: read_u64(data, 24)?, phoff: phoff as usize, phnum };
+    for (index, segment) in elf.segments().enumerate() {
+      if segment.kind == PT_INTERP {
+        return Err(ElfError::Dynamic);
+      }
+      if segment.is_load() && !segment.check(data.len()) {
+        return Err(ElfError::BadSegment(index));
+      }
+    }
+
+    let executable = elf.loads().any(|s| s.flags & PF_X != 0 && s.contains(elf.entry));
+    if !executable {
+      return Err(ElfError::BadEntry(elf.entry));
+    }
+
+    Ok(elf)
+  }
+
+  /// The address execution starts at.
+  #[inline]
+  pub fn entry(&self) -> u64
+  {
+    self.entry
+  }
+
+  /// How many program headers there are.
+  #[inline]
+  pub fn phnum(&self) -> usize
+  {
+    self.phnum
+  }
+
+  /// Every program header.
+  pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a
+  {
+    let (data, phoff) = (self.data, self.phoff);
+    // The table was checked to be in the image.
+    (0..self.phnum).map(move |i| Segment::parse(data, phoff + i * PHDR_SIZE).unwrap())
+  }
+
+  /// The program headers of the segments to load.
+  pub fn loads(&self) -> impl Iterator<Item = Segment> + 'a
+  {
+    self.segments().filter(Segment::is_load)
+  }
+
+  /// The bytes of `segment` the image holds.
+  pub fn contents(&self, segment: &Segment) -> &'a [u8]
+  {
+    let start = segment.offset as usize;
+    &self.data[start..start + segment.filesz as usize]
+  }
+
+  /// Where the program headers are once loaded, if a segment covers them.
+  pub fn phdr(&self) -> Option<u64>
+  {
+    let (start, end) = (self.phoff as u64, (self.phoff + self.phnum * PHDR_SIZE) as u64);
+    self.loads()
+        .find(|s| s.offset <= start && end <= s.offset + s.filesz)
+        .map(|s| s.vaddr + (start - s.offset))
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  const BASE: u64 = 0x1_0000;
+
+  /// An image with `segments` as (type, flags, vaddr, filesz, memsz), the
+  /// contents of each at its address less `BASE`.
+  fn image(entry: u64, segments: &[(u32, u32, u64, u64, u64)]) -> Vec<u8>
+  {
+    let mut data = vec![0; 0x3000];
+    data[..4].copy_from_slice(&MAGIC);
+    data[4] = CLASS_64;
+    data[5] = DATA_LSB;
+    data[6] = VERSION;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+    data[24..32].copy_from_slice(&entry.to_le_bytes());
+    data[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    data[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+    for (i, &(kind, flags, vaddr, filesz, memsz)) in segments.iter().enumerate() {
+      let ph = &mut data[HEADER_SIZE + i * PHDR_SIZE..][..PHDR_SIZE];
+      ph[0..4].copy_from_slice(&kind.to_le_bytes());
+      ph[4..8].copy_from_slice(&flags.to_le_bytes());
+      ph[8..16].copy_from_slice(&(vaddr - BASE).to_le_bytes());
+      ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+      ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+      ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+      ph[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+    }
+    data
+  }
+
+  #[test]
+  fn executable()
+  {
+    let text = (PT_LOAD, PF_R | PF_X, BASE, 0x1000, 0x1000);
+    let data = image(BASE + 0x100, &[text, (PT_LOAD, PF_R | PF_W, BASE + 0x1800, 0x100, 0x2000)]);
+    let elf = Elf::parse(&data).unwrap();
+    assert_eq!(elf.entry(), BASE + 0x100);
+    assert_eq!(elf.loads().count(), 2);
+    assert_eq!(elf.phdr(), Some(BASE + HEADER_SIZE as u64));
+
+    let data_segment = elf.loads().nth(1).unwrap();
+    assert_eq!(data_segment.end(), BASE + 0x3800);
+    assert_eq!(elf.contents(&data_segment).len(), 0x100);
+  }
+
+  #[test]
+  fn rejected()
+  {
+    let text = (PT_LOAD, PF_R | PF_X, BASE, 0x1000, 0x1000);
+
+    let mut data = image(BASE, &[text]);
+    data[0] = 0;
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadMagic));
+
+    let mut data = image(BASE, &[text]);
+    data[18] = 62;
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::WrongMachine(62)));
+
+    let data = image(BASE, &[text, (PT_INTERP, PF_R, BASE, 0x10, 0x10)]);
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::Dynamic));
+
+    let data = image(BASE, &[text, (PT_LOAD, PF_R | PF_W, BASE + 0x2000, 0x2000, 0x2000)]);
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadSegment(1)));
+
+    let data = image(BASE, &[(PT_LOAD, PF_R, BASE, 0x1000, 0x800)]);
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadSegment(0)));
+
+    let data = image(BASE + 0x1000, &[text]);
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadEntry(BASE + 0x1000)));
+
+    let data = image(BASE, &[(PT_LOAD, PF_R | PF_W, BASE, 0x1000, 0x1000)]);
+    assert_eq!(Elf::parse(&data).err(), Some(ElfError::BadEntry(BASE)));
+
+    assert_eq!(Elf::parse(&data[..40]).err(), Some(ElfError::Truncated));
+  }
+}
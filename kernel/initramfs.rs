@@ -0,0 +1,66 @@
+//! The initial RAM file system.
+//!
+//! The archive comes from the bootloader, through `linux,initrd-start` and
+//! `linux,initrd-end` in `/chosen`, or failing that from the kernel image:
+//! building with the `initramfs` feature embeds the cpio or tar file named
+//! by the `TRIDENT_INITRAMFS` environment variable. Either way it is read
+//! in place and never changes.
+
+use core::slice;
+
+use fdt::Fdt;
+use fs::initramfs::{Archive, Entry, Kind};
+use system::alloc::spin::Once;
+use system::console::println;
+
+#[cfg(feature = "initramfs")]
+static EMBEDDED: &[u8] = include_bytes!(env!("TRIDENT_INITRAMFS"));
+#[cfg(not(feature = "initramfs"))]
+static EMBEDDED: &[u8] = &[];
+
+static ARCHIVE: Once<Archive<'static>> = Once::new();
+
+/// Finds the archive and checks every entry in it can be read. Returns
+/// `None` if there is none, or it is unreadable.
+///
+/// An archive from the bootloader must already be reserved from the frame
+/// allocator, as `mem::init` does.
+pub fn init(fdt: &Fdt) -> Option<&'static Archive<'static>>
+{
+  let data = match fdt.initrd() {
+    // RAM is identity mapped.
+    Some(r) => unsafe { slice::from_raw_parts(r.base as usize as *const u8, r.size as usize) },
+    None => EMBEDDED,
+  };
+  if data.is_empty() {
+    return None;
+  }
+
+  match Archive::new(data).and_then(|archive| archive.validate().map(|_| archive)) {
+    Ok(archive) => Some(ARCHIVE.call_once(|| archive)),
+    Err(e) => {
+      println!("initramfs: {}", e);
+      None
+    }
+  }
+}
+
+/// The archive `init` found.
+#[inline]
+pub fn archive() -> Option<&'static Archive<'static>>
+{
+  ARCHIVE.get()
+}
+
+/// The entry at `path`, which is relative to the root of the archive
+/// whether or not it starts with `/`.
+pub fn find(path: &str) -> Option<Entry<'static>>
+{
+  archive()?.find(path)
+}
+
+/// The contents of the regular file at `path`.
+pub fn read(path: &str) -> Option<&'static [u8]>
+{
+  find(path).filter(|entry| entry.kind == Kind::File).map(|entry| entry.data)
+}
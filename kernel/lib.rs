@@ -7,6 +7,8 @@ Trident is a kernel system designed for performance, stability, and modularity.
 
 #![deny(clippy::all)]
 #![warn(missing_docs)]
+#![feature(asm)]
+#![feature(global_asm)]
 #![cfg_attr(not(test), no_std)]
 
 //=================================KERNEL ENTRY MODULE==================================//
@@ -20,23 +22,127 @@ Trident is a kernel system designed for performance, stability, and modularity.
 // Trident system crate; contains core functionality and an interface with the underlying hardware.
 extern crate t_system as system;
 
+// Flattened device tree reader.
+extern crate t_fdt as fdt;
+
+// File systems.
+extern crate t_fs as fs;
+
 
 use system::alloc::uart;
 
+pub mod block;
+pub mod drivers;
+pub mod elf;
+pub mod initramfs;
+pub mod mem;
+pub mod process;
+pub mod sched;
+pub mod syscall;
+pub mod time;
+pub mod trap;
+pub mod vfs;
+
 #[cfg(test)]
 mod test;
 
 /// The Program Entry Point.
 ///
 /// All setup for the hardware's resources and the Kernel's internal workings
-/// happens here.
-#[no_mangle]
+/// happens here. `dtb` is the physical address of the device tree blob.
 #[boot::entry]
-pub extern "Rust" fn kmain() -> !
+fn kmain(dtb: usize) -> !
+{
+  let fdt = unsafe { fdt::Fdt::from_ptr(dtb as *const u8) }.expect("invalid device tree");
+
+  let serial_node = fdt
+      .stdout()
+      .or_else(|| fdt.find_compatible(&["ns16550a"]))
+      .expect("no console UART in the device tree");
+  let serial = serial_node
+      .reg()
+      .and_then(|mut reg| reg.next())
+      .expect("console UART has no registers");
+  uart::init(serial.base as usize);
+
+  let plic = drivers::plic::init(&fdt).expect("no PLIC in the device tree");
+  let virtio = drivers::virtio::init(&fdt).unwrap_or((0, 0));
+
+  mem::init(&fdt, &[(serial.base as usize, serial.size as usize), plic, virtio]);
+  let initramfs = initramfs::init(&fdt);
+  vfs::init();
+  block::init();
+
+  drivers::plic::enable();
+  if let Some(source) = serial_node.interrupts().and_then(|mut irqs| irqs.next()) {
+    let base = serial.base as usize as *mut ();
+    drivers::plic::PLIC
+        .get()
+        .unwrap()
+        .attach(boot::hart_id(), source, 1, drivers::uart::handle_rx, base)
+        .expect("console UART interrupt is taken");
+  }
+  drivers::virtio::probe();
+  drivers::virtio::blk::init();
+  vfs::mount_disks();
+
+  time::init(&fdt);
+  time::start_tick();
+
+  let harts = boot::smp::start_secondaries();
+  system::console::println!("{} harts online", harts);
+
+  sched::init_hart();
+  sched::spawn("hello", hello, 0).expect("cannot start the first thread");
+  sched::spawn("console", drivers::uart::echo, 0).expect("cannot start the console thread");
+  if initramfs.is_some() {
+    sched::spawn("init", init, 0).expect("cannot start the init thread");
+  }
+
+  unsafe {
+    boot::irq::enable_global();
+  }
+
+  sched::run()
+}
+
+fn hello(_arg: usize) -> usize
+{
+  system::console::println!("Hello world!");
+  0
+}
+
+/// Runs `/init` from the initramfs as the first process, if there is one,
+/// and reports how it ends.
+fn init(_arg: usize) -> usize
 {
-  uart::init(0x1000_0000);
+  let image = match initramfs::read("init") {
+    Some(image) => image,
+    None => return 0,
+  };
 
-  loop {
-    system::console::println!("Hello world!");
+  match process::exec("init", image, &[b"/init"], &[]).and_then(process::wait) {
+    Ok(code) => system::console::println!("init exited with {}", code),
+    Err(e) => system::console::println!("cannot run /init: {}", e),
   }
+  0
+}
+
+/// The entry point of every other hart, once `kmain` has started it.
+///
+/// Shares the kernel address space and interrupt handlers `kmain` set up;
+/// each hart only has to turn them on for itself.
+#[boot::secondary_entry]
+fn kmain_secondary(_hartid: usize) -> !
+{
+  mem::init_hart();
+  drivers::plic::enable();
+  time::start_tick();
+  sched::init_hart();
+
+  unsafe {
+    boot::irq::enable_global();
+  }
+
+  sched::run()
 }
@@ -0,0 +1,228 @@
+//! Kernel memory setup.
+
+use core::iter;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use fdt::Fdt;
+use system::alloc::alloc::frame::{self, Region};
+use system::alloc::alloc::page::{entry::EntryBits, AddressSpace, PagingMode};
+use system::alloc::spin::{Mutex, Once};
+
+use crate::process::MAX_PROCESSES;
+
+/// The address space the kernel runs in, or `None` before `init` is called.
+pub static KERNEL_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+/// The ASID reserved for the kernel's own address space.
+pub const KERNEL_ASID: u16 = 0;
+
+/// The most RAM or MMIO ranges the kernel can map.
+pub const MAX_RANGES: usize = 16;
+
+/// `satp` for the kernel address space, once `init` has built it.
+static KERNEL_SATP: AtomicUsize = AtomicUsize::new(0);
+
+/// The fewest ASID bits any hart started so far implements.
+static ASID_BITS: AtomicU32 = AtomicU32::new(16);
+
+/// The ranges `init` identity mapped, which every address space repeats
+/// so that the kernel keeps running whichever one is installed.
+struct KernelMap
+{
+  ram: [(usize, usize); MAX_RANGES],
+  mmio: [(usize, usize); MAX_RANGES],
+}
+
+static KERNEL_MAP: Once<KernelMap> = Once::new();
+
+impl KernelMap
+{
+  /// Every range, RAM and MMIO, including the empty ones.
+  fn ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_
+  {
+    self.ram.iter().chain(&self.mmio).copied()
+  }
+
+  /// Maps RAM, the kernel image and MMIO into `space`, for the kernel only.
+  fn map_into(&self, space: &mut AddressSpace)
+  {
+    let global = EntryBits::Global.val();
+
+    // All of RAM first, so that the frame allocator keeps working once
+    // paging is on; the kernel sections then carve out their permissions.
+    for &(start, end) in &self.ram {
+      space.identity_map(start, end, EntryBits::ReadWrite.val() | global);
+    }
+    space.map_kernel();
+    for &(start, end) in &self.mmio {
+      space.identity_map(start, end, EntryBits::ReadWrite.val() | global);
+    }
+  }
+}
+
+fn region(r: fdt::Region) -> Region
+{
+  Region::new(r.base as usize, r.size as usize)
+}
+
+/// The paging mode to run in: the largest the boot hart accepted when
+/// `satp` was probed in M-mode.
+///
+/// Under OpenSBI nothing probed, so we go by the `mmu-type` of each cpu in
+/// `fdt` instead. Every RISC-V MMU implements Sv39, which is assumed when a
+/// cpu does not say.
+fn paging_mode(fdt: &Fdt) -> PagingMode
+{
+  if let Some(mode) = boot::machine::paging_mode(boot::hart_id()) {
+    return mode;
+  }
+
+  fdt.find_node("/cpus")
+      .into_iter()
+      .flat_map(|cpus| cpus.children())
+      .filter(|node| node.device_type() == Some("cpu"))
+      .map(|cpu| {
+        cpu.property("mmu-type")
+            .and_then(|prop| prop.as_str())
+            .and_then(PagingMode::from_mmu_type)
+            .unwrap_or(PagingMode::Sv39)
+      })
+      .min_by_key(|mode| mode.satp_mode())
+      .unwrap_or(PagingMode::Sv39)
+}
+
+/// Builds the frame allocator from the memory map in `fdt`, then builds
+/// the kernel address space, identity maps RAM, the kernel image and the
+/// given MMIO regions into it, and turns on paging in the largest mode
+/// every hart supports.
+pub fn init(fdt: &Fdt, mmio: &[(usize, usize)])
+{
+  let kernel = frame::kernel_image();
+  let memory = fdt.memory().map(region);
+  // The blob itself, the firmware's reservations, the initramfs and the
+  // kernel image must never be handed out.
+  let reserved = fdt
+      .mem_reservations()
+      .chain(fdt.reserved_memory())
+      .chain(iter::once(fdt.blob_region()))
+      .chain(fdt.initrd())
+      .map(region)
+      .chain(kernel.iter().copied());
+
+  unsafe {
+    frame::init_frames(memory.clone(), reserved);
+  }
+
+  PagingMode::set_current(paging_mode(fdt));
+
+  let mut map = KernelMap { ram: [(0, 0); MAX_RANGES], mmio: [(0, 0); MAX_RANGES] };
+  for (slot, r) in map.ram.iter_mut().zip(memory) {
+    *slot = (r.base, r.end());
+  }
+  for (slot, &(base, size)) in map.mmio.iter_mut().zip(mmio) {
+    *slot = (base, base + size);
+  }
+  let map = KERNEL_MAP.call_once(|| map);
+
+  let mut space = AddressSpace::new(KERNEL_ASID).expect("no memory for the kernel page table");
+  map.map_into(&mut space);
+
+  unsafe {
+    space.activate();
+    ASID_BITS.fetch_min(probe_asid_bits(space.satp()), Ordering::Relaxed);
+  }
+
+  KERNEL_SATP.store(space.satp(), Ordering::Relaxed);
+  *KERNEL_SPACE.lock() = Some(space);
+}
+
+/// A new address space tagged with `asid`, in which only the kernel is
+/// mapped, and only for S-mode.
+///
+/// Returns `None` if there is no memory for its tables.
+pub fn user_space(asid: u16) -> Option<AddressSpace>
+{
+  let map = KERNEL_MAP.get()?;
+  let mut space = AddressSpace::new(asid)?;
+  map.map_into(&mut space);
+  Some(space)
+}
+
+/// The end of the highest range the kernel maps which overlaps
+/// `start..end`, if there is one. Such addresses are never user memory.
+pub fn kernel_overlap(start: usize, end: usize) -> Option<usize>
+{
+  KERNEL_MAP
+      .get()?
+      .ranges()
+      .filter(|&(base, limit)| start < limit && base < end)
+      .map(|(_, limit)| limit)
+      .max()
+}
+
+/// `satp` for the kernel address space.
+#[inline]
+pub fn kernel_satp() -> usize
+{
+  KERNEL_SATP.load(Ordering::Relaxed)
+}
+
+/// How many ASID bits this hart implements, with `satp` installed. Only
+/// the bits which stick when all of them are written with ones are there;
+/// the kernel is mapped globally, so it keeps running meanwhile.
+unsafe fn probe_asid_bits(satp: usize) -> u32
+{
+  let probed: usize;
+  asm!(
+    "csrw satp, {1}",
+    "csrr {0}, satp",
+    "csrw satp, {2}",
+    out(reg) probed,
+    in(reg) satp | 0xffff << 44,
+    in(reg) satp,
+  );
+  ((probed >> 44) & 0xffff).count_ones()
+}
+
+/// Returns true if some hart has too few ASID bits for every process to
+/// have its own, so that processes share TLB entries unless they are
+/// flushed.
+pub fn asids_shared() -> bool
+{
+  MAX_PROCESSES >= 1 << ASID_BITS.load(Ordering::Relaxed)
+}
+
+/// Installs the address space whose `satp` value is `satp`, or the
+/// kernel's for zero. The TLB is left alone if every space has its own
+/// ASID, as the kernel is mapped the same way in all of them; otherwise
+/// the last space's entries are flushed.
+///
+/// # Safety
+///
+/// The space must stay alive for as long as it is installed.
+pub unsafe fn switch_space(satp: usize)
+{
+  let satp = if satp == 0 { kernel_satp() } else { satp };
+  asm!("csrw satp, {}", in(reg) satp);
+  if asids_shared() {
+    asm!("sfence.vma zero, zero");
+  }
+}
+
+/// Turns on paging on a secondary hart, in the address space `init` built
+/// on the boot hart.
+pub fn init_hart()
+{
+  let space = KERNEL_SPACE.lock();
+  let space = space.as_ref().expect("kernel address space not built yet");
+
+  let hart = boot::hart_id();
+  if let Some(mode) = boot::machine::paging_mode(hart) {
+    assert!(mode.levels() >= space.mode().levels(), "hart {} has no {:?}", hart, space.mode());
+  }
+
+  unsafe {
+    space.activate();
+    ASID_BITS.fetch_min(probe_asid_bits(space.satp()), Ordering::Relaxed);
+  }
+}
@@ -0,0 +1,538 @@
+//! User processes.
+//!
+//! A process is an address space of its own, the threads which run in it
+//! in U-mode, and the handles those threads use to name kernel objects.
+//! Its address space maps the kernel too, but only for S-mode, so nothing
+//! a process does can reach the kernel's memory or another process's;
+//! user memory is whatever lies between `USER_BASE` and `USER_END` that
+//! the kernel does not map for itself.
+//!
+//! Each of its threads is a kernel thread which drops into U-mode, and
+//! comes back into the kernel on its own stack whenever it traps: for an
+//! `ecall`, after which it returns, or for a fault, which ends it.
+
+use core::fmt::{self, Display};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use boot::sbi;
+use boot::trap::TrapFrame;
+use system::alloc::alloc::align_up;
+use system::alloc::alloc::frame::FRAMES;
+use system::alloc::alloc::page::{entry::EntryBits, AddressSpace, PAGE_SIZE};
+
+use crate::elf::ElfError;
+use crate::mem;
+use crate::sched::{self, Mutex, SchedError, ThreadId};
+
+pub use self::exec::{exec, MAX_ARGS, MAX_ARG_BYTES};
+pub use self::handle::{Handle, HandleTable, MAX_HANDLES};
+
+pub mod exec;
+pub mod handle;
+
+/// The most processes which can exist at once.
+pub const MAX_PROCESSES: usize = 64;
+
+/// The most threads a process can have had until it is waited for.
+pub const MAX_PROCESS_THREADS: usize = 16;
+
+/// The most separate ranges of memory a process can map.
+pub const MAX_MAPPINGS: usize = 16;
+
+/// The lowest address of user memory, where static executables are
+/// linked. Null pointers, and small offsets from them, always fault.
+pub const USER_BASE: usize = 0x1_0000;
+
+/// The end of user memory, where the first thread's stack starts.
+pub const USER_END: usize = 0x20_0000_0000;
+
+/// The size of the stack `start` maps for a process's first thread.
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// The exit code of a thread ended by a fault.
+pub const KILLED: usize = usize::MAX;
+
+/// Identifies a process. Ids are never reused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub usize);
+
+impl Display for Pid
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "{}", self.0)
+  }
+}
+
+/// The ways a process call can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ProcessError
+{
+  /// There was no memory for its page tables or user memory.
+  NoMemory,
+  /// `MAX_PROCESSES` processes already exist.
+  TooManyProcesses,
+  /// The process already has `MAX_PROCESS_THREADS` threads.
+  TooManyThreads,
+  /// The process already has `MAX_MAPPINGS` mappings.
+  TooManyMappings,
+  /// No process has this id.
+  NoSuchProcess(Pid),
+  /// The address is not in user memory, is unaligned, or is mapped when
+  /// it should not be or not when it should.
+  BadAddress(usize),
+  /// The permission bits cannot make a user page: they have bits besides
+  /// read, write, execute and user, none of the first three, or write
+  /// without read.
+  BadPermissions(usize),
+  /// The scheduler could not start or join a thread.
+  Sched(SchedError),
+  /// The image given to `exec` is not an executable it can load.
+  BadImage(ElfError),
+  /// The arguments and environment given to `exec` do not fit.
+  ArgsTooLong,
+}
+
+impl Display for ProcessError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      ProcessError::NoMemory => write!(f, "no memory for the process"),
+      ProcessError::TooManyProcesses => write!(f, "too many processes"),
+      ProcessError::TooManyThreads => write!(f, "too many threads in the process"),
+      ProcessError::TooManyMappings => write!(f, "too many mappings in the process"),
+      ProcessError::NoSuchProcess(pid) => write!(f, "no process {}", pid),
+      ProcessError::BadAddress(addr) => write!(f, "bad user address {:#x}", addr),
+      ProcessError::BadPermissions(bits) => write!(f, "bad page permissions {:#x}", bits),
+      ProcessError::Sched(e) => write!(f, "{}", e),
+      ProcessError::BadImage(e) => write!(f, "{}", e),
+      ProcessError::ArgsTooLong => write!(f, "arguments too long"),
+    }
+  }
+}
+
+impl From<SchedError> for ProcessError
+{
+  fn from(e: SchedError) -> Self
+  {
+    ProcessError::Sched(e)
+  }
+}
+
+impl From<ElfError> for ProcessError
+{
+  fn from(e: ElfError) -> Self
+  {
+    ProcessError::BadImage(e)
+  }
+}
+
+/// User memory mapped into a process, and the frames behind it.
+#[derive(Copy, Clone, Debug)]
+struct Mapping
+{
+  vaddr: usize,
+  frames: usize,
+  pages: usize,
+  bits: usize,
+}
+
+impl Mapping
+{
+  fn end(&self) -> usize
+  {
+    self.vaddr + self.pages * PAGE_SIZE
+  }
+
+  fn contains(&self, vaddr: usize) -> bool
+  {
+    self.vaddr <= vaddr && vaddr < self.end()
+  }
+}
+
+/// Returns true if the `size` bytes at `vaddr` lie between `USER_BASE`
+/// and `USER_END`.
+fn in_user_range(vaddr: usize, size: usize) -> bool
+{
+  vaddr >= USER_BASE && vaddr.checked_add(size).map_or(false, |end| end <= USER_END)
+}
+
+/// Returns true if the `size` bytes at `vaddr` are all user memory: in
+/// the user range, and clear of the RAM and devices the kernel maps.
+pub fn is_user(vaddr: usize, size: usize) -> bool
+{
+  in_user_range(vaddr, size) && mem::kernel_overlap(vaddr, vaddr + size).is_none()
+}
+
+/// A user process.
+pub struct Process
+{
+  pid: Pid,
+  name: &'static str,
+  space: AddressSpace,
+  threads: [Option<ThreadId>; MAX_PROCESS_THREADS],
+  mappings: [Option<Mapping>; MAX_MAPPINGS],
+  handles: HandleTable,
+}
+
+impl Process
+{
+  fn new(pid: Pid, name: &'static str, asid: u16) -> Result<Self, ProcessError>
+  {
+    Ok(Self {
+      pid,
+      name,
+      space: mem::user_space(asid).ok_or(ProcessError::NoMemory)?,
+      threads: [None; MAX_PROCESS_THREADS],
+      mappings: [None; MAX_MAPPINGS],
+      handles: HandleTable::with_console(),
+    })
+  }
+
+  /// The process's id.
+  #[inline]
+  pub fn pid(&self) -> Pid
+  {
+    self.pid
+  }
+
+  /// The name the process was created with.
+  #[inline]
+  pub fn name(&self) -> &'static str
+  {
+    self.name
+  }
+
+  /// The process's address space.
+  #[inline]
+  pub fn space(&self) -> &AddressSpace
+  {
+    &self.space
+  }
+
+  /// The handles the process holds.
+  #[inline]
+  pub fn handles(&mut self) -> &mut HandleTable
+  {
+    &mut self.handles
+  }
+
+  /// The threads of the process which have not been waited for.
+  pub fn threads(&self) -> impl Iterator<Item = ThreadId> + '_
+  {
+    self.threads.iter().flatten().copied()
+  }
+
+  /// Maps `size` bytes of zeroed memory at `vaddr`, accessible from U-mode
+  /// with the permissions in `bits`: some of read, write and execute, with
+  /// read whenever there is write.
+  ///
+  /// `vaddr` must be page aligned, and the range must be user memory not
+  /// already mapped; `size` is rounded up to whole pages.
+  pub fn map(&mut self, vaddr: usize, size: usize, bits: usize) -> Result<(), ProcessError>
+  {
+    let (read, write) = (EntryBits::Read.val(), EntryBits::Write.val());
+    let rwx = EntryBits::ReadWriteExecute.val();
+    let bad = bits & !(rwx | EntryBits::User.val()) != 0
+        || bits & rwx == 0
+        || bits & (read | write) == write;
+    if bad {
+      return Err(ProcessError::BadPermissions(bits));
+    }
+
+    let size = align_up(size, PAGE_SIZE);
+    let valid = vaddr % PAGE_SIZE == 0 && size > 0 && is_user(vaddr, size);
+    if !valid || self.overlaps(vaddr, size).is_some() {
+      return Err(ProcessError::BadAddress(vaddr));
+    }
+
+    let slot = self
+        .mappings
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(ProcessError::TooManyMappings)?;
+    let pages = size / PAGE_SIZE;
+    let frames = FRAMES
+        .lock()
+        .as_mut()
+        .and_then(|frames| frames.alloc_contiguous(pages))
+        .ok_or(ProcessError::NoMemory)?;
+
+    // RAM is identity mapped, so the frames can be cleared where they are.
+    unsafe {
+      ptr::write_bytes(frames as *mut u8, 0, size);
+    }
+    let bits = bits | EntryBits::User.val();
+    self.space.map(vaddr, frames, size, bits);
+    *slot = Some(Mapping { vaddr, frames, pages, bits });
+    Ok(())
+  }
+
+  /// Unmaps the memory `map` mapped at `vaddr`, which must be the whole
+  /// of one mapping, and frees it.
+  pub fn unmap(&mut self, vaddr: usize, size: usize) -> Result<(), ProcessError>
+  {
+    let pages = align_up(size, PAGE_SIZE) / PAGE_SIZE;
+    let slot = self
+        .mappings
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |m| m.vaddr == vaddr && m.pages == pages))
+        .ok_or(ProcessError::BadAddress(vaddr))?;
+    let mapping = slot.take().unwrap();
+
+    self.space.unmap(vaddr, pages * PAGE_SIZE);
+    // Other harts may be running threads of this process.
+    self.shootdown(vaddr, pages * PAGE_SIZE);
+
+    if let Some(frames) = FRAMES.lock().as_mut() {
+      frames.free_contiguous(mapping.frames, mapping.pages);
+    }
+    Ok(())
+  }
+
+  /// The lowest page aligned address in user memory with `size` bytes
+  /// free after it.
+  pub fn find_free(&self, size: usize) -> Option<usize>
+  {
+    let size = align_up(size, PAGE_SIZE);
+    let mut vaddr = USER_BASE;
+    while in_user_range(vaddr, size) {
+      let used = self.overlaps(vaddr, size).or_else(|| mem::kernel_overlap(vaddr, vaddr + size));
+      match used {
+        Some(end) => vaddr = align_up(end, PAGE_SIZE),
+        None => return Some(vaddr),
+      }
+    }
+
+    None
+  }
+
+  /// The end of a mapping in the `size` bytes at `vaddr`, if there is one.
+  fn overlaps(&self, vaddr: usize, size: usize) -> Option<usize>
+  {
+    self.mappings
+        .iter()
+        .flatten()
+        .find(|m| vaddr < m.end() && m.vaddr < vaddr + size)
+        .map(Mapping::end)
+  }
+
+  /// Checks that the `len` bytes at `vaddr` are all mapped with at least
+  /// the permissions in `bits`.
+  pub fn check(&self, vaddr: usize, len: usize, bits: usize) -> Result<(), ProcessError>
+  {
+    let end = vaddr.checked_add(len).ok_or(ProcessError::BadAddress(vaddr))?;
+    let mut addr = vaddr;
+    while addr < end {
+      let m = self
+          .mappings
+          .iter()
+          .flatten()
+          .find(|m| m.contains(addr) && m.bits & bits == bits)
+          .ok_or(ProcessError::BadAddress(addr))?;
+      addr = m.end();
+    }
+
+    Ok(())
+  }
+
+  /// Calls `f` with each piece of the `len` bytes of user memory at
+  /// `vaddr` which lies within one page, as a physical address, and its
+  /// offset into the range.
+  fn for_each_page(&self, vaddr: usize, len: usize, mut f: impl FnMut(usize, usize, usize))
+    -> Result<(), ProcessError>
+  {
+    if !is_user(vaddr, len) {
+      return Err(ProcessError::BadAddress(vaddr));
+    }
+
+    let mut done = 0;
+    while done < len {
+      let addr = vaddr + done;
+      let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(len - done);
+      let paddr = self.space.translate(addr).ok_or(ProcessError::BadAddress(addr))?;
+      f(paddr, done, chunk);
+      done += chunk;
+    }
+
+    Ok(())
+  }
+
+  /// Copies `data` into the process's memory at `vaddr`, whatever the
+  /// permissions of the pages there.
+  pub fn load(&self, vaddr: usize, data: &[u8]) -> Result<(), ProcessError>
+  {
+    self.for_each_page(vaddr, data.len(), |paddr, off, len| unsafe {
+      ptr::copy_nonoverlapping(data[off..].as_ptr(), paddr as *mut u8, len);
+    })
+  }
+
+  /// Flushes every hart's translations of the `size` bytes at `vaddr` in
+  /// this process; of every process if they share ASIDs.
+  fn shootdown(&self, vaddr: usize, size: usize)
+  {
+    let _ = if mem::asids_shared() {
+      sbi::remote_sfence_vma(0, usize::MAX, vaddr, size)
+    } else {
+      sbi::remote_sfence_vma_asid(0, usize::MAX, vaddr, size, self.space.asid() as usize)
+    };
+  }
+
+  /// Copies `data` into the process's memory at `vaddr`, which the process
+  /// must be able to write.
+  pub fn copy_to_user(&self, vaddr: usize, data: &[u8]) -> Result<(), ProcessError>
+  {
+    self.check(vaddr, data.len(), EntryBits::UserReadWrite.val())?;
+    self.load(vaddr, data)
+  }
+
+  /// Fills `buf` from the process's memory at `vaddr`, which the process
+  /// must be able to read.
+  pub fn copy_from_user(&self, vaddr: usize, buf: &mut [u8]) -> Result<(), ProcessError>
+  {
+    let len = buf.len();
+    self.check(vaddr, len, EntryBits::Read.val() | EntryBits::User.val())?;
+    self.for_each_page(vaddr, len, |paddr, off, len| unsafe {
+      ptr::copy_nonoverlapping(paddr as *const u8, buf[off..].as_mut_ptr(), len);
+    })
+  }
+}
+
+impl Drop for Process
+{
+  fn drop(&mut self)
+  {
+    // The ASID goes to the next process in this slot and the frames back
+    // to the allocator, so no hart may keep translations cached for
+    // either. The tables go last, when the space is dropped.
+    self.shootdown(0, usize::MAX);
+    if let Some(frames) = FRAMES.lock().as_mut() {
+      for m in self.mappings.iter().flatten() {
+        frames.free_contiguous(m.frames, m.pages);
+      }
+    }
+  }
+}
+
+type Processes = [Option<Process>; MAX_PROCESSES];
+
+const NO_PROCESS: Option<Process> = None;
+
+/// Every process, in slots whose index plus one is the ASID of the
+/// process in them.
+static PROCESSES: Mutex<Processes> = Mutex::new([NO_PROCESS; MAX_PROCESSES]);
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+fn find(processes: &mut Processes, pid: Pid) -> Result<&mut Process, ProcessError>
+{
+  processes
+      .iter_mut()
+      .flatten()
+      .find(|process| process.pid == pid)
+      .ok_or(ProcessError::NoSuchProcess(pid))
+}
+
+/// Creates a process with nothing mapped and no threads, holding only
+/// the console.
+pub fn create(name: &'static str) -> Result<Pid, ProcessError>
+{
+  let mut processes = PROCESSES.lock();
+  let slot = processes
+      .iter()
+      .position(Option::is_none)
+      .ok_or(ProcessError::TooManyProcesses)?;
+
+  let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+  processes[slot] = Some(Process::new(pid, name, slot as u16 + 1)?);
+  Ok(pid)
+}
+
+/// Runs `f` on process `pid`. Other calls on any process wait meanwhile.
+pub fn with<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Result<R, ProcessError>
+{
+  let mut processes = PROCESSES.lock();
+  find(&mut processes, pid).map(f)
+}
+
+/// Starts a thread in process `pid` which enters U-mode at `pc` with the
+/// stack pointer `sp` and `arg` in `a0`.
+pub fn spawn_thread(pid: Pid, pc: usize, sp: usize, arg: usize) -> Result<ThreadId, ProcessError>
+{
+  let mut processes = PROCESSES.lock();
+  let process = find(&mut processes, pid)?;
+  let slot = process
+      .threads
+      .iter()
+      .position(Option::is_none)
+      .ok_or(ProcessError::TooManyThreads)?;
+
+  let mut frame = TrapFrame::user(pc, sp);
+  frame.set_arg(0, arg);
+  let id = sched::spawn_user(process.name, pid, process.space.satp(), frame)?;
+  process.threads[slot] = Some(id);
+  Ok(id)
+}
+
+/// Maps the `USER_STACK_SIZE` bytes below `USER_END` as the stack of the
+/// first thread of `process`.
+fn map_stack(process: &mut Process) -> Result<(), ProcessError>
+{
+  let stack = USER_END - USER_STACK_SIZE;
+  process.map(stack, USER_STACK_SIZE, EntryBits::UserReadWrite.val())
+}
+
+/// Maps a stack for process `pid` at the end of user memory and starts
+/// its first thread on it at `pc`, with `arg` in `a0`.
+pub fn start(pid: Pid, pc: usize, arg: usize) -> Result<ThreadId, ProcessError>
+{
+  with(pid, map_stack)??;
+  spawn_thread(pid, pc, USER_END, arg)
+}
+
+/// Waits for every thread of process `pid` to exit, then frees it.
+/// Returns the exit code of its first thread.
+pub fn wait(pid: Pid) -> Result<usize, ProcessError>
+{
+  let mut code = None;
+  loop {
+    let next = with(pid, |process| process.threads.iter_mut().find_map(Option::take))?;
+    match next {
+      Some(id) => {
+        let exit_code = sched::join(id)?;
+        code.get_or_insert(exit_code);
+      }
+      None => break,
+    }
+  }
+
+  remove(pid);
+  Ok(code.unwrap_or(0))
+}
+
+/// Frees process `pid`, which must have no threads left.
+fn remove(pid: Pid)
+{
+  let mut processes = PROCESSES.lock();
+  let slot = processes
+      .iter_mut()
+      .find(|slot| slot.as_ref().map_or(false, |process| process.pid == pid));
+  // Dropped under the lock, so that no process takes the slot, and its
+  // ASID, before the old one's translations are flushed.
+  drop(slot.and_then(Option::take));
+}
+
+/// The process the current thread runs in, or `None` in a kernel thread.
+pub fn current() -> Option<Pid>
+{
+  sched::current_process()
+}
+
+/// Ends the current thread of a process, from a trap it took in U-mode.
+pub fn exit(code: usize) -> !
+{
+  debug_assert!(current().is_some(), "a kernel thread called process::exit");
+  sched::exit(code)
+}
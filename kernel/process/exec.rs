@@ -0,0 +1,230 @@
+//! Starting processes from ELF executables.
+//!
+//! `exec` maps each loadable segment of a static RISC-V executable with
+//! the permissions its flags ask for, copies in what the image holds of
+//! it and leaves the rest, the `.bss`, as the zeroed memory `map` hands
+//! out. The first thread starts at the entry point on a stack laid out as
+//! the SysV ABI has it:
+//!
+//! ```text
+//! USER_END -> strings of argv and envp, the AT_RANDOM bytes
+//!             padding to 16 bytes
+//!             auxv: (type, value) pairs up to AT_NULL
+//!             envp[0..envc], null
+//!             argv[0..argc], null
+//! sp       -> argc
+//! ```
+//!
+//! with zero in `a0`, as there is no dynamic linker cleanup to register.
+
+use system::alloc::alloc::page::{entry::EntryBits, PAGE_SIZE};
+use system::alloc::alloc::align_up;
+
+use self::auxv::*;
+use super::{create, map_stack, remove, spawn_thread, with, Pid, Process, ProcessError, USER_END};
+use crate::elf::{Elf, ElfError, Segment, PF_R, PF_W, PF_X, PHDR_SIZE};
+use crate::time;
+
+/// The most strings `argv` and `envp` can hold between them.
+pub const MAX_ARGS: usize = 64;
+
+/// The most bytes the strings of `argv` and `envp` can take, with their
+/// terminating nulls.
+pub const MAX_ARG_BYTES: usize = 4 * PAGE_SIZE;
+
+/// Auxiliary vector entry types.
+pub mod auxv
+{
+  /// Ends the vector.
+  pub const AT_NULL: usize = 0;
+  /// Where the program headers are in memory.
+  pub const AT_PHDR: usize = 3;
+  /// The size of a program header.
+  pub const AT_PHENT: usize = 4;
+  /// How many program headers there are.
+  pub const AT_PHNUM: usize = 5;
+  /// The page size.
+  pub const AT_PAGESZ: usize = 6;
+  /// The entry point of the program.
+  pub const AT_ENTRY: usize = 9;
+  /// The real user id.
+  pub const AT_UID: usize = 11;
+  /// The effective user id.
+  pub const AT_EUID: usize = 12;
+  /// The real group id.
+  pub const AT_GID: usize = 13;
+  /// The effective group id.
+  pub const AT_EGID: usize = 14;
+  /// Whether the program runs with more privilege than its caller.
+  pub const AT_SECURE: usize = 23;
+  /// The address of 16 random bytes.
+  pub const AT_RANDOM: usize = 25;
+}
+
+/// The most auxiliary vector entries `exec` passes, `AT_NULL` included.
+const MAX_AUXV: usize = 12;
+
+/// The most words between `sp` and the auxiliary vector's end.
+const MAX_WORDS: usize = 1 + MAX_ARGS + 2 + 2 * MAX_AUXV;
+
+/// Creates a process named `name` running the executable `image`, with
+/// the arguments `argv` and environment `envp`, and starts its first
+/// thread. Nothing is left behind if it fails.
+pub fn exec(name: &'static str, image: &[u8], argv: &[&[u8]], envp: &[&[u8]])
+  -> Result<Pid, ProcessError>
+{
+  let elf = Elf::parse(image)?;
+  let bytes: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+  if argv.len() + envp.len() > MAX_ARGS || bytes > MAX_ARG_BYTES {
+    return Err(ProcessError::ArgsTooLong);
+  }
+
+  let pid = create(name)?;
+  let started = with(pid, |process| {
+    load(process, &elf)?;
+    map_stack(process)?;
+    push_args(process, &elf, argv, envp)
+  })
+  .and_then(|result| result)
+  .and_then(|sp| spawn_thread(pid, elf.entry() as usize, sp, 0));
+
+  match started {
+    Ok(_) => Ok(pid),
+    Err(e) => {
+      remove(pid);
+      Err(e)
+    }
+  }
+}
+
+/// The page table permissions for the `PF_*` flags of segment `index`.
+///
+/// Pages cannot be writable without being readable, so `PF_W` implies
+/// `PF_R`; a segment with no permissions at all is refused.
+fn segment_bits(index: usize, segment: &Segment) -> Result<usize, ElfError>
+{
+  let mut bits = 0;
+  if segment.flags & (PF_R | PF_W) != 0 {
+    bits |= EntryBits::Read.val();
+  }
+  if segment.flags & PF_W != 0 {
+    bits |= EntryBits::Write.val();
+  }
+  if segment.flags & PF_X != 0 {
+    bits |= EntryBits::Execute.val();
+  }
+
+  match bits {
+    0 => Err(ElfError::BadSegment(index)),
+    bits => Ok(bits),
+  }
+}
+
+/// Maps and fills every loadable segment of `elf`. Segments may not share
+/// a page, as each page has only one set of permissions.
+fn load(process: &mut Process, elf: &Elf) -> Result<(), ProcessError>
+{
+  for (index, segment) in elf.segments().enumerate().filter(|(_, s)| s.is_load()) {
+    if segment.memsz == 0 {
+      continue;
+    }
+
+    let bits = segment_bits(index, &segment)?;
+    let start = segment.vaddr as usize & !(PAGE_SIZE - 1);
+    let end = align_up(segment.end() as usize, PAGE_SIZE);
+    process.map(start, end - start, bits)?;
+    process.load(segment.vaddr as usize, elf.contents(&segment))?;
+  }
+
+  Ok(())
+}
+
+/// 16 bytes for `AT_RANDOM`. They only need to differ between runs, to
+/// seed things like stack protector canaries, so they come from the
+/// timer mixed with the process id.
+fn random_bytes(pid: Pid) -> [u8; 16]
+{
+  // splitmix64.
+  let mut state = time::ticks() ^ (pid.0 as u64).rotate_left(32);
+  let mut next = || {
+    state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  };
+
+  let mut bytes = [0; 16];
+  bytes[..8].copy_from_slice(&next().to_le_bytes());
+  bytes[8..].copy_from_slice(&next().to_le_bytes());
+  bytes
+}
+
+/// Copies `bytes` and a null below `*sp`, moving it down, and returns
+/// the address they start at.
+fn push_str(process: &Process, sp: &mut usize, bytes: &[u8]) -> Result<usize, ProcessError>
+{
+  *sp -= bytes.len() + 1;
+  process.load(*sp, bytes)?;
+  process.load(*sp + bytes.len(), &[0])?;
+  Ok(*sp)
+}
+
+/// Lays out `argv`, `envp` and the auxiliary vector at the top of the
+/// stack `map_stack` mapped, and returns the stack pointer to start with.
+fn push_args(process: &Process, elf: &Elf, argv: &[&[u8]], envp: &[&[u8]])
+  -> Result<usize, ProcessError>
+{
+  let mut sp = USER_END;
+  let random = sp - 16;
+  process.load(random, &random_bytes(process.pid()))?;
+  sp = random;
+
+  let mut words = [0; MAX_WORDS];
+  let mut count = 0;
+  let mut push = |word: usize| {
+    words[count] = word;
+    count += 1;
+  };
+
+  push(argv.len());
+  // The strings go above the vectors, highest last; the pointers to them
+  // are only known as each is copied.
+  let mut pointers = [0; MAX_ARGS];
+  for (pointer, s) in pointers.iter_mut().zip(argv.iter().chain(envp)) {
+    *pointer = push_str(process, &mut sp, s)?;
+  }
+  let (argv_ptrs, envp_ptrs) = pointers[..argv.len() + envp.len()].split_at(argv.len());
+  argv_ptrs.iter().for_each(|&p| push(p));
+  push(0);
+  envp_ptrs.iter().for_each(|&p| push(p));
+  push(0);
+
+  if let Some(phdr) = elf.phdr() {
+    push(AT_PHDR);
+    push(phdr as usize);
+  }
+  for &(kind, value) in &[
+    (AT_PHENT, PHDR_SIZE),
+    (AT_PHNUM, elf.phnum()),
+    (AT_PAGESZ, PAGE_SIZE),
+    (AT_ENTRY, elf.entry() as usize),
+    (AT_UID, 0),
+    (AT_EUID, 0),
+    (AT_GID, 0),
+    (AT_EGID, 0),
+    (AT_SECURE, 0),
+    (AT_RANDOM, random),
+    (AT_NULL, 0),
+  ] {
+    push(kind);
+    push(value);
+  }
+
+  sp = (sp - count * 8) & !15;
+  for (i, word) in words[..count].iter().enumerate() {
+    process.load(sp + i * 8, &word.to_le_bytes())?;
+  }
+
+  Ok(sp)
+}
@@ -0,0 +1,125 @@
+//! Per-process handle tables.
+//!
+//! A process names the kernel objects it uses by small integers, like file
+//! descriptors: the index of the object's slot in its table. Freed slots
+//! are reused lowest first.
+
+use crate::sched::ThreadId;
+
+use super::Pid;
+
+/// The most handles a process can hold at once.
+pub const MAX_HANDLES: usize = 32;
+
+/// A kernel object a process holds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Handle
+{
+  /// The kernel console.
+  Console,
+  /// A thread.
+  Thread(ThreadId),
+  /// A process.
+  Process(Pid),
+}
+
+/// The handles of one process.
+pub struct HandleTable
+{
+  slots: [Option<Handle>; MAX_HANDLES],
+}
+
+impl HandleTable
+{
+  /// A table holding no handles.
+  pub const fn new() -> Self
+  {
+    Self { slots: [None; MAX_HANDLES] }
+  }
+
+  /// A table with the console as handles 0, 1 and 2: standard input,
+  /// output and error.
+  pub fn with_console() -> Self
+  {
+    let mut table = Self::new();
+    for _ in 0..3 {
+      table.insert(Handle::Console);
+    }
+    table
+  }
+
+  /// Adds `handle` in the lowest free slot and returns its number, or
+  /// `None` if the table is full.
+  pub fn insert(&mut self, handle: Handle) -> Option<usize>
+  {
+    let index = self.slots.iter().position(Option::is_none)?;
+    self.slots[index] = Some(handle);
+    Some(index)
+  }
+
+  /// The handle numbered `index`.
+  pub fn get(&self, index: usize) -> Option<Handle>
+  {
+    self.slots.get(index).copied().flatten()
+  }
+
+  /// Takes out the handle numbered `index`, freeing its number.
+  pub fn remove(&mut self, index: usize) -> Option<Handle>
+  {
+    self.slots.get_mut(index)?.take()
+  }
+
+  /// Copies the handle numbered `index` into the lowest free slot, and
+  /// returns the new number.
+  pub fn dup(&mut self, index: usize) -> Option<usize>
+  {
+    let handle = self.get(index)?;
+    self.insert(handle)
+  }
+
+  /// The handles held, with their numbers.
+  pub fn iter(&self) -> impl Iterator<Item = (usize, Handle)> + '_
+  {
+    self.slots.iter().enumerate().filter_map(|(i, slot)| slot.map(|handle| (i, handle)))
+  }
+}
+
+impl Default for HandleTable
+{
+  fn default() -> Self
+  {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn lowest_free()
+  {
+    let mut table = HandleTable::with_console();
+    assert_eq!(table.get(2), Some(Handle::Console));
+
+    assert_eq!(table.insert(Handle::Process(Pid(7))), Some(3));
+    assert_eq!(table.remove(1), Some(Handle::Console));
+    assert_eq!(table.remove(1), None);
+    assert_eq!(table.dup(3), Some(1));
+    assert_eq!(table.get(1), Some(Handle::Process(Pid(7))));
+    assert_eq!(table.iter().count(), 4);
+  }
+
+  #[test]
+  fn full()
+  {
+    let mut table = HandleTable::new();
+    for i in 0..MAX_HANDLES {
+      assert_eq!(table.insert(Handle::Thread(ThreadId(i))), Some(i));
+    }
+    assert_eq!(table.insert(Handle::Console), None);
+    assert_eq!(table.get(MAX_HANDLES), None);
+    assert_eq!(table.remove(MAX_HANDLES), None);
+  }
+}
@@ -0,0 +1,799 @@
+//! The kernel thread scheduler.
+//!
+//! Every hart has its own run queues, one per scheduling class (see
+//! `class`), and its own idle thread, which is whatever it was running
+//! when it called `init_hart` and which runs only when its queues are
+//! empty. A woken thread goes back to the hart it last ran on; harts with
+//! little to do pull threads from the busiest one.
+//!
+//! A thread gives up its hart in `yield_now`, `sleep`, `join`, `park`,
+//! `exit` or on a contended `Mutex`, or is preempted: when its class says
+//! its turn is up, or a more urgent thread becomes ready, its hart is asked
+//! to reschedule, and it yields on the way out of the next interrupt unless
+//! it has disabled preemption.
+//!
+//! Threads of user processes are kernel threads which run in U-mode most
+//! of the time; switching to one installs its process's address space.
+//!
+//! The queues and the thread table share one lock, which stays held across
+//! a switch and is released by the thread switched to.
+
+use core::cell::Cell;
+use core::fmt::{self, Display};
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use boot::irq::{self, Interrupt};
+use boot::trap::TrapFrame;
+use boot::{percpu, sbi, MAX_HARTS};
+use system::alloc::alloc::frame::FRAMES;
+use system::alloc::spin;
+
+use crate::process::Pid;
+use crate::time::Instant;
+
+use self::class::{Classes, Fair, BANDWIDTH_SHIFT};
+use self::switch::{switch_context, SwitchContext};
+use self::thread::{Threads, NO_THREAD};
+
+pub mod class;
+pub mod mutex;
+pub mod queue;
+pub mod switch;
+pub mod thread;
+
+pub use self::class::{Policy, Prio};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::thread::{Entry, State, Thread, ThreadId, MAX_THREADS, STACK_PAGES, STACK_SIZE};
+
+/// How many kernel ticks pass between a hart's checks for imbalance.
+pub const BALANCE_INTERVAL: u64 = 10;
+
+/// The ways a scheduler call can fail.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SchedError
+{
+  /// There was no memory for the thread's stack.
+  NoMemory,
+  /// `MAX_THREADS` threads already exist.
+  TooManyThreads,
+  /// No thread has this id, or it is an idle thread.
+  NoSuchThread(ThreadId),
+  /// Another thread is already joining this one.
+  AlreadyJoined(ThreadId),
+  /// A thread cannot join itself.
+  JoinSelf,
+  /// The policy's parameters are out of range.
+  InvalidPolicy,
+  /// Admitting the deadline policy would reserve more than the harts have.
+  Overcommitted,
+}
+
+impl Display for SchedError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      SchedError::NoMemory => write!(f, "no memory for a thread stack"),
+      SchedError::TooManyThreads => write!(f, "too many threads"),
+      SchedError::NoSuchThread(id) => write!(f, "no thread {}", id),
+      SchedError::AlreadyJoined(id) => write!(f, "thread {} is already being joined", id),
+      SchedError::JoinSelf => write!(f, "a thread cannot join itself"),
+      SchedError::InvalidPolicy => write!(f, "invalid scheduling policy"),
+      SchedError::Overcommitted => write!(f, "not enough hart time for the deadline policy"),
+    }
+  }
+}
+
+struct Scheduler
+{
+  threads: Threads,
+  harts: [Classes; MAX_HARTS],
+  /// The total `Policy::bandwidth` of the deadline threads.
+  bandwidth: u64,
+}
+
+impl Scheduler
+{
+  fn thread(&self, id: ThreadId) -> Option<&Thread>
+  {
+    self.threads.get(id.0).and_then(Option::as_ref)
+  }
+
+  fn thread_mut(&mut self, id: ThreadId) -> Option<&mut Thread>
+  {
+    self.threads.get_mut(id.0).and_then(Option::as_mut)
+  }
+
+  /// The thread `id`, which the caller knows exists.
+  fn get(&mut self, id: ThreadId) -> &mut Thread
+  {
+    self.thread_mut(id).expect("scheduler lost a thread")
+  }
+
+  fn insert(&mut self, make: impl FnOnce(ThreadId) -> Thread) -> Option<ThreadId>
+  {
+    let index = self.threads.iter().position(Option::is_none)?;
+    let id = ThreadId(index);
+    self.threads[index] = Some(make(id));
+    Some(id)
+  }
+
+  /// The started hart with the fewest queued threads.
+  fn least_loaded(&self) -> Option<usize>
+  {
+    (0..MAX_HARTS)
+        .filter(|&hart| self.harts[hart].started)
+        .min_by_key(|&hart| self.harts[hart].len())
+  }
+
+  /// Queues the ready thread `id` on `hart`, asking the hart to reschedule
+  /// if it is more urgent than what runs there.
+  fn enqueue(&mut self, id: ThreadId, hart: usize, now: u64)
+  {
+    let Scheduler { threads, harts, .. } = self;
+    harts[hart].enqueue(threads, id, hart, now);
+
+    let prio = self.get(id).prio();
+    let running = self.harts[hart].current.and_then(|current| self.thread(current));
+    if running.map_or(true, |running| prio > running.prio()) {
+      resched(hart);
+    }
+  }
+
+  /// Makes a waiting thread ready, on the hart it last ran on if that can
+  /// still take it.
+  fn wake(&mut self, id: ThreadId)
+  {
+    let thread = self.get(id);
+    thread.state = State::Ready;
+    let last = thread.hart;
+    let hart = match last {
+      hart if self.harts[hart].started => hart,
+      _ => self.least_loaded().unwrap_or_else(boot::hart_id),
+    };
+
+    self.enqueue(id, hart, now());
+  }
+
+  /// Takes the current thread `me` off this hart, charging it for the time
+  /// it ran and leaving it in `state`; a ready thread other than the idle
+  /// thread is queued again. Call `switch` next.
+  fn deschedule(&mut self, me: ThreadId, state: State)
+  {
+    let hart = boot::hart_id();
+    let now = now();
+
+    let Scheduler { threads, harts, .. } = self;
+    let thread = threads[me.0].as_mut().expect("scheduler lost a thread");
+    harts[hart].charge(thread, now);
+    thread.state = state;
+
+    if state == State::Ready && me != idle() {
+      self.harts[hart].enqueue(&mut self.threads, me, hart, now);
+    }
+  }
+
+  /// Changes thread `id` with `change`, moving it to the right queue if
+  /// that changes its priority.
+  fn reprioritize(&mut self, id: ThreadId, change: impl FnOnce(&mut Thread))
+  {
+    let queued = self.get(id).queued;
+    if let Some(hart) = queued {
+      self.harts[hart].dequeue(&mut self.threads, id);
+    }
+
+    change(self.get(id));
+
+    if let Some(hart) = queued {
+      self.enqueue(id, hart, now());
+    }
+  }
+
+  /// The highest priority of the threads waiting on mutexes `id` holds.
+  fn inherited(&self, id: ThreadId) -> Prio
+  {
+    self.threads
+        .iter()
+        .flatten()
+        .filter(|waiter| waiter.blocked_on.and_then(|addr| unsafe { mutex::holder(addr) }) == Some(id))
+        .map(Thread::prio)
+        .max()
+        .unwrap_or(Prio::Idle)
+  }
+
+  /// Brings the priority `id` has inherited up to date, and passes the
+  /// change on down the chain of holders if it is blocked itself.
+  fn update_prio(&mut self, id: ThreadId)
+  {
+    let mut id = id;
+    for _ in 0..MAX_THREADS {
+      let boost = self.inherited(id);
+      let thread = self.get(id);
+      if thread.boost == boost {
+        return;
+      }
+
+      let (state, hart, blocked_on) = (thread.state, thread.hart, thread.blocked_on);
+      self.reprioritize(id, |thread| thread.boost = boost);
+      if state == State::Running {
+        // Let its hart see whether something else should run now.
+        resched(hart);
+      }
+
+      match blocked_on.and_then(|addr| unsafe { mutex::holder(addr) }) {
+        Some(holder) => id = holder,
+        None => return,
+      }
+    }
+  }
+
+  /// Moves a queued thread from the busiest started hart to `hart` if the
+  /// load is uneven, or if `hart` would otherwise go idle.
+  fn balance(&mut self, hart: usize, idle: bool, now: u64)
+  {
+    let mine = self.harts[hart].len();
+    let busiest = (0..MAX_HARTS)
+        .filter(|&other| other != hart && self.harts[other].started)
+        .max_by_key(|&other| self.harts[other].len());
+    let busiest = match busiest {
+      Some(busiest) => busiest,
+      None => return,
+    };
+
+    let theirs = self.harts[busiest].len();
+    let uneven = if idle { theirs > 0 } else { theirs > mine + 1 };
+    if !uneven {
+      return;
+    }
+
+    let Scheduler { threads, harts, .. } = self;
+    let id = match harts[busiest].steal(threads) {
+      Some(id) => id,
+      None => return,
+    };
+    let thread = threads[id.0].as_mut().unwrap();
+    if thread.prio() == Prio::Fair {
+      Fair::rebase(thread, &harts[busiest].fair, &harts[hart].fair);
+    }
+    thread.hart = hart;
+
+    self.enqueue(id, hart, now);
+  }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_STARTED: Classes = Classes::new();
+
+static SCHED: spin::Mutex<Scheduler> = spin::Mutex::new(Scheduler {
+  threads: [NO_THREAD; MAX_THREADS],
+  harts: [NOT_STARTED; MAX_HARTS],
+  bandwidth: 0,
+});
+
+boot::percpu! {
+  /// The thread running on this hart.
+  static CURRENT: Cell<Option<ThreadId>> = Cell::new(None);
+  /// This hart's idle thread.
+  static IDLE: Cell<Option<ThreadId>> = Cell::new(None);
+  /// Set to ask this hart to reschedule, possibly by another hart.
+  static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+  /// Ticks until this hart next checks for imbalance.
+  static BALANCE: Cell<u64> = Cell::new(BALANCE_INTERVAL);
+}
+
+/// Locks the scheduler, masking interrupts on this hart first so that the
+/// tick cannot spin on the lock while we hold it. Returns whether they were
+/// enabled, for `irq::restore`.
+fn lock() -> (spin::MutexGuard<'static, Scheduler>, bool)
+{
+  let enabled = irq::save_and_disable();
+  (SCHED.lock(), enabled)
+}
+
+fn now() -> u64
+{
+  Instant::now().ticks()
+}
+
+/// Asks `hart` to reschedule, interrupting it if it is another one.
+fn resched(hart: usize)
+{
+  NEED_RESCHED.remote(hart).store(true, Ordering::Relaxed);
+  if hart != boot::hart_id() {
+    let _ = sbi::send_ipi(1 << hart, 0);
+  }
+}
+
+/// Acknowledges a reschedule request from another hart; `interrupt_exit`
+/// does the rest.
+fn handle_resched(_ctx: *mut ())
+{
+  unsafe {
+    // sip.SSIP
+    asm!("csrc sip, {}", in(reg) 1usize << 1);
+  }
+}
+
+/// The thread running on this hart.
+pub fn current() -> ThreadId
+{
+  unsafe { CURRENT.get_unchecked() }.get().expect("scheduler not started on this hart")
+}
+
+/// The process the current thread runs in, or `None` for a kernel thread.
+pub fn current_process() -> Option<Pid>
+{
+  let (mut sched, enabled) = lock();
+  let process = sched.get(current()).process;
+  drop(sched);
+  irq::restore(enabled);
+
+  process
+}
+
+fn idle() -> ThreadId
+{
+  unsafe { IDLE.get_unchecked() }.get().expect("scheduler not started on this hart")
+}
+
+/// Returns true if the caller may block: it is a thread other than the
+/// idle one, and not an interrupt handler.
+pub fn can_block() -> bool
+{
+  let current = unsafe { CURRENT.get_unchecked() }.get();
+  let idle = unsafe { IDLE.get_unchecked() }.get();
+  current.is_some() && current != idle && !irq::in_interrupt()
+}
+
+/// Makes whatever the calling hart is running its idle thread, so that it
+/// can start running threads. Call once on each hart.
+pub fn init_hart()
+{
+  let hart = boot::hart_id();
+  let (mut sched, enabled) = lock();
+  let id = sched
+      .insert(|id| Thread::idle(id, hart))
+      .expect("no room for an idle thread");
+  sched.harts[hart].current = Some(id);
+  sched.harts[hart].started = true;
+  drop(sched);
+  irq::restore(enabled);
+
+  unsafe {
+    CURRENT.get_unchecked().set(Some(id));
+    IDLE.get_unchecked().set(Some(id));
+  }
+
+  // Other harts interrupt this one to make it reschedule.
+  let _ = irq::register(Interrupt::SupervisorSoft, handle_resched, core::ptr::null_mut());
+  irq::enable(Interrupt::SupervisorSoft);
+}
+
+/// Starts an ordinary fair share thread running `entry(arg)`.
+pub fn spawn(name: &'static str, entry: Entry, arg: usize) -> Result<ThreadId, SchedError>
+{
+  spawn_with(name, entry, arg, Policy::default())
+}
+
+/// Starts a thread running `entry(arg)`, scheduled by `policy`.
+pub fn spawn_with(name: &'static str, entry: Entry, arg: usize, policy: Policy)
+  -> Result<ThreadId, SchedError>
+{
+  if !policy.is_valid() {
+    return Err(SchedError::InvalidPolicy);
+  }
+
+  let stack = alloc_stack()?;
+  start(policy, stack, |id| Thread::new(id, name, entry, arg, stack, policy))
+}
+
+/// Starts a thread of process `process`, in the address space `satp`
+/// installs, which goes straight to U-mode with the registers in `frame`.
+pub fn spawn_user(name: &'static str, process: Pid, satp: usize, frame: TrapFrame)
+  -> Result<ThreadId, SchedError>
+{
+  let policy = Policy::default();
+  let stack = alloc_stack()?;
+  start(policy, stack, |id| {
+    let mut thread = Thread::new(id, name, enter_user, 0, stack, policy);
+    thread.process = Some(process);
+    thread.satp = satp;
+    // Nothing else can see the stack yet.
+    unsafe {
+      thread.user_frame().write(frame);
+    }
+    thread
+  })
+}
+
+/// The entry of every user thread: drops into U-mode with the frame
+/// `spawn_user` left at the top of its stack.
+fn enter_user(_arg: usize) -> usize
+{
+  let (mut sched, enabled) = lock();
+  let frame = sched.get(current()).user_frame();
+  drop(sched);
+  irq::restore(enabled);
+
+  unsafe { boot::trap::enter_user(frame) }
+}
+
+fn alloc_stack() -> Result<usize, SchedError>
+{
+  FRAMES
+      .lock()
+      .as_mut()
+      .and_then(|frames| frames.alloc_contiguous(STACK_PAGES))
+      .ok_or(SchedError::NoMemory)
+}
+
+/// Admits and queues the thread `make` builds on `stack`, or frees the
+/// stack if it cannot.
+fn start(policy: Policy, stack: usize, make: impl FnOnce(ThreadId) -> Thread)
+  -> Result<ThreadId, SchedError>
+{
+  let (mut sched, enabled) = lock();
+  let result = admit(&mut sched, Policy::default(), policy).and_then(|_| {
+    let id = sched.insert(make);
+    id.ok_or_else(|| {
+      sched.bandwidth -= policy.bandwidth();
+      SchedError::TooManyThreads
+    })
+  });
+  if let Ok(id) = result {
+    let hart = sched.least_loaded().unwrap_or_else(boot::hart_id);
+    sched.get(id).hart = hart;
+    sched.enqueue(id, hart, now());
+  }
+  drop(sched);
+  irq::restore(enabled);
+
+  result.map_err(|e| {
+    free_stack(stack);
+    e
+  })
+}
+
+/// Reserves the bandwidth of a thread's new policy in place of its old.
+fn admit(sched: &mut Scheduler, old: Policy, new: Policy) -> Result<(), SchedError>
+{
+  let harts = sched.harts.iter().filter(|hart| hart.started).count().max(1) as u64;
+  let bandwidth = sched.bandwidth - old.bandwidth() + new.bandwidth();
+  if bandwidth > harts << BANDWIDTH_SHIFT {
+    return Err(SchedError::Overcommitted);
+  }
+
+  sched.bandwidth = bandwidth;
+  Ok(())
+}
+
+/// Changes how thread `id` is scheduled. A deadline policy starts a new
+/// period straight away.
+pub fn set_policy(id: ThreadId, policy: Policy) -> Result<(), SchedError>
+{
+  if !policy.is_valid() {
+    return Err(SchedError::InvalidPolicy);
+  }
+
+  let (mut sched, enabled) = lock();
+  let result = set_policy_locked(&mut sched, id, policy);
+  drop(sched);
+  irq::restore(enabled);
+
+  result
+}
+
+fn set_policy_locked(sched: &mut Scheduler, id: ThreadId, policy: Policy) -> Result<(), SchedError>
+{
+  let thread = match sched.thread(id) {
+    Some(thread) if thread.entry.is_some() && thread.state != State::Exited => thread,
+    _ => return Err(SchedError::NoSuchThread(id)),
+  };
+  let (old, state, hart, blocked_on) = (thread.policy, thread.state, thread.hart, thread.blocked_on);
+  admit(sched, old, policy)?;
+
+  let now = now();
+  sched.reprioritize(id, |thread| {
+    thread.policy = policy;
+    if let Policy::Deadline { runtime, deadline, .. } = policy {
+      thread.dl_deadline = now + crate::time::to_ticks(deadline);
+      thread.dl_budget = crate::time::to_ticks(runtime) as i64;
+    }
+  });
+
+  // It may be lending its priority to a mutex holder.
+  if let Some(holder) = blocked_on.and_then(|addr| unsafe { mutex::holder(addr) }) {
+    sched.update_prio(holder);
+  }
+  if state == State::Running {
+    resched(hart);
+  }
+
+  Ok(())
+}
+
+fn free_stack(stack: usize)
+{
+  if let Some(frames) = FRAMES.lock().as_mut() {
+    frames.free_contiguous(stack, STACK_PAGES);
+  }
+}
+
+/// Switches to the most urgent thread queued on this hart, pulling one
+/// from another hart if there are none, or to this hart's idle thread.
+/// The current thread must already have been descheduled.
+///
+/// Returns once the current thread is switched back to, on whichever hart,
+/// with the lock released but interrupts still masked.
+fn switch(mut sched: spin::MutexGuard<'static, Scheduler>)
+{
+  debug_assert!(percpu::preemptible(), "switching threads with preemption disabled");
+
+  let hart = boot::hart_id();
+  let now = now();
+  let prev = current();
+
+  if sched.harts[hart].is_empty() {
+    sched.balance(hart, true, now);
+  }
+  let Scheduler { threads, harts, .. } = &mut *sched;
+  let next = harts[hart].pick(threads).unwrap_or_else(idle);
+  harts[hart].current = Some(next);
+  NEED_RESCHED.remote(hart).store(false, Ordering::Relaxed);
+
+  let thread = sched.get(next);
+  thread.state = State::Running;
+  thread.hart = hart;
+  thread.ran_since = now;
+  thread.slice = 0;
+  let satp = thread.satp;
+
+  if next == prev {
+    return;
+  }
+  unsafe { CURRENT.get_unchecked() }.set(Some(next));
+
+  // The kernel is mapped the same way in every address space, so it can
+  // carry on in the next thread's before switching to it.
+  if sched.get(prev).satp != satp {
+    unsafe {
+      crate::mem::switch_space(satp);
+    }
+  }
+
+  let prev_ctx = &mut sched.get(prev).context as *mut SwitchContext;
+  let next_ctx = &sched.get(next).context as *const SwitchContext;
+  mem::forget(sched);
+
+  unsafe {
+    switch_context(prev_ctx, next_ctx);
+    // Whoever switched back to us still holds the lock.
+    SCHED.force_unlock();
+  }
+}
+
+/// Lets the other ready threads run before the current one continues.
+pub fn yield_now()
+{
+  let (mut sched, enabled) = lock();
+  sched.deschedule(current(), State::Ready);
+
+  switch(sched);
+  irq::restore(enabled);
+}
+
+/// Yields if this hart has been asked to reschedule and may.
+fn preempt_point()
+{
+  let wanted = NEED_RESCHED.remote(boot::hart_id()).load(Ordering::Relaxed);
+  let started = unsafe { CURRENT.get_unchecked() }.get().is_some();
+
+  if wanted && started && percpu::preemptible() {
+    yield_now();
+  }
+}
+
+/// Blocks the current thread for at least `duration`. It wakes on the
+/// first tick after that.
+pub fn sleep(duration: Duration)
+{
+  let wake_at = (Instant::now() + duration).ticks();
+
+  let (mut sched, enabled) = lock();
+  let me = current();
+  assert!(me != idle(), "the idle thread cannot sleep");
+  sched.deschedule(me, State::Sleeping);
+  sched.get(me).wake_at = wake_at;
+
+  switch(sched);
+  irq::restore(enabled);
+}
+
+/// Blocks the current thread until another, or an interrupt handler,
+/// calls `unpark` for it. Returns at once if `unpark` was called since it
+/// last parked.
+pub fn park()
+{
+  let (mut sched, enabled) = lock();
+  let me = current();
+  assert!(me != idle(), "the idle thread cannot park");
+
+  if mem::replace(&mut sched.get(me).unparked, false) {
+    drop(sched);
+    irq::restore(enabled);
+    return;
+  }
+  sched.deschedule(me, State::Parked);
+
+  switch(sched);
+  irq::restore(enabled);
+}
+
+/// Wakes thread `id` if it is parked, or else makes its next `park`
+/// return at once. Safe to call from interrupt handlers.
+pub fn unpark(id: ThreadId)
+{
+  let (mut sched, enabled) = lock();
+  match sched.thread_mut(id) {
+    Some(thread) if thread.state == State::Parked => sched.wake(id),
+    Some(thread) => thread.unparked = true,
+    None => {}
+  }
+  drop(sched);
+  irq::restore(enabled);
+}
+
+/// Waits for thread `id` to exit and returns what its entry returned. Its
+/// stack and id are freed; every thread must be joined once.
+pub fn join(id: ThreadId) -> Result<usize, SchedError>
+{
+  let me = current();
+  if id == me {
+    return Err(SchedError::JoinSelf);
+  }
+
+  let (mut sched, enabled) = lock();
+  let waited = wait_for(&mut sched, me, id);
+  let mut sched = match waited {
+    Ok(true) => {
+      switch(sched);
+      SCHED.lock()
+    }
+    Ok(false) => sched,
+    Err(e) => {
+      drop(sched);
+      irq::restore(enabled);
+      return Err(e);
+    }
+  };
+
+  let thread = sched.threads[id.0].take().expect("joined thread vanished");
+  drop(sched);
+  irq::restore(enabled);
+
+  free_stack(thread.stack);
+  Ok(thread.exit_code)
+}
+
+/// Registers `me` to be woken when `id` exits. Returns false if it already
+/// has, and true if `me` must now switch away to wait.
+fn wait_for(sched: &mut Scheduler, me: ThreadId, id: ThreadId) -> Result<bool, SchedError>
+{
+  let target = match sched.thread_mut(id) {
+    Some(target) if target.entry.is_some() => target,
+    _ => return Err(SchedError::NoSuchThread(id)),
+  };
+  if target.state == State::Exited {
+    return Ok(false);
+  }
+  if target.joiner.is_some() {
+    return Err(SchedError::AlreadyJoined(id));
+  }
+
+  target.joiner = Some(me);
+  sched.deschedule(me, State::Joining);
+  Ok(true)
+}
+
+/// Ends the current thread, handing `code` to whoever joins it.
+pub fn exit(code: usize) -> !
+{
+  let (mut sched, _) = lock();
+  let me = current();
+  assert!(me != idle(), "the idle thread cannot exit");
+
+  sched.deschedule(me, State::Exited);
+  let thread = sched.get(me);
+  thread.exit_code = code;
+  let (joiner, policy) = (thread.joiner, thread.policy);
+  sched.bandwidth -= policy.bandwidth();
+  if let Some(joiner) = joiner {
+    sched.wake(joiner);
+  }
+
+  switch(sched);
+  unreachable!("an exited thread was switched back to");
+}
+
+/// Where every new thread starts, from `thread_entry`, with the lock held
+/// by the thread that switched to it.
+#[no_mangle]
+extern "C" fn thread_start(id: usize) -> !
+{
+  unsafe {
+    SCHED.force_unlock();
+  }
+
+  let (entry, arg) = {
+    let sched = SCHED.lock();
+    let thread = sched.thread(ThreadId(id)).expect("started a thread that does not exist");
+    (thread.entry.expect("started an idle thread"), thread.arg)
+  };
+
+  unsafe {
+    irq::enable_global();
+  }
+
+  exit(entry(arg));
+}
+
+/// Runs threads on this hart forever, waiting for interrupts whenever
+/// there is nothing to run. Called by each hart's idle thread once it has
+/// nothing else to do.
+pub fn run() -> !
+{
+  loop {
+    yield_now();
+    unsafe { asm!("wfi") };
+  }
+}
+
+/// Wakes the sleepers whose time has come, charges the running thread for
+/// its time, and now and then evens out the load between harts. Called
+/// from the kernel tick on every hart.
+pub fn tick(now: Instant)
+{
+  let hart = boot::hart_id();
+  let current = match unsafe { CURRENT.get_unchecked() }.get() {
+    Some(current) => current,
+    None => return,
+  };
+
+  let (mut sched, enabled) = lock();
+  for index in 0..MAX_THREADS {
+    let due = sched.threads[index]
+        .as_ref()
+        .map_or(false, |thread| thread.state == State::Sleeping && thread.wake_at <= now.ticks());
+    if due {
+      sched.wake(ThreadId(index));
+    }
+  }
+
+  let balance = unsafe { BALANCE.get_unchecked() };
+  balance.set(balance.get() - 1);
+  if balance.get() == 0 {
+    balance.set(BALANCE_INTERVAL);
+    sched.balance(hart, false, now.ticks());
+  }
+
+  let Scheduler { threads, harts, .. } = &mut *sched;
+  let thread = threads[current.0].as_mut().expect("scheduler lost a thread");
+  let expired = harts[hart].charge(thread, now.ticks());
+  let prio = thread.prio();
+  if expired || harts[hart].best(threads).map_or(false, |best| best > prio) {
+    resched(hart);
+  }
+  drop(sched);
+  irq::restore(enabled);
+}
+
+/// Called by the boot trap path after every interrupt: preempts the
+/// interrupted thread if its hart has been asked to reschedule and it
+/// allows it.
+#[export_name = "_interrupt_exit"]
+pub fn interrupt_exit()
+{
+  preempt_point();
+}
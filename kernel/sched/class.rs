@@ -0,0 +1,319 @@
+//! Scheduling classes.
+//!
+//! Each hart has one run queue per class, and always runs the best thread
+//! of the highest class with any ready: deadline threads first, then
+//! realtime, then fair share, then its idle thread. A thread's class
+//! follows from its `Prio`, which is its policy's unless a thread waiting
+//! on a mutex it holds has lent it a higher one.
+
+use core::cmp::Reverse;
+use core::time::Duration;
+
+use super::thread::{Thread, ThreadId, Threads};
+
+pub use self::deadline::Deadline;
+pub use self::fair::Fair;
+pub use self::realtime::Realtime;
+
+pub mod deadline;
+pub mod fair;
+pub mod realtime;
+
+/// How a thread asks to be scheduled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Policy
+{
+  /// A share of the hart in proportion to `weight`, against the other fair
+  /// threads. `fair::DEFAULT_WEIGHT` is an ordinary thread's.
+  Fair
+  {
+    /// Its share; must not be zero.
+    weight: u32,
+  },
+  /// Runs before any thread of lower priority, and before every fair
+  /// thread.
+  Realtime
+  {
+    /// From 0 to `realtime::MAX_PRIORITY`, highest first.
+    priority: u8,
+  },
+  /// `runtime` in every `period`, finished within `deadline` of the period
+  /// starting. Runs before every other class.
+  Deadline
+  {
+    /// The run time it needs per period.
+    runtime: Duration,
+    /// When in each period that run time must be done by.
+    deadline: Duration,
+    /// How often it needs it.
+    period: Duration,
+  },
+}
+
+impl Default for Policy
+{
+  fn default() -> Self
+  {
+    Policy::Fair { weight: fair::DEFAULT_WEIGHT }
+  }
+}
+
+impl Policy
+{
+  /// Returns true if the parameters make sense: a non-zero weight, a
+  /// priority in range, or `0 < runtime <= deadline <= period`.
+  pub fn is_valid(&self) -> bool
+  {
+    match *self {
+      Policy::Fair { weight } => weight > 0,
+      Policy::Realtime { priority } => priority <= realtime::MAX_PRIORITY,
+      Policy::Deadline { runtime, deadline, period } => {
+        runtime > Duration::ZERO && runtime <= deadline && deadline <= period
+      }
+    }
+  }
+
+  /// The fraction of a hart a deadline policy reserves, in units of
+  /// `1 << BANDWIDTH_SHIFT`; zero for the other policies.
+  pub fn bandwidth(&self) -> u64
+  {
+    match *self {
+      Policy::Deadline { runtime, period, .. } => {
+        ((runtime.as_nanos() << BANDWIDTH_SHIFT) / period.as_nanos()) as u64
+      }
+      _ => 0,
+    }
+  }
+
+  /// The priority this policy gives a thread whose absolute deadline, if
+  /// it has one, is `dl_deadline`.
+  pub fn prio(&self, dl_deadline: u64) -> Prio
+  {
+    match *self {
+      Policy::Fair { .. } => Prio::Fair,
+      Policy::Realtime { priority } => Prio::Realtime(priority),
+      Policy::Deadline { .. } => Prio::Deadline(Reverse(dl_deadline)),
+    }
+  }
+}
+
+/// The precision of `Policy::bandwidth`: one whole hart is
+/// `1 << BANDWIDTH_SHIFT`.
+pub const BANDWIDTH_SHIFT: u32 = 20;
+
+/// How urgently a thread should run, from least to most.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Prio
+{
+  /// Only when there is nothing else.
+  Idle,
+  /// Sharing the hart fairly.
+  Fair,
+  /// A fixed realtime priority.
+  Realtime(u8),
+  /// An absolute deadline, in ticks of `mtime`: earlier is more urgent.
+  Deadline(Reverse<u64>),
+}
+
+/// A scheduling class: one hart's queue of ready threads of that class,
+/// and the rules for ordering and preempting them.
+pub trait Class
+{
+  /// Queues a ready thread.
+  fn enqueue(&mut self, threads: &mut Threads, id: ThreadId, now: u64);
+
+  /// Takes `id` out of the queue. Returns false if it was not queued.
+  fn dequeue(&mut self, threads: &mut Threads, id: ThreadId) -> bool;
+
+  /// Takes the thread which should run next.
+  fn pick(&mut self, threads: &mut Threads) -> Option<ThreadId>;
+
+  /// The thread `pick` would take.
+  fn peek(&self) -> Option<ThreadId>;
+
+  /// The thread which would run last, and so is the best to move to
+  /// another hart.
+  fn back(&self, threads: &Threads) -> Option<ThreadId>;
+
+  /// Charges the running thread of this class for the `ran` ticks up to
+  /// `now`. Returns true if it should give up the hart.
+  fn charge(&mut self, thread: &mut Thread, ran: u64, now: u64) -> bool;
+
+  /// The number of queued threads.
+  fn len(&self) -> usize;
+
+  /// Returns true if no thread is queued.
+  fn is_empty(&self) -> bool
+  {
+    self.len() == 0
+  }
+}
+
+/// A hart's run queues, one per class.
+#[derive(Default)]
+pub struct Classes
+{
+  /// Deadline threads.
+  pub deadline: Deadline,
+  /// Realtime threads.
+  pub realtime: Realtime,
+  /// Fair share threads.
+  pub fair: Fair,
+  /// The thread running on the hart.
+  pub current: Option<ThreadId>,
+  /// Set once the hart has an idle thread, and so will run queued threads.
+  pub started: bool,
+}
+
+fn prio(threads: &Threads, id: ThreadId) -> Prio
+{
+  threads[id.0].as_ref().expect("queued thread does not exist").prio()
+}
+
+impl Classes
+{
+  /// Empty queues for a hart which has not started.
+  pub const fn new() -> Self
+  {
+    Self {
+      deadline: Deadline::new(),
+      realtime: Realtime::new(),
+      fair: Fair::new(),
+      current: None,
+      started: false,
+    }
+  }
+
+  /// The class of threads at `prio`, or `None` for the idle thread, which
+  /// is never queued.
+  fn class(&mut self, prio: Prio) -> Option<&mut dyn Class>
+  {
+    match prio {
+      Prio::Idle => None,
+      Prio::Fair => Some(&mut self.fair),
+      Prio::Realtime(_) => Some(&mut self.realtime),
+      Prio::Deadline(_) => Some(&mut self.deadline),
+    }
+  }
+
+  /// The classes, most urgent first.
+  fn in_order(&self) -> [&dyn Class; 3]
+  {
+    [&self.deadline, &self.realtime, &self.fair]
+  }
+
+  /// Queues the ready thread `id` on hart `hart`, which these are the
+  /// queues of.
+  pub fn enqueue(&mut self, threads: &mut Threads, id: ThreadId, hart: usize, now: u64)
+  {
+    let prio = prio(threads, id);
+    let class = self.class(prio).expect("queued an idle thread");
+    class.enqueue(threads, id, now);
+    threads[id.0].as_mut().unwrap().queued = Some(hart);
+  }
+
+  /// Takes `id` out of whichever queue it is in. Returns false if it was
+  /// not queued here.
+  pub fn dequeue(&mut self, threads: &mut Threads, id: ThreadId) -> bool
+  {
+    let prio = prio(threads, id);
+    let removed = match self.class(prio) {
+      Some(class) => class.dequeue(threads, id),
+      None => false,
+    };
+    if removed {
+      threads[id.0].as_mut().unwrap().queued = None;
+    }
+    removed
+  }
+
+  /// Takes the thread which should run next, if any is queued.
+  pub fn pick(&mut self, threads: &mut Threads) -> Option<ThreadId>
+  {
+    let id = self
+        .deadline
+        .pick(threads)
+        .or_else(|| self.realtime.pick(threads))
+        .or_else(|| self.fair.pick(threads))?;
+    threads[id.0].as_mut().unwrap().queued = None;
+    Some(id)
+  }
+
+  /// The priority of the thread `pick` would take.
+  pub fn best(&self, threads: &Threads) -> Option<Prio>
+  {
+    self.in_order().iter().find_map(|class| class.peek()).map(|id| prio(threads, id))
+  }
+
+  /// Charges `thread`, running here, for the time up to `now`. Returns
+  /// true if its class says it should give up the hart.
+  pub fn charge(&mut self, thread: &mut Thread, now: u64) -> bool
+  {
+    let ran = now.saturating_sub(thread.ran_since);
+    thread.ran_since = now;
+    thread.slice += ran;
+
+    match self.class(thread.prio()) {
+      Some(class) => class.charge(thread, ran, now),
+      None => false,
+    }
+  }
+
+  /// Takes a queued thread to move to another hart: the one which would
+  /// run last in the least urgent class.
+  pub fn steal(&mut self, threads: &mut Threads) -> Option<ThreadId>
+  {
+    let id = [&self.fair as &dyn Class, &self.realtime, &self.deadline]
+        .iter()
+        .find_map(|class| class.back(threads))?;
+    self.dequeue(threads, id);
+    Some(id)
+  }
+
+  /// The number of queued threads.
+  pub fn len(&self) -> usize
+  {
+    self.in_order().iter().map(|class| class.len()).sum()
+  }
+
+  /// Returns true if no thread is queued.
+  pub fn is_empty(&self) -> bool
+  {
+    self.in_order().iter().all(|class| class.is_empty())
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn prio_order()
+  {
+    assert!(Prio::Idle < Prio::Fair);
+    assert!(Prio::Fair < Prio::Realtime(0));
+    assert!(Prio::Realtime(1) < Prio::Realtime(2));
+    assert!(Prio::Realtime(realtime::MAX_PRIORITY) < Prio::Deadline(Reverse(u64::MAX)));
+    // The earlier deadline wins.
+    assert!(Prio::Deadline(Reverse(20)) < Prio::Deadline(Reverse(10)));
+  }
+
+  #[test]
+  fn policy()
+  {
+    let ms = Duration::from_millis;
+    let deadline = |runtime, deadline, period| Policy::Deadline { runtime, deadline, period };
+
+    assert!(Policy::default().is_valid());
+    assert!(!Policy::Fair { weight: 0 }.is_valid());
+    assert!(!Policy::Realtime { priority: realtime::MAX_PRIORITY + 1 }.is_valid());
+    assert!(deadline(ms(2), ms(5), ms(10)).is_valid());
+    assert!(!deadline(ms(6), ms(5), ms(10)).is_valid());
+    assert!(!deadline(ms(0), ms(5), ms(10)).is_valid());
+    assert!(!deadline(ms(2), ms(15), ms(10)).is_valid());
+
+    assert_eq!(deadline(ms(5), ms(10), ms(10)).bandwidth(), 1 << (BANDWIDTH_SHIFT - 1));
+    assert_eq!(Policy::default().bandwidth(), 0);
+  }
+}
@@ -0,0 +1,94 @@
+//! Earliest deadline first scheduling.
+//!
+//! A deadline thread reserves `runtime` in every `period`. It runs with an
+//! absolute deadline and a budget of run time; threads run in order of
+//! their deadlines. A thread which wakes after its deadline has passed
+//! starts a new period. One which uses up its budget has its deadline put
+//! back a period and its budget topped up, rather than being stopped, so
+//! it only ever delays threads with later deadlines than its own.
+
+use core::cmp::Reverse;
+
+use crate::sched::queue::RunQueue;
+use crate::sched::thread::{Thread, ThreadId, Threads};
+use crate::time;
+
+use super::{Class, Policy};
+
+/// A hart's deadline threads.
+#[derive(Default)]
+pub struct Deadline
+{
+  queue: RunQueue,
+}
+
+impl Deadline
+{
+  /// An empty queue.
+  pub const fn new() -> Self
+  {
+    Self { queue: RunQueue::new() }
+  }
+}
+
+impl Class for Deadline
+{
+  fn enqueue(&mut self, threads: &mut Threads, id: ThreadId, now: u64)
+  {
+    let thread = threads[id.0].as_mut().unwrap();
+    if let Policy::Deadline { runtime, deadline, .. } = thread.policy {
+      if now >= thread.dl_deadline {
+        thread.dl_deadline = now + time::to_ticks(deadline);
+        thread.dl_budget = time::to_ticks(runtime) as i64;
+      }
+    }
+
+    self.queue.insert(threads, id, |thread| Reverse(thread.prio()));
+  }
+
+  fn dequeue(&mut self, threads: &mut Threads, id: ThreadId) -> bool
+  {
+    self.queue.remove(threads, id)
+  }
+
+  fn pick(&mut self, threads: &mut Threads) -> Option<ThreadId>
+  {
+    self.queue.pop(threads)
+  }
+
+  fn peek(&self) -> Option<ThreadId>
+  {
+    self.queue.peek()
+  }
+
+  fn back(&self, threads: &Threads) -> Option<ThreadId>
+  {
+    self.queue.back(threads)
+  }
+
+  fn charge(&mut self, thread: &mut Thread, ran: u64, _now: u64) -> bool
+  {
+    // A thread only borrowing a deadline from a mutex waiter has no budget
+    // of its own.
+    let (runtime, period) = match thread.policy {
+      Policy::Deadline { runtime, period, .. } => (runtime, period),
+      _ => return false,
+    };
+
+    thread.dl_budget -= ran as i64;
+    if thread.dl_budget > 0 {
+      return false;
+    }
+
+    while thread.dl_budget <= 0 {
+      thread.dl_deadline += time::to_ticks(period);
+      thread.dl_budget += time::to_ticks(runtime) as i64;
+    }
+    true
+  }
+
+  fn len(&self) -> usize
+  {
+    self.queue.len()
+  }
+}
@@ -0,0 +1,100 @@
+//! Weighted fair share scheduling.
+//!
+//! Each fair thread accumulates virtual run time: the time it has run,
+//! scaled down by its weight. The thread with the least runs next, for up
+//! to `SLICE` before the others are considered again, so that over time
+//! each gets a share of the hart in proportion to its weight.
+
+use core::time::Duration;
+
+use crate::sched::queue::RunQueue;
+use crate::sched::thread::{Thread, ThreadId, Threads};
+use crate::time;
+
+use super::{Class, Policy};
+
+/// The weight of an ordinary thread.
+pub const DEFAULT_WEIGHT: u32 = 1024;
+
+/// How long a fair thread runs before the others are considered again.
+pub const SLICE: Duration = Duration::from_millis(4);
+
+/// A hart's fair share threads.
+#[derive(Default)]
+pub struct Fair
+{
+  queue: RunQueue,
+  /// The least virtual run time of the threads here, never decreasing.
+  min_vruntime: u64,
+}
+
+impl Fair
+{
+  /// An empty queue.
+  pub const fn new() -> Self
+  {
+    Self { queue: RunQueue::new(), min_vruntime: 0 }
+  }
+
+  /// Moves `thread`'s virtual run time from `from`'s clock to `to`'s, for
+  /// a thread migrating between harts.
+  pub fn rebase(thread: &mut Thread, from: &Fair, to: &Fair)
+  {
+    thread.vruntime = thread.vruntime.saturating_sub(from.min_vruntime) + to.min_vruntime;
+  }
+}
+
+impl Class for Fair
+{
+  fn enqueue(&mut self, threads: &mut Threads, id: ThreadId, _now: u64)
+  {
+    // A thread which slept a long time gets at most a slice of credit,
+    // rather than the hart to itself until it catches up.
+    let floor = self.min_vruntime.saturating_sub(time::to_ticks(SLICE));
+    let thread = threads[id.0].as_mut().unwrap();
+    thread.vruntime = thread.vruntime.max(floor);
+
+    self.queue.insert(threads, id, |thread| thread.vruntime);
+  }
+
+  fn dequeue(&mut self, threads: &mut Threads, id: ThreadId) -> bool
+  {
+    self.queue.remove(threads, id)
+  }
+
+  fn pick(&mut self, threads: &mut Threads) -> Option<ThreadId>
+  {
+    let id = self.queue.pop(threads)?;
+    let vruntime = threads[id.0].as_ref().unwrap().vruntime;
+    self.min_vruntime = self.min_vruntime.max(vruntime);
+    Some(id)
+  }
+
+  fn peek(&self) -> Option<ThreadId>
+  {
+    self.queue.peek()
+  }
+
+  fn back(&self, threads: &Threads) -> Option<ThreadId>
+  {
+    self.queue.back(threads)
+  }
+
+  fn charge(&mut self, thread: &mut Thread, ran: u64, _now: u64) -> bool
+  {
+    // A thread boosted into this class by a mutex waiter keeps its own
+    // policy, which may not be a fair one.
+    let weight = match thread.policy {
+      Policy::Fair { weight } => weight,
+      _ => DEFAULT_WEIGHT,
+    };
+    thread.vruntime += ran * DEFAULT_WEIGHT as u64 / weight as u64;
+
+    thread.slice >= time::to_ticks(SLICE) && !self.queue.is_empty()
+  }
+
+  fn len(&self) -> usize
+  {
+    self.queue.len()
+  }
+}
@@ -0,0 +1,76 @@
+//! Fixed-priority realtime scheduling.
+//!
+//! Realtime threads run strictly by priority, highest first. Threads of
+//! equal priority take turns, each for up to `SLICE` at a time.
+
+use core::cmp::Reverse;
+use core::time::Duration;
+
+use crate::sched::queue::RunQueue;
+use crate::sched::thread::{Thread, ThreadId, Threads};
+use crate::time;
+
+use super::Class;
+
+/// The highest realtime priority.
+pub const MAX_PRIORITY: u8 = 99;
+
+/// How long a realtime thread runs before another of its priority gets a
+/// turn.
+pub const SLICE: Duration = Duration::from_millis(10);
+
+/// A hart's realtime threads.
+#[derive(Default)]
+pub struct Realtime
+{
+  queue: RunQueue,
+}
+
+impl Realtime
+{
+  /// An empty queue.
+  pub const fn new() -> Self
+  {
+    Self { queue: RunQueue::new() }
+  }
+}
+
+impl Class for Realtime
+{
+  fn enqueue(&mut self, threads: &mut Threads, id: ThreadId, _now: u64)
+  {
+    self.queue.insert(threads, id, |thread| Reverse(thread.prio()));
+  }
+
+  fn dequeue(&mut self, threads: &mut Threads, id: ThreadId) -> bool
+  {
+    self.queue.remove(threads, id)
+  }
+
+  fn pick(&mut self, threads: &mut Threads) -> Option<ThreadId>
+  {
+    self.queue.pop(threads)
+  }
+
+  fn peek(&self) -> Option<ThreadId>
+  {
+    self.queue.peek()
+  }
+
+  fn back(&self, threads: &Threads) -> Option<ThreadId>
+  {
+    self.queue.back(threads)
+  }
+
+  fn charge(&mut self, thread: &mut Thread, _ran: u64, _now: u64) -> bool
+  {
+    // Anything queued of a higher priority would already have preempted
+    // it, so this only rotates threads of the same one.
+    thread.slice >= time::to_ticks(SLICE) && !self.queue.is_empty()
+  }
+
+  fn len(&self) -> usize
+  {
+    self.queue.len()
+  }
+}
@@ -0,0 +1,245 @@
+//! A sleeping mutex with priority inheritance.
+//!
+//! A thread which finds the mutex held blocks until the holder hands it
+//! over, lending the holder its priority meanwhile so that a less urgent
+//! holder cannot keep it waiting behind threads more urgent than the
+//! holder but less urgent than itself. Loans pass down chains of holders
+//! blocked on other mutexes.
+//!
+//! Uncontended locking and unlocking never touch the scheduler. Only use
+//! these from threads, never from interrupt handlers, and only once the
+//! scheduler has started on the hart.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use boot::irq;
+
+use super::thread::{State, ThreadId};
+
+// The state word: zero if the mutex is free, otherwise the holder's id
+// plus one shifted left by one, with WAITERS set while threads are
+// blocked on it. WAITERS is only set and cleared under the scheduler lock.
+const WAITERS: usize = 1;
+
+fn held_by(id: ThreadId) -> usize
+{
+  (id.0 + 1) << 1
+}
+
+/// The holder of the mutex whose state word is at `addr`.
+///
+/// # Safety
+///
+/// `addr` must be the state word of a live mutex, as it is while a thread
+/// is blocked on it.
+pub(super) unsafe fn holder(addr: usize) -> Option<ThreadId>
+{
+  match (*(addr as *const AtomicUsize)).load(Ordering::Acquire) >> 1 {
+    0 => None,
+    owner => Some(ThreadId(owner - 1)),
+  }
+}
+
+/// A mutual exclusion lock which puts waiting threads to sleep.
+pub struct Mutex<T: ?Sized>
+{
+  state: AtomicUsize,
+  data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T>
+{
+  /// An unlocked mutex holding `value`.
+  pub const fn new(value: T) -> Self
+  {
+    Self { state: AtomicUsize::new(0), data: UnsafeCell::new(value) }
+  }
+
+  /// Consumes the mutex, returning the value.
+  pub fn into_inner(self) -> T
+  {
+    self.data.into_inner()
+  }
+}
+
+impl<T: ?Sized> Mutex<T>
+{
+  fn addr(&self) -> usize
+  {
+    &self.state as *const AtomicUsize as usize
+  }
+
+  /// Locks the mutex, blocking until it is free.
+  pub fn lock(&self) -> MutexGuard<'_, T>
+  {
+    debug_assert!(!irq::in_interrupt(), "locked a sleeping mutex in an interrupt handler");
+
+    let me = super::current();
+    if self
+        .state
+        .compare_exchange(0, held_by(me), Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+      self.lock_slow(me);
+    }
+
+    MutexGuard { mutex: self, _not_send: PhantomData }
+  }
+
+  /// Locks the mutex if it is free.
+  pub fn try_lock(&self) -> Option<MutexGuard<'_, T>>
+  {
+    let me = super::current();
+    self.state
+        .compare_exchange(0, held_by(me), Ordering::Acquire, Ordering::Relaxed)
+        .ok()
+        .map(|_| MutexGuard { mutex: self, _not_send: PhantomData })
+  }
+
+  /// Returns true if some thread holds the mutex.
+  pub fn is_locked(&self) -> bool
+  {
+    self.state.load(Ordering::Relaxed) != 0
+  }
+
+  /// The value, through a unique reference, which needs no locking.
+  pub fn get_mut(&mut self) -> &mut T
+  {
+    self.data.get_mut()
+  }
+
+  fn lock_slow(&self, me: ThreadId)
+  {
+    let (mut sched, enabled) = super::lock();
+
+    // Either take it after all, or mark it as waited on so that the
+    // holder takes the slow path to unlock it.
+    let mut state = self.state.load(Ordering::Relaxed);
+    loop {
+      let (want, take) = match state {
+        0 => (held_by(me), true),
+        held => (held | WAITERS, false),
+      };
+      match self.state.compare_exchange_weak(state, want, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) if take => {
+          drop(sched);
+          irq::restore(enabled);
+          return;
+        }
+        Ok(_) => break,
+        Err(actual) => state = actual,
+      }
+    }
+
+    let holder = ThreadId((state >> 1) - 1);
+    assert!(holder != me, "thread {} locked a mutex it holds", me);
+
+    sched.deschedule(me, State::Blocked);
+    sched.get(me).blocked_on = Some(self.addr());
+    sched.update_prio(holder);
+
+    // The unlocking thread hands the mutex straight to us.
+    super::switch(sched);
+    irq::restore(enabled);
+  }
+
+  fn unlock(&self)
+  {
+    let me = super::current();
+    if self
+        .state
+        .compare_exchange(held_by(me), 0, Ordering::Release, Ordering::Relaxed)
+        .is_ok()
+    {
+      return;
+    }
+
+    let (mut sched, enabled) = super::lock();
+    let addr = self.addr();
+
+    // Hand it to the most urgent waiter.
+    let next = sched
+        .threads
+        .iter()
+        .flatten()
+        .filter(|thread| thread.blocked_on == Some(addr))
+        .max_by_key(|thread| thread.prio())
+        .map(|thread| thread.id)
+        .expect("mutex marked as waited on has no waiters");
+    let others = sched
+        .threads
+        .iter()
+        .flatten()
+        .any(|thread| thread.blocked_on == Some(addr) && thread.id != next);
+
+    let state = held_by(next) | if others { WAITERS } else { 0 };
+    self.state.store(state, Ordering::Release);
+
+    sched.get(next).blocked_on = None;
+    sched.update_prio(me);
+    sched.update_prio(next);
+    sched.wake(next);
+    drop(sched);
+    irq::restore(enabled);
+
+    // The new holder may be more urgent than we now are.
+    super::preempt_point();
+  }
+}
+
+impl<T: Default> Default for Mutex<T>
+{
+  fn default() -> Self
+  {
+    Self::new(T::default())
+  }
+}
+
+impl<T: ?Sized> fmt::Debug for Mutex<T>
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    f.debug_struct("Mutex").field("locked", &self.is_locked()).finish()
+  }
+}
+
+/// Holds a `Mutex` locked until it is dropped. Only the thread which
+/// locked it may unlock it, so it cannot be sent to another.
+pub struct MutexGuard<'a, T: ?Sized>
+{
+  mutex: &'a Mutex<T>,
+  _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T>
+{
+  type Target = T;
+
+  fn deref(&self) -> &T
+  {
+    unsafe { &*self.mutex.data.get() }
+  }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T>
+{
+  fn deref_mut(&mut self) -> &mut T
+  {
+    unsafe { &mut *self.mutex.data.get() }
+  }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T>
+{
+  fn drop(&mut self)
+  {
+    self.mutex.unlock();
+  }
+}
@@ -0,0 +1,189 @@
+//! An ordered queue of threads, linked through the thread table.
+//!
+//! Queues hold no storage of their own: each queued thread keeps the id of
+//! the one behind it in `Thread::next`, so a thread can be in at most one
+//! queue, and any number of queues costs nothing until threads join them.
+
+use super::thread::{Thread, ThreadId, Threads};
+
+/// The threads waiting for a hart in one scheduling class, in the order
+/// they should run.
+#[derive(Default)]
+pub struct RunQueue
+{
+  head: Option<ThreadId>,
+  len: usize,
+}
+
+fn thread(threads: &mut Threads, id: ThreadId) -> &mut Thread
+{
+  threads[id.0].as_mut().expect("queued thread does not exist")
+}
+
+impl RunQueue
+{
+  /// An empty queue.
+  pub const fn new() -> Self
+  {
+    Self { head: None, len: 0 }
+  }
+
+  /// The number of queued threads.
+  #[inline]
+  pub fn len(&self) -> usize
+  {
+    self.len
+  }
+
+  /// Returns true if no thread is queued.
+  #[inline]
+  pub fn is_empty(&self) -> bool
+  {
+    self.len == 0
+  }
+
+  /// The thread which would be taken next.
+  #[inline]
+  pub fn peek(&self) -> Option<ThreadId>
+  {
+    self.head
+  }
+
+  /// Queues `id` in order of `key`, smallest first, behind every queued
+  /// thread with an equal key.
+  pub fn insert<K: Ord>(&mut self, threads: &mut Threads, id: ThreadId, key: impl Fn(&Thread) -> K)
+  {
+    let new_key = key(thread(threads, id));
+
+    let mut prev = None;
+    let mut cursor = self.head;
+    while let Some(at) = cursor {
+      let queued = thread(threads, at);
+      if key(queued) > new_key {
+        break;
+      }
+      prev = Some(at);
+      cursor = queued.next;
+    }
+
+    thread(threads, id).next = cursor;
+    match prev {
+      Some(prev) => thread(threads, prev).next = Some(id),
+      None => self.head = Some(id),
+    }
+    self.len += 1;
+  }
+
+  /// Takes the thread at the front.
+  pub fn pop(&mut self, threads: &mut Threads) -> Option<ThreadId>
+  {
+    let id = self.head?;
+    let queued = thread(threads, id);
+    self.head = queued.next.take();
+    self.len -= 1;
+    Some(id)
+  }
+
+  /// Takes `id` out of the queue, wherever it is. Returns false if it was
+  /// not queued.
+  pub fn remove(&mut self, threads: &mut Threads, id: ThreadId) -> bool
+  {
+    let mut prev = None;
+    let mut cursor = self.head;
+    while let Some(at) = cursor {
+      if at == id {
+        let next = thread(threads, id).next.take();
+        match prev {
+          Some(prev) => thread(threads, prev).next = next,
+          None => self.head = next,
+        }
+        self.len -= 1;
+        return true;
+      }
+      prev = Some(at);
+      cursor = thread(threads, at).next;
+    }
+
+    false
+  }
+
+  /// The queued thread which would be taken last.
+  pub fn back(&self, threads: &Threads) -> Option<ThreadId>
+  {
+    self.iter(threads).last()
+  }
+
+  /// The queued threads, front first.
+  pub fn iter<'a>(&self, threads: &'a Threads) -> impl Iterator<Item = ThreadId> + 'a
+  {
+    let mut cursor = self.head;
+    core::iter::from_fn(move || {
+      let id = cursor?;
+      cursor = threads[id.0].as_ref().and_then(|thread| thread.next);
+      Some(id)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::sched::thread::{MAX_THREADS, NO_THREAD};
+
+  fn table(keys: &[u64]) -> Threads
+  {
+    let mut threads = [NO_THREAD; MAX_THREADS];
+    for (i, &key) in keys.iter().enumerate() {
+      let mut thread = Thread::idle(ThreadId(i), 0);
+      thread.vruntime = key;
+      threads[i] = Some(thread);
+    }
+    threads
+  }
+
+  fn by_key(thread: &Thread) -> u64
+  {
+    thread.vruntime
+  }
+
+  #[test]
+  fn ordered()
+  {
+    let mut threads = table(&[5, 1, 5, 3]);
+    let mut queue = RunQueue::new();
+    for i in 0..4 {
+      queue.insert(&mut threads, ThreadId(i), by_key);
+    }
+
+    assert_eq!(queue.len(), 4);
+    // Equal keys keep the order they were queued in.
+    let order: Vec<_> = queue.iter(&threads).collect();
+    assert_eq!(order, [ThreadId(1), ThreadId(3), ThreadId(0), ThreadId(2)]);
+    assert_eq!(queue.back(&threads), Some(ThreadId(2)));
+
+    assert_eq!(queue.pop(&mut threads), Some(ThreadId(1)));
+    assert_eq!(queue.peek(), Some(ThreadId(3)));
+  }
+
+  #[test]
+  fn remove()
+  {
+    let mut threads = table(&[0, 1, 2, 3]);
+    let mut queue = RunQueue::new();
+    for i in 0..4 {
+      queue.insert(&mut threads, ThreadId(i), by_key);
+    }
+
+    assert!(queue.remove(&mut threads, ThreadId(0)));
+    assert!(queue.remove(&mut threads, ThreadId(2)));
+    assert!(!queue.remove(&mut threads, ThreadId(2)));
+    let order: Vec<_> = queue.iter(&threads).collect();
+    assert_eq!(order, [ThreadId(1), ThreadId(3)]);
+
+    assert_eq!(queue.pop(&mut threads), Some(ThreadId(1)));
+    assert_eq!(queue.pop(&mut threads), Some(ThreadId(3)));
+    assert!(queue.pop(&mut threads).is_none());
+    assert!(queue.is_empty());
+  }
+}
@@ -0,0 +1,118 @@
+//! The context switch.
+//!
+//! A thread which gives up its hart does so by calling `switch_context`, so
+//! only the registers the calling convention says survive a call need to
+//! be kept: `ra`, `sp`, `s0`..`s11` and `fs0`..`fs11`. `tp` belongs to the
+//! hart and is left alone.
+
+/// The registers saved by `switch_context`.
+///
+/// The layout is shared with the assembly below; keep the two in sync.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SwitchContext
+{
+  /// Where `switch_context` returns to.
+  pub ra: usize,
+  /// The stack pointer.
+  pub sp: usize,
+  /// `s0`..`s11`.
+  pub s: [usize; 12],
+  /// `fs0`..`fs11`.
+  pub fs: [u64; 12],
+}
+
+impl SwitchContext
+{
+  /// A context which starts a new thread: the first switch to it enters
+  /// `thread_entry` on the stack ending at `stack_top`, with `arg` in `a0`.
+  pub fn new(stack_top: usize, arg: usize) -> Self
+  {
+    extern "C"
+    {
+      fn thread_entry();
+    }
+
+    let mut s = [0; 12];
+    s[0] = arg;
+    Self { ra: thread_entry as usize, sp: stack_top, s, fs: [0; 12] }
+  }
+}
+
+extern "C"
+{
+  /// Saves the callee-saved registers into `prev` and loads them from
+  /// `next`, returning into whatever `next` last switched away from.
+  pub fn switch_context(prev: *mut SwitchContext, next: *const SwitchContext);
+}
+
+global_asm!(
+  r#"
+.section .text
+.global switch_context
+.align 2
+switch_context:
+    sd ra, 0(a0)
+    sd sp, 8(a0)
+    sd s0, 16(a0)
+    sd s1, 24(a0)
+    sd s2, 32(a0)
+    sd s3, 40(a0)
+    sd s4, 48(a0)
+    sd s5, 56(a0)
+    sd s6, 64(a0)
+    sd s7, 72(a0)
+    sd s8, 80(a0)
+    sd s9, 88(a0)
+    sd s10, 96(a0)
+    sd s11, 104(a0)
+    fsd fs0, 112(a0)
+    fsd fs1, 120(a0)
+    fsd fs2, 128(a0)
+    fsd fs3, 136(a0)
+    fsd fs4, 144(a0)
+    fsd fs5, 152(a0)
+    fsd fs6, 160(a0)
+    fsd fs7, 168(a0)
+    fsd fs8, 176(a0)
+    fsd fs9, 184(a0)
+    fsd fs10, 192(a0)
+    fsd fs11, 200(a0)
+
+    ld ra, 0(a1)
+    ld sp, 8(a1)
+    ld s0, 16(a1)
+    ld s1, 24(a1)
+    ld s2, 32(a1)
+    ld s3, 40(a1)
+    ld s4, 48(a1)
+    ld s5, 56(a1)
+    ld s6, 64(a1)
+    ld s7, 72(a1)
+    ld s8, 80(a1)
+    ld s9, 88(a1)
+    ld s10, 96(a1)
+    ld s11, 104(a1)
+    fld fs0, 112(a1)
+    fld fs1, 120(a1)
+    fld fs2, 128(a1)
+    fld fs3, 136(a1)
+    fld fs4, 144(a1)
+    fld fs5, 152(a1)
+    fld fs6, 160(a1)
+    fld fs7, 168(a1)
+    fld fs8, 176(a1)
+    fld fs9, 184(a1)
+    fld fs10, 192(a1)
+    fld fs11, 200(a1)
+    ret
+
+// The first switch to a new thread returns here, with its argument in s0.
+.global thread_entry
+.align 2
+thread_entry:
+    mv a0, s0
+    mv ra, zero
+    j thread_start
+"#
+);
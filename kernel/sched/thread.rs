@@ -0,0 +1,263 @@
+//! Kernel threads.
+
+use core::fmt::{self, Display};
+use core::mem::size_of;
+
+use boot::trap::TrapFrame;
+use system::alloc::alloc::ctx::Context;
+use system::alloc::alloc::page::PAGE_SIZE;
+
+use super::class::{Policy, Prio};
+use super::switch::SwitchContext;
+use crate::process::Pid;
+
+/// The most threads which can exist at once, including each hart's idle
+/// thread.
+pub const MAX_THREADS: usize = 256;
+
+/// The size of a kernel thread's stack, in pages.
+pub const STACK_PAGES: usize = 4;
+
+/// The size of a kernel thread's stack, in bytes.
+pub const STACK_SIZE: usize = STACK_PAGES * PAGE_SIZE;
+
+/// The bytes at the top of every stack kept for the frame of a trap from
+/// U-mode; the thread itself starts below them.
+const USER_FRAME_SIZE: usize = size_of::<TrapFrame>();
+
+/// The thread table, indexed by `ThreadId`.
+pub type Threads = [Option<Thread>; MAX_THREADS];
+
+/// An empty slot of the thread table.
+pub const NO_THREAD: Option<Thread> = None;
+
+/// Identifies a thread for as long as it exists. Ids are reused once a
+/// thread has been joined.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(pub usize);
+
+impl Display for ThreadId
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "{}", self.0)
+  }
+}
+
+/// The body of a thread. Its argument is the one given to `spawn`, and
+/// the value it returns is handed to whoever joins it.
+pub type Entry = fn(arg: usize) -> usize;
+
+/// What a thread is doing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State
+{
+  /// Waiting in a run queue.
+  Ready,
+  /// Running on a hart.
+  Running,
+  /// Waiting for its wake-up time to pass.
+  Sleeping,
+  /// Waiting for another thread to exit.
+  Joining,
+  /// Waiting for a kernel mutex.
+  Blocked,
+  /// Waiting in `park` for `unpark`.
+  Parked,
+  /// Finished, waiting to be joined.
+  Exited,
+}
+
+/// A kernel thread.
+pub struct Thread
+{
+  pub(super) id: ThreadId,
+  pub(super) name: &'static str,
+  pub(super) state: State,
+  pub(super) context: SwitchContext,
+  /// The physical address of the stack, or zero for an idle thread, which
+  /// runs on its hart's boot stack.
+  pub(super) stack: usize,
+  pub(super) entry: Option<Entry>,
+  pub(super) arg: usize,
+  /// The hart it last ran on.
+  pub(super) hart: usize,
+  /// The tick count a sleeping thread wakes at.
+  pub(super) wake_at: u64,
+  /// The thread waiting in `join` for this one.
+  pub(super) joiner: Option<ThreadId>,
+  /// The value the entry returned, once it has.
+  pub(super) exit_code: usize,
+  /// The process it runs in, or `None` for a kernel thread.
+  pub(super) process: Option<Pid>,
+  /// `satp` for the address space it runs in, or zero for the kernel's.
+  pub(super) satp: usize,
+
+  /// How it is scheduled.
+  pub(super) policy: Policy,
+  /// The priority lent to it by threads waiting on mutexes it holds, or
+  /// `Prio::Idle` if none.
+  pub(super) boost: Prio,
+  /// The address of the kernel mutex it is blocked on.
+  pub(super) blocked_on: Option<usize>,
+  /// Set by `unpark` when it is not parked, so that its next `park`
+  /// returns at once.
+  pub(super) unparked: bool,
+  /// The hart whose run queue it is in.
+  pub(super) queued: Option<usize>,
+  /// The thread behind it in that queue.
+  pub(super) next: Option<ThreadId>,
+  /// The time it was last switched to or charged for, in ticks of `mtime`.
+  pub(super) ran_since: u64,
+  /// The ticks it has run since it was last switched to.
+  pub(super) slice: u64,
+  /// Its weighted run time, for the fair class.
+  pub(super) vruntime: u64,
+  /// Its current absolute deadline, for the deadline class.
+  pub(super) dl_deadline: u64,
+  /// The run time left before its deadline, for the deadline class.
+  pub(super) dl_budget: i64,
+}
+
+impl Thread
+{
+  /// A thread which will run `entry(arg)` on the stack at `stack`.
+  pub(super) fn new(
+    id: ThreadId,
+    name: &'static str,
+    entry: Entry,
+    arg: usize,
+    stack: usize,
+    policy: Policy,
+  ) -> Self
+  {
+    Self {
+      name,
+      state: State::Ready,
+      context: SwitchContext::new(stack + STACK_SIZE - USER_FRAME_SIZE, id.0),
+      stack,
+      entry: Some(entry),
+      arg,
+      policy,
+      ..Self::idle(id, 0)
+    }
+  }
+
+  /// The idle thread of `hart`: whatever was running there when the
+  /// scheduler started. Its context is filled in the first time it is
+  /// switched away from.
+  pub(super) fn idle(id: ThreadId, hart: usize) -> Self
+  {
+    Self {
+      id,
+      name: "idle",
+      state: State::Running,
+      context: SwitchContext::default(),
+      stack: 0,
+      entry: None,
+      arg: 0,
+      hart,
+      wake_at: 0,
+      joiner: None,
+      exit_code: 0,
+      process: None,
+      satp: 0,
+      policy: Policy::default(),
+      boost: Prio::Idle,
+      blocked_on: None,
+      unparked: false,
+      queued: None,
+      next: None,
+      ran_since: 0,
+      slice: 0,
+      vruntime: 0,
+      dl_deadline: 0,
+      dl_budget: 0,
+    }
+  }
+
+  /// The priority its policy gives it.
+  pub fn base_prio(&self) -> Prio
+  {
+    if self.entry.is_none() {
+      return Prio::Idle;
+    }
+
+    self.policy.prio(self.dl_deadline)
+  }
+
+  /// The priority it is scheduled at: its own, or a higher one lent by a
+  /// thread waiting on a mutex it holds.
+  #[inline]
+  pub fn prio(&self) -> Prio
+  {
+    self.base_prio().max(self.boost)
+  }
+
+  /// How it is scheduled.
+  #[inline]
+  pub fn policy(&self) -> Policy
+  {
+    self.policy
+  }
+
+  /// The thread's id.
+  #[inline]
+  pub fn id(&self) -> ThreadId
+  {
+    self.id
+  }
+
+  /// The name the thread was spawned with.
+  #[inline]
+  pub fn name(&self) -> &'static str
+  {
+    self.name
+  }
+
+  /// What the thread is doing.
+  #[inline]
+  pub fn state(&self) -> State
+  {
+    self.state
+  }
+
+  /// The hart the thread runs or last ran on.
+  #[inline]
+  pub fn hart(&self) -> usize
+  {
+    self.hart
+  }
+
+  /// The process the thread runs in, or `None` for a kernel thread.
+  #[inline]
+  pub fn process(&self) -> Option<Pid>
+  {
+    self.process
+  }
+
+  /// Where `_start_trap` saves the thread's registers when it traps from
+  /// U-mode: the top of its stack.
+  pub(super) fn user_frame(&self) -> *mut TrapFrame
+  {
+    (self.stack + STACK_SIZE - USER_FRAME_SIZE) as *mut TrapFrame
+  }
+}
+
+impl Context for Thread
+{
+  fn is_idle(&self) -> bool
+  {
+    self.entry.is_none()
+  }
+
+  fn is_live(&self) -> bool
+  {
+    self.state != State::Exited
+  }
+
+  fn is_threaded(&self) -> bool
+  {
+    true
+  }
+}
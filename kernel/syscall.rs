@@ -0,0 +1,328 @@
+//! The system call interface.
+//!
+//! A process makes a call with `ecall`: the call's number in `a7` and up
+//! to six arguments in `a0`..`a5`. It returns past the `ecall` with the
+//! result in `a0` and zero in `a1`, or zero in `a0` and an `Errno` in `a1`.
+//!
+//! Calls are declared with `#[syscall(number)]`, which puts them in the
+//! `.syscalls` section along with the code decoding their arguments; the
+//! dispatcher builds its table from there the first time it runs. Pointer
+//! arguments are `UserPtr`s, which only reach memory the caller has mapped
+//! with the permissions the access needs.
+
+use core::convert::TryFrom;
+use core::fmt::{self, Display};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::slice;
+
+use boot::trap::TrapFrame;
+use system::alloc::spin::Once;
+
+use crate::process::{self, Pid, ProcessError};
+
+pub mod calls;
+
+/// One more than the highest call number: the size of the dispatch table.
+pub const MAX_SYSCALLS: usize = 64;
+
+/// The raw arguments of a call, from `a0`..`a5`.
+pub type Args = [usize; 6];
+
+/// What a call returns to its caller.
+pub type SysResult<T = usize> = Result<T, Errno>;
+
+/// Why a call failed, as returned in `a1`.
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Errno
+{
+  /// No such process.
+  NoProcess = 3,
+  /// The handle is not open, or not for this.
+  BadHandle = 9,
+  /// Out of memory, or of some other resource.
+  NoMemory = 12,
+  /// A pointer argument does not point at memory the caller can access.
+  Fault = 14,
+  /// An argument is out of range.
+  Invalid = 22,
+  /// No call has this number.
+  NoSys = 38,
+}
+
+impl Display for Errno
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    match self {
+      Errno::NoProcess => write!(f, "no such process"),
+      Errno::BadHandle => write!(f, "bad handle"),
+      Errno::NoMemory => write!(f, "out of memory"),
+      Errno::Fault => write!(f, "bad address"),
+      Errno::Invalid => write!(f, "invalid argument"),
+      Errno::NoSys => write!(f, "no such call"),
+    }
+  }
+}
+
+impl From<ProcessError> for Errno
+{
+  fn from(e: ProcessError) -> Self
+  {
+    match e {
+      ProcessError::NoMemory
+      | ProcessError::TooManyProcesses
+      | ProcessError::TooManyThreads
+      | ProcessError::TooManyMappings => Errno::NoMemory,
+      ProcessError::NoSuchProcess(_) => Errno::NoProcess,
+      ProcessError::BadAddress(_) => Errno::Fault,
+      ProcessError::BadPermissions(_)
+      | ProcessError::Sched(_)
+      | ProcessError::BadImage(_)
+      | ProcessError::ArgsTooLong => Errno::Invalid,
+    }
+  }
+}
+
+/// A parameter type of a `#[syscall]` function, decoded from one argument
+/// register.
+pub trait FromArg: Sized
+{
+  /// Decodes `raw`, failing the call if it is not a valid `Self`.
+  fn from_arg(raw: usize) -> SysResult<Self>;
+}
+
+impl FromArg for usize
+{
+  fn from_arg(raw: usize) -> SysResult<Self>
+  {
+    Ok(raw)
+  }
+}
+
+impl FromArg for isize
+{
+  fn from_arg(raw: usize) -> SysResult<Self>
+  {
+    Ok(raw as isize)
+  }
+}
+
+impl FromArg for u64
+{
+  fn from_arg(raw: usize) -> SysResult<Self>
+  {
+    Ok(raw as u64)
+  }
+}
+
+impl FromArg for u32
+{
+  fn from_arg(raw: usize) -> SysResult<Self>
+  {
+    Self::try_from(raw).map_err(|_| Errno::Invalid)
+  }
+}
+
+/// A pointer into the caller's memory, which has been checked to lie in
+/// user memory and be aligned for `T`, but nothing else until it is used.
+#[derive(Debug)]
+pub struct UserPtr<T>
+{
+  addr: usize,
+  _marker: PhantomData<*const T>,
+}
+
+impl<T> Clone for UserPtr<T>
+{
+  fn clone(&self) -> Self
+  {
+    *self
+  }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> FromArg for UserPtr<T>
+{
+  fn from_arg(raw: usize) -> SysResult<Self>
+  {
+    if !process::is_user(raw, 1) {
+      return Err(Errno::Fault);
+    }
+    if raw % align_of::<T>() != 0 {
+      return Err(Errno::Invalid);
+    }
+
+    Ok(Self { addr: raw, _marker: PhantomData })
+  }
+}
+
+impl<T> UserPtr<T>
+{
+  /// The address in the caller's memory.
+  #[inline]
+  pub fn addr(&self) -> usize
+  {
+    self.addr
+  }
+}
+
+impl<T: Copy> UserPtr<T>
+{
+  /// Stores `value` at the pointer, which the caller must be able to write.
+  pub fn write(&self, value: &T) -> SysResult<()>
+  {
+    let bytes = unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    let pid = caller()?;
+    process::with(pid, |process| process.copy_to_user(self.addr, bytes))??;
+    Ok(())
+  }
+}
+
+impl UserPtr<u8>
+{
+  /// Fills `buf` from `offset` bytes past the pointer, which the caller must
+  /// be able to read.
+  pub fn read(&self, offset: usize, buf: &mut [u8]) -> SysResult<()>
+  {
+    let addr = self.addr.checked_add(offset).ok_or(Errno::Fault)?;
+    let pid = caller()?;
+    process::with(pid, |process| process.copy_from_user(addr, buf))??;
+    Ok(())
+  }
+}
+
+/// The process making the current call.
+pub fn caller() -> SysResult<Pid>
+{
+  process::current().ok_or(Errno::NoProcess)
+}
+
+/// A call, as `#[syscall]` registers it.
+pub struct Syscall
+{
+  /// The number in `a7` which selects it.
+  pub number: usize,
+  /// The name of the function implementing it.
+  pub name: &'static str,
+  /// Decodes the arguments and makes the call.
+  pub handler: fn(&Args) -> SysResult,
+}
+
+/// The calls, indexed by number.
+pub struct Table([Option<&'static Syscall>; MAX_SYSCALLS]);
+
+impl Table
+{
+  /// A table of `calls`. Panics if a number is out of range or used twice.
+  pub fn new(calls: &'static [Syscall]) -> Self
+  {
+    let mut table = [None; MAX_SYSCALLS];
+    for call in calls {
+      let slot = table
+          .get_mut(call.number)
+          .unwrap_or_else(|| panic!("syscall {} has number {}", call.name, call.number));
+      if let Some(other) = slot.replace(call) {
+        panic!("syscalls {} and {} share number {}", other.name, call.name, call.number);
+      }
+    }
+
+    Table(table)
+  }
+
+  /// The call numbered `number`.
+  pub fn get(&self, number: usize) -> Option<&'static Syscall>
+  {
+    self.0.get(number).copied().flatten()
+  }
+}
+
+static TABLE: Once<Table> = Once::new();
+
+/// Every call `#[syscall]` registered.
+fn registered() -> &'static [Syscall]
+{
+  extern "C"
+  {
+    // Boundaries of the .syscalls section, in .rodata.
+    static _syscalls_start: u8;
+    static _syscalls_end: u8;
+  }
+
+  unsafe {
+    let start = &_syscalls_start as *const u8 as usize;
+    let end = &_syscalls_end as *const u8 as usize;
+    slice::from_raw_parts(start as *const Syscall, (end - start) / size_of::<Syscall>())
+  }
+}
+
+/// Makes the call whose number and arguments are in `frame`, and puts
+/// the result in its `a0` and `a1`.
+pub fn dispatch(frame: &mut TrapFrame)
+{
+  let table = TABLE.call_once(|| Table::new(registered()));
+  let args = [frame.arg(0), frame.arg(1), frame.arg(2), frame.arg(3), frame.arg(4), frame.arg(5)];
+
+  let result = match table.get(frame.arg(7)) {
+    Some(call) => (call.handler)(&args),
+    None => Err(Errno::NoSys),
+  };
+
+  let (value, error) = match result {
+    Ok(value) => (value, 0),
+    Err(errno) => (0, errno as usize),
+  };
+  frame.set_arg(0, value);
+  frame.set_arg(1, error);
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use crate::process::{USER_BASE, USER_END};
+
+  fn nothing(_: &Args) -> SysResult
+  {
+    Ok(0)
+  }
+
+  static CALLS: [Syscall; 2] = [
+    Syscall { number: 3, name: "three", handler: nothing },
+    Syscall { number: 0, name: "zero", handler: nothing },
+  ];
+
+  static CLASH: [Syscall; 2] = [
+    Syscall { number: 1, name: "one", handler: nothing },
+    Syscall { number: 1, name: "uno", handler: nothing },
+  ];
+
+  #[test]
+  fn table()
+  {
+    let table = Table::new(&CALLS);
+    assert_eq!(table.get(3).map(|call| call.name), Some("three"));
+    assert_eq!(table.get(0).map(|call| call.name), Some("zero"));
+    assert!(table.get(1).is_none());
+    assert!(table.get(MAX_SYSCALLS).is_none());
+  }
+
+  #[test]
+  #[should_panic]
+  fn clash()
+  {
+    Table::new(&CLASH);
+  }
+
+  #[test]
+  fn user_ptr()
+  {
+    assert_eq!(UserPtr::<u64>::from_arg(USER_BASE).map(|ptr| ptr.addr()), Ok(USER_BASE));
+    assert_eq!(UserPtr::<u8>::from_arg(0).err(), Some(Errno::Fault));
+    assert_eq!(UserPtr::<u8>::from_arg(USER_END).err(), Some(Errno::Fault));
+    assert_eq!(UserPtr::<u64>::from_arg(USER_BASE + 4).err(), Some(Errno::Invalid));
+    assert_eq!(u32::from_arg(usize::MAX), Err(Errno::Invalid));
+  }
+}
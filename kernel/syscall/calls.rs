@@ -0,0 +1,177 @@
+//! The calls every process can make.
+
+use core::fmt::{self, Display, Write};
+use core::time::Duration;
+
+use boot::syscall;
+use system::alloc::alloc::page::entry::EntryBits;
+use system::console::print;
+
+use super::{caller, Errno, SysResult, UserPtr};
+use crate::process::{self, Handle};
+use crate::sched;
+use crate::time::Instant;
+
+/// The call numbers.
+pub mod number
+{
+  /// `exit(code)`
+  pub const EXIT: usize = 0;
+  /// `write(handle, buf, len)`
+  pub const WRITE: usize = 1;
+  /// `mmap(addr, len, prot)`
+  pub const MMAP: usize = 2;
+  /// `munmap(addr, len)`
+  pub const MUNMAP: usize = 3;
+  /// `yield()`
+  pub const YIELD: usize = 4;
+  /// `sleep(nanos)`
+  pub const SLEEP: usize = 5;
+  /// `getpid()`
+  pub const GETPID: usize = 6;
+  /// `clock_gettime(clock, timespec)`
+  pub const CLOCK_GETTIME: usize = 7;
+}
+
+/// `mmap` protection: the memory can be read.
+pub const PROT_READ: usize = 1 << 0;
+/// `mmap` protection: the memory can be written.
+pub const PROT_WRITE: usize = 1 << 1;
+/// `mmap` protection: the memory can be executed.
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// The only clock: the time since the machine was reset.
+pub const CLOCK_MONOTONIC: usize = 0;
+
+/// A point in time, as `clock_gettime` stores it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timespec
+{
+  /// Whole seconds.
+  pub secs: u64,
+  /// Nanoseconds past `secs`.
+  pub nanos: u64,
+}
+
+/// How much of a buffer `write` copies in at a time.
+const CHUNK: usize = 128;
+
+/// Shows arbitrary bytes one character each, as the console would.
+struct Bytes<'a>(&'a [u8]);
+
+impl Display for Bytes<'_>
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    self.0.iter().try_for_each(|&byte| f.write_char(byte as char))
+  }
+}
+
+/// Ends the calling thread; its process ends with its last thread.
+#[syscall(number::EXIT)]
+fn exit(code: usize) -> SysResult
+{
+  process::exit(code)
+}
+
+/// Writes `len` bytes from `buf` to `handle`. Returns the number written.
+#[syscall(number::WRITE)]
+fn write(handle: usize, buf: UserPtr<u8>, len: usize) -> SysResult
+{
+  let pid = caller()?;
+  match process::with(pid, |process| process.handles().get(handle))? {
+    Some(Handle::Console) => {}
+    _ => return Err(Errno::BadHandle),
+  }
+
+  let mut chunk = [0; CHUNK];
+  let mut done = 0;
+  while done < len {
+    let n = (len - done).min(CHUNK);
+    buf.read(done, &mut chunk[..n])?;
+    print!("{}", Bytes(&chunk[..n]));
+    done += n;
+  }
+
+  Ok(len)
+}
+
+/// Maps `len` bytes of zeroed memory with the `PROT_*` permissions in
+/// `prot`, at `addr` or, if it is zero, wherever there is room. Returns
+/// the address. Writable memory is readable too, as pages cannot be
+/// write-only.
+#[syscall(number::MMAP)]
+fn mmap(addr: usize, len: usize, prot: usize) -> SysResult
+{
+  let all = PROT_READ | PROT_WRITE | PROT_EXEC;
+  if prot & !all != 0 || prot & all == 0 || len == 0 {
+    return Err(Errno::Invalid);
+  }
+
+  let mut bits = 0;
+  for &(flag, bit) in &[
+    (PROT_READ | PROT_WRITE, EntryBits::Read),
+    (PROT_WRITE, EntryBits::Write),
+    (PROT_EXEC, EntryBits::Execute),
+  ] {
+    if prot & flag != 0 {
+      bits |= bit.val();
+    }
+  }
+
+  let pid = caller()?;
+  process::with(pid, |process| {
+    let addr = match addr {
+      0 => process.find_free(len).ok_or(Errno::NoMemory)?,
+      addr => addr,
+    };
+    process.map(addr, len, bits)?;
+    Ok(addr)
+  })?
+}
+
+/// Unmaps the whole of what one `mmap` mapped.
+#[syscall(number::MUNMAP)]
+fn munmap(addr: usize, len: usize) -> SysResult
+{
+  let pid = caller()?;
+  process::with(pid, |process| process.unmap(addr, len))??;
+  Ok(0)
+}
+
+/// Lets the other ready threads run first.
+#[syscall(number::YIELD)]
+fn yield_now() -> SysResult
+{
+  sched::yield_now();
+  Ok(0)
+}
+
+/// Blocks the calling thread for at least `nanos` nanoseconds.
+#[syscall(number::SLEEP)]
+fn sleep(nanos: u64) -> SysResult
+{
+  sched::sleep(Duration::from_nanos(nanos));
+  Ok(0)
+}
+
+/// The caller's process id.
+#[syscall(number::GETPID)]
+fn getpid() -> SysResult
+{
+  Ok(caller()?.0)
+}
+
+/// Stores the current time of `clock` at `tp`.
+#[syscall(number::CLOCK_GETTIME)]
+fn clock_gettime(clock: usize, tp: UserPtr<Timespec>) -> SysResult
+{
+  if clock != CLOCK_MONOTONIC {
+    return Err(Errno::Invalid);
+  }
+
+  let now = Instant::now().duration_since(Instant::from_ticks(0));
+  tp.write(&Timespec { secs: now.as_secs(), nanos: now.subsec_nanos() as u64 })?;
+  Ok(0)
+}
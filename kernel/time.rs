@@ -0,0 +1,276 @@
+//! The kernel clock and per-hart timers.
+//!
+//! Time is counted in ticks of the `time` CSR, which mirrors the shared
+//! `mtime` counter and whose frequency the device tree gives as
+//! `/cpus/timebase-frequency`. Each hart has one timer, which can be armed
+//! for a single deadline or to fire periodically; the kernel tick is a
+//! periodic timer on every hart. Timers are programmed through the SBI.
+
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use boot::irq::{self, Interrupt};
+use boot::{sbi, MAX_HARTS};
+use fdt::Fdt;
+use system::alloc::spin::Mutex;
+
+/// How often the kernel tick fires, per second.
+pub const TICK_HZ: u64 = 100;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// The frequency of `mtime`, in Hz.
+static TIMEBASE: AtomicU64 = AtomicU64::new(0);
+
+/// The number of kernel ticks handled, over all harts.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A callback run from the timer interrupt, with the time it fired at.
+pub type Callback = fn(now: Instant);
+
+/// The state of one hart's timer.
+#[derive(Copy, Clone)]
+struct Timer
+{
+  /// The tick count the timer fires at next.
+  deadline: u64,
+  /// Ticks between firings, or zero for a one-shot timer.
+  period: u64,
+  callback: Option<Callback>,
+}
+
+const DISARMED: Timer = Timer { deadline: u64::MAX, period: 0, callback: None };
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IDLE: Mutex<Timer> = Mutex::new(DISARMED);
+
+static TIMERS: [Mutex<Timer>; MAX_HARTS] = [IDLE; MAX_HARTS];
+
+/// A point in time, measured by `time` since the machine was reset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant
+{
+  /// The current time.
+  pub fn now() -> Self
+  {
+    Instant(sbi::time())
+  }
+
+  /// The instant `ticks` ticks of `mtime` after reset.
+  #[inline]
+  pub const fn from_ticks(ticks: u64) -> Self
+  {
+    Instant(ticks)
+  }
+
+  /// The number of ticks of `mtime` since reset.
+  #[inline]
+  pub const fn ticks(self) -> u64
+  {
+    self.0
+  }
+
+  /// The time from `earlier` to `self`, or zero if `earlier` is later.
+  pub fn duration_since(self, earlier: Instant) -> Duration
+  {
+    to_duration(self.0.saturating_sub(earlier.0))
+  }
+
+  /// The time that has passed since `self`.
+  pub fn elapsed(self) -> Duration
+  {
+    Instant::now().duration_since(self)
+  }
+
+  /// `self + duration`, or `None` if that cannot be represented.
+  pub fn checked_add(self, duration: Duration) -> Option<Instant>
+  {
+    self.0.checked_add(to_ticks(duration)).map(Instant)
+  }
+
+  /// `self - duration`, or `None` if that is before reset.
+  pub fn checked_sub(self, duration: Duration) -> Option<Instant>
+  {
+    self.0.checked_sub(to_ticks(duration)).map(Instant)
+  }
+}
+
+impl Add<Duration> for Instant
+{
+  type Output = Instant;
+
+  fn add(self, rhs: Duration) -> Instant
+  {
+    self.checked_add(rhs).expect("overflow when adding duration to instant")
+  }
+}
+
+impl AddAssign<Duration> for Instant
+{
+  fn add_assign(&mut self, rhs: Duration)
+  {
+    *self = *self + rhs;
+  }
+}
+
+impl Sub<Duration> for Instant
+{
+  type Output = Instant;
+
+  fn sub(self, rhs: Duration) -> Instant
+  {
+    self.checked_sub(rhs).expect("overflow when subtracting duration from instant")
+  }
+}
+
+impl Sub<Instant> for Instant
+{
+  type Output = Duration;
+
+  fn sub(self, rhs: Instant) -> Duration
+  {
+    self.duration_since(rhs)
+  }
+}
+
+/// The frequency of `mtime`, in Hz.
+#[inline]
+pub fn timebase() -> u64
+{
+  TIMEBASE.load(Ordering::Relaxed)
+}
+
+/// `ticks` ticks of `mtime` as a `Duration`; zero until `init` has read the
+/// timebase.
+fn to_duration(ticks: u64) -> Duration
+{
+  let timebase = timebase();
+  if timebase == 0 {
+    return Duration::from_secs(0);
+  }
+  let nanos = ticks as u128 * NANOS_PER_SEC / timebase as u128;
+  Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+}
+
+/// `duration` in ticks of `mtime`, saturating.
+pub(crate) fn to_ticks(duration: Duration) -> u64
+{
+  let ticks = duration.as_nanos() * timebase() as u128 / NANOS_PER_SEC;
+  if ticks > u64::MAX as u128 { u64::MAX } else { ticks as u64 }
+}
+
+/// The number of kernel ticks handled so far, over all harts.
+#[inline]
+pub fn ticks() -> u64
+{
+  TICKS.load(Ordering::Relaxed)
+}
+
+/// Reads the timebase frequency from the device tree.
+pub fn init(fdt: &Fdt)
+{
+  // Some trees only give it per cpu.
+  let timebase = fdt
+      .find_node("/cpus")
+      .and_then(|cpus| {
+        cpus.property("timebase-frequency")
+            .or_else(|| cpus.children().find_map(|cpu| cpu.property("timebase-frequency")))
+      })
+      .and_then(|prop| prop.as_u64())
+      .expect("no timebase-frequency in the device tree");
+  TIMEBASE.store(timebase, Ordering::Relaxed);
+
+  let _ = irq::register(Interrupt::SupervisorTimer, handle_timer, core::ptr::null_mut());
+}
+
+/// Runs `f` on this hart's timer with interrupts masked, so that the
+/// handler cannot spin on the lock while we hold it and the thread cannot
+/// be moved to another hart.
+fn with_timer<R>(f: impl FnOnce(&mut Timer) -> R) -> R
+{
+  irq::free(|| f(&mut TIMERS[boot::hart_id()].lock()))
+}
+
+/// Arms this hart's timer to call `callback` once, at `deadline`, and
+/// unmasks its interrupt. Replaces whatever the timer was armed for.
+pub fn set_oneshot(deadline: Instant, callback: Callback)
+{
+  with_timer(|timer| {
+    *timer = Timer { deadline: deadline.0, period: 0, callback: Some(callback) };
+    sbi::set_timer(deadline.0);
+    irq::enable(Interrupt::SupervisorTimer);
+  });
+}
+
+/// Arms this hart's timer to call `callback` every `period`, starting one
+/// period from now, and unmasks its interrupt. Replaces whatever the timer
+/// was armed for.
+pub fn set_periodic(period: Duration, callback: Callback)
+{
+  let period = to_ticks(period).max(1);
+  with_timer(|timer| {
+    let deadline = sbi::time().saturating_add(period);
+    *timer = Timer { deadline, period, callback: Some(callback) };
+    sbi::set_timer(deadline);
+    irq::enable(Interrupt::SupervisorTimer);
+  });
+}
+
+/// Disarms this hart's timer.
+pub fn cancel()
+{
+  with_timer(|timer| {
+    *timer = DISARMED;
+    sbi::set_timer(u64::MAX);
+  });
+}
+
+/// Starts the kernel tick on this hart.
+pub fn start_tick()
+{
+  set_periodic(Duration::from_nanos(1_000_000_000 / TICK_HZ), tick);
+}
+
+fn tick(now: Instant)
+{
+  TICKS.fetch_add(1, Ordering::Relaxed);
+  crate::sched::tick(now);
+}
+
+/// Services this hart's timer interrupt.
+fn handle_timer(_ctx: *mut ())
+{
+  let hart = boot::hart_id();
+  let now = sbi::time();
+
+  let callback = {
+    let mut timer = TIMERS[hart].lock();
+    if now < timer.deadline {
+      // Re-armed after this interrupt was raised.
+      sbi::set_timer(timer.deadline);
+      return;
+    }
+
+    if timer.period == 0 {
+      timer.deadline = u64::MAX;
+    } else {
+      // Skip any periods we were too late for.
+      let missed = (now - timer.deadline) / timer.period;
+      timer.deadline = timer.deadline.saturating_add((missed + 1) * timer.period);
+    }
+    sbi::set_timer(timer.deadline);
+
+    let callback = timer.callback;
+    if timer.period == 0 {
+      timer.callback = None;
+    }
+    callback
+  };
+
+  if let Some(callback) = callback {
+    callback(Instant(now));
+  }
+}
@@ -0,0 +1,59 @@
+//! Kernel exception handling.
+
+use boot::irq;
+use boot::trap::{Exception, Trap, TrapFrame};
+use system::console::println;
+
+use crate::{process, syscall};
+
+/// Called by the boot trap entry for every exception.
+///
+/// Exceptions from U-mode belong to the process which took them. None of
+/// the kernel's own can be recovered from yet, so the registers are dumped
+/// to the console and the kernel panics with the cause and, for faults,
+/// the address which was accessed.
+#[export_name = "ExceptionHandler"]
+pub extern "C" fn exception_handler(frame: &mut TrapFrame)
+{
+  let exception = match frame.trap() {
+    Trap::Exception(exception) => exception,
+    Trap::Interrupt(code) => panic!("interrupt {} delivered as an exception", code),
+  };
+
+  if frame.from_user() {
+    return user_exception(exception, frame);
+  }
+
+  println!("{}", frame);
+
+  if exception.has_address() {
+    panic!("{} at {:#x} accessing {:#x}", exception, frame.epc, frame.tval);
+  } else {
+    panic!("{} at {:#x}", exception, frame.epc);
+  }
+}
+
+/// Makes the system call an `ecall` from U-mode asks for, with interrupts
+/// enabled meanwhile, and returns past it; any other exception ends the
+/// thread which took it.
+fn user_exception(exception: Exception, frame: &mut TrapFrame)
+{
+  let pid = process::current().expect("U-mode trap in a kernel thread");
+
+  if exception == Exception::UserEnvCall {
+    frame.epc += 4;
+    unsafe {
+      irq::enable_global();
+    }
+    syscall::dispatch(frame);
+    irq::save_and_disable();
+    return;
+  }
+
+  if exception.has_address() {
+    println!("process {}: {} at {:#x} accessing {:#x}", pid, exception, frame.epc, frame.tval);
+  } else {
+    println!("process {}: {} at {:#x}", pid, exception, frame.epc);
+  }
+  process::exit(process::KILLED);
+}
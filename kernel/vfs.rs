@@ -0,0 +1,97 @@
+//! The kernel's file tree.
+//!
+//! The root is a tmpfs, allowed a share of the frames free at boot; file
+//! systems on devices are mounted into it as their drivers find them.
+
+use fs::block::BlockDevice;
+use fs::ext2::Ext2;
+use fs::fat::Fat;
+use fs::tmpfs::Tmpfs;
+use fs::vfs::{FileSystem, FsError, FsResult, Vfs};
+use system::alloc::alloc::frame::FRAMES;
+use system::alloc::alloc::page::PAGE_SIZE;
+use system::alloc::spin::Once;
+use system::alloc::{Shared, String};
+use system::console::println;
+
+use crate::block;
+
+/// The root tmpfs may hold one in this many of the frames free at boot.
+pub const ROOT_SHARE: usize = 4;
+
+static VFS: Once<Vfs> = Once::new();
+
+/// Builds the tree, with an empty tmpfs at `/`. The frame allocator must
+/// be set up.
+pub fn init() -> &'static Vfs
+{
+  let free = FRAMES.lock().as_ref().map_or(0, |frames| frames.free_frames());
+  let root: Shared<dyn FileSystem> = Shared::new(Tmpfs::new(free / ROOT_SHARE * PAGE_SIZE));
+  VFS.call_once(|| Vfs::new(root))
+}
+
+/// The tree `init` built.
+#[inline]
+pub fn vfs() -> &'static Vfs
+{
+  VFS.get().expect("the VFS is not initialised")
+}
+
+/// Where disk `index` is mounted: `/mnt/disk<index>`.
+fn mount_point(index: usize) -> String
+{
+  let mut path = String::from("/mnt/disk");
+  if index >= 10 {
+    path.push(char::from_digit((index / 10) as u32, 10).unwrap_or('?'));
+  }
+  path.push(char::from_digit((index % 10) as u32, 10).unwrap_or('?'));
+  path
+}
+
+/// The file system on `disk`, or `Invalid` if it has none the kernel
+/// knows.
+fn probe(disk: Shared<dyn BlockDevice>) -> FsResult<Shared<dyn FileSystem>>
+{
+  match Fat::new(disk.clone()) {
+    Ok(fat) => Ok(Shared::new(fat)),
+    Err(FsError::Invalid) => Ok(Shared::new(Ext2::new(disk)?)),
+    Err(e) => Err(e),
+  }
+}
+
+/// Mounts the file system on each disk registered, if it is one the
+/// kernel knows, at `/mnt/disk<index>`, through the buffer cache.
+pub fn mount_disks()
+{
+  let vfs = vfs();
+  match vfs.mkdir("/mnt", 0o755) {
+    Ok(()) | Err(FsError::Exists) => {}
+    Err(e) => {
+      println!("/mnt: {}", e);
+      return;
+    }
+  }
+
+  for index in 0..block::MAX_DISKS {
+    let disk: Shared<dyn BlockDevice> = match block::cached(index) {
+      Some(disk) => Shared::new(disk),
+      None => continue,
+    };
+    let fs = match probe(disk) {
+      Ok(fs) => fs,
+      Err(FsError::Invalid) => continue,
+      Err(e) => {
+        println!("disk {}: {}", index, e);
+        continue;
+      }
+    };
+
+    let path = mount_point(index);
+    let name = fs.name();
+    let mounted = vfs.mkdir(&path, 0o755).and_then(|_| vfs.mount(&path, fs));
+    match mounted {
+      Ok(_) => println!("disk {}: {} mounted at {}", index, name, path),
+      Err(e) => println!("disk {}: cannot mount at {}: {}", index, path, e),
+    }
+  }
+}
@@ -15,7 +15,7 @@ use rand::SeedableRng;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 use syn::{
-  parse, spanned::Spanned, Ident, ItemFn, ReturnType, Type, Visibility,
+  parse, spanned::Spanned, Expr, FnArg, Ident, ItemFn, ReturnType, Type, Visibility,
 };
 
 static CALL_COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -32,7 +32,9 @@ use proc_macro::TokenStream;
 /// The specified function will be called by the reset handler *after* RAM has been initialized.
 /// If present, the FPU will also be enabled before the function is called.
 ///
-/// The type of the specified function must be `[unsafe] fn() -> !` (never ending function)
+/// The type of the specified function must be `[unsafe] fn() -> !` (never ending function), or
+/// `[unsafe] fn(dtb: usize) -> !` to receive the physical address of the device tree blob the
+/// firmware passed in `a1`.
 ///
 /// # Properties
 ///
@@ -53,6 +55,19 @@ use proc_macro::TokenStream;
 ///     }
 /// }
 /// ```
+///
+/// - Entry point which inspects the device tree
+///
+/// ``` no_run
+/// # #![no_main]
+/// # use t_macros::entry;
+/// #[entry]
+/// fn main(dtb: usize) -> ! {
+///     loop {
+///         /* .. */
+///     }
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
   let f = parse_macro_input!(input as ItemFn);
@@ -62,7 +77,7 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
       && f.sig.asyncness.is_none()
       && f.vis == Visibility::Inherited
       && f.sig.abi.is_none()
-      && f.sig.inputs.is_empty()
+      && f.sig.inputs.len() <= 1
       && f.sig.generics.params.is_empty()
       && f.sig.generics.where_clause.is_none()
       && f.sig.variadic.is_none()
@@ -77,7 +92,7 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
   if !valid_signature {
     return parse::Error::new(
       f.span(),
-      "`#[entry]` function must have signature `[unsafe] fn() -> !`",
+      "`#[entry]` function must have signature `[unsafe] fn() -> !` or `[unsafe] fn(usize) -> !`",
     )
         .to_compile_error()
         .into();
@@ -95,10 +110,97 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
   let hash = random_ident();
   let stmts = f.block.stmts;
 
+  // `_start_rust` always passes the device tree pointer; an entry point
+  // which doesn't want it simply ignores it.
+  let dtb = match f.sig.inputs.first() {
+    Some(arg) => quote!(#arg),
+    None => quote!(_: usize),
+  };
+
   quote!(
         #[export_name = "kmain"]
         #(#attrs)*
-        pub #unsafety fn #hash() -> ! {
+        pub #unsafety fn #hash(#dtb) -> ! {
+            #(#stmts)*
+        }
+    )
+      .into()
+}
+
+/// Attribute to declare the entry point of the secondary harts
+///
+/// **IMPORTANT**: This attribute can appear at most *once* in the dependency graph. Without it,
+/// secondary harts stop again as soon as they are started.
+///
+/// Each hart other than the boot hart calls the specified function once
+/// `boot::smp::start_secondaries` has started it, on its own stack and with its per-hart data set
+/// up, but with paging off and every interrupt masked.
+///
+/// The type of the specified function must be `[unsafe] fn() -> !` (never ending function), or
+/// `[unsafe] fn(hartid: usize) -> !` to receive the id of the hart.
+///
+/// # Examples
+///
+/// ``` no_run
+/// # #![no_main]
+/// # use t_macros::secondary_entry;
+/// #[secondary_entry]
+/// fn kmain_secondary(hartid: usize) -> ! {
+///     loop {
+///         /* .. */
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn secondary_entry(args: TokenStream, input: TokenStream) -> TokenStream {
+  let f = parse_macro_input!(input as ItemFn);
+
+  // check the function signature
+  let valid_signature = f.sig.constness.is_none()
+      && f.sig.asyncness.is_none()
+      && f.vis == Visibility::Inherited
+      && f.sig.abi.is_none()
+      && f.sig.inputs.len() <= 1
+      && f.sig.generics.params.is_empty()
+      && f.sig.generics.where_clause.is_none()
+      && f.sig.variadic.is_none()
+      && match f.sig.output {
+    ReturnType::Default => false,
+    ReturnType::Type(_, ref ty) => match **ty {
+      Type::Never(_) => true,
+      _ => false,
+    },
+  };
+
+  if !valid_signature {
+    return parse::Error::new(
+      f.span(),
+      "`#[secondary_entry]` function must have signature `[unsafe] fn() -> !` or `[unsafe] fn(usize) -> !`",
+    )
+        .to_compile_error()
+        .into();
+  }
+
+  if !args.is_empty() {
+    return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+        .to_compile_error()
+        .into();
+  }
+
+  let attrs = f.attrs;
+  let unsafety = f.sig.unsafety;
+  let hash = random_ident();
+  let stmts = f.block.stmts;
+
+  let hartid = match f.sig.inputs.first() {
+    Some(arg) => quote!(#arg),
+    None => quote!(_: usize),
+  };
+
+  quote!(
+        #[export_name = "kmain_secondary"]
+        #(#attrs)*
+        pub #unsafety fn #hash(#hartid) -> ! {
             #(#stmts)*
         }
     )
@@ -179,6 +281,92 @@ pub fn pre_init(args: TokenStream, input: TokenStream) -> TokenStream {
       .into()
 }
 
+/// The most arguments a system call can take: `a0`..`a5`.
+const MAX_SYSCALL_ARGS: usize = 6;
+
+/// Attribute to declare a system call
+///
+/// The argument is the call's number, any constant expression of type `usize`. The function is
+/// left as it is; the attribute adds an entry for it to the `.syscalls` section, where the
+/// kernel's dispatcher finds it, with a wrapper which decodes `a0`..`a5` into its parameters
+/// with `crate::syscall::FromArg`, failing the call if any of them is invalid.
+///
+/// The function must be an ordinary `fn` of at most six parameters, each of a type implementing
+/// `FromArg`, returning `crate::syscall::SysResult`. Each number may only be used once.
+///
+/// # Examples
+///
+/// ``` ignore
+/// #[syscall(number::GETPID)]
+/// fn getpid() -> SysResult {
+///     Ok(current()?.0)
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn syscall(args: TokenStream, input: TokenStream) -> TokenStream {
+  let f = parse_macro_input!(input as ItemFn);
+
+  if args.is_empty() {
+    return parse::Error::new(Span::call_site(), "`#[syscall]` needs the number of the call")
+        .to_compile_error()
+        .into();
+  }
+  let number = parse_macro_input!(args as Expr);
+
+  // check the function signature
+  let valid_signature = f.sig.constness.is_none()
+      && f.sig.asyncness.is_none()
+      && f.sig.unsafety.is_none()
+      && f.sig.abi.is_none()
+      && f.sig.inputs.len() <= MAX_SYSCALL_ARGS
+      && f.sig.inputs.iter().all(|arg| match arg {
+    FnArg::Typed(_) => true,
+    FnArg::Receiver(_) => false,
+  })
+      && f.sig.generics.params.is_empty()
+      && f.sig.generics.where_clause.is_none()
+      && f.sig.variadic.is_none()
+      && match f.sig.output {
+    ReturnType::Default => false,
+    ReturnType::Type(..) => true,
+  };
+
+  if !valid_signature {
+    return parse::Error::new(
+      f.span(),
+      "`#[syscall]` function must have signature `fn(..) -> SysResult`, with at most six parameters",
+    )
+        .to_compile_error()
+        .into();
+  }
+
+  let ident = f.sig.ident.clone();
+  let name = ident.to_string();
+  let hash = random_ident();
+  let decoded = (0..f.sig.inputs.len())
+      .map(|i| quote!(crate::syscall::FromArg::from_arg(args[#i])?));
+  let args = if f.sig.inputs.is_empty() { quote!(_) } else { quote!(args) };
+
+  quote!(
+        #f
+
+        #[used]
+        #[allow(non_upper_case_globals)]
+        #[link_section = ".syscalls"]
+        static #hash: crate::syscall::Syscall = crate::syscall::Syscall {
+            number: #number,
+            name: #name,
+            handler: {
+                fn decode(#args: &crate::syscall::Args) -> crate::syscall::SysResult {
+                    #ident(#(#decoded),*)
+                }
+                decode
+            },
+        };
+    )
+      .into()
+}
+
 // Creates a random identifier
 fn random_ident() -> Ident {
   let secs = SystemTime::now()